@@ -0,0 +1,282 @@
+// ArchiveFS :: A read-only filesystem exposing the contents of a tar archive.
+//
+// Implemented using fuse_mt::FilesystemMT.
+//
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use fuse_mt::*;
+
+use crate::tar::{self, Member, MemberKind};
+
+// The archive can't change underneath us, so the kernel can cache everything for a good while.
+const TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Node {
+    attr: FileAttr,
+    /// Offset of the file's data within the archive.
+    offset: u64,
+    /// Target of a symbolic link.
+    link: Option<PathBuf>,
+    children: BTreeMap<OsString, FileType>,
+}
+
+impl Node {
+    fn directory(mtime: SystemTime) -> Node {
+        Node {
+            attr: FileAttr {
+                size: 0,
+                blocks: 0,
+                atime: mtime,
+                mtime,
+                ctime: mtime,
+                crtime: mtime,
+                kind: FileType::Directory,
+                perm: 0o755,
+                nlink: 2,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+                flags: 0,
+            },
+            offset: 0,
+            link: None,
+            children: BTreeMap::new(),
+        }
+    }
+}
+
+fn member_attr(member: &Member) -> FileAttr {
+    let kind = match member.kind {
+        MemberKind::File | MemberKind::HardLink(_) => FileType::RegularFile,
+        MemberKind::Symlink(_) => FileType::Symlink,
+        MemberKind::CharDevice => FileType::CharDevice,
+        MemberKind::BlockDevice => FileType::BlockDevice,
+        MemberKind::Directory => FileType::Directory,
+        MemberKind::Fifo => FileType::NamedPipe,
+    };
+    let size = match member.kind {
+        MemberKind::File => member.size,
+        MemberKind::Symlink(ref target) => target.as_os_str().len() as u64,
+        _ => 0,
+    };
+    let mtime = if member.mtime >= 0 {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(member.mtime as u64)
+    } else {
+        SystemTime::UNIX_EPOCH - Duration::from_secs(member.mtime.unsigned_abs())
+    };
+    FileAttr {
+        size,
+        blocks: size.div_ceil(512),
+        atime: mtime,
+        mtime,
+        ctime: mtime,
+        crtime: mtime,
+        kind,
+        perm: (member.mode & 0o7777) as u16,
+        nlink: if kind == FileType::Directory { 2 } else { 1 },
+        uid: member.uid,
+        gid: member.gid,
+        rdev: member.rdev,
+        flags: 0,
+    }
+}
+
+pub struct ArchiveFS {
+    archive: File,
+    nodes: HashMap<PathBuf, Node>,
+}
+
+impl ArchiveFS {
+    pub fn new(archive: File) -> io::Result<ArchiveFS> {
+        let archive_mtime = archive.metadata()?.modified()?;
+        let members = tar::read_members(&archive)?;
+        info!("archive has {} members", members.len());
+
+        let mut nodes = HashMap::new();
+        nodes.insert(PathBuf::from("/"), Node::directory(archive_mtime));
+
+        let mut hard_links = vec![];
+        for member in &members {
+            let path = Path::new("/").join(&member.path);
+            let attr = member_attr(member);
+
+            // Archives aren't required to have entries for every directory, so create any missing
+            // parents as we go.
+            let mut child = path.as_path();
+            while let Some(parent) = child.parent() {
+                let name = child.file_name().unwrap().to_owned();
+                let kind = if child == path { attr.kind } else { FileType::Directory };
+                let parent_node = nodes.entry(parent.to_owned())
+                    .or_insert_with(|| Node::directory(archive_mtime));
+                if parent_node.children.insert(name, kind).is_some() {
+                    break;
+                }
+                child = parent;
+            }
+
+            if let MemberKind::HardLink(ref target) = member.kind {
+                hard_links.push((path.clone(), Path::new("/").join(target)));
+            }
+
+            // Later members replace earlier ones with the same path, same as when extracting,
+            // but a directory keeps the children it has accumulated so far.
+            let children = nodes.remove(&path).map(|node| node.children).unwrap_or_default();
+            nodes.insert(path, Node {
+                attr,
+                offset: member.offset,
+                link: match member.kind {
+                    MemberKind::Symlink(ref target) => Some(target.clone()),
+                    _ => None,
+                },
+                children,
+            });
+        }
+
+        for (path, target) in hard_links {
+            let (attr, offset) = match nodes.get_mut(&target) {
+                Some(node) if node.attr.kind == FileType::RegularFile => {
+                    node.attr.nlink += 1;
+                    (node.attr, node.offset)
+                }
+                _ => {
+                    warn!("hard link {:?} has a bad target {:?}", path, target);
+                    continue;
+                }
+            };
+            let node = nodes.get_mut(&path).unwrap();
+            node.attr = attr;
+            node.offset = offset;
+        }
+
+        // Each subdirectory's ".." entry is a link to its parent.
+        let subdir_counts: Vec<(PathBuf, u32)> = nodes.iter()
+            .map(|(path, node)| {
+                let subdirs = node.children.values().filter(|k| **k == FileType::Directory).count();
+                (path.clone(), subdirs as u32)
+            })
+            .collect();
+        for (path, subdirs) in subdir_counts {
+            nodes.get_mut(&path).unwrap().attr.nlink += subdirs;
+        }
+
+        Ok(ArchiveFS { archive, nodes })
+    }
+
+    fn node(&self, path: &Path) -> Result<&Node, libc::c_int> {
+        self.nodes.get(path).ok_or(libc::ENOENT)
+    }
+}
+
+impl FilesystemMT<'_> for ArchiveFS {
+    fn getattr(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
+        debug!("getattr: {:?}", path);
+        Ok((TTL, self.node(path)?.attr))
+    }
+
+    fn readlink(&self, _req: RequestInfo, path: &Path) -> ResultData {
+        debug!("readlink: {:?}", path);
+        match self.node(path)?.link {
+            Some(ref target) => Ok(target.as_os_str().as_bytes().to_vec()),
+            None => Err(libc::EINVAL),
+        }
+    }
+
+    fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        debug!("open: {:?} flags={:#x}", path, flags);
+        let node = self.node(path)?;
+        if node.attr.kind != FileType::RegularFile {
+            return Err(libc::EINVAL);
+        }
+        if flags & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32 {
+            return Err(libc::EROFS);
+        }
+        Ok((0, 0))
+    }
+
+    fn read(&self, _req: RequestInfo, path: &Path, _fh: u64, offset: u64, size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
+        debug!("read: {:?} {:#x} @ {:#x}", path, size, offset);
+        let node = match self.node(path) {
+            Ok(node) => node,
+            Err(e) => return callback(Err(e)),
+        };
+
+        // Reads past the end of the file return nothing; otherwise, don't run past the end of the
+        // member into whatever follows it in the archive.
+        let file_size = node.attr.size;
+        if offset >= file_size {
+            return callback(Ok(&[]));
+        }
+        let len = std::cmp::min(u64::from(size), file_size - offset) as usize;
+
+        let mut data = vec![0u8; len];
+        if let Err(e) = self.archive.read_exact_at(&mut data, node.offset + offset) {
+            error!("read {:?}, {:#x} @ {:#x}: {}", path, size, offset, e);
+            return callback(Err(e.raw_os_error().unwrap_or(libc::EIO)));
+        }
+
+        callback(Ok(&data))
+    }
+
+    fn release(&self, _req: RequestInfo, _path: &Path, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
+        Ok(())
+    }
+
+    fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
+        debug!("opendir: {:?}", path);
+        match self.node(path)?.attr.kind {
+            FileType::Directory => Ok((0, 0)),
+            _ => Err(libc::ENOTDIR),
+        }
+    }
+
+    fn readdir(&self, _req: RequestInfo, path: &Path, _fh: u64) -> ResultReaddir {
+        debug!("readdir: {:?}", path);
+        let node = self.node(path)?;
+        let mut entries = vec![
+            DirectoryEntry { name: ".".into(), kind: FileType::Directory },
+            DirectoryEntry { name: "..".into(), kind: FileType::Directory },
+        ];
+        entries.extend(node.children.iter().map(|(name, kind)| {
+            DirectoryEntry { name: name.clone(), kind: *kind }
+        }));
+        Ok(entries)
+    }
+
+    fn releasedir(&self, _req: RequestInfo, _path: &Path, _fh: u64, _flags: u32) -> ResultEmpty {
+        Ok(())
+    }
+
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        let blocks = self.nodes.values()
+            .filter(|node| node.attr.kind == FileType::RegularFile)
+            .map(|node| node.attr.blocks)
+            .sum();
+        Ok(Statfs {
+            blocks,
+            bfree: 0,
+            bavail: 0,
+            files: self.nodes.len() as u64,
+            ffree: 0,
+            bsize: 512,
+            namelen: 255,
+            frsize: 512,
+        })
+    }
+
+    fn access(&self, _req: RequestInfo, path: &Path, mask: u32) -> ResultEmpty {
+        self.node(path)?;
+        if mask & libc::W_OK as u32 != 0 {
+            return Err(libc::EROFS);
+        }
+        Ok(())
+    }
+}
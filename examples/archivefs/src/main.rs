@@ -0,0 +1,64 @@
+// Main Entry Point :: A fuse_mt sample program that mounts a tar archive.
+//
+
+#![deny(rust_2018_idioms)]
+
+use std::env;
+use std::ffi::OsString;
+use std::fs::File;
+
+#[macro_use]
+extern crate log;
+
+mod archivefs;
+mod tar;
+
+struct ConsoleLogger;
+
+impl log::Log for ConsoleLogger {
+    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        println!("{}: {}: {}", record.target(), record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: ConsoleLogger = ConsoleLogger;
+
+fn main() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Debug);
+
+    let args: Vec<OsString> = env::args_os().collect();
+
+    if args.len() != 3 {
+        println!("usage: {} <archive.tar> <mountpoint>", &env::args().next().unwrap());
+        std::process::exit(-1);
+    }
+
+    let archive = match File::open(&args[1]) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("failed to open {:?}: {}", args[1], e);
+            std::process::exit(1);
+        }
+    };
+
+    let filesystem = match archivefs::ArchiveFS::new(archive) {
+        Ok(fs) => fs,
+        Err(e) => {
+            println!("failed to read {:?}: {}", args[1], e);
+            std::process::exit(1);
+        }
+    };
+
+    let options = fuse_mt::MountOptions::new()
+        .option(fuse_mt::MountOption::FSName("archivefs".to_owned()))
+        .option(fuse_mt::MountOption::RO);
+
+    fuse_mt::mount_with_options(fuse_mt::FuseMT::new(filesystem, 1), &args[2], &options).unwrap();
+}
@@ -0,0 +1,235 @@
+// Tar :: A minimal reader for the headers of a tar archive.
+//
+// Only the metadata is parsed; file contents are left in place in the archive and are located by
+// their offset.
+//
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+const BLOCK_SIZE: u64 = 512;
+
+/// The kind of a tar archive member.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemberKind {
+    File,
+    HardLink(PathBuf),
+    Symlink(PathBuf),
+    CharDevice,
+    BlockDevice,
+    Directory,
+    Fifo,
+}
+
+/// A member of a tar archive.
+#[derive(Clone, Debug)]
+pub struct Member {
+    /// Path of the member, relative to the root of the archive.
+    pub path: PathBuf,
+    pub kind: MemberKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Modification time, in seconds since the epoch.
+    pub mtime: i64,
+    /// Size of the member's data.
+    pub size: u64,
+    /// Offset of the member's data within the archive.
+    pub offset: u64,
+    pub rdev: u32,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parse a numeric header field: either NUL/space-terminated octal, or the GNU base-256
+/// extension for values too large for octal.
+fn parse_number(field: &[u8]) -> io::Result<u64> {
+    if let Some(&first) = field.first() {
+        if first & 0x80 != 0 {
+            let mut value = u64::from(first & 0x7f);
+            for &b in &field[1..] {
+                value = (value << 8) | u64::from(b);
+            }
+            return Ok(value);
+        }
+    }
+
+    let mut value = 0u64;
+    for &b in field.iter().skip_while(|b| **b == b' ') {
+        match b {
+            b'0' ..= b'7' => value = value * 8 + u64::from(b - b'0'),
+            b'\0' | b' ' => break,
+            _ => return Err(invalid(format!("bad numeric field in tar header: {:?}", field))),
+        }
+    }
+    Ok(value)
+}
+
+/// Get the bytes of a NUL-terminated string header field.
+fn parse_string(field: &[u8]) -> &[u8] {
+    match field.iter().position(|b| *b == b'\0') {
+        Some(end) => &field[..end],
+        None => field,
+    }
+}
+
+/// Strip any trailing NUL or newline from a long name or pax value.
+fn trim_name(mut name: Vec<u8>) -> Vec<u8> {
+    while let Some(b'\0') | Some(b'\n') = name.last() {
+        name.pop();
+    }
+    name
+}
+
+/// Parse the records of a pax extended header, returning the path and link path, if present.
+fn parse_pax(data: &[u8]) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+    let mut path = None;
+    let mut linkpath = None;
+    let mut rest = data;
+    // Each record is "<length> <key>=<value>\n", where length includes the whole record.
+    while let Some(space) = rest.iter().position(|b| *b == b' ') {
+        let len = match std::str::from_utf8(&rest[..space]).ok().and_then(|s| s.parse::<usize>().ok()) {
+            Some(len) if len > space && len <= rest.len() => len,
+            _ => break,
+        };
+        let record = &rest[space + 1 .. len];
+        if let Some(eq) = record.iter().position(|b| *b == b'=') {
+            let value = trim_name(record[eq + 1 ..].to_vec());
+            match &record[..eq] {
+                b"path" => path = Some(value),
+                b"linkpath" => linkpath = Some(value),
+                _ => (),
+            }
+        }
+        rest = &rest[len..];
+    }
+    (path, linkpath)
+}
+
+/// Normalize a path from the archive to be relative and free of `.` components.
+fn member_path(name: Vec<u8>) -> PathBuf {
+    PathBuf::from(OsString::from_vec(name))
+        .components()
+        .filter(|c| matches!(c, std::path::Component::Normal(_)))
+        .collect()
+}
+
+/// Read all the member headers from the given tar archive.
+pub fn read_members(file: &File) -> io::Result<Vec<Member>> {
+    let mut members = vec![];
+    let mut offset = 0u64;
+    let mut long_name: Option<Vec<u8>> = None;
+    let mut long_link: Option<Vec<u8>> = None;
+    let mut header = [0u8; BLOCK_SIZE as usize];
+    let archive_len = file.metadata()?.len();
+
+    loop {
+        if let Err(e) = file.read_exact_at(&mut header, offset) {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                // Some writers omit the end-of-archive marker.
+                break;
+            }
+            return Err(e);
+        }
+
+        if header.iter().all(|b| *b == 0) {
+            // End of archive.
+            break;
+        }
+
+        let checksum = parse_number(&header[148..156])?;
+        let computed: u64 = header.iter().enumerate()
+            .map(|(i, b)| if (148..156).contains(&i) { u64::from(b' ') } else { u64::from(*b) })
+            .sum();
+        if checksum != computed {
+            return Err(invalid(format!("bad tar header checksum at offset {:#x}", offset)));
+        }
+
+        let size = parse_number(&header[124..136])?;
+        let data_offset = offset + BLOCK_SIZE;
+        offset = data_offset.saturating_add(size.div_ceil(BLOCK_SIZE).saturating_mul(BLOCK_SIZE));
+
+        let read_data = || -> io::Result<Vec<u8>> {
+            // The size comes from the archive, so check it before allocating for it.
+            if size > archive_len.saturating_sub(data_offset) {
+                return Err(invalid(format!("tar member at offset {:#x} runs past the end of the archive",
+                    data_offset - BLOCK_SIZE)));
+            }
+            let mut data = vec![0u8; size as usize];
+            file.read_exact_at(&mut data, data_offset)?;
+            Ok(data)
+        };
+
+        let typeflag = header[156];
+        match typeflag {
+            b'L' => {
+                long_name = Some(trim_name(read_data()?));
+                continue;
+            }
+            b'K' => {
+                long_link = Some(trim_name(read_data()?));
+                continue;
+            }
+            b'x' => {
+                let (path, linkpath) = parse_pax(&read_data()?);
+                long_name = path.or(long_name);
+                long_link = linkpath.or(long_link);
+                continue;
+            }
+            b'g' => continue,
+            _ => (),
+        }
+
+        let name = long_name.take().unwrap_or_else(|| {
+            let name = parse_string(&header[0..100]);
+            let prefix = parse_string(&header[345..500]);
+            if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                [prefix, b"/", name].concat()
+            } else {
+                name.to_vec()
+            }
+        });
+        let link = long_link.take()
+            .unwrap_or_else(|| parse_string(&header[157..257]).to_vec());
+
+        let kind = match typeflag {
+            b'0' | b'\0' | b'7' => {
+                if name.ends_with(b"/") { MemberKind::Directory } else { MemberKind::File }
+            }
+            b'1' => MemberKind::HardLink(member_path(link)),
+            b'2' => MemberKind::Symlink(PathBuf::from(OsString::from_vec(link))),
+            b'3' => MemberKind::CharDevice,
+            b'4' => MemberKind::BlockDevice,
+            b'5' => MemberKind::Directory,
+            b'6' => MemberKind::Fifo,
+            other => {
+                warn!("skipping tar member {:?} of unknown type {:?}",
+                    OsString::from_vec(name), other as char);
+                continue;
+            }
+        };
+
+        let major = parse_number(&header[329..337])? as u32;
+        let minor = parse_number(&header[337..345])? as u32;
+
+        members.push(Member {
+            path: member_path(name),
+            kind,
+            mode: parse_number(&header[100..108])? as u32,
+            uid: parse_number(&header[108..116])? as u32,
+            gid: parse_number(&header[116..124])? as u32,
+            mtime: parse_number(&header[136..148])? as i64,
+            size,
+            offset: data_offset,
+            rdev: (major << 8) | (minor & 0xff),
+        });
+    }
+
+    Ok(members)
+}
@@ -0,0 +1,218 @@
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fuse_mt::{CallbackResult, DirectoryEntry, FileAttr, FilesystemMT, FileType, OpSet, ReadCache, RequestInfo, ResultEntry, ResultOpen, ResultReaddir, ResultSlice, ResultStatfs, Statfs};
+
+/// A flat directory of generated files, served as if from a slow and unreliable remote store.
+struct ChaosFS {
+    files: u64,
+    file_size: u64,
+    latency: Duration,
+    jitter: Duration,
+    error_rate: f64,
+    rng: AtomicU64,
+    bootup: SystemTime,
+}
+
+impl ChaosFS {
+    const TTL: Duration = Duration::from_secs(1);
+
+    fn from_env() -> ChaosFS {
+        ChaosFS {
+            files: env_var("CHAOSFS_FILES", 16),
+            file_size: env_var("CHAOSFS_FILE_SIZE", 1 << 20),
+            latency: Duration::from_millis(env_var("CHAOSFS_LATENCY_MS", 50)),
+            jitter: Duration::from_millis(env_var("CHAOSFS_JITTER_MS", 0)),
+            error_rate: env_var("CHAOSFS_ERROR_RATE", 0.),
+            rng: AtomicU64::new(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64 | 1),
+            bootup: SystemTime::now(),
+        }
+    }
+
+    /// A random number in [0, 1), from a xorshift generator shared by all threads.
+    fn random(&self) -> f64 {
+        let mut x = self.rng.load(Ordering::Relaxed);
+        loop {
+            let mut next = x;
+            next ^= next << 13;
+            next ^= next >> 7;
+            next ^= next << 17;
+            match self.rng.compare_exchange_weak(x, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return (next >> 11) as f64 / (1u64 << 53) as f64,
+                Err(current) => x = current,
+            }
+        }
+    }
+
+    /// Make a round trip to the "backend": wait, and then maybe fail.
+    fn round_trip(&self, op: &str) -> Result<(), libc::c_int> {
+        std::thread::sleep(self.latency + self.jitter.mul_f64(self.random()));
+        if self.random() < self.error_rate {
+            log::warn!("{}: injecting EIO", op);
+            return Err(libc::EIO);
+        }
+        Ok(())
+    }
+
+    /// The number of the file at a path, if there is one.
+    fn file_index(&self, path: &Path) -> Option<u64> {
+        let name = path.strip_prefix("/").ok()?.to_str()?;
+        let index: u64 = name.strip_prefix("file")?.parse().ok()?;
+        if index < self.files && name == format!("file{}", index) {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn attr(&self, kind: FileType, size: u64) -> FileAttr {
+        FileAttr {
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.bootup,
+            mtime: self.bootup,
+            ctime: self.bootup,
+            crtime: self.bootup,
+            kind,
+            perm: if kind == FileType::Directory { 0o555 } else { 0o444 },
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            rdev: 0,
+            flags: 0,
+        }
+    }
+}
+
+/// The byte at an offset in a file: a pattern which differs between files, so that data served
+/// from the wrong place is easy to spot.
+fn content(index: u64, offset: u64) -> u8 {
+    ((offset + index * 7) % 251) as u8
+}
+
+fn env_var<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("invalid value for {}: {:?}", name, value);
+            std::process::exit(-1);
+        }),
+        Err(_) => default,
+    }
+}
+
+impl FilesystemMT<'_> for ChaosFS {
+    fn capabilities(&self) -> OpSet {
+        OpSet::GETATTR | OpSet::OPEN | OpSet::READ | OpSet::RELEASE | OpSet::OPENDIR | OpSet::READDIR
+            | OpSet::RELEASEDIR | OpSet::STATFS
+    }
+
+    fn getattr(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
+        self.round_trip("getattr")?;
+        if path == Path::new("/") {
+            Ok((Self::TTL, self.attr(FileType::Directory, 0)))
+        } else if self.file_index(path).is_some() {
+            Ok((Self::TTL, self.attr(FileType::RegularFile, self.file_size)))
+        } else {
+            Err(libc::ENOENT)
+        }
+    }
+
+    fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        if flags as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(libc::EROFS);
+        }
+        match self.file_index(path) {
+            Some(index) => Ok((index, 0)),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    fn read(&self, _req: RequestInfo, _path: &Path, fh: u64, offset: u64, size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
+        if let Err(e) = self.round_trip("read") {
+            return callback(Err(e));
+        }
+        let end = self.file_size.min(offset.saturating_add(u64::from(size)));
+        let data: Vec<u8> = (offset.min(end) .. end).map(|offset| content(fh, offset)).collect();
+        callback(Ok(&data))
+    }
+
+    fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
+        if path == Path::new("/") {
+            Ok((0, 0))
+        } else {
+            Err(libc::ENOTDIR)
+        }
+    }
+
+    fn readdir(&self, _req: RequestInfo, _path: &Path, _fh: u64) -> ResultReaddir {
+        self.round_trip("readdir")?;
+        let mut entries = vec![
+            DirectoryEntry { name: ".".into(), kind: FileType::Directory },
+            DirectoryEntry { name: "..".into(), kind: FileType::Directory },
+        ];
+        entries.extend((0 .. self.files).map(|index| DirectoryEntry {
+            name: format!("file{}", index).into(),
+            kind: FileType::RegularFile,
+        }));
+        Ok(entries)
+    }
+
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        Ok(Statfs {
+            blocks: self.files * self.file_size.div_ceil(512),
+            bfree: 0,
+            bavail: 0,
+            files: self.files + 1,
+            ffree: 0,
+            bsize: 512,
+            namelen: 255,
+            frsize: 512,
+        })
+    }
+}
+
+struct ConsoleLogger;
+
+impl log::Log for ConsoleLogger {
+    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        println!("{}: {}: {}", record.target(), record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: ConsoleLogger = ConsoleLogger;
+
+fn main() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+
+    let args: Vec<OsString> = env::args_os().collect();
+
+    if args.len() != 2 {
+        println!("usage: {} <mountpoint>", &env::args().next().unwrap());
+        std::process::exit(-1);
+    }
+
+    let filesystem = ChaosFS::from_env();
+    let threads = env_var("CHAOSFS_THREADS", 8);
+    let fuse_args = [OsStr::new("-o"), OsStr::new("fsname=chaosfs"), OsStr::new("-o"), OsStr::new("ro")];
+
+    match env::var("CHAOSFS_CACHE_BYTES") {
+        Ok(_) => {
+            let filesystem = ReadCache::new(filesystem, env_var("CHAOSFS_CACHE_BYTES", 0), 128 << 10);
+            fuse_mt::mount(fuse_mt::FuseMT::new(filesystem, threads), &args[1], &fuse_args[..]).unwrap();
+        }
+        Err(_) => {
+            fuse_mt::mount(fuse_mt::FuseMT::new(filesystem, threads), &args[1], &fuse_args[..]).unwrap();
+        }
+    }
+}
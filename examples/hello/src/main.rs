@@ -2,6 +2,7 @@ use std::env;
 use std::ffi::{OsStr, OsString};
 use std::time::{Duration, SystemTime};
 
+use fuse_mt::helpers::read_from_slice;
 use fuse_mt::{CallbackResult, DirectoryEntry, FileAttr, FilesystemMT, FileType, Inode, RawFileAttr, RawFilesystemMT, RequestInfo, ResultEmpty, ResultEntry, ResultInode, ResultOpen, ResultReaddir, ResultSlice, ResultStatfs, Statfs};
 
 #[derive(Debug)]
@@ -18,19 +19,10 @@ impl HelloFS {
 
     fn root_attr(&self, uid: u32, gid: u32) -> FileAttr {
         FileAttr {
-            size: 0,
-            blocks: 0,
-            atime: self.bootup,
-            mtime: self.bootup,
-            ctime: self.bootup,
-            crtime: self.bootup,
             kind: FileType::Directory,
             perm: 0o755,
             nlink: 2,
-            uid,
-            gid,
-            rdev: 0,
-            flags: 0,
+            ..self.attr(uid, gid)
         }
     }
 
@@ -38,17 +30,22 @@ impl HelloFS {
         FileAttr {
             size: Self::HELLO_CONTENT.len() as u64,
             blocks: 1,
+            kind: FileType::RegularFile,
+            perm: 0o755,
+            ..self.attr(uid, gid)
+        }
+    }
+
+    /// The attributes all the files have in common.
+    fn attr(&self, uid: u32, gid: u32) -> FileAttr {
+        FileAttr {
             atime: self.bootup,
             mtime: self.bootup,
             ctime: self.bootup,
             crtime: self.bootup,
-            kind: FileType::RegularFile,
-            perm: 0o755,
-            nlink: 1,
             uid,
             gid,
-            rdev: 0,
-            flags: 0,
+            ..FileAttr::default()
         }
     }
 }
@@ -71,10 +68,7 @@ impl FilesystemMT<'_, Inode, RawFileAttr> for HelloFS {
 
     fn read(&self, _req: RequestInfo, path: Inode, _fh: u64, offset: u64, size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
         let result = if path == Self::HELLO_INODE {
-            let start = offset as usize;
-            let end = start + size as usize;
-
-            Ok(&Self::HELLO_CONTENT[start..end])
+            Ok(read_from_slice(Self::HELLO_CONTENT, offset, size))
         } else {
             Err(libc::ENOENT)
         };
@@ -105,7 +99,7 @@ impl FilesystemMT<'_, Inode, RawFileAttr> for HelloFS {
              },
              DirectoryEntry {
                  name: "hello.txt".into(),
-                 kind: FileType::Directory,
+                 kind: FileType::RegularFile,
              }
         ])
     }
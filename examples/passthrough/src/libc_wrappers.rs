@@ -91,6 +91,8 @@ pub fn lstat(path: OsString) -> Result<libc::stat64, libc::c_int> {
     Ok(buf)
 }
 
+// Only the macOS futimens shim in libc_extras still needs the raw stat.
+#[cfg(target_os = "macos")]
 pub fn fstat(fd: u64) -> Result<libc::stat64, libc::c_int> {
     let mut buf: libc::stat64 = unsafe { mem::zeroed() };
     if -1 == unsafe { libc::fstat64(fd as libc::c_int, &mut buf) } {
@@ -23,49 +23,18 @@ pub struct PassthroughFS {
     pub target: OsString,
 }
 
-fn mode_to_filetype(mode: libc::mode_t) -> FileType {
-    match mode & libc::S_IFMT {
-        libc::S_IFDIR => FileType::Directory,
-        libc::S_IFREG => FileType::RegularFile,
-        libc::S_IFLNK => FileType::Symlink,
-        libc::S_IFBLK => FileType::BlockDevice,
-        libc::S_IFCHR => FileType::CharDevice,
-        libc::S_IFIFO  => FileType::NamedPipe,
-        libc::S_IFSOCK => FileType::Socket,
-        _ => { panic!("unknown file type"); }
-    }
+fn lstat_attr(path: impl AsRef<Path>) -> Result<FileAttr, libc::c_int> {
+    fs::symlink_metadata(path)
+        .map(|metadata| FileAttr::from(&metadata))
+        .map_err(|e| e.raw_os_error().unwrap())
 }
 
-fn stat_to_fuse(stat: libc::stat64) -> FileAttr {
-    // st_mode encodes both the kind and the permissions
-    let kind = mode_to_filetype(stat.st_mode);
-    let perm = (stat.st_mode & 0o7777) as u16;
-
-    let time = |secs: i64, nanos: i64|
-        SystemTime::UNIX_EPOCH + Duration::new(secs as u64, nanos as u32);
-
-    // libc::nlink_t is wildly different sizes on different platforms:
-    // linux amd64: u64
-    // linux x86:   u32
-    // macOS amd64: u16
-    #[allow(clippy::cast_lossless)]
-    let nlink = stat.st_nlink as u32;
-
-    FileAttr {
-        size: stat.st_size as u64,
-        blocks: stat.st_blocks as u64,
-        atime: time(stat.st_atime, stat.st_atime_nsec),
-        mtime: time(stat.st_mtime, stat.st_mtime_nsec),
-        ctime: time(stat.st_ctime, stat.st_ctime_nsec),
-        crtime: SystemTime::UNIX_EPOCH,
-        kind,
-        perm,
-        nlink,
-        uid: stat.st_uid,
-        gid: stat.st_gid,
-        rdev: stat.st_rdev as u32,
-        flags: 0,
-    }
+fn fstat_attr(fh: u64) -> Result<FileAttr, libc::c_int> {
+    // Borrow the file descriptor without closing it afterwards.
+    let file = mem::ManuallyDrop::new(unsafe { File::from_raw_fd(fh as libc::c_int) });
+    file.metadata()
+        .map(|metadata| FileAttr::from(&metadata))
+        .map_err(|e| e.raw_os_error().unwrap())
 }
 
 #[cfg(target_os = "macos")]
@@ -107,10 +76,8 @@ impl PassthroughFS {
         let real: OsString = self.real_path(path);
         debug!("stat_real: {:?}", real);
 
-        match libc_wrappers::lstat(real) {
-            Ok(stat) => {
-                Ok(stat_to_fuse(stat))
-            },
+        match lstat_attr(real) {
+            Ok(attr) => Ok(attr),
             Err(e) => {
                 let err = io::Error::from_raw_os_error(e);
                 error!("lstat({:?}): {}", path, err);
@@ -136,8 +103,8 @@ impl FilesystemMT<'_> for PassthroughFS {
         debug!("getattr: {:?}", path);
 
         if let Some(fh) = fh {
-            match libc_wrappers::fstat(fh) {
-                Ok(stat) => Ok((TTL, stat_to_fuse(stat))),
+            match fstat_attr(fh) {
+                Ok(attr) => Ok((TTL, attr)),
                 Err(e) => Err(e)
             }
         } else {
@@ -196,7 +163,11 @@ impl FilesystemMT<'_> for PassthroughFS {
                             let entry_path = PathBuf::from(path).join(&name);
                             let real_path = self.real_path(&entry_path);
                             match libc_wrappers::lstat(real_path) {
-                                Ok(stat64) => mode_to_filetype(stat64.st_mode),
+                                Ok(stat64) => {
+                                    #[allow(clippy::unnecessary_cast)] // mode_t is u16 on macOS
+                                    let mode = stat64.st_mode as u32;
+                                    FileType::from_mode(mode).expect("unknown file type")
+                                },
                                 Err(errno) => {
                                     let ioerr = io::Error::from_raw_os_error(errno);
                                     panic!("lstat failed after readdir_r gave no file type for {:?}: {}",
@@ -376,32 +347,43 @@ impl FilesystemMT<'_> for PassthroughFS {
         }
     }
 
-    fn utimens(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>) -> ResultEmpty {
+    fn utimens(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>) -> ResultEmpty {
         debug!("utimens: {:?}: {:?}, {:?}", path, atime, mtime);
 
-        let systemtime_to_libc = |time: Option<SystemTime>| -> libc::timespec {
-            if let Some(time) = time {
-                let (secs, nanos) = match time.duration_since(SystemTime::UNIX_EPOCH) {
-                    Ok(duration) => (duration.as_secs() as i64, duration.subsec_nanos()),
-                    Err(in_past) => {
-                        let duration = in_past.duration();
-                        (-(duration.as_secs() as i64), duration.subsec_nanos())
-                    }
-                };
-
-                libc::timespec {
-                    tv_sec: secs,
-                    tv_nsec: i64::from(nanos),
+        let systemtime_to_libc = |time: SystemTime| -> libc::timespec {
+            let (secs, nanos) = match time.duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(duration) => (duration.as_secs() as i64, duration.subsec_nanos()),
+                Err(in_past) => {
+                    let duration = in_past.duration();
+                    (-(duration.as_secs() as i64), duration.subsec_nanos())
                 }
-            } else {
-                libc::timespec {
+            };
+
+            libc::timespec {
+                tv_sec: secs,
+                tv_nsec: i64::from(nanos),
+            }
+        };
+
+        let time_to_libc = |time: Option<TimeOrNow>| -> libc::timespec {
+            match time {
+                Some(TimeOrNow::SpecificTime(time)) => systemtime_to_libc(time),
+                // The Mac OS X stand-ins for futimens and utimensat don't understand UTIME_NOW.
+                #[cfg(target_os = "macos")]
+                Some(TimeOrNow::Now) => systemtime_to_libc(SystemTime::now()),
+                #[cfg(not(target_os = "macos"))]
+                Some(TimeOrNow::Now) => libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_NOW,
+                },
+                None => libc::timespec {
                     tv_sec: 0,
                     tv_nsec: libc::UTIME_OMIT,
-                }
+                },
             }
         };
 
-        let times = [systemtime_to_libc(atime), systemtime_to_libc(mtime)];
+        let times = [time_to_libc(atime), time_to_libc(mtime)];
 
         let result = if let Some(fd) = fh {
             unsafe { libc::futimens(fd as libc::c_int, &times as *const libc::timespec) }
@@ -465,7 +447,7 @@ impl FilesystemMT<'_> for PassthroughFS {
         }
     }
 
-    fn mknod(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, mode: u32, rdev: u32) -> ResultEntry {
+    fn mknod(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, mode: u32, _umask: u32, rdev: u32) -> ResultEntry {
         debug!("mknod: {:?}/{:?} (mode={:#o}, rdev={})", parent_path, name, mode, rdev);
 
         let real = PathBuf::from(self.real_path(parent_path)).join(name);
@@ -479,14 +461,14 @@ impl FilesystemMT<'_> for PassthroughFS {
             error!("mknod({:?}, {}, {}): {}", real, mode, rdev, e);
             Err(e.raw_os_error().unwrap())
         } else {
-            match libc_wrappers::lstat(real.into_os_string()) {
-                Ok(attr) => Ok((TTL, stat_to_fuse(attr))),
+            match lstat_attr(&real) {
+                Ok(attr) => Ok((TTL, attr)),
                 Err(e) => Err(e),   // if this happens, yikes
             }
         }
     }
 
-    fn mkdir(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, mode: u32) -> ResultEntry {
+    fn mkdir(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, mode: u32, _umask: u32) -> ResultEntry {
         debug!("mkdir {:?}/{:?} (mode={:#o})", parent_path, name, mode);
 
         let real = PathBuf::from(self.real_path(parent_path)).join(name);
@@ -500,8 +482,8 @@ impl FilesystemMT<'_> for PassthroughFS {
             error!("mkdir({:?}, {:#o}): {}", real, mode, e);
             Err(e.raw_os_error().unwrap())
         } else {
-            match libc_wrappers::lstat(real.clone().into_os_string()) {
-                Ok(attr) => Ok((TTL, stat_to_fuse(attr))),
+            match lstat_attr(&real) {
+                Ok(attr) => Ok((TTL, attr)),
                 Err(e) => {
                     error!("lstat after mkdir({:?}, {:#o}): {}", real, mode, e);
                     Err(e)   // if this happens, yikes
@@ -538,8 +520,8 @@ impl FilesystemMT<'_> for PassthroughFS {
         let real = PathBuf::from(self.real_path(parent_path)).join(name);
         match ::std::os::unix::fs::symlink(target, &real) {
             Ok(()) => {
-                match libc_wrappers::lstat(real.clone().into_os_string()) {
-                    Ok(attr) => Ok((TTL, stat_to_fuse(attr))),
+                match lstat_attr(&real) {
+                    Ok(attr) => Ok((TTL, attr)),
                     Err(e) => {
                         error!("lstat after symlink({:?}, {:?}): {}", real, target, e);
                         Err(e)
@@ -572,8 +554,8 @@ impl FilesystemMT<'_> for PassthroughFS {
         let newreal = PathBuf::from(self.real_path(newparent)).join(newname);
         match fs::hard_link(&real, &newreal) {
             Ok(()) => {
-                match libc_wrappers::lstat(real.clone()) {
-                    Ok(attr) => Ok((TTL, stat_to_fuse(attr))),
+                match lstat_attr(&real) {
+                    Ok(attr) => Ok((TTL, attr)),
                     Err(e) => {
                         error!("lstat after link({:?}, {:?}): {}", real, newreal, e);
                         Err(e)
@@ -587,7 +569,7 @@ impl FilesystemMT<'_> for PassthroughFS {
         }
     }
 
-    fn create(&self, _req: RequestInfo, parent: &Path, name: &OsStr, mode: u32, flags: u32) -> ResultCreate {
+    fn create(&self, _req: RequestInfo, parent: &Path, name: &OsStr, mode: u32, _umask: u32, flags: u32) -> ResultCreate {
         debug!("create: {:?}/{:?} (mode={:#o}, flags={:#x})", parent, name, mode, flags);
 
         let real = PathBuf::from(self.real_path(parent)).join(name);
@@ -601,13 +583,8 @@ impl FilesystemMT<'_> for PassthroughFS {
             error!("create({:?}): {}", real, ioerr);
             Err(ioerr.raw_os_error().unwrap())
         } else {
-            match libc_wrappers::lstat(real.clone().into_os_string()) {
-                Ok(attr) => Ok(CreatedEntry {
-                    ttl: TTL,
-                    attr: stat_to_fuse(attr),
-                    fh: fd as u64,
-                    flags,
-                }),
+            match lstat_attr(&real) {
+                Ok(attr) => Ok(CreatedEntry::new(attr, fd as u64).ttl(TTL).flags(flags)),
                 Err(e) => {
                     error!("lstat after create({:?}): {}", real, io::Error::from_raw_os_error(e));
                     Err(e)
@@ -702,7 +679,7 @@ impl Drop for UnmanagedFile {
     fn drop(&mut self) {
         // Release control of the file descriptor so it is not closed.
         let file = self.inner.take().unwrap();
-        file.into_raw_fd();
+        let _ = file.into_raw_fd();
     }
 }
 
@@ -0,0 +1,130 @@
+// AttrCache :: a short-lived cache of attributes handed over by readdir.
+//
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::FileAttr;
+
+/// Attribute cache.
+///
+/// When a filesystem returns attributes along with its directory entries (from
+/// `readdir_with_attrs`), they are kept here so that the `lookup` or `getattr` which usually
+/// follows for each entry (as with `ls -l`) can be answered without calling the filesystem again.
+///
+/// Each entry is used at most once: after that the kernel has the attributes cached itself, for
+/// the duration the filesystem specified, and the next time it asks they are expected to be fresh.
+/// Entries also expire after that same duration if they aren't used, and are removed whenever the
+/// file they describe might have been changed through the filesystem.
+///
+/// It can be shared with the thread pool, so that changes made there can invalidate entries once
+/// they're done.
+///
+/// Paths are kept in order, which puts everything under a directory right after the directory
+/// itself, so a subtree can be dropped without looking at the rest.
+#[derive(Debug)]
+pub struct AttrCache {
+    entries: Mutex<BTreeMap<PathBuf, AttrCacheEntry>>,
+}
+
+#[derive(Debug)]
+struct AttrCacheEntry {
+    expires: Instant,
+    attr: FileAttr,
+}
+
+impl AttrCache {
+    pub fn new() -> AttrCache {
+        AttrCache {
+            entries: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Add attributes for the given path, valid for the given duration.
+    pub fn insert(&self, path: PathBuf, ttl: Duration, attr: FileAttr) {
+        if let Some(expires) = Instant::now().checked_add(ttl) {
+            self.entries.lock().unwrap().insert(path, AttrCacheEntry { expires, attr });
+        }
+    }
+
+    /// Remove expired attributes. Nothing else removes entries that are never used, so this is
+    /// called once per listing, before adding its attributes.
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        self.entries.lock().unwrap().retain(|_, entry| entry.expires > now);
+    }
+
+    /// Remove and return the attributes for the given path, if there are any which haven't
+    /// expired, along with how much longer they are valid for.
+    pub fn take(&self, path: &Path) -> Option<(Duration, FileAttr)> {
+        let entry = self.entries.lock().unwrap().remove(path)?;
+        let now = Instant::now();
+        if entry.expires <= now {
+            return None;
+        }
+        Some((entry.expires - now, entry.attr))
+    }
+
+    /// Remove all attributes. This is for when a change could affect any number of paths, such as
+    /// the link count of a file which has other names.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Remove the attributes for the given path, but not for anything under it, for a change to
+    /// just that file or directory.
+    pub fn remove(&self, path: &Path) {
+        self.entries.lock().unwrap().remove(path);
+    }
+
+    /// Remove the attributes for the given path, and for anything under it.
+    pub fn invalidate(&self, path: &Path) {
+        let mut entries = self.entries.lock().unwrap();
+        let under: Vec<PathBuf> = entries.range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+            .map(|(entry_path, _)| entry_path)
+            .take_while(|entry_path| entry_path.starts_with(path))
+            .cloned()
+            .collect();
+        for entry_path in under {
+            entries.remove(&entry_path);
+        }
+    }
+}
+
+#[test]
+fn test_attr_cache() {
+    let attr = FileAttr { perm: 0o644, ..Default::default() };
+
+    let cache = AttrCache::new();
+    cache.insert(PathBuf::from("/a/b"), Duration::from_secs(60), attr);
+    cache.insert(PathBuf::from("/a/c"), Duration::from_secs(60), attr);
+    cache.insert(PathBuf::from("/a-b"), Duration::from_secs(60), attr);
+    cache.insert(PathBuf::from("/expired"), Duration::from_secs(0), attr);
+    cache.insert(PathBuf::from("/stale"), Duration::from_nanos(1), attr);
+
+    // Entries are used only once.
+    assert!(cache.take(Path::new("/a/b")).is_some());
+    assert!(cache.take(Path::new("/a/b")).is_none());
+
+    assert!(cache.take(Path::new("/expired")).is_none());
+    std::thread::sleep(Duration::from_millis(1));
+    cache.sweep();
+    assert!(!cache.entries.lock().unwrap().contains_key(Path::new("/stale")));
+
+    // Invalidating a directory drops what's under it, and nothing beside it.
+    cache.insert(PathBuf::from("/a"), Duration::from_secs(60), attr);
+    cache.invalidate(Path::new("/a"));
+    assert!(cache.take(Path::new("/a")).is_none());
+    assert!(cache.take(Path::new("/a/c")).is_none());
+    assert!(cache.take(Path::new("/a-b")).is_some());
+
+    // Removing a directory's own attributes leaves its children's.
+    cache.insert(PathBuf::from("/a"), Duration::from_secs(60), attr);
+    cache.insert(PathBuf::from("/a/b"), Duration::from_secs(60), attr);
+    cache.remove(Path::new("/a"));
+    assert!(cache.take(Path::new("/a")).is_none());
+    assert!(cache.take(Path::new("/a/b")).is_some());
+}
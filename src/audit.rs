@@ -0,0 +1,358 @@
+// AuditLog :: a wrapper recording every operation which was denied.
+//
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::types::*;
+
+/// A record of an operation which was denied, as passed to the sink of an `AuditLog`.
+#[derive(Clone, Debug)]
+pub struct AuditRecord {
+    /// The name of the operation, which is the name of the `FilesystemMT` method.
+    pub operation: &'static str,
+    /// The path the operation was on. For operations which create or remove an entry, this is
+    /// the path of the entry.
+    pub path: PathBuf,
+    /// The second path involved in a `rename`, `exchange`, `link` or `symlink`: the new name, or
+    /// the target of the link.
+    pub target: Option<PathBuf>,
+    /// The error the operation failed with: `EACCES`, `EPERM` or `EROFS`.
+    pub error: libc::c_int,
+    /// Who made the request.
+    pub req: RequestInfo,
+}
+
+/// Wraps a filesystem, passing everything through, but calling a function (the sink) with an
+/// `AuditRecord` each time an operation fails with `EACCES`, `EPERM` or `EROFS`.
+///
+/// The sink is called on whichever thread the operation ran on, possibly several at once, before
+/// the result goes back to the kernel, so it should be quick; it could send the record to a
+/// channel, or write it to a log, for example. Denials by the kernel itself, based on the
+/// permissions the filesystem reports (with `MountOption::DefaultPermissions`), never reach the
+/// filesystem and so aren't recorded.
+///
+/// This can wrap any other wrapper, such as `ReadOnlyPaths`, to record what that denies too.
+pub struct AuditLog<FS, F> {
+    inner: FS,
+    sink: F,
+}
+
+impl<FS, F: Fn(&AuditRecord)> AuditLog<FS, F> {
+    /// Wrap a filesystem, passing records of denied operations to `sink`.
+    pub fn new(inner: FS, sink: F) -> AuditLog<FS, F> {
+        AuditLog { inner, sink }
+    }
+
+    /// Get the wrapped filesystem.
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    fn audit<R>(&self, req: RequestInfo, operation: &'static str, path: &Path,
+        target: Option<&Path>, result: Result<R, libc::c_int>) -> Result<R, libc::c_int>
+    {
+        if let Err(error @ (libc::EACCES | libc::EPERM | libc::EROFS)) = result {
+            (self.sink)(&AuditRecord {
+                operation,
+                path: path.to_owned(),
+                target: target.map(Path::to_owned),
+                error,
+                req,
+            });
+        }
+        result
+    }
+}
+
+impl<'a, FS, F> FilesystemMT<'a> for AuditLog<FS, F>
+    where FS: FilesystemMT<'a>,
+          F: Fn(&AuditRecord),
+{
+    fn init(&self, req: RequestInfo) -> ResultEmpty {
+        self.inner.init(req)
+    }
+
+    fn init_with_config(&self, req: RequestInfo, config: &mut KernelConfig<'_>) -> ResultEmpty {
+        self.inner.init_with_config(req, config)
+    }
+
+    fn destroy(&self) {
+        self.inner.destroy()
+    }
+
+    fn try_destroy(&self) -> ResultEmpty {
+        self.inner.try_destroy()
+    }
+
+    fn capabilities(&self) -> OpSet {
+        self.inner.capabilities()
+    }
+
+    fn on_forget(&self, path: &'a Path) {
+        self.inner.on_forget(path)
+    }
+
+    fn getattr(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>) -> ResultEntry {
+        self.audit(req, "getattr", path, None, self.inner.getattr(req, path, fh))
+    }
+
+    fn setattr(&self, req: RequestInfo, path: &'a Path, attrs: SetAttrRequest) -> ResultEntry {
+        self.audit(req, "setattr", path, None, self.inner.setattr(req, path, attrs))
+    }
+
+    fn chmod(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, mode: u32) -> ResultEmpty {
+        self.audit(req, "chmod", path, None, self.inner.chmod(req, path, fh, mode))
+    }
+
+    fn chown(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, uid: Option<u32>, gid: Option<u32>) -> ResultEmpty {
+        self.audit(req, "chown", path, None, self.inner.chown(req, path, fh, uid, gid))
+    }
+
+    fn truncate(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, size: u64) -> ResultEmpty {
+        self.audit(req, "truncate", path, None, self.inner.truncate(req, path, fh, size))
+    }
+
+    fn utimens(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, atime: Option<crate::TimeOrNow>, mtime: Option<crate::TimeOrNow>) -> ResultEmpty {
+        self.audit(req, "utimens", path, None, self.inner.utimens(req, path, fh, atime, mtime))
+    }
+
+    fn utimens_macos(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> ResultEmpty {
+        let result = self.inner.utimens_macos(req, path, fh, crtime, chgtime, bkuptime, flags);
+        self.audit(req, "utimens_macos", path, None, result)
+    }
+
+    fn readlink(&self, req: RequestInfo, path: &'a Path) -> ResultData {
+        self.audit(req, "readlink", path, None, self.inner.readlink(req, path))
+    }
+
+    fn mknod(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32, rdev: u32) -> ResultEntry {
+        let result = self.inner.mknod(req, parent, name, mode, umask, rdev);
+        self.audit(req, "mknod", &parent.join(name), None, result)
+    }
+
+    fn mkdir(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32) -> ResultEntry {
+        let result = self.inner.mkdir(req, parent, name, mode, umask);
+        self.audit(req, "mkdir", &parent.join(name), None, result)
+    }
+
+    fn unlink(&self, req: RequestInfo, parent: &'a Path, name: &OsStr) -> ResultEmpty {
+        let result = self.inner.unlink(req, parent, name);
+        self.audit(req, "unlink", &parent.join(name), None, result)
+    }
+
+    fn rmdir(&self, req: RequestInfo, parent: &'a Path, name: &OsStr) -> ResultEmpty {
+        let result = self.inner.rmdir(req, parent, name);
+        self.audit(req, "rmdir", &parent.join(name), None, result)
+    }
+
+    fn symlink(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, target: &Path) -> ResultEntry {
+        let result = self.inner.symlink(req, parent, name, target);
+        self.audit(req, "symlink", &parent.join(name), Some(target), result)
+    }
+
+    fn rename(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr) -> ResultEmpty {
+        let result = self.inner.rename(req, parent, name, newparent, newname);
+        self.audit(req, "rename", &parent.join(name), Some(&newparent.join(newname)), result)
+    }
+
+    fn exchange(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr) -> ResultEmpty {
+        let result = self.inner.exchange(req, parent, name, newparent, newname);
+        self.audit(req, "exchange", &parent.join(name), Some(&newparent.join(newname)), result)
+    }
+
+    fn rename_flags(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr, flags: u32) -> ResultEmpty {
+        let result = self.inner.rename_flags(req, parent, name, newparent, newname, flags);
+        let operation = if flags & RENAME_EXCHANGE != 0 { "exchange" } else { "rename" };
+        self.audit(req, operation, &parent.join(name), Some(&newparent.join(newname)), result)
+    }
+
+    fn link(&self, req: RequestInfo, path: &'a Path, newparent: &'a Path, newname: &OsStr) -> ResultEntry {
+        let result = self.inner.link(req, path, newparent, newname);
+        self.audit(req, "link", path, Some(&newparent.join(newname)), result)
+    }
+
+    fn open(&self, req: RequestInfo, path: &'a Path, flags: u32) -> ResultOpen {
+        self.audit(req, "open", path, None, self.inner.open(req, path, flags))
+    }
+
+    fn read(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
+        self.inner.read(req, path, fh, offset, size, |result| {
+            callback(self.audit(req, "read", path, None, result))
+        })
+    }
+
+    fn write(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, data: Vec<u8>, flags: u32) -> ResultWrite {
+        self.audit(req, "write", path, None, self.inner.write(req, path, fh, offset, data, flags))
+    }
+
+    fn write_buf(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, data: &[u8], flags: u32) -> ResultWrite {
+        self.audit(req, "write", path, None, self.inner.write_buf(req, path, fh, offset, data, flags))
+    }
+
+    fn flush(&self, req: RequestInfo, path: &'a Path, fh: u64, lock_owner: u64) -> ResultEmpty {
+        self.audit(req, "flush", path, None, self.inner.flush(req, path, fh, lock_owner))
+    }
+
+    fn release(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> ResultEmpty {
+        let result = self.inner.release(req, path, fh, flags, lock_owner, flush);
+        self.audit(req, "release", path, None, result)
+    }
+
+    fn fsync(&self, req: RequestInfo, path: &'a Path, fh: u64, datasync: bool) -> ResultEmpty {
+        self.audit(req, "fsync", path, None, self.inner.fsync(req, path, fh, datasync))
+    }
+
+    fn opendir(&self, req: RequestInfo, path: &'a Path, flags: u32) -> ResultOpen {
+        self.audit(req, "opendir", path, None, self.inner.opendir(req, path, flags))
+    }
+
+    fn readdir(&self, req: RequestInfo, path: &'a Path, fh: u64) -> ResultReaddir {
+        self.audit(req, "readdir", path, None, self.inner.readdir(req, path, fh))
+    }
+
+    fn readdir_with_attrs(&self, req: RequestInfo, path: &'a Path, fh: u64) -> ResultReaddirAttrs {
+        self.audit(req, "readdir", path, None, self.inner.readdir_with_attrs(req, path, fh))
+    }
+
+    fn readdir_offset(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, filler: &mut DirFiller<'_>)
+        -> ResultEmpty
+    {
+        self.audit(req, "readdir", path, None, self.inner.readdir_offset(req, path, fh, offset, filler))
+    }
+
+    fn releasedir(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32) -> ResultEmpty {
+        self.audit(req, "releasedir", path, None, self.inner.releasedir(req, path, fh, flags))
+    }
+
+    fn fsyncdir(&self, req: RequestInfo, path: &'a Path, fh: u64, datasync: bool) -> ResultEmpty {
+        self.audit(req, "fsyncdir", path, None, self.inner.fsyncdir(req, path, fh, datasync))
+    }
+
+    fn statfs(&self, req: RequestInfo, path: &'a Path) -> ResultStatfs {
+        self.audit(req, "statfs", path, None, self.inner.statfs(req, path))
+    }
+
+    fn setxattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr, value: &[u8], flags: u32, position: u32) -> ResultEmpty {
+        let result = self.inner.setxattr(req, path, name, value, flags, position);
+        self.audit(req, "setxattr", path, None, result)
+    }
+
+    fn getxattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr, size: u32) -> ResultXattr {
+        self.audit(req, "getxattr", path, None, self.inner.getxattr(req, path, name, size))
+    }
+
+    fn listxattr(&self, req: RequestInfo, path: &'a Path, size: u32) -> ResultXattr {
+        self.audit(req, "listxattr", path, None, self.inner.listxattr(req, path, size))
+    }
+
+    fn removexattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr) -> ResultEmpty {
+        self.audit(req, "removexattr", path, None, self.inner.removexattr(req, path, name))
+    }
+
+    fn access(&self, req: RequestInfo, path: &'a Path, mask: u32) -> ResultEmpty {
+        self.audit(req, "access", path, None, self.inner.access(req, path, mask))
+    }
+
+    fn create(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32, flags: u32) -> ResultCreate {
+        let result = self.inner.create(req, parent, name, mode, umask, flags);
+        self.audit(req, "create", &parent.join(name), None, result)
+    }
+
+    fn lseek(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, whence: i32) -> ResultLseek {
+        self.audit(req, "lseek", path, None, self.inner.lseek(req, path, fh, offset, whence))
+    }
+
+    fn copy_file_range(&self, req: RequestInfo, path_in: &'a Path, fh_in: u64, offset_in: u64, path_out: &'a Path, fh_out: u64, offset_out: u64, len: u64, flags: u32) -> ResultWrite {
+        let result = self.inner.copy_file_range(req, path_in, fh_in, offset_in, path_out, fh_out, offset_out, len, flags);
+        self.audit(req, "copy_file_range", path_in, Some(path_out), result)
+    }
+
+    fn fallocate(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, length: u64, mode: i32) -> ResultEmpty {
+        self.audit(req, "fallocate", path, None, self.inner.fallocate(req, path, fh, offset, length, mode))
+    }
+
+    fn ioctl(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32, cmd: u32, in_data: &[u8], out_size: u32) -> ResultIoctl {
+        self.audit(req, "ioctl", path, None, self.inner.ioctl(req, path, fh, flags, cmd, in_data, out_size))
+    }
+
+    fn poll(&self, req: RequestInfo, path: &'a Path, fh: u64, ph: PollHandle, events: u32, flags: u32) -> ResultPoll {
+        self.audit(req, "poll", path, None, self.inner.poll(req, path, fh, ph, events, flags))
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&self, req: RequestInfo, name: &OsStr) -> ResultEmpty {
+        self.audit(req, "setvolname", Path::new("/"), None, self.inner.setvolname(req, name))
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&self, req: RequestInfo, path: &'a Path) -> ResultXTimes {
+        self.audit(req, "getxtimes", path, None, self.inner.getxtimes(req, path))
+    }
+}
+
+#[test]
+fn test_audit_log() {
+    use std::sync::Mutex;
+
+    // A filesystem where only root may remove things.
+    struct RootOnlyFS;
+
+    impl FilesystemMT<'_> for RootOnlyFS {
+        fn unlink(&self, req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+            if req.uid == 0 { Ok(()) } else { Err(libc::EACCES) }
+        }
+    }
+
+    let records = Mutex::new(vec![]);
+    let fs = AuditLog::new(RootOnlyFS, |record: &AuditRecord| {
+        records.lock().unwrap().push(record.clone());
+    });
+    let root = RequestInfo { unique: 1, uid: 0, gid: 0, pid: 100 };
+    let user = RequestInfo { unique: 2, uid: 1000, gid: 1000, pid: 200 };
+
+    assert_eq!(Ok(()), fs.unlink(root, Path::new("/dir"), OsStr::new("a")));
+    assert_eq!(Err(libc::EACCES), fs.unlink(user, Path::new("/dir"), OsStr::new("b")));
+    // Errors other than denials aren't recorded.
+    assert_eq!(Err(libc::ENOSYS), fs.rmdir(user, Path::new("/"), OsStr::new("dir")));
+
+    let records = records.into_inner().unwrap();
+    assert_eq!(1, records.len());
+    assert_eq!("unlink", records[0].operation);
+    assert_eq!(Path::new("/dir/b"), records[0].path);
+    assert_eq!(libc::EACCES, records[0].error);
+    assert_eq!((1000, 200), (records[0].req.uid, records[0].req.pid));
+}
+
+#[test]
+fn test_audit_rename_flags() {
+    use std::sync::Mutex;
+
+    // A filesystem which allows renames but not exchanges.
+    struct NoExchangeFS;
+
+    impl FilesystemMT<'_> for NoExchangeFS {
+        fn rename(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _newparent: &Path, _newname: &OsStr) -> ResultEmpty {
+            Ok(())
+        }
+
+        fn exchange(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _newparent: &Path, _newname: &OsStr) -> ResultEmpty {
+            Err(libc::EPERM)
+        }
+    }
+
+    let operations = Mutex::new(vec![]);
+    let fs = AuditLog::new(NoExchangeFS, |record: &AuditRecord| {
+        operations.lock().unwrap().push(record.operation);
+    });
+    let req = RequestInfo { unique: 1, uid: 1000, gid: 1000, pid: 100 };
+    let (dir, a, b) = (Path::new("/"), OsStr::new("a"), OsStr::new("b"));
+
+    // The default rename_flags passes plain renames and exchanges on to rename and exchange,
+    // and refuses other flags.
+    assert_eq!(Ok(()), fs.rename_flags(req, dir, a, dir, b, 0));
+    assert_eq!(Err(libc::EPERM), fs.rename_flags(req, dir, a, dir, b, RENAME_EXCHANGE));
+    assert_eq!(Err(libc::EINVAL), fs.rename_flags(req, dir, a, dir, b, RENAME_NOREPLACE));
+    assert_eq!(vec!["exchange"], operations.into_inner().unwrap());
+}
@@ -0,0 +1,51 @@
+// AutoCache :: deciding whether the kernel can keep its cached data for a file being opened.
+//
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// The modification time and size of files opened with `FOPEN_AUTO_CACHE`, as of when they were
+/// last opened.
+#[derive(Debug, Default)]
+pub struct AutoCache {
+    versions: HashMap<u64, (SystemTime, u64)>,
+}
+
+impl AutoCache {
+    pub fn new() -> AutoCache {
+        AutoCache::default()
+    }
+
+    /// Record the modification time and size of a file being opened, and return whether they are
+    /// the same as when it was last opened, in which case the kernel's cached data is still good.
+    pub fn check(&mut self, ino: u64, mtime: SystemTime, size: u64) -> bool {
+        let unchanged = self.versions.insert(ino, (mtime, size)) == Some((mtime, size));
+        debug!("auto cache: inode {} is {}", ino, if unchanged { "unchanged" } else { "new or changed" });
+        unchanged
+    }
+
+    /// Forget about a file, so that its cached data isn't kept the next time it's opened.
+    pub fn forget(&mut self, ino: u64) {
+        self.versions.remove(&ino);
+    }
+}
+
+#[test]
+fn test_auto_cache() {
+    use std::time::Duration;
+
+    let mut cache = AutoCache::new();
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+
+    // The first open has nothing to compare with.
+    assert!(!cache.check(2, mtime, 10));
+    assert!(cache.check(2, mtime, 10));
+
+    // A change to either the size or the modification time counts.
+    assert!(!cache.check(2, mtime, 11));
+    assert!(!cache.check(2, mtime + Duration::from_nanos(1), 11));
+    assert!(cache.check(2, mtime + Duration::from_nanos(1), 11));
+
+    cache.forget(2);
+    assert!(!cache.check(2, mtime + Duration::from_nanos(1), 11));
+}
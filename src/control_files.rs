@@ -0,0 +1,767 @@
+// ControlFiles :: a wrapper adding virtual files, with contents made on demand, to a filesystem.
+//
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::helpers::read_from_slice;
+use crate::read_only::opens_for_writing;
+use crate::types::*;
+
+/// The offset from which `ControlFiles` lists control entries in `readdir_offset`, after the
+/// wrapped filesystem's entries, whose offsets have to be below it.
+pub const CONTROL_OFFSET: u64 = (i64::MAX as u64) - (u32::MAX as u64);
+
+type Reader = Box<dyn Fn() -> ResultData + Send + Sync>;
+type Writer = Box<dyn Fn(&[u8]) -> ResultEmpty + Send + Sync>;
+
+/// The handlers of one control file.
+#[derive(Default)]
+struct ControlFile {
+    read: Option<Reader>,
+    write: Option<Writer>,
+}
+
+/// Wraps a filesystem, adding control files at fixed paths, whose contents are made by a function
+/// when they're read, and which pass what's written to them to another function: the files many
+/// filesystems have for reporting statistics, reloading their configuration, dropping caches and
+/// the like.
+///
+/// ```
+/// # use fuse_mt::*;
+/// # struct MyFS;
+/// # impl FilesystemMT<'_> for MyFS {}
+/// let fs = ControlFiles::new(MyFS)
+///     .readable("/.control/version", || Ok(format!("{}\n", fuse_mt::VERSION).into_bytes()))
+///     .writable("/.control/reload", |_data| {
+///         // ... reload the configuration ...
+///         Ok(())
+///     });
+/// ```
+///
+/// Control files appear as regular files of size zero, like the files in `/proc`, and are opened
+/// with `FOPEN_DIRECT_IO` so that they can be read anyway. Each open of a readable file calls its
+/// read function once, and reads of that open file are answered from the result, so a reader
+/// sees one consistent version of the contents. Each write to a writable file calls its write
+/// function with the data written, whose error, if any, is what the write fails with; the offset
+/// written at is ignored, and so is truncating the file. A file can be both readable and
+/// writable. Errors from the functions are passed on as they are.
+///
+/// Directories which hold control files, other than the root, are made up too, and hide any
+/// directory the wrapped filesystem has at the same path: anything of the wrapped filesystem's
+/// in them fails with `ENOENT`, as if it didn't exist. Control files and their directories are
+/// added to listings of the directories they're in, replacing any entries of the wrapped
+/// filesystem's with the same names. In listings made with `readdir_offset`, the control entries
+/// come last, at offsets from `CONTROL_OFFSET` up, so the wrapped filesystem's offsets must be
+/// below that. They can't be removed, renamed, linked to or
+/// have their attributes changed (which fails with `EPERM`), and nothing else can be created in
+/// the made-up directories. Everything else is passed through to the wrapped filesystem.
+///
+/// The attributes of control files are owned by whoever is asking, with permissions which say
+/// whether they can be read and written, and their times are when this was created.
+pub struct ControlFiles<FS> {
+    inner: FS,
+    files: BTreeMap<PathBuf, ControlFile>,
+    dirs: BTreeSet<PathBuf>,
+    handles: Mutex<HashMap<u64, Arc<[u8]>>>,
+    next_fh: AtomicU64,
+    created: SystemTime,
+}
+
+impl<FS> ControlFiles<FS> {
+    /// Wrap a filesystem, with no control files yet.
+    pub fn new(inner: FS) -> ControlFiles<FS> {
+        ControlFiles {
+            inner,
+            files: BTreeMap::new(),
+            dirs: BTreeSet::new(),
+            handles: Mutex::new(HashMap::new()),
+            next_fh: AtomicU64::new(1),
+            created: SystemTime::now(),
+        }
+    }
+
+    /// Add a control file at `path` whose contents are made by `read`. If there's already a
+    /// control file there, it becomes readable, replacing any read function it had.
+    ///
+    /// # Panics
+    ///
+    /// If `path` isn't absolute, is the root, or is a directory of other control files.
+    pub fn readable(mut self, path: impl Into<PathBuf>, read: impl Fn() -> ResultData + Send + Sync + 'static)
+        -> ControlFiles<FS>
+    {
+        self.file(path.into()).read = Some(Box::new(read));
+        self
+    }
+
+    /// Add a control file at `path` which passes the data written to it to `write`. If there's
+    /// already a control file there, it becomes writable, replacing any write function it had.
+    ///
+    /// # Panics
+    ///
+    /// If `path` isn't absolute, is the root, or is a directory of other control files.
+    pub fn writable(mut self, path: impl Into<PathBuf>, write: impl Fn(&[u8]) -> ResultEmpty + Send + Sync + 'static)
+        -> ControlFiles<FS>
+    {
+        self.file(path.into()).write = Some(Box::new(write));
+        self
+    }
+
+    /// Get the wrapped filesystem.
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    fn file(&mut self, path: PathBuf) -> &mut ControlFile {
+        assert!(path.is_absolute() && path.parent().is_some(), "control file path {:?} must be absolute, and not the root", path);
+        assert!(!self.dirs.contains(&path), "control file path {:?} is already a directory", path);
+        for dir in path.ancestors().skip(1) {
+            assert!(!self.files.contains_key(dir), "control file path {:?} is inside another control file", path);
+            if dir.parent().is_some() {
+                self.dirs.insert(dir.to_owned());
+            }
+        }
+        self.files.entry(path).or_default()
+    }
+
+    /// Whether a path is a control file or one of the directories made up for them.
+    fn is_control(&self, path: &Path) -> bool {
+        self.files.contains_key(path) || self.dirs.contains(path)
+    }
+
+    /// Whether a path is in a made-up directory without being a control file or directory
+    /// itself, and so is hidden from the wrapped filesystem.
+    fn hidden(&self, path: &Path) -> bool {
+        !self.is_control(path) && path.ancestors().skip(1).any(|dir| self.dirs.contains(dir))
+    }
+
+    /// Refuse to pass on anything to do with a hidden path, as if it didn't exist.
+    fn check_hidden(&self, path: &Path) -> ResultEmpty {
+        if self.hidden(path) {
+            Err(libc::ENOENT)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Refuse to change a control file or directory, or a hidden path.
+    fn check(&self, path: &Path) -> ResultEmpty {
+        self.check_hidden(path)?;
+        if self.is_control(path) {
+            Err(libc::EPERM)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Refuse to make a new entry in a made-up directory, or over a control file or directory.
+    fn check_new(&self, parent: &Path, name: &OsStr) -> ResultEmpty {
+        if self.dirs.contains(parent) {
+            Err(libc::EPERM)
+        } else if self.is_control(&parent.join(name)) {
+            Err(libc::EEXIST)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The control files and directories directly in a directory.
+    fn children(&self, dir: &Path) -> Vec<DirectoryEntry> {
+        let files = self.files.keys().map(|path| (path, crate::FileType::RegularFile));
+        let dirs = self.dirs.iter().map(|path| (path, crate::FileType::Directory));
+        files.chain(dirs)
+            .filter(|(path, _)| path.parent() == Some(dir))
+            .map(|(path, kind)| DirectoryEntry { name: path.file_name().unwrap().to_owned(), kind })
+            .collect()
+    }
+
+    fn attr(&self, req: RequestInfo, path: &Path) -> Option<FileAttr> {
+        let (kind, perm, nlink) = if let Some(file) = self.files.get(path) {
+            let perm = if file.read.is_some() { 0o444 } else { 0 } | if file.write.is_some() { 0o222 } else { 0 };
+            (crate::FileType::RegularFile, perm, 1)
+        } else if self.dirs.contains(path) {
+            (crate::FileType::Directory, 0o555, 2)
+        } else {
+            return None;
+        };
+        Some(FileAttr {
+            atime: self.created,
+            mtime: self.created,
+            ctime: self.created,
+            crtime: self.created,
+            kind,
+            perm,
+            nlink,
+            uid: req.uid,
+            gid: req.gid,
+            ..FileAttr::default()
+        })
+    }
+
+    fn read_handle(&self, fh: u64) -> Result<Arc<[u8]>, libc::c_int> {
+        self.handles.lock().unwrap().get(&fh).cloned().ok_or(libc::EBADF)
+    }
+
+    fn write_control(&self, file: &ControlFile, data: &[u8]) -> ResultWrite {
+        let write = file.write.as_ref().ok_or(libc::EBADF)?;
+        write(data)?;
+        Ok(data.len() as u32)
+    }
+}
+
+impl<'a, FS: FilesystemMT<'a>> FilesystemMT<'a> for ControlFiles<FS> {
+    fn init(&self, req: RequestInfo) -> ResultEmpty {
+        self.inner.init(req)
+    }
+
+    fn init_with_config(&self, req: RequestInfo, config: &mut KernelConfig<'_>) -> ResultEmpty {
+        self.inner.init_with_config(req, config)
+    }
+
+    fn destroy(&self) {
+        self.inner.destroy()
+    }
+
+    fn try_destroy(&self) -> ResultEmpty {
+        self.inner.try_destroy()
+    }
+
+    fn capabilities(&self) -> OpSet {
+        // The operations the control files need, whatever the wrapped filesystem supports.
+        self.inner.capabilities() | OpSet::GETATTR | OpSet::TRUNCATE | OpSet::UTIMENS | OpSet::OPEN
+            | OpSet::READ | OpSet::WRITE | OpSet::RELEASE | OpSet::OPENDIR | OpSet::READDIR
+            | OpSet::RELEASEDIR | OpSet::ACCESS
+    }
+
+    fn on_forget(&self, path: &'a Path) {
+        if !self.is_control(path) {
+            self.inner.on_forget(path)
+        }
+    }
+
+    fn getattr(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>) -> ResultEntry {
+        self.check_hidden(path)?;
+        match self.attr(req, path) {
+            Some(attr) => Ok((Duration::ZERO, attr)),
+            None => self.inner.getattr(req, path, fh),
+        }
+    }
+
+    fn setattr(&self, req: RequestInfo, path: &'a Path, attrs: SetAttrRequest) -> ResultEntry {
+        self.check_hidden(path)?;
+        if self.is_control(path) {
+            // Make the separate calls below.
+            return Err(libc::ENOSYS);
+        }
+        self.inner.setattr(req, path, attrs)
+    }
+
+    fn chmod(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, mode: u32) -> ResultEmpty {
+        self.check_hidden(path)?;
+        self.check(path)?;
+        self.inner.chmod(req, path, fh, mode)
+    }
+
+    fn chown(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, uid: Option<u32>, gid: Option<u32>) -> ResultEmpty {
+        self.check_hidden(path)?;
+        self.check(path)?;
+        self.inner.chown(req, path, fh, uid, gid)
+    }
+
+    fn truncate(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, size: u64) -> ResultEmpty {
+        self.check_hidden(path)?;
+        // Opening with O_TRUNC, as shell redirection does, truncates the file first.
+        if self.files.contains_key(path) {
+            return Ok(());
+        }
+        self.check(path)?;
+        self.inner.truncate(req, path, fh, size)
+    }
+
+    fn utimens(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, atime: Option<crate::TimeOrNow>, mtime: Option<crate::TimeOrNow>) -> ResultEmpty {
+        self.check_hidden(path)?;
+        // So that `touch` works, as it does on files in `/proc`.
+        if self.is_control(path) {
+            return Ok(());
+        }
+        self.inner.utimens(req, path, fh, atime, mtime)
+    }
+
+    fn utimens_macos(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> ResultEmpty {
+        self.check_hidden(path)?;
+        self.check(path)?;
+        self.inner.utimens_macos(req, path, fh, crtime, chgtime, bkuptime, flags)
+    }
+
+    fn readlink(&self, req: RequestInfo, path: &'a Path) -> ResultData {
+        self.check_hidden(path)?;
+        if self.is_control(path) {
+            return Err(libc::EINVAL);
+        }
+        self.inner.readlink(req, path)
+    }
+
+    fn mknod(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32, rdev: u32) -> ResultEntry {
+        self.check_hidden(parent)?;
+        self.check_new(parent, name)?;
+        self.inner.mknod(req, parent, name, mode, umask, rdev)
+    }
+
+    fn mkdir(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32) -> ResultEntry {
+        self.check_hidden(parent)?;
+        self.check_new(parent, name)?;
+        self.inner.mkdir(req, parent, name, mode, umask)
+    }
+
+    fn unlink(&self, req: RequestInfo, parent: &'a Path, name: &OsStr) -> ResultEmpty {
+        self.check_hidden(parent)?;
+        self.check(&parent.join(name))?;
+        self.inner.unlink(req, parent, name)
+    }
+
+    fn rmdir(&self, req: RequestInfo, parent: &'a Path, name: &OsStr) -> ResultEmpty {
+        self.check_hidden(parent)?;
+        self.check(&parent.join(name))?;
+        self.inner.rmdir(req, parent, name)
+    }
+
+    fn symlink(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, target: &Path) -> ResultEntry {
+        self.check_hidden(parent)?;
+        self.check_new(parent, name)?;
+        self.inner.symlink(req, parent, name, target)
+    }
+
+    fn rename(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr) -> ResultEmpty {
+        self.check_hidden(parent)?;
+        self.check_hidden(newparent)?;
+        self.check(&parent.join(name))?;
+        self.check_new(newparent, newname).map_err(|_| libc::EPERM)?;
+        self.inner.rename(req, parent, name, newparent, newname)
+    }
+
+    fn exchange(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr) -> ResultEmpty {
+        self.check_hidden(parent)?;
+        self.check_hidden(newparent)?;
+        self.check(&parent.join(name))?;
+        self.check(&newparent.join(newname))?;
+        self.inner.exchange(req, parent, name, newparent, newname)
+    }
+
+    fn rename_flags(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr, flags: u32) -> ResultEmpty {
+        self.check_hidden(parent)?;
+        self.check_hidden(newparent)?;
+        self.check(&parent.join(name))?;
+        self.check_new(newparent, newname).map_err(|_| libc::EPERM)?;
+        self.inner.rename_flags(req, parent, name, newparent, newname, flags)
+    }
+
+    fn link(&self, req: RequestInfo, path: &'a Path, newparent: &'a Path, newname: &OsStr) -> ResultEntry {
+        self.check_hidden(path)?;
+        self.check_hidden(newparent)?;
+        self.check(path)?;
+        self.check_new(newparent, newname)?;
+        self.inner.link(req, path, newparent, newname)
+    }
+
+    fn open(&self, req: RequestInfo, path: &'a Path, flags: u32) -> ResultOpen {
+        self.check_hidden(path)?;
+        let file = match self.files.get(path) {
+            Some(file) => file,
+            None if self.dirs.contains(path) => return Err(libc::EISDIR),
+            None => return self.inner.open(req, path, flags),
+        };
+        let reading = flags as libc::c_int & libc::O_ACCMODE != libc::O_WRONLY;
+        let writing = opens_for_writing(flags as libc::c_int);
+        if (reading && file.read.is_none()) || (writing && file.write.is_none()) {
+            return Err(libc::EACCES);
+        }
+        let contents: Arc<[u8]> = match file.read {
+            Some(ref read) if reading => Arc::from(read()?),
+            _ => Arc::from(&[][..]),
+        };
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().unwrap().insert(fh, contents);
+        Ok((fh, FOPEN_DIRECT_IO))
+    }
+
+    fn read(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
+        if self.hidden(path) {
+            return callback(Err(libc::ENOENT));
+        } else if !self.files.contains_key(path) {
+            return self.inner.read(req, path, fh, offset, size, callback);
+        }
+        match self.read_handle(fh) {
+            Ok(contents) => callback(Ok(read_from_slice(&contents, offset, size))),
+            Err(e) => callback(Err(e)),
+        }
+    }
+
+    fn write(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, data: Vec<u8>, flags: u32) -> ResultWrite {
+        self.check_hidden(path)?;
+        match self.files.get(path) {
+            Some(file) => self.write_control(file, &data),
+            None => self.inner.write(req, path, fh, offset, data, flags),
+        }
+    }
+
+    fn write_buf(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, data: &[u8], flags: u32) -> ResultWrite {
+        self.check_hidden(path)?;
+        match self.files.get(path) {
+            Some(file) => self.write_control(file, data),
+            None => self.inner.write_buf(req, path, fh, offset, data, flags),
+        }
+    }
+
+    fn flush(&self, req: RequestInfo, path: &'a Path, fh: u64, lock_owner: u64) -> ResultEmpty {
+        self.check_hidden(path)?;
+        if self.files.contains_key(path) {
+            return Ok(());
+        }
+        self.inner.flush(req, path, fh, lock_owner)
+    }
+
+    fn release(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> ResultEmpty {
+        self.check_hidden(path)?;
+        if self.files.contains_key(path) {
+            self.handles.lock().unwrap().remove(&fh);
+            return Ok(());
+        }
+        self.inner.release(req, path, fh, flags, lock_owner, flush)
+    }
+
+    fn fsync(&self, req: RequestInfo, path: &'a Path, fh: u64, datasync: bool) -> ResultEmpty {
+        self.check_hidden(path)?;
+        if self.files.contains_key(path) {
+            return Ok(());
+        }
+        self.inner.fsync(req, path, fh, datasync)
+    }
+
+    fn opendir(&self, req: RequestInfo, path: &'a Path, flags: u32) -> ResultOpen {
+        self.check_hidden(path)?;
+        if self.dirs.contains(path) {
+            return Ok((0, 0));
+        } else if self.files.contains_key(path) {
+            return Err(libc::ENOTDIR);
+        }
+        self.inner.opendir(req, path, flags)
+    }
+
+    fn readdir(&self, req: RequestInfo, path: &'a Path, fh: u64) -> ResultReaddir {
+        self.check_hidden(path)?;
+        let mut entries = if self.dirs.contains(path) {
+            let dot = |name: &str| DirectoryEntry { name: name.into(), kind: crate::FileType::Directory };
+            vec![dot("."), dot("..")]
+        } else {
+            self.inner.readdir(req, path, fh)?
+        };
+        let children = self.children(path);
+        entries.retain(|entry| !children.iter().any(|child| child.name == entry.name));
+        entries.extend(children);
+        Ok(entries)
+    }
+
+    fn readdir_with_attrs(&self, req: RequestInfo, path: &'a Path, fh: u64) -> ResultReaddirAttrs {
+        self.check_hidden(path)?;
+        if self.dirs.contains(path) {
+            return Ok(self.readdir(req, path, fh)?.into_iter().map(|entry| (entry, None)).collect());
+        }
+        let mut entries = self.inner.readdir_with_attrs(req, path, fh)?;
+        let children = self.children(path);
+        entries.retain(|(entry, _)| !children.iter().any(|child| child.name == entry.name));
+        entries.extend(children.into_iter().map(|child| (child, None)));
+        Ok(entries)
+    }
+
+    fn readdir_offset(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, filler: &mut DirFiller<'_>)
+        -> ResultEmpty
+    {
+        self.check_hidden(path)?;
+        let mut entries = self.children(path);
+        if self.dirs.contains(path) {
+            let dot = |name: &str| DirectoryEntry { name: name.into(), kind: crate::FileType::Directory };
+            entries.splice(0..0, [dot("."), dot("..")]);
+        } else if offset < CONTROL_OFFSET {
+            // The wrapped filesystem's entries first, leaving out any the control entries replace.
+            let mut full = false;
+            let mut add = |ino: u64, entry_offset: i64, kind: crate::FileType, name: &OsStr| {
+                if entries.iter().any(|entry| entry.name == name) {
+                    return true;
+                }
+                full = !filler.add(ino, entry_offset as u64, kind, name);
+                !full
+            };
+            self.inner.readdir_offset(req, path, fh, offset, &mut DirFiller::new(&mut add))?;
+            if full {
+                return Ok(());
+            }
+        }
+        let start = offset.saturating_sub(CONTROL_OFFSET) as usize;
+        for (index, entry) in entries.iter().enumerate().skip(start) {
+            if !filler.add(0, CONTROL_OFFSET + index as u64 + 1, entry.kind, &entry.name) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn releasedir(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32) -> ResultEmpty {
+        self.check_hidden(path)?;
+        if self.dirs.contains(path) {
+            return Ok(());
+        }
+        self.inner.releasedir(req, path, fh, flags)
+    }
+
+    fn fsyncdir(&self, req: RequestInfo, path: &'a Path, fh: u64, datasync: bool) -> ResultEmpty {
+        self.check_hidden(path)?;
+        if self.dirs.contains(path) {
+            return Ok(());
+        }
+        self.inner.fsyncdir(req, path, fh, datasync)
+    }
+
+    fn statfs(&self, req: RequestInfo, path: &'a Path) -> ResultStatfs {
+        self.check_hidden(path)?;
+        if self.is_control(path) {
+            return self.inner.statfs(req, Path::new("/"));
+        }
+        self.inner.statfs(req, path)
+    }
+
+    fn setxattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr, value: &[u8], flags: u32, position: u32) -> ResultEmpty {
+        self.check_hidden(path)?;
+        if self.is_control(path) {
+            return Err(libc::ENOTSUP);
+        }
+        self.inner.setxattr(req, path, name, value, flags, position)
+    }
+
+    fn getxattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr, size: u32) -> ResultXattr {
+        self.check_hidden(path)?;
+        if self.is_control(path) {
+            return Err(libc::ENOTSUP);
+        }
+        self.inner.getxattr(req, path, name, size)
+    }
+
+    fn listxattr(&self, req: RequestInfo, path: &'a Path, size: u32) -> ResultXattr {
+        self.check_hidden(path)?;
+        if self.is_control(path) {
+            return Err(libc::ENOTSUP);
+        }
+        self.inner.listxattr(req, path, size)
+    }
+
+    fn removexattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr) -> ResultEmpty {
+        self.check_hidden(path)?;
+        if self.is_control(path) {
+            return Err(libc::ENOTSUP);
+        }
+        self.inner.removexattr(req, path, name)
+    }
+
+    fn access(&self, req: RequestInfo, path: &'a Path, mask: u32) -> ResultEmpty {
+        self.check_hidden(path)?;
+        match self.attr(req, path) {
+            Some(attr) => {
+                let allowed = u32::from(attr.perm) & 0o7;
+                if mask & !allowed & 0o7 != 0 { Err(libc::EACCES) } else { Ok(()) }
+            },
+            None => self.inner.access(req, path, mask),
+        }
+    }
+
+    fn create(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32, flags: u32) -> ResultCreate {
+        self.check_hidden(parent)?;
+        self.check_new(parent, name)?;
+        self.inner.create(req, parent, name, mode, umask, flags)
+    }
+
+    fn lseek(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, whence: i32) -> ResultLseek {
+        self.check_hidden(path)?;
+        if self.is_control(path) {
+            return Err(libc::EINVAL);
+        }
+        self.inner.lseek(req, path, fh, offset, whence)
+    }
+
+    fn copy_file_range(&self, req: RequestInfo, path_in: &'a Path, fh_in: u64, offset_in: u64, path_out: &'a Path, fh_out: u64, offset_out: u64, len: u64, flags: u32) -> ResultWrite {
+        self.check_hidden(path_in)?;
+        self.check_hidden(path_out)?;
+        if self.is_control(path_in) || self.is_control(path_out) {
+            // The caller falls back to reading and writing.
+            return Err(libc::EXDEV);
+        }
+        self.inner.copy_file_range(req, path_in, fh_in, offset_in, path_out, fh_out, offset_out, len, flags)
+    }
+
+    fn fallocate(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, length: u64, mode: i32) -> ResultEmpty {
+        self.check_hidden(path)?;
+        if self.is_control(path) {
+            return Err(libc::EOPNOTSUPP);
+        }
+        self.inner.fallocate(req, path, fh, offset, length, mode)
+    }
+
+    fn ioctl(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32, cmd: u32, in_data: &[u8], out_size: u32) -> ResultIoctl {
+        self.check_hidden(path)?;
+        if self.is_control(path) {
+            return Err(libc::ENOTTY);
+        }
+        self.inner.ioctl(req, path, fh, flags, cmd, in_data, out_size)
+    }
+
+    fn poll(&self, req: RequestInfo, path: &'a Path, fh: u64, ph: PollHandle, events: u32, flags: u32) -> ResultPoll {
+        self.check_hidden(path)?;
+        if self.is_control(path) {
+            // Like regular files, always ready.
+            return Ok(events & (libc::POLLIN | libc::POLLOUT) as u32);
+        }
+        self.inner.poll(req, path, fh, ph, events, flags)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&self, req: RequestInfo, name: &OsStr) -> ResultEmpty {
+        self.inner.setvolname(req, name)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&self, req: RequestInfo, path: &'a Path) -> ResultXTimes {
+        self.check_hidden(path)?;
+        if self.is_control(path) {
+            return Ok(XTimes { bkuptime: self.created, crtime: self.created });
+        }
+        self.inner.getxtimes(req, path)
+    }
+}
+
+#[test]
+fn test_control_files() {
+    use std::sync::atomic::AtomicUsize;
+
+    // A filesystem with just an empty root directory.
+    struct EmptyFS;
+
+    impl FilesystemMT<'_> for EmptyFS {
+        fn readdir(&self, _req: RequestInfo, _path: &Path, _fh: u64) -> ResultReaddir {
+            let dot = |name: &str| DirectoryEntry { name: name.into(), kind: crate::FileType::Directory };
+            Ok(vec![dot("."), dot("..")])
+        }
+    }
+
+    let reloads = Arc::new(AtomicUsize::new(0));
+    let reloads_seen = reloads.clone();
+    let fs = ControlFiles::new(EmptyFS)
+        .readable("/.control/stats", || Ok(b"reads: 1\n".to_vec()))
+        .writable("/.control/reload", move |data| {
+            if data != b"1\n" {
+                return Err(libc::EINVAL);
+            }
+            reloads.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+    let req = RequestInfo { unique: 0, uid: 1000, gid: 1000, pid: 0 };
+    let names = |path| -> Vec<String> {
+        fs.readdir(req, Path::new(path), 0).unwrap().into_iter()
+            .map(|entry| entry.name.into_string().unwrap())
+            .collect()
+    };
+
+    // The made-up directory is listed in the root, and the files in it.
+    assert_eq!(vec![".", "..", ".control"], names("/"));
+    assert_eq!(vec![".", "..", "reload", "stats"], names("/.control"));
+    let (_, attr) = fs.getattr(req, Path::new("/.control/stats"), None).unwrap();
+    assert_eq!((crate::FileType::RegularFile, 0o444, 1000), (attr.kind, attr.perm, attr.uid));
+    assert_eq!(Err(libc::ENOSYS), fs.getattr(req, Path::new("/other"), None).map(|_| ()));
+
+    // Reading is answered from what the read function gave at open.
+    let stats = Path::new("/.control/stats");
+    assert_eq!(Err(libc::EACCES), fs.open(req, stats, libc::O_WRONLY as u32));
+    let (fh, flags) = fs.open(req, stats, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(FOPEN_DIRECT_IO, flags);
+    let mut data = vec![];
+    fs.read(req, stats, fh, 7, 100, |result| {
+        data = result.unwrap().to_vec();
+        CallbackResult { _private: std::marker::PhantomData {} }
+    });
+    assert_eq!(b"1\n", &data[..]);
+    fs.release(req, stats, fh, 0, 0, false).unwrap();
+
+    // Writes go to the write function, errors and all.
+    let reload = Path::new("/.control/reload");
+    let (fh, _) = fs.open(req, reload, (libc::O_WRONLY | libc::O_TRUNC) as u32).unwrap();
+    fs.truncate(req, reload, Some(fh), 0).unwrap();
+    assert_eq!(Ok(2), fs.write(req, reload, fh, 0, b"1\n".to_vec(), 0));
+    assert_eq!(Err(libc::EINVAL), fs.write_buf(req, reload, fh, 2, b"x", 0));
+    assert_eq!(1, reloads_seen.load(Ordering::SeqCst));
+
+    // Control files can't be moved or removed, or have things made next to them.
+    assert_eq!(Err(libc::EPERM), fs.unlink(req, Path::new("/.control"), OsStr::new("stats")));
+    assert_eq!(Err(libc::EPERM), fs.rename(req, Path::new("/"), OsStr::new("a"), Path::new("/.control"), OsStr::new("b")));
+    assert_eq!(Err(libc::EEXIST), fs.mkdir(req, Path::new("/"), OsStr::new(".control"), 0o755, 0).map(|_| ()));
+
+    // Whatever the wrapped filesystem has in the made-up directory is hidden.
+    assert_eq!(Err(libc::ENOENT), fs.getattr(req, Path::new("/.control/real"), None).map(|_| ()));
+    assert_eq!(Err(libc::ENOENT), fs.unlink(req, Path::new("/.control"), OsStr::new("real")));
+    assert_eq!(Err(libc::ENOENT), fs.open(req, Path::new("/.control/real/file"), 0));
+}
+
+#[test]
+fn test_control_files_readdir_offset() {
+    // A filesystem which can only list its directories a part at a time.
+    struct OffsetFS;
+
+    impl FilesystemMT<'_> for OffsetFS {
+        fn readdir_offset(&self, _req: RequestInfo, _path: &Path, _fh: u64, offset: u64, filler: &mut DirFiller<'_>)
+            -> ResultEmpty
+        {
+            for (index, name) in ["a", "b", "version"].iter().enumerate().skip(offset as usize) {
+                if !filler.add(0, index as u64 + 1, crate::FileType::RegularFile, name) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    let fs = ControlFiles::new(OffsetFS)
+        .readable("/version", || Ok(b"1\n".to_vec()))
+        .readable("/.control/stats", || Ok(vec![]));
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+
+    // List a directory, continuing from the last offset each time the filler fills up.
+    let list = |path, room: usize| -> Vec<(String, crate::FileType)> {
+        let mut listed = vec![];
+        let mut offset = 0;
+        loop {
+            let mut batch = vec![];
+            let mut add = |_ino: u64, entry_offset: i64, kind: crate::FileType, name: &OsStr| {
+                if batch.len() == room {
+                    return false;
+                }
+                batch.push((entry_offset as u64, name.to_string_lossy().into_owned(), kind));
+                true
+            };
+            fs.readdir_offset(req, Path::new(path), 0, offset, &mut DirFiller::new(&mut add)).unwrap();
+            match batch.last() {
+                Some(&(last, _, _)) => offset = last,
+                None => return listed,
+            }
+            listed.extend(batch.into_iter().map(|(_, name, kind)| (name, kind)));
+        }
+    };
+
+    // The control entries come after the wrapped filesystem's, replacing its entry with the
+    // same name, however many entries fit at a time.
+    let file = |name: &str| (name.to_owned(), crate::FileType::RegularFile);
+    let dir = |name: &str| (name.to_owned(), crate::FileType::Directory);
+    let expected = vec![file("a"), file("b"), file("version"), dir(".control")];
+    assert_eq!(expected, list("/", 100));
+    assert_eq!(expected, list("/", 1));
+    assert_eq!(vec![dir("."), dir(".."), file("stats")], list("/.control", 2));
+}
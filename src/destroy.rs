@@ -0,0 +1,36 @@
+// DestroyHandle :: the outcome of a filesystem's last chance to write out its data.
+//
+
+use std::sync::{Arc, Mutex};
+
+use crate::types::ResultEmpty;
+
+/// A handle for finding out whether `FilesystemMT::try_destroy` failed when the filesystem was unmounted, obtained with `FuseMT::destroy_handle` or
+/// `RawFuseMT::destroy_handle` before mounting.
+///
+/// `mount` can't return the error itself, as it doesn't know what kind of filesystem it was given,
+/// so check here once it has returned, or once `BackgroundSession::unmount` has. A filesystem
+/// which writes out buffered data when it is unmounted should do so in `try_destroy`, so that a
+/// failure doesn't go unnoticed.
+///
+/// The handle can be cloned and used from any thread.
+#[derive(Clone, Debug, Default)]
+pub struct DestroyHandle {
+    result: Arc<Mutex<Option<ResultEmpty>>>,
+}
+
+impl DestroyHandle {
+    pub(crate) fn new() -> DestroyHandle {
+        DestroyHandle::default()
+    }
+
+    /// What `try_destroy` returned, or `None` if it hasn't been called yet. `fuser` calls it when
+    /// the session ends, whether or not the kernel asked for it first.
+    pub fn result(&self) -> Option<ResultEmpty> {
+        *self.result.lock().unwrap()
+    }
+
+    pub(crate) fn set(&self, result: ResultEmpty) {
+        *self.result.lock().unwrap() = Some(result);
+    }
+}
@@ -37,7 +37,13 @@ impl DirectoryCache {
 
     /// Add a new entry with the given file handle and an un-populated directory entry list.
     /// This is intended to be called on opendir().
+    ///
+    /// Keys are never 0, because that is the file handle the kernel uses when the filesystem
+    /// doesn't implement opendir().
     pub fn new_entry(&mut self, fh: u64) -> u64 {
+        if self.next_key.0 == 0 {
+            self.next_key += Wrapping(1);
+        }
         let key = self.next_key.0;
         self.entries.insert(key, DirectoryCacheEntry::new(fh));
         self.next_key += Wrapping(1);
@@ -60,6 +66,34 @@ impl DirectoryCache {
         })
     }
 
+    /// Get the entries fetched for the given cache entry key, if they have been.
+    /// Panics if there is no such key.
+    pub fn entries(&self, key: u64) -> Option<&[DirectoryEntry]> {
+        self.entries.get(&key).unwrap_or_else(|| {
+            panic!("no such directory cache key {}", key);
+        }).entries.as_deref()
+    }
+
+    /// Make sure the entries for the given cache entry key are fetched, for reading from the given
+    /// offset, by calling `fetch` with the real file handle if they need to be: when they haven't
+    /// been fetched yet, or when reading starts over from the beginning. The kernel asks for offset
+    /// 0 again only after a `rewinddir` (or a seek back to the start), which POSIX says should see
+    /// the directory as it is now, like a new `opendir` would.
+    /// Panics if there is no such key.
+    pub fn load(
+        &mut self,
+        key: u64,
+        offset: u64,
+        fetch: impl FnOnce(u64) -> Result<Vec<DirectoryEntry>, libc::c_int>,
+    ) -> Result<(), libc::c_int> {
+        let entry = self.get_mut(key);
+        if offset == 0 || entry.entries.is_none() {
+            debug!("fetching directory entries with fh {}", entry.fh);
+            entry.entries = Some(fetch(entry.fh)?);
+        }
+        Ok(())
+    }
+
     /// Delete the cache entry with the given key.
     /// This is intended to be called on releasedir().
     /// Panics if there is no such key.
@@ -82,3 +116,72 @@ impl DirectoryCacheEntry {
         }
     }
 }
+
+#[test]
+fn test_directory_snapshot() {
+    let entry = |name: &str| DirectoryEntry { name: name.into(), kind: fuser::FileType::RegularFile };
+    let mut directory = vec![entry("a"), entry("b")];
+
+    let mut cache = DirectoryCache::new();
+    let key = cache.new_entry(42);
+    assert_ne!(0, key);
+    assert_eq!(42, cache.real_fh(key));
+    assert!(cache.entries(key).is_none());
+    cache.get_mut(key).entries = Some(directory.clone());
+
+    // Changes to the directory after the first read don't affect the open handle...
+    directory.remove(0);
+    directory.push(entry("c"));
+    let names: Vec<_> = cache.entries(key).unwrap().iter().map(|e| e.name.clone()).collect();
+    assert_eq!(vec![std::ffi::OsString::from("a"), "b".into()], names);
+
+    // ...but a new one sees them.
+    let other = cache.new_entry(43);
+    assert_ne!(key, other);
+    assert!(cache.entries(other).is_none());
+    cache.get_mut(other).entries = Some(directory);
+    assert_eq!(2, cache.entries(other).unwrap().len());
+    assert_eq!("b", cache.entries(other).unwrap()[0].name);
+
+    cache.delete(key);
+    assert_eq!(43, cache.real_fh(other));
+}
+
+#[test]
+fn test_rewind() {
+    let entry = |name: &str| DirectoryEntry { name: name.into(), kind: fuser::FileType::RegularFile };
+    let names = |cache: &DirectoryCache, key| -> Vec<std::ffi::OsString> {
+        cache.entries(key).unwrap().iter().map(|e| e.name.clone()).collect()
+    };
+    let mut directory = vec![entry("a"), entry("b"), entry("c")];
+    let mut fetches = 0;
+    let mut cache = DirectoryCache::new();
+    let key = cache.new_entry(42);
+
+    // The first read fetches the entries, and the rest of the listing comes from them, even if
+    // the directory changes in between.
+    let mut readdir = |cache: &mut DirectoryCache, directory: &[DirectoryEntry], offset| {
+        cache.load(key, offset, |fh| {
+            assert_eq!(42, fh);
+            fetches += 1;
+            Ok(directory.to_vec())
+        }).unwrap();
+    };
+    readdir(&mut cache, &directory, 0);
+    directory.remove(0);
+    readdir(&mut cache, &directory, 1);
+    assert_eq!(vec!["a", "b", "c"], names(&cache, key));
+
+    // A rewind starts over with the directory as it is now, rather than carrying on from the old
+    // entries, and later reads carry on from the new ones.
+    readdir(&mut cache, &directory, 0);
+    assert_eq!(vec!["b", "c"], names(&cache, key));
+    directory.push(entry("d"));
+    readdir(&mut cache, &directory, 1);
+    assert_eq!(vec!["b", "c"], names(&cache, key));
+    assert_eq!(2, fetches);
+
+    // Errors are passed on, and leave the old entries.
+    cache.load(key, 0, |_| Err(libc::EIO)).unwrap_err();
+    assert_eq!(vec!["b", "c"], names(&cache, key));
+}
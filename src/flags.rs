@@ -0,0 +1,173 @@
+// Flags :: readable formatting of the flag arguments in requests, for logging.
+//
+
+use std::fmt::{self, Debug, Formatter};
+
+use crate::types::FileTypeExt;
+
+/// Write out the names of the flags set in `bits`, separated by `|`, followed by any bits that
+/// don't have names, in hex. `first` is written first, if given. Flags which combine others have
+/// to come before them in `names`.
+fn fmt_flags<'a>(f: &mut Formatter<'_>, bits: u32, first: Option<&str>,
+    names: impl IntoIterator<Item = &'a (u32, &'a str)>) -> fmt::Result
+{
+    let mut rest = bits;
+    let mut sep = "";
+    if let Some(first) = first {
+        f.write_str(first)?;
+        sep = "|";
+    }
+    for &(flag, name) in names {
+        if flag != 0 && rest & flag == flag {
+            write!(f, "{}{}", sep, name)?;
+            rest &= !flag;
+            sep = "|";
+        }
+    }
+    if rest != 0 || sep.is_empty() {
+        write!(f, "{}{:#x}", sep, rest)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+const OS_OPEN_FLAGS: &[(u32, &str)] = &[
+    (libc::O_TMPFILE as u32, "O_TMPFILE"),
+    (libc::O_DIRECT as u32, "O_DIRECT"),
+    (libc::O_LARGEFILE as u32, "O_LARGEFILE"),
+    (libc::O_NOATIME as u32, "O_NOATIME"),
+    (libc::O_PATH as u32, "O_PATH"),
+];
+
+#[cfg(not(target_os = "linux"))]
+const OS_OPEN_FLAGS: &[(u32, &str)] = &[];
+
+const OPEN_FLAGS: &[(u32, &str)] = &[
+    (libc::O_CREAT as u32, "O_CREAT"),
+    (libc::O_EXCL as u32, "O_EXCL"),
+    (libc::O_NOCTTY as u32, "O_NOCTTY"),
+    (libc::O_TRUNC as u32, "O_TRUNC"),
+    (libc::O_APPEND as u32, "O_APPEND"),
+    (libc::O_NONBLOCK as u32, "O_NONBLOCK"),
+    (libc::O_SYNC as u32, "O_SYNC"),
+    (libc::O_DSYNC as u32, "O_DSYNC"),
+    (libc::O_DIRECTORY as u32, "O_DIRECTORY"),
+    (libc::O_NOFOLLOW as u32, "O_NOFOLLOW"),
+    (libc::O_CLOEXEC as u32, "O_CLOEXEC"),
+];
+
+/// Flags given to `open`, `create` and others, such as `O_WRONLY|O_APPEND|O_CREAT`.
+pub struct OpenFlags(pub i32);
+
+impl Debug for OpenFlags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let access = match self.0 & libc::O_ACCMODE {
+            libc::O_RDONLY => "O_RDONLY",
+            libc::O_WRONLY => "O_WRONLY",
+            libc::O_RDWR => "O_RDWR",
+            _ => "O_ACCMODE",
+        };
+        let names = OS_OPEN_FLAGS.iter().chain(OPEN_FLAGS);
+        fmt_flags(f, (self.0 & !libc::O_ACCMODE) as u32, Some(access), names)
+    }
+}
+
+/// The mask given to `access`, such as `R_OK|W_OK`.
+pub struct AccessMask(pub i32);
+
+impl Debug for AccessMask {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.0 == libc::F_OK {
+            return f.write_str("F_OK");
+        }
+        fmt_flags(f, self.0 as u32, None, &[
+            (libc::R_OK as u32, "R_OK"),
+            (libc::W_OK as u32, "W_OK"),
+            (libc::X_OK as u32, "X_OK"),
+        ])
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+const XATTR_FLAGS: &[(u32, &str)] = &[
+    (libc::XATTR_CREATE as u32, "XATTR_CREATE"),
+    (libc::XATTR_REPLACE as u32, "XATTR_REPLACE"),
+];
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+const XATTR_FLAGS: &[(u32, &str)] = &[];
+
+/// Flags given to `setxattr`, such as `XATTR_CREATE`.
+pub struct XattrFlags(pub i32);
+
+impl Debug for XattrFlags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_flags(f, self.0 as u32, None, XATTR_FLAGS)
+    }
+}
+
+/// Flags given to `rename`, as with `renameat2`.
+pub struct RenameFlags(pub u32);
+
+impl Debug for RenameFlags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_flags(f, self.0, None, &[
+            (1 << 0, "RENAME_NOREPLACE"),
+            (1 << 1, "RENAME_EXCHANGE"),
+            (1 << 2, "RENAME_WHITEOUT"),
+        ])
+    }
+}
+
+/// The FUSE-specific flags given to `write`.
+pub struct WriteFlags(pub u32);
+
+impl Debug for WriteFlags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_flags(f, self.0, None, &[
+            (1 << 0, "FUSE_WRITE_CACHE"),
+            (1 << 1, "FUSE_WRITE_LOCKOWNER"),
+            (1 << 2, "FUSE_WRITE_KILL_PRIV"),
+        ])
+    }
+}
+
+/// A file mode: the file type, if there is one, and the permission bits in octal.
+pub struct Mode(pub u32);
+
+impl Debug for Mode {
+    #[allow(clippy::unnecessary_cast)] // mode_t is u16 on macOS
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        use crate::FileType::*;
+        let kind = match crate::FileType::from_mode(self.0) {
+            Some(RegularFile) => Some("S_IFREG"),
+            Some(Directory) => Some("S_IFDIR"),
+            Some(Symlink) => Some("S_IFLNK"),
+            Some(NamedPipe) => Some("S_IFIFO"),
+            Some(Socket) => Some("S_IFSOCK"),
+            Some(CharDevice) => Some("S_IFCHR"),
+            Some(BlockDevice) => Some("S_IFBLK"),
+            None if self.0 & libc::S_IFMT as u32 == 0 => None,
+            None => Some("S_IFMT"),
+        };
+        if let Some(kind) = kind {
+            write!(f, "{}|", kind)?;
+        }
+        write!(f, "{:#o}", self.0 & !(libc::S_IFMT as u32))
+    }
+}
+
+#[test]
+fn test_flags() {
+    assert_eq!("O_RDONLY", format!("{:?}", OpenFlags(libc::O_RDONLY)));
+    assert_eq!("O_WRONLY|O_CREAT|O_APPEND",
+        format!("{:?}", OpenFlags(libc::O_WRONLY | libc::O_APPEND | libc::O_CREAT)));
+    assert_eq!("O_RDWR|O_SYNC", format!("{:?}", OpenFlags(libc::O_RDWR | libc::O_SYNC)));
+    assert_eq!("O_RDONLY|0x80000000", format!("{:?}", OpenFlags(i32::MIN)));
+    assert_eq!("F_OK", format!("{:?}", AccessMask(libc::F_OK)));
+    assert_eq!("R_OK|W_OK", format!("{:?}", AccessMask(libc::R_OK | libc::W_OK)));
+    assert_eq!("0x0", format!("{:?}", RenameFlags(0)));
+    assert_eq!("RENAME_EXCHANGE|0x10", format!("{:?}", RenameFlags(0x12)));
+    assert_eq!("S_IFDIR|0o755", format!("{:?}", Mode(0o40755)));
+    assert_eq!("0o4755", format!("{:?}", Mode(0o4755)));
+}
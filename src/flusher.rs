@@ -0,0 +1,168 @@
+// BackgroundFlusher :: a thread for writing out buffered data after the request that asked for
+// it has been answered.
+//
+
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+
+use crate::types::ResultEmpty;
+
+type Job = Box<dyn FnOnce() -> ResultEmpty + Send>;
+
+#[derive(Debug, Default)]
+struct State {
+    pending: usize,
+    error: Option<libc::c_int>,
+}
+
+/// A low-priority background thread for filesystems (or wrappers around them) which buffer
+/// writes, to write the data out to their backend without holding up the request that asked for
+/// it.
+///
+/// Typically, `release` and `flush` submit a job to write out the file's buffered data and return
+/// straight away, and `fsync` calls `wait`, so that it only returns once everything submitted
+/// before it is written. Jobs run one at a time, in the order they were submitted.
+///
+/// This trades durability for latency: when `close` returns, the data isn't written yet, and if
+/// writing it fails, there is no way to tell the process that closed the file. The first error
+/// from a job is kept, and returned by the next `wait` or `take_error`, which is the best that can
+/// be done; processes which need to know that their data is safe must call `fsync`. Data which
+/// hasn't been written when the filesystem process exits is lost, so `try_destroy` should return
+/// the result of `wait` too.
+///
+/// The handle can be cloned and used from any thread. The thread finishes the jobs submitted and
+/// exits once all the handles are dropped. A job which panics is counted as having failed with
+/// `EIO`.
+#[derive(Clone)]
+pub struct BackgroundFlusher {
+    sender: mpsc::Sender<Job>,
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl BackgroundFlusher {
+    /// Start the background thread. On Linux, it runs at a lower priority (a nice value 10 higher)
+    /// than the rest of the process, so that it yields to the threads answering requests.
+    pub fn new() -> io::Result<BackgroundFlusher> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let state = Arc::new((Mutex::new(State::default()), Condvar::new()));
+        let thread_state = state.clone();
+        std::thread::Builder::new()
+            .name("fuse_mt flusher".to_owned())
+            .spawn(move || {
+                lower_priority();
+                for job in receiver {
+                    // A job which panics counts as failed, rather than taking the thread down and
+                    // leaving `wait` waiting for it forever.
+                    let result = panic::catch_unwind(AssertUnwindSafe(job)).unwrap_or_else(|_| {
+                        error!("background flush job panicked");
+                        Err(libc::EIO)
+                    });
+                    let (state, condvar) = &*thread_state;
+                    let mut state = state.lock().unwrap();
+                    if let Err(e) = result {
+                        error!("background flush failed: {}", io::Error::from_raw_os_error(e));
+                        state.error.get_or_insert(e);
+                    }
+                    state.pending -= 1;
+                    condvar.notify_all();
+                }
+            })?;
+        Ok(BackgroundFlusher { sender, state })
+    }
+
+    /// Queue a job to run on the background thread.
+    pub fn submit(&self, job: impl FnOnce() -> ResultEmpty + Send + 'static) {
+        self.state.0.lock().unwrap().pending += 1;
+        if self.sender.send(Box::new(job)).is_err() {
+            // Only if the thread is gone, which it never should be, since jobs can't take it down.
+            error!("background flusher thread has exited; job not run");
+            let (state, condvar) = &*self.state;
+            let mut state = state.lock().unwrap();
+            state.pending -= 1;
+            state.error.get_or_insert(libc::EIO);
+            condvar.notify_all();
+        }
+    }
+
+    /// Wait until all the jobs submitted so far have run, and return the first error from any job
+    /// since the last one was returned.
+    pub fn wait(&self) -> ResultEmpty {
+        let (state, condvar) = &*self.state;
+        let mut state = state.lock().unwrap();
+        while state.pending > 0 {
+            state = condvar.wait(state).unwrap();
+        }
+        match state.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Return the first error from any job since the last one was returned, without waiting.
+    pub fn take_error(&self) -> Option<libc::c_int> {
+        self.state.0.lock().unwrap().error.take()
+    }
+
+    /// The number of jobs submitted which haven't finished running.
+    pub fn pending(&self) -> usize {
+        self.state.0.lock().unwrap().pending
+    }
+}
+
+impl std::fmt::Debug for BackgroundFlusher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BackgroundFlusher")
+            .field("state", &*self.state.0.lock().unwrap())
+            .finish()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn lower_priority() {
+    // On Linux, the nice value is per thread, and this only affects the calling thread.
+    unsafe {
+        let tid = libc::gettid();
+        let nice = libc::getpriority(libc::PRIO_PROCESS, tid as libc::id_t);
+        if libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice + 10) == -1 {
+            warn!("failed to lower the priority of the flusher thread: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lower_priority() {}
+
+#[test]
+fn test_background_flusher() {
+    use std::sync::mpsc::channel;
+
+    let flusher = BackgroundFlusher::new().unwrap();
+
+    // Hold the thread up until the jobs have all been submitted.
+    let (go, wait_for_go) = channel::<()>();
+    flusher.submit(move || {
+        wait_for_go.recv().unwrap();
+        Ok(())
+    });
+    flusher.submit(|| Err(libc::EIO));
+    flusher.submit(|| Err(libc::ENOSPC));
+    assert_eq!(3, flusher.pending());
+    assert_eq!(None, flusher.take_error());
+
+    go.send(()).unwrap();
+    // Only the first error is kept, and it's only returned once.
+    assert_eq!(Err(libc::EIO), flusher.wait());
+    assert_eq!(0, flusher.pending());
+    assert_eq!(Ok(()), flusher.wait());
+
+    let other = flusher.clone();
+    other.submit(|| Err(libc::EIO));
+    assert_eq!(Err(libc::EIO), flusher.wait());
+
+    // A job which panics fails, and the thread carries on with the next ones.
+    flusher.submit(|| panic!("flush job panicked"));
+    assert_eq!(Err(libc::EIO), flusher.wait());
+    flusher.submit(|| Ok(()));
+    assert_eq!(Ok(()), flusher.wait());
+}
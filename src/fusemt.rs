@@ -4,16 +4,28 @@
 // Copyright (c) 2016-2022 by William R. Fraser
 //
 
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::ffi::OsStr;
+use std::fmt::Debug;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
-use fuser::TimeOrNow;
+use fuser::{FileType, TimeOrNow};
 use threadpool::ThreadPool;
 
+use crate::attr_cache::*;
+use crate::auto_cache::*;
+use crate::destroy::*;
 use crate::directory_cache::*;
+use crate::flags::*;
+use crate::inode_locks::*;
 use crate::inode_table::*;
+use crate::open_counts::*;
+use crate::pause::*;
+use crate::read_only::*;
+use crate::usage::*;
 use crate::types::*;
 
 trait IntoRequestInfo {
@@ -31,55 +43,702 @@ impl<'a> IntoRequestInfo for fuser::Request<'a> {
     }
 }
 
-fn fuse_fileattr(attr: FileAttr, ino: u64) -> fuser::FileAttr {
+/// Attributes with timestamps further in the future than this are considered bogus.
+const MAX_FUTURE_TIME: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Check file attributes for internal inconsistencies, returning a description of each problem.
+fn attr_problems(attr: &FileAttr, now: SystemTime) -> Vec<&'static str> {
+    let mut problems = vec![];
+    if attr.kind == fuser::FileType::Directory && attr.nlink < 2 {
+        problems.push("directory has fewer than 2 links");
+    }
+    if attr.perm & !0o7777 != 0 {
+        problems.push("permissions include bits other than mode bits");
+    }
+    // Allow for preallocated space, but not blocks out of all proportion to the size.
+    if attr.blocks.saturating_mul(512) > attr.size.saturating_mul(8).saturating_add(1 << 20) {
+        problems.push("blocks is much larger than the size requires");
+    }
+    if [attr.atime, attr.mtime, attr.ctime, attr.crtime].iter().any(|t| *t > now + MAX_FUTURE_TIME) {
+        problems.push("timestamp is more than a year in the future");
+    }
+    problems
+}
+
+/// Check filesystem statistics for inconsistencies, returning a description of each one found.
+fn statfs_problems(statfs: &Statfs) -> Vec<&'static str> {
+    let mut problems = vec![];
+    if statfs.bfree > statfs.blocks {
+        problems.push("more free blocks than blocks");
+    }
+    // bfree includes blocks reserved for root, which bavail doesn't, so it can't be the smaller.
+    if statfs.bavail > statfs.bfree {
+        problems.push("more blocks available than free");
+    }
+    if statfs.ffree > statfs.files {
+        problems.push("more free file nodes than file nodes");
+    }
+    if statfs.frsize == 0 && statfs.blocks != 0 {
+        problems.push("fragment size (the unit of the block counts) is zero");
+    }
+    problems
+}
+
+/// Whether a name is longer than the limit set with `max_name_len`, if any.
+fn name_too_long(name: &OsStr, max: Option<u32>) -> bool {
+    max.is_some_and(|max| name.len() > max as usize)
+}
+
+/// How long the kernel should remember that a lookup failed with the given error, if at all.
+fn negative_entry_ttl(e: libc::c_int, negative_ttl: Duration) -> Option<Duration> {
+    if e == libc::ENOENT && !negative_ttl.is_zero() {
+        Some(Ttl::clamp(negative_ttl))
+    } else {
+        None
+    }
+}
+
+/// Attributes for a negative lookup entry: the kernel takes inode 0 to mean "not found", and
+/// ignores the rest.
+fn negative_entry_attr() -> fuser::FileAttr {
+    let epoch = SystemTime::UNIX_EPOCH;
     fuser::FileAttr {
-        ino,
-        size: attr.size,
-        blocks: attr.blocks,
-        atime: attr.atime,
-        mtime: attr.mtime,
-        ctime: attr.ctime,
-        crtime: attr.crtime,
-        kind: attr.kind,
-        perm: attr.perm,
-        nlink: attr.nlink,
-        uid: attr.uid,
-        gid: attr.gid,
-        rdev: attr.rdev,
-        blksize: 4096, // TODO
-        flags: attr.flags,
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: epoch,
+        mtime: epoch,
+        ctime: epoch,
+        crtime: epoch,
+        kind: fuser::FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 0,
+        flags: 0,
+    }
+}
+
+/// How long to wait before the given retry of an operation which failed with `EINTR`.
+fn eintr_backoff(attempt: u32) -> Duration {
+    Duration::from_millis(1 << attempt.min(8))
+}
+
+/// Call a function, calling it again up to `retries` times for as long as it fails with `EINTR`.
+fn retry_eintr<R>(retries: u32, mut f: impl FnMut() -> Result<R, libc::c_int>) -> Result<R, libc::c_int> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(libc::EINTR) if attempt < retries => {
+                debug!("operation interrupted; retrying");
+                std::thread::sleep(eintr_backoff(attempt));
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Run a call into the filesystem, catching a panic, which is logged and then handled according
+/// to `mode`: either the process is aborted, or `None` is returned.
+fn catch_panic<R>(mode: PanicMode, f: impl FnOnce() -> R) -> Option<R> {
+    let payload = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(result) => return Some(result),
+        Err(payload) => payload,
+    };
+    let message = payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("(no message)");
+    error!("filesystem panicked: {}", message);
+    if mode == PanicMode::Abort {
+        std::process::abort();
+    }
+    None
+}
+
+/// Run a call into the filesystem, turning a panic into `EIO` (or aborting; see `catch_panic`).
+fn call<R>(mode: PanicMode, f: impl FnOnce() -> Result<R, libc::c_int>) -> Result<R, libc::c_int> {
+    catch_panic(mode, f).unwrap_or(Err(libc::EIO))
+}
+
+/// Read from a file, calling the filesystem again up to `retries` times for as long as it fails
+/// with `EINTR`, and pass the result on to `reply`. Any other error is passed on as it is, so that
+/// the reader sees exactly the errno the filesystem returned (say, `ESTALE` rather than `EIO`).
+#[allow(clippy::too_many_arguments)]
+fn read_retrying<'a, P: Copy, A: Copy>(target: &impl FilesystemMT<'a, P, A>, req: RequestInfo,
+    path: P, fh: u64, offset: u64, size: u32, retries: u32, reply: impl FnOnce(ResultSlice<'_>))
+{
+    let mut reply = Some(reply);
+    for attempt in 0.. {
+        target.read(req, path, fh, offset, size, |result| {
+            match result {
+                Err(libc::EINTR) if attempt < retries => (),
+                Ok(data) if data.len() > size as usize => {
+                    error!("read returned {} bytes when {} were asked for; truncating",
+                        data.len(), size);
+                    reply.take().unwrap()(Ok(&data[..size as usize]))
+                },
+                result => reply.take().unwrap()(result),
+            }
+            CallbackResult {
+                _private: std::marker::PhantomData {},
+            }
+        });
+        if reply.is_none() {
+            break;
+        }
+        debug!("read interrupted; retrying");
+        std::thread::sleep(eintr_backoff(attempt));
+    }
+}
+
+/// Write through `write_buf`, or `write` if the filesystem doesn't implement it. The data is only
+/// copied if it is borrowed and `write` is needed.
+fn write_data<'a, P: Copy, A: Copy>(target: &impl FilesystemMT<'a, P, A>, req: RequestInfo,
+    path: P, fh: u64, offset: u64, data: Cow<'_, [u8]>, flags: u32) -> ResultWrite
+{
+    match target.write_buf(req, path, fh, offset, &data, flags) {
+        Err(libc::ENOSYS) => target.write(req, path, fh, offset, data.into_owned(), flags),
+        result => result,
+    }
+}
+
+/// Convert attributes to the form `fuser` wants, checking them first if `strict_attrs` is set,
+/// and rounding their times down to `time_granularity` if there is one.
+fn fuse_fileattr(attr: FileAttr, ino: u64, strict_attrs: bool, time_granularity: Option<Duration>) -> fuser::FileAttr {
+    if strict_attrs {
+        for problem in attr_problems(&attr, SystemTime::now()) {
+            error!("inode {} has bad attributes: {}: {:?}", ino, problem, attr);
+        }
+    }
+    match time_granularity {
+        Some(granularity) => attr.round_times(granularity).to_fuser(ino),
+        None => attr.to_fuser(ino),
+    }
+}
+
+/// Change attributes with `setattr`, or if the filesystem doesn't implement it, with a separate
+/// call for each kind of change (`chmod`, `chown`, `truncate`, `utimens` and `utimens_macos`),
+/// followed by a `getattr` for the attributes to reply with.
+fn setattr_calls<'a, P: Copy, A: Copy>(target: &impl FilesystemMT<'a, P, A>, capabilities: OpSet,
+    req: RequestInfo, path: P, attrs: SetAttrRequest) -> ResultEntry<A>
+{
+    let SetAttrRequest { fh, mode, uid, gid, size, atime, mtime, ctime: _, crtime, chgtime, bkuptime, flags } = attrs;
+
+    if capabilities.contains(OpSet::SETATTR) {
+        match target.setattr(req, path, attrs) {
+            Err(libc::ENOSYS) => debug!("setattr not implemented; making separate calls"),
+            result => return result,
+        }
+    }
+
+    // TODO: figure out what C FUSE does when only some of these are implemented.
+
+    if let Some(mode) = mode {
+        target.chmod(req, path, fh, mode)?;
+    }
+
+    if uid.is_some() || gid.is_some() {
+        target.chown(req, path, fh, uid, gid)?;
+    }
+
+    if let Some(size) = size {
+        target.truncate(req, path, fh, size)?;
+    }
+
+    if atime.is_some() || mtime.is_some() {
+        target.utimens(req, path, fh, atime, mtime)?;
+    }
+
+    if crtime.is_some() || chgtime.is_some() || bkuptime.is_some() || flags.is_some() {
+        target.utimens_macos(req, path, fh, crtime, chgtime, bkuptime, flags)?;
+    }
+
+    target.getattr(req, path, fh)
+}
+
+/// Rename through `rename_flags`, unless the filesystem says it doesn't implement the plain
+/// rename or exchange asked for, in which case this fails the way the kernel expects: `ENOSYS`
+/// for a plain rename, so that it stops asking, and `EINVAL` for an exchange.
+#[allow(clippy::too_many_arguments)]
+fn rename_with_flags<'a, P: Copy, A: Copy>(target: &impl FilesystemMT<'a, P, A>, capabilities: OpSet,
+    req: RequestInfo, parent: P, name: &OsStr, newparent: P, newname: &OsStr, flags: u32) -> ResultEmpty
+{
+    match flags {
+        0 if !capabilities.contains(OpSet::RENAME) => Err(libc::ENOSYS),
+        RENAME_EXCHANGE if !capabilities.contains(OpSet::EXCHANGE) => Err(libc::EINVAL),
+        _ => target.rename_flags(req, parent, name, newparent, newname, flags),
     }
 }
 
-trait TimeOrNowExt {
-    fn time(self) -> SystemTime;
+/// Set in the flags of an ioctl on a directory, whose file handle is then one from `opendir`. The
+/// kernel only sends those from protocol 7.18 on, and `fuser` only defines this from then.
+#[cfg(feature = "ioctl")]
+const FUSE_IOCTL_DIR: u32 = 1 << 4;
+
+/// The most entries `readdirplus` asks `readdir_offset` for at once. The reply usually has room
+/// for fewer; the kernel asks again for the rest.
+#[cfg(feature = "readdirplus")]
+const READDIRPLUS_BATCH: usize = 128;
+
+/// List part of a directory through `readdir_offset`, for `readdirplus`, which needs the entries
+/// in hand to look up their attributes. Returns up to `READDIRPLUS_BATCH` entries, each with the
+/// offset to continue from after it, leaving out names longer than `max_name_len`.
+#[cfg(feature = "readdirplus")]
+fn readdir_offset_entries<'a, P: Copy + Debug, A: Copy>(target: &impl FilesystemMT<'a, P, A>, req: RequestInfo,
+    path: P, fh: u64, offset: u64, max_name_len: Option<u32>) -> Result<Vec<(i64, DirectoryEntry)>, libc::c_int>
+{
+    let mut entries = vec![];
+    let mut add = |_ino: u64, entry_offset: i64, kind: FileType, name: &OsStr| {
+        if entries.len() == READDIRPLUS_BATCH {
+            return false;
+        }
+        if name_too_long(name, max_name_len) {
+            warn!("readdirplus: {:?} has entry {:?}, which is longer than the maximum name length", path, name);
+        } else {
+            entries.push((entry_offset, DirectoryEntry { name: name.to_owned(), kind }));
+        }
+        true
+    };
+    target.readdir_offset(req, path, fh, offset, &mut DirFiller::new(&mut add))?;
+    Ok(entries)
+}
+
+/// Check that the filesystem implements the operations a mount can't work without, warning about
+/// the ones it doesn't. That's only `getattr`: the kernel needs the root's attributes before
+/// anything in the filesystem can be used, and without them, everything fails with `ENOSYS`.
+/// This only goes by the capabilities; a filesystem which claims `getattr` but leaves it
+/// unimplemented is caught by `check_root_getattr` when the kernel first asks for the root.
+fn check_required_ops(capabilities: OpSet) {
+    if !capabilities.contains(OpSet::GETATTR) {
+        warn!("the filesystem doesn't implement getattr, so nothing in it can be used");
+    }
+}
+
+/// Warn, once per mount, if `getattr` on the root inode `ino` failed with `ENOSYS`. `warned`
+/// records whether that has happened already.
+fn check_root_getattr<A>(ino: u64, result: &Result<A, libc::c_int>, warned: &mut bool) {
+    if ino == fuser::FUSE_ROOT_ID && matches!(result, Err(libc::ENOSYS)) && !*warned {
+        warn!("the filesystem doesn't implement getattr, so nothing in it can be used");
+        *warned = true;
+    }
+}
+
+/// The name of the extended attribute answered by the `capabilities_xattr` option.
+const CAPABILITIES_XATTR: &str = "user.fuse_mt.capabilities";
+
+/// Answer a `getxattr` of `CAPABILITIES_XATTR`, given the size of the caller's buffer.
+fn get_capabilities_xattr(capabilities: OpSet, size: u32) -> ResultXattr {
+    let value = capabilities.names().collect::<Vec<_>>().join(",");
+    xattr_reply(value.into_bytes(), size)
+}
+
+/// Add `CAPABILITIES_XATTR` to the result of a `listxattr`, given the size of the caller's buffer.
+/// A filesystem which doesn't support extended attributes has an empty list.
+fn list_capabilities_xattr(result: ResultXattr, size: u32) -> ResultXattr {
+    let extra = CAPABILITIES_XATTR.len() as u32 + 1;
+    let mut list = match result {
+        Ok(Xattr::Size(fs_size)) => return Ok(Xattr::Size(fs_size + extra)),
+        Ok(Xattr::Data(list)) => list,
+        Err(libc::ENOSYS) | Err(libc::ENOTSUP) => vec![],
+        Err(e) => return Err(e),
+    };
+    list.extend_from_slice(CAPABILITIES_XATTR.as_bytes());
+    list.push(0);
+    xattr_reply(list, size)
+}
+
+/// Reply to an extended attribute request with the size of the data if the caller's buffer size
+/// is zero, or with the data itself if it fits.
+fn xattr_reply(data: Vec<u8>, size: u32) -> ResultXattr {
+    if size == 0 {
+        Ok(Xattr::Size(data.len() as u32))
+    } else if data.len() > size as usize {
+        Err(libc::ERANGE)
+    } else {
+        Ok(Xattr::Data(data))
+    }
 }
 
-impl TimeOrNowExt for TimeOrNow {
-    fn time(self) -> SystemTime {
-        match self {
-            TimeOrNow::SpecificTime(t) => t,
-            TimeOrNow::Now => SystemTime::now(),
+/// Open a file, doing any truncation asked for with `O_TRUNC` as a separate `truncate` to zero
+/// with the new file handle, so that filesystems always see it the same way, whichever way the
+/// kernel asks for it. If the truncation fails, the file is released again and the open fails.
+fn open_truncating<'a, P: Copy, A: Copy>(target: &impl FilesystemMT<'a, P, A>, req: RequestInfo,
+    path: P, flags: u32) -> ResultOpen
+{
+    let trunc = libc::O_TRUNC as u32;
+    let (fh, open_flags) = target.open(req, path, flags & !trunc)?;
+    if flags & trunc != 0 {
+        if let Err(e) = target.truncate(req, path, Some(fh), 0) {
+            if let Err(e) = target.release(req, path, fh, flags & !trunc, 0, false) {
+                warn!("release after failed truncate-on-open failed: {}", e);
+            }
+            return Err(e);
+        }
+    }
+    Ok((fh, open_flags))
+}
+
+/// Look for entries with the same name in a directory listing, which is a bug in the filesystem.
+/// These are logged in debug builds, and removed (keeping the first) if `dedup` is set.
+fn check_duplicate_entries(dir: &dyn Debug, entries: &mut Vec<DirectoryEntry>, dedup: bool) {
+    if !dedup && !cfg!(debug_assertions) {
+        return;
+    }
+
+    let mut seen = HashSet::new();
+    let duplicates: Vec<usize> = entries.iter()
+        .enumerate()
+        .filter(|(_, entry)| !seen.insert(entry.name.as_os_str()))
+        .map(|(index, _)| index)
+        .collect();
+
+    for &index in duplicates.iter().rev() {
+        warn!("readdir: {:?} has duplicate entry {:?}", dir, entries[index].name);
+        if dedup {
+            entries.remove(index);
         }
     }
 }
 
+/// Drop entries from a directory listing whose names are longer than the limit set with
+/// `max_name_len`, logging them. The limit is reported in `statfs` and lookups of longer names
+/// are refused, so tools would be shown names which they can't then use.
+fn check_entry_names(dir: &dyn Debug, entries: &mut Vec<DirectoryEntry>, max: Option<u32>) {
+    entries.retain(|entry| {
+        let too_long = name_too_long(&entry.name, max);
+        if too_long {
+            warn!("readdir: {:?} has entry {:?}, which is longer than the maximum name length", dir, entry.name);
+        }
+        !too_long
+    });
+}
+
+/// Swap the kernel's root node ID with the filesystem's root inode number, leaving all other
+/// numbers unchanged. This is its own inverse, so it translates in either direction.
+fn swap_root_inode(root: Inode, ino: u64) -> u64 {
+    if ino == fuser::FUSE_ROOT_ID {
+        root
+    } else if ino == root {
+        fuser::FUSE_ROOT_ID
+    } else {
+        ino
+    }
+}
+
 impl<T: RawFilesystemMT + Sync + Send + 'static> RawFuseMT<T> {
+    /// Wrap a filesystem, to be mounted with `mount` or `spawn_mount`.
+    ///
+    /// I/O operations (`read`, `write`, `flush`, `fsync` and the like) run on a pool of
+    /// `num_threads` threads, started on the first such request; everything else runs on the
+    /// thread receiving requests from the kernel. With zero threads, I/O runs there too, one
+    /// request at a time.
     pub fn new(target_fs: T, num_threads: usize) -> RawFuseMT<T> {
+        let root = target_fs.root();
+        let capabilities = target_fs.capabilities();
         RawFuseMT {
             target: Arc::new(target_fs),
             threads: None,
             num_threads,
+            thread_name: "fuse_mt worker".to_owned(),
+            thread_stack_size: None,
+            root,
+            directory_cache: None,
+            dedup_readdir: false,
+            strict_attrs: false,
+            write_locks: None,
+            negative_ttl: Duration::ZERO,
+            max_name_len: None,
+            open_counts: Arc::new(OpenCounts::new(None)),
+            auto_cache: AutoCache::new(),
+            eintr_retries: 0,
+            pause: PauseHandle::new(),
+            read_only: ReadOnlyHandle::new(),
+            usage: UsageHandle::new(),
+            destroyed: DestroyHandle::new(),
+            capabilities,
+            capabilities_xattr: false,
+            #[cfg(feature = "umask")]
+            dont_mask: false,
+            panic_mode: PanicMode::default(),
+            time_granularity: None,
+            warned_getattr: false,
+        }
+    }
+
+    /// Drop entries with duplicate names from directory listings, keeping the first one.
+    ///
+    /// Duplicate names are always a bug in the filesystem, and are logged as a warning in debug
+    /// builds. By default they are passed along to the kernel anyway, so that the bug stays
+    /// visible; with this set, they are removed instead.
+    pub fn dedup_readdir_entries(mut self, dedup: bool) -> RawFuseMT<T> {
+        self.dedup_readdir = dedup;
+        self
+    }
+
+    /// Get each open directory's entries from the filesystem only once, on the first `readdir`,
+    /// and serve every later `readdir` on the same handle from that snapshot, until a rewind.
+    ///
+    /// The kernel reads a directory in pieces, asking for the entries from a given offset each
+    /// time, and uses the offsets for `telldir` and `seekdir` too. Without a snapshot, the
+    /// filesystem's `readdir` is called again for each piece, so if the directory changes in
+    /// between, the offsets no longer line up, and entries can be skipped or listed twice. With
+    /// this set, a listing is consistent for as long as the directory is open, as POSIX prefers;
+    /// changes show up the next time it is opened, or when it is read from the start again after
+    /// a `rewinddir`. This is how `FuseMT` always behaves. The filesystem must implement `opendir`
+    /// for this to work, since the handle it returns is what the snapshot is kept with. By
+    /// default, there are no snapshots.
+    pub fn snapshot_readdir(mut self, snapshot: bool) -> RawFuseMT<T> {
+        self.directory_cache = if snapshot { Some(DirectoryCache::new()) } else { None };
+        self
+    }
+
+    /// Check all attributes returned by the filesystem for inconsistencies, such as a directory
+    /// with fewer than two links or permissions which include file type bits, and log an error
+    /// for each problem found. Filesystem statistics from `statfs` are checked too, for example
+    /// for more blocks available than free. This is meant as a debugging aid, and is off by
+    /// default; either way, everything is passed on to the kernel as is.
+    pub fn strict_attrs(mut self, strict: bool) -> RawFuseMT<T> {
+        self.strict_attrs = strict;
+        self
+    }
+
+    /// Round down the timestamps in all attributes returned by the filesystem to a multiple of
+    /// the given granularity, for filesystems which store them less precisely than `SystemTime`.
+    /// See `FileAttr::round_times` for why this matters.
+    pub fn time_granularity(mut self, granularity: Duration) -> RawFuseMT<T> {
+        self.time_granularity = Some(granularity);
+        self
+    }
+
+    /// Don't let writes or truncates of the same file run at the same time.
+    ///
+    /// Writes are run on the thread pool, so two writes to the same file can otherwise be in the
+    /// filesystem at once, and a filesystem which doesn't do its own locking can end up with the
+    /// data of overlapping writes mixed together. With this set, each file's writes (and
+    /// truncates) are run one at a time, at the cost of write throughput to any single file;
+    /// reads are not affected, and writes to different files still run in parallel. Truncates,
+    /// whether from `setattr` or opening with `O_TRUNC`, then run on the thread pool as well, so
+    /// that waiting for a file's writes doesn't hold up other requests. By default, writes are not
+    /// serialized and the filesystem is responsible for its own locking.
+    pub fn serialize_writes(mut self, serialize: bool) -> RawFuseMT<T> {
+        self.write_locks = if serialize { Some(Arc::new(InodeLocks::new())) } else { None };
+        self
+    }
+
+    /// Have the kernel remember names which were not found for the given duration.
+    ///
+    /// By default this is zero: when a lookup fails with `ENOENT`, the kernel asks again the next
+    /// time the name is used, so a file which is created behind the kernel's back (by another
+    /// machine, say) is visible right away. A filesystem whose contents only change through the
+    /// mount can set this to avoid repeated lookups of names that don't exist, such as when
+    /// searching a path for a program; the kernel forgets these itself when it creates a file.
+    pub fn negative_lookup_ttl(mut self, ttl: Duration) -> RawFuseMT<T> {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// Refuse names longer than the given number of bytes with `ENAMETOOLONG`, without passing
+    /// the request on to the filesystem.
+    ///
+    /// The kernel already refuses names longer than 255 bytes, but a filesystem's backend may
+    /// have a lower limit. This applies to new names given to `mknod`, `mkdir`, `symlink`,
+    /// `create`, `link` and `rename`, and to names being looked up, since a name too long to
+    /// create can't exist. The limit is also reported as the maximum name length in `statfs`, if
+    /// the filesystem gives a larger one, and entries with longer names are left out of directory
+    /// listings, with a warning. By default, there is no limit.
+    pub fn max_name_len(mut self, max: u32) -> RawFuseMT<T> {
+        self.max_name_len = Some(max);
+        self
+    }
+
+    /// Limit how many times any one file can be open at once. Opens beyond the limit fail with
+    /// `ENFILE` without reaching the filesystem; they aren't queued, because the kernel can't
+    /// close a file while one of its requests is waiting.
+    ///
+    /// This is for filesystems backed by something with a hard limit on handles per object. Files
+    /// opened by `create` count towards the limit, but are never refused. By default, there is no
+    /// limit.
+    pub fn max_opens_per_inode(mut self, max: usize) -> RawFuseMT<T> {
+        self.open_counts = Arc::new(OpenCounts::new(Some(max)));
+        self
+    }
+
+    /// Retry operations which fail with `EINTR` up to the given number of times, waiting a little
+    /// longer before each retry, before passing the error on.
+    ///
+    /// This only applies to operations which can safely be repeated: `lookup`, `getattr`, `read`
+    /// and `readdir`. Everything else could have taken effect before failing, so it is left to
+    /// the filesystem to decide whether to retry. Note that all but `read` run on the thread which
+    /// handles all requests, so long waits hold up the whole filesystem. By default, operations
+    /// are not retried.
+    pub fn retry_on_eintr(mut self, retries: u32) -> RawFuseMT<T> {
+        self.eintr_retries = retries;
+        self
+    }
+
+    /// Answer reads of the extended attribute `user.fuse_mt.capabilities` on every file with the
+    /// names of the operations the filesystem supports, from `FilesystemMT::capabilities`,
+    /// separated by commas (like `getattr,read,readdir`), and include it in listings of extended
+    /// attributes, without passing the requests on to the filesystem. This lets tools find out
+    /// what a filesystem can do. Setting or removing the attribute is passed on as usual. By
+    /// default, the attribute doesn't exist, so as not to add to the namespace.
+    pub fn capabilities_xattr(mut self, enable: bool) -> RawFuseMT<T> {
+        self.capabilities_xattr = enable;
+        self
+    }
+
+    /// Ask the kernel not to apply the umask to the modes given to `create`, `mkdir` and `mknod`,
+    /// leaving it to the filesystem, which gets the umask to apply alongside the mode. This is for
+    /// filesystems which support default ACLs, which replace the umask for entries created in
+    /// directories which have them. Kernels which don't support this keep applying the umask
+    /// themselves. By default, the kernel applies it. This needs the `umask` feature.
+    #[cfg(feature = "umask")]
+    pub fn dont_mask(mut self, enable: bool) -> RawFuseMT<T> {
+        self.dont_mask = enable;
+        self
+    }
+
+    /// Name the thread pool's threads, for `top -H`, debuggers and panic messages. The default
+    /// is `fuse_mt worker`. Linux only shows the first 15 bytes of thread names.
+    pub fn thread_name(mut self, name: &str) -> RawFuseMT<T> {
+        self.thread_name = name.to_owned();
+        self
+    }
+
+    /// Set the stack size of the thread pool's threads, in bytes. The default is the standard
+    /// library's, which is 2 MiB unless the `RUST_MIN_STACK` environment variable says otherwise.
+    pub fn thread_stack_size(mut self, bytes: usize) -> RawFuseMT<T> {
+        self.thread_stack_size = Some(bytes);
+        self
+    }
+
+    /// Choose what happens when the filesystem panics. See `PanicMode`.
+    pub fn panic_mode(mut self, mode: PanicMode) -> RawFuseMT<T> {
+        self.panic_mode = mode;
+        self
+    }
+
+    /// Get a handle which can be used to pause and resume the processing of requests once the
+    /// filesystem is mounted. See `PauseHandle` for details.
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.pause.clone()
+    }
+
+    /// Get a handle which can be used to make the filesystem read-only, and writable again, once
+    /// it is mounted. See `ReadOnlyHandle` for details.
+    pub fn read_only_handle(&self) -> ReadOnlyHandle {
+        self.read_only.clone()
+    }
+
+    /// Get a handle which can be used to see how many requests are in flight, and how much data is
+    /// buffered for them. See `UsageHandle` for details.
+    pub fn usage_handle(&self) -> UsageHandle {
+        self.usage.clone()
+    }
+
+    /// Get a handle which can be used to find out whether the filesystem failed to shut down
+    /// cleanly once it is unmounted. See `DestroyHandle` for details.
+    pub fn destroy_handle(&self) -> DestroyHandle {
+        self.destroyed.clone()
+    }
+
+    /// Convert attributes to the form `fuser` wants, checking them first if strict mode is on.
+    fn fuse_fileattr(&self, attr: FileAttr, ino: u64) -> fuser::FileAttr {
+        fuse_fileattr(attr, ino, self.strict_attrs, self.time_granularity)
+    }
+
+    /// Reply to a failed lookup, with a negative entry if the kernel should remember it.
+    fn lookup_error(&self, e: libc::c_int, reply: fuser::ReplyEntry) {
+        match negative_entry_ttl(e, self.negative_ttl) {
+            Some(ttl) => reply.entry(&ttl, &negative_entry_attr(), 0),
+            None => reply.error(e),
+        }
+    }
+
+    /// Translate a node ID from the kernel into the filesystem's inode number.
+    fn fs_ino(&self, ino: u64) -> Inode {
+        swap_root_inode(self.root, ino)
+    }
+
+    /// Get the attributes of the given node ID for the kernel, as `getattr` replies with them. The
+    /// root reports the filesystem's own root inode number as `st_ino`, and other nodes report
+    /// their node ID, as the kernel knows them (so the node swapped with the root reports 1).
+    fn getattr_reply(&mut self, req: RequestInfo, ino: u64) -> Result<(Duration, fuser::FileAttr), libc::c_int> {
+        let result = retry_eintr(self.eintr_retries, || {
+            call(self.panic_mode, || self.target.getattr(req, self.fs_ino(ino), None))
+        });
+        check_root_getattr(ino, &result, &mut self.warned_getattr);
+        let (ttl, attr) = result?;
+        Ok((Ttl::clamp(ttl), self.fuse_fileattr(attr.into(), self.fs_ino(ino))))
+    }
+
+    /// Translate one of the filesystem's inode numbers into a node ID for the kernel.
+    fn kernel_ino(&self, ino: Inode) -> u64 {
+        swap_root_inode(self.root, ino)
+    }
+
+    /// Replace `FOPEN_AUTO_CACHE` in the flags returned by `open` or `create` with
+    /// `FOPEN_KEEP_CACHE` if the file hasn't changed since it was last opened.
+    fn auto_cache_flags(&mut self, req: &fuser::Request<'_>, ino: u64, fh: u64, flags: u32) -> u32 {
+        if flags & FOPEN_AUTO_CACHE == 0 {
+            return flags;
+        }
+        let flags = flags & !FOPEN_AUTO_CACHE;
+        match call(self.panic_mode, || self.target.getattr(req.info(), self.fs_ino(ino), Some(fh))) {
+            Ok((_, attr)) if self.auto_cache.check(ino, attr.mtime, attr.size) => flags | FOPEN_KEEP_CACHE,
+            Ok(_) => flags,
+            Err(e) => {
+                warn!("auto cache: getattr of {:?} failed: {}", ino, e);
+                self.auto_cache.forget(ino);
+                flags
+            }
         }
     }
 
+    /// Get the entries of a directory from the filesystem.
+    fn fetch_dir_entries(&self, req: &fuser::Request<'_>, ino: u64, fh: u64) -> ResultReaddir {
+        let mut entries = retry_eintr(self.eintr_retries, || {
+            call(self.panic_mode, || self.target.readdir(req.info(), self.fs_ino(ino), fh))
+        })?;
+        check_duplicate_entries(&ino, &mut entries, self.dedup_readdir);
+        check_entry_names(&ino, &mut entries, self.max_name_len);
+        Ok(entries)
+    }
+
     fn threadpool_run<F: FnOnce() + Send + 'static>(&mut self, f: F) {
+        self.threadpool_run_buffered(0, f)
+    }
+
+    /// Like `threadpool_run`, but counting the given number of bytes as buffered for the request
+    /// until it's done.
+    fn threadpool_run_buffered<F: FnOnce() + Send + 'static>(&mut self, bytes: u64, f: F) {
+        let guard = self.usage.track(bytes);
+        let panic_mode = self.panic_mode;
+        let pause = self.pause.clone();
+        let f = move || {
+            // Waiting here rather than on the session thread leaves it free to take forgets,
+            // releases and the like from the kernel while paused.
+            pause.wait();
+            // If the filesystem panics, the reply is dropped without being sent, and `fuser`
+            // answers with `EIO`.
+            catch_panic(panic_mode, f);
+            drop(guard);
+        };
         if self.num_threads == 0 {
             f()
         } else {
             if self.threads.is_none() {
                 debug!("initializing threadpool with {} threads", self.num_threads);
-                self.threads = Some(ThreadPool::new(self.num_threads));
+                let mut builder = threadpool::Builder::new()
+                    .num_threads(self.num_threads)
+                    .thread_name(self.thread_name.clone());
+                if let Some(size) = self.thread_stack_size {
+                    builder = builder.thread_stack_size(size);
+                }
+                self.threads = Some(builder.build());
             }
             self.threads.as_ref().unwrap().execute(f);
         }
@@ -91,6 +750,29 @@ pub struct RawFuseMT<T> {
     target: Arc<T>,
     threads: Option<ThreadPool>,
     num_threads: usize,
+    thread_name: String,
+    thread_stack_size: Option<usize>,
+    root: Inode,
+    directory_cache: Option<DirectoryCache>,
+    dedup_readdir: bool,
+    strict_attrs: bool,
+    write_locks: Option<Arc<InodeLocks>>,
+    negative_ttl: Duration,
+    max_name_len: Option<u32>,
+    open_counts: Arc<OpenCounts>,
+    auto_cache: AutoCache,
+    eintr_retries: u32,
+    pause: PauseHandle,
+    read_only: ReadOnlyHandle,
+    usage: UsageHandle,
+    destroyed: DestroyHandle,
+    capabilities: OpSet,
+    capabilities_xattr: bool,
+    #[cfg(feature = "umask")]
+    dont_mask: bool,
+    panic_mode: PanicMode,
+    time_granularity: Option<Duration>,
+    warned_getattr: bool,
 }
 
 #[derive(Debug)]
@@ -99,33 +781,458 @@ pub struct FuseMT<T> {
     inodes: InodeTable,
     threads: Option<ThreadPool>,
     num_threads: usize,
+    thread_name: String,
+    thread_stack_size: Option<usize>,
     directory_cache: DirectoryCache,
+    attr_cache: Arc<AttrCache>,
+    dedup_readdir: bool,
+    strict_attrs: bool,
+    write_locks: Option<Arc<InodeLocks>>,
+    negative_ttl: Duration,
+    max_name_len: Option<u32>,
+    open_counts: Arc<OpenCounts>,
+    auto_cache: AutoCache,
+    eintr_retries: u32,
+    pause: PauseHandle,
+    read_only: ReadOnlyHandle,
+    usage: UsageHandle,
+    destroyed: DestroyHandle,
+    capabilities: OpSet,
+    capabilities_xattr: bool,
+    #[cfg(feature = "umask")]
+    dont_mask: bool,
+    panic_mode: PanicMode,
+    time_granularity: Option<Duration>,
+    warned_getattr: bool,
 }
 
 impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> FuseMT<T> {
+    /// Wrap a filesystem, to be mounted with `mount` or `spawn_mount`.
+    ///
+    /// I/O operations (`read`, `write`, `flush`, `fsync` and the like) run on a pool of
+    /// `num_threads` threads, started on the first such request; everything else runs on the
+    /// thread receiving requests from the kernel. With zero threads, I/O runs there too, one
+    /// request at a time.
     pub fn new(target_fs: T, num_threads: usize) -> FuseMT<T> {
+        let capabilities = target_fs.capabilities();
         FuseMT {
             target: Arc::new(target_fs),
             inodes: InodeTable::new(),
             threads: None,
             num_threads,
+            thread_name: "fuse_mt worker".to_owned(),
+            thread_stack_size: None,
             directory_cache: DirectoryCache::new(),
+            attr_cache: Arc::new(AttrCache::new()),
+            dedup_readdir: false,
+            strict_attrs: false,
+            write_locks: None,
+            negative_ttl: Duration::ZERO,
+            max_name_len: None,
+            open_counts: Arc::new(OpenCounts::new(None)),
+            auto_cache: AutoCache::new(),
+            eintr_retries: 0,
+            pause: PauseHandle::new(),
+            read_only: ReadOnlyHandle::new(),
+            usage: UsageHandle::new(),
+            destroyed: DestroyHandle::new(),
+            capabilities,
+            capabilities_xattr: false,
+            #[cfg(feature = "umask")]
+            dont_mask: false,
+            panic_mode: PanicMode::default(),
+            time_granularity: None,
+            warned_getattr: false,
+        }
+    }
+
+    /// Drop entries with duplicate names from directory listings, keeping the first one.
+    ///
+    /// Duplicate names are always a bug in the filesystem, and are logged as a warning in debug
+    /// builds. By default they are passed along to the kernel anyway, so that the bug stays
+    /// visible; with this set, they are removed instead.
+    pub fn dedup_readdir_entries(mut self, dedup: bool) -> FuseMT<T> {
+        self.dedup_readdir = dedup;
+        self
+    }
+
+    /// Check all attributes returned by the filesystem for inconsistencies, such as a directory
+    /// with fewer than two links or permissions which include file type bits, and log an error
+    /// for each problem found. Filesystem statistics from `statfs` are checked too, for example
+    /// for more blocks available than free. This is meant as a debugging aid, and is off by
+    /// default; either way, everything is passed on to the kernel as is.
+    pub fn strict_attrs(mut self, strict: bool) -> FuseMT<T> {
+        self.strict_attrs = strict;
+        self
+    }
+
+    /// Round down the timestamps in all attributes returned by the filesystem to a multiple of
+    /// the given granularity, for filesystems which store them less precisely than `SystemTime`.
+    /// See `FileAttr::round_times` for why this matters.
+    pub fn time_granularity(mut self, granularity: Duration) -> FuseMT<T> {
+        self.time_granularity = Some(granularity);
+        self
+    }
+
+    /// Don't let writes or truncates of the same file run at the same time.
+    ///
+    /// Writes are run on the thread pool, so two writes to the same file can otherwise be in the
+    /// filesystem at once, and a filesystem which doesn't do its own locking can end up with the
+    /// data of overlapping writes mixed together. With this set, each file's writes (and
+    /// truncates) are run one at a time, at the cost of write throughput to any single file;
+    /// reads are not affected, and writes to different files still run in parallel. Truncates,
+    /// whether from `setattr` or opening with `O_TRUNC`, then run on the thread pool as well, so
+    /// that waiting for a file's writes doesn't hold up other requests. By default, writes are not
+    /// serialized and the filesystem is responsible for its own locking.
+    pub fn serialize_writes(mut self, serialize: bool) -> FuseMT<T> {
+        self.write_locks = if serialize { Some(Arc::new(InodeLocks::new())) } else { None };
+        self
+    }
+
+    /// Have the kernel remember names which were not found for the given duration.
+    ///
+    /// By default this is zero: when a lookup fails with `ENOENT`, the kernel asks again the next
+    /// time the name is used, so a file which is created behind the kernel's back (by another
+    /// machine, say) is visible right away. A filesystem whose contents only change through the
+    /// mount can set this to avoid repeated lookups of names that don't exist, such as when
+    /// searching a path for a program; the kernel forgets these itself when it creates a file.
+    pub fn negative_lookup_ttl(mut self, ttl: Duration) -> FuseMT<T> {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// Refuse names longer than the given number of bytes with `ENAMETOOLONG`, without passing
+    /// the request on to the filesystem.
+    ///
+    /// The kernel already refuses names longer than 255 bytes, but a filesystem's backend may
+    /// have a lower limit. This applies to new names given to `mknod`, `mkdir`, `symlink`,
+    /// `create`, `link` and `rename`, and to names being looked up, since a name too long to
+    /// create can't exist. The limit is also reported as the maximum name length in `statfs`, if
+    /// the filesystem gives a larger one, and entries with longer names are left out of directory
+    /// listings, with a warning. By default, there is no limit.
+    pub fn max_name_len(mut self, max: u32) -> FuseMT<T> {
+        self.max_name_len = Some(max);
+        self
+    }
+
+    /// Limit how many times any one file can be open at once. Opens beyond the limit fail with
+    /// `ENFILE` without reaching the filesystem; they aren't queued, because the kernel can't
+    /// close a file while one of its requests is waiting.
+    ///
+    /// This is for filesystems backed by something with a hard limit on handles per object. Files
+    /// opened by `create` count towards the limit, but are never refused. By default, there is no
+    /// limit.
+    pub fn max_opens_per_inode(mut self, max: usize) -> FuseMT<T> {
+        self.open_counts = Arc::new(OpenCounts::new(Some(max)));
+        self
+    }
+
+    /// Retry operations which fail with `EINTR` up to the given number of times, waiting a little
+    /// longer before each retry, before passing the error on.
+    ///
+    /// This only applies to operations which can safely be repeated: `lookup`, `getattr`, `read`
+    /// and `readdir`. Everything else could have taken effect before failing, so it is left to
+    /// the filesystem to decide whether to retry. Note that all but `read` run on the thread which
+    /// handles all requests, so long waits hold up the whole filesystem. By default, operations
+    /// are not retried.
+    pub fn retry_on_eintr(mut self, retries: u32) -> FuseMT<T> {
+        self.eintr_retries = retries;
+        self
+    }
+
+    /// Answer reads of the extended attribute `user.fuse_mt.capabilities` on every file with the
+    /// names of the operations the filesystem supports, from `FilesystemMT::capabilities`,
+    /// separated by commas (like `getattr,read,readdir`), and include it in listings of extended
+    /// attributes, without passing the requests on to the filesystem. This lets tools find out
+    /// what a filesystem can do. Setting or removing the attribute is passed on as usual. By
+    /// default, the attribute doesn't exist, so as not to add to the namespace.
+    pub fn capabilities_xattr(mut self, enable: bool) -> FuseMT<T> {
+        self.capabilities_xattr = enable;
+        self
+    }
+
+    /// Ask the kernel not to apply the umask to the modes given to `create`, `mkdir` and `mknod`,
+    /// leaving it to the filesystem, which gets the umask to apply alongside the mode. This is for
+    /// filesystems which support default ACLs, which replace the umask for entries created in
+    /// directories which have them. Kernels which don't support this keep applying the umask
+    /// themselves. By default, the kernel applies it. This needs the `umask` feature.
+    #[cfg(feature = "umask")]
+    pub fn dont_mask(mut self, enable: bool) -> FuseMT<T> {
+        self.dont_mask = enable;
+        self
+    }
+
+    /// Name the thread pool's threads, for `top -H`, debuggers and panic messages. The default
+    /// is `fuse_mt worker`. Linux only shows the first 15 bytes of thread names.
+    pub fn thread_name(mut self, name: &str) -> FuseMT<T> {
+        self.thread_name = name.to_owned();
+        self
+    }
+
+    /// Set the stack size of the thread pool's threads, in bytes. The default is the standard
+    /// library's, which is 2 MiB unless the `RUST_MIN_STACK` environment variable says otherwise.
+    pub fn thread_stack_size(mut self, bytes: usize) -> FuseMT<T> {
+        self.thread_stack_size = Some(bytes);
+        self
+    }
+
+    /// Choose what happens when the filesystem panics. See `PanicMode`.
+    pub fn panic_mode(mut self, mode: PanicMode) -> FuseMT<T> {
+        self.panic_mode = mode;
+        self
+    }
+
+    /// Choose how generation numbers are assigned to inodes. The default is
+    /// `GenerationStrategy::Incrementing`; see `GenerationStrategy` for why this matters.
+    pub fn generation_strategy(mut self, strategy: GenerationStrategy) -> FuseMT<T> {
+        self.inodes.set_generation_strategy(strategy);
+        self
+    }
+
+    /// Give each path the same inode number for as long as the filesystem is mounted, so that
+    /// inode numbers don't depend on when the kernel forgets about files, for reproducible
+    /// snapshots of a filesystem's structure in tests. With the same operations in the same
+    /// order, the numbers are the same on every run. Forgotten paths aren't removed from the inode
+    /// table, so it grows with every path ever looked up; this isn't meant for general use. By
+    /// default, inode numbers are re-used once the kernel forgets them.
+    pub fn stable_inode_numbers(mut self, stable: bool) -> FuseMT<T> {
+        self.inodes.set_stable_inodes(stable);
+        self
+    }
+
+    /// Get a handle which can be used to pause and resume the processing of requests once the
+    /// filesystem is mounted. See `PauseHandle` for details.
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.pause.clone()
+    }
+
+    /// Get a handle which can be used to make the filesystem read-only, and writable again, once
+    /// it is mounted. See `ReadOnlyHandle` for details.
+    pub fn read_only_handle(&self) -> ReadOnlyHandle {
+        self.read_only.clone()
+    }
+
+    /// Get a handle which can be used to see how many requests are in flight, and how much data is
+    /// buffered for them. See `UsageHandle` for details.
+    pub fn usage_handle(&self) -> UsageHandle {
+        self.usage.clone()
+    }
+
+    /// Get a handle which can be used to find out whether the filesystem failed to shut down
+    /// cleanly once it is unmounted. See `DestroyHandle` for details.
+    pub fn destroy_handle(&self) -> DestroyHandle {
+        self.destroyed.clone()
+    }
+
+    /// Convert attributes to the form `fuser` wants, checking them first if strict mode is on.
+    fn fuse_fileattr(&self, attr: FileAttr, ino: u64) -> fuser::FileAttr {
+        fuse_fileattr(attr, ino, self.strict_attrs, self.time_granularity)
+    }
+
+    /// Reply to a failed lookup, with a negative entry if the kernel should remember it.
+    fn lookup_error(&self, e: libc::c_int, reply: fuser::ReplyEntry) {
+        match negative_entry_ttl(e, self.negative_ttl) {
+            Some(ttl) => reply.entry(&ttl, &negative_entry_attr(), 0),
+            None => reply.error(e),
+        }
+    }
+
+    /// Get attributes for a path, from the attribute cache if `readdir_with_attrs` left some
+    /// there, or else from the filesystem.
+    fn getattr_cached(&mut self, req: &fuser::Request<'_>, path: &Path) -> ResultEntry {
+        if let Some(entry) = self.attr_cache.take(path) {
+            debug!("using attributes from readdir for {:?}", path);
+            return Ok(entry);
+        }
+        retry_eintr(self.eintr_retries, || call(self.panic_mode, || self.target.getattr(req.info(), path, None)))
+    }
+
+    /// Replace `FOPEN_AUTO_CACHE` in the flags returned by `open` or `create` with
+    /// `FOPEN_KEEP_CACHE` if the file hasn't changed since it was last opened.
+    fn auto_cache_flags(&mut self, req: &fuser::Request<'_>, ino: u64, path: &Path, fh: u64, flags: u32) -> u32 {
+        if flags & FOPEN_AUTO_CACHE == 0 {
+            return flags;
+        }
+        let flags = flags & !FOPEN_AUTO_CACHE;
+        match call(self.panic_mode, || self.target.getattr(req.info(), path, Some(fh))) {
+            Ok((_, attr)) if self.auto_cache.check(ino, attr.mtime, attr.size) => flags | FOPEN_KEEP_CACHE,
+            Ok(_) => flags,
+            Err(e) => {
+                warn!("auto cache: getattr of {:?} failed: {}", path, e);
+                self.auto_cache.forget(ino);
+                flags
+            }
+        }
+    }
+
+    /// Drop lookups of an inode, telling the filesystem if that removes its path from the table.
+    fn forget_inode(&mut self, ino: u64, nlookup: u64) {
+        let path = self.inodes.get_path(ino);
+        let lookups = self.inodes.forget(ino, nlookup);
+        debug!("forget: inode {} ({:?}) now at {} lookups", ino, path, lookups);
+        if lookups == 0 {
+            self.auto_cache.forget(ino);
+            if let Some(path) = path {
+                catch_panic(self.panic_mode, || self.target.on_forget(&path));
+            }
+        }
+    }
+
+    /// Get the entries of a directory from the filesystem, keeping any attributes that come with
+    /// them in the attribute cache.
+    fn fetch_dir_entries(&mut self, req: &fuser::Request<'_>, path: &Path, fh: u64) -> ResultReaddir {
+        let mut entries = Vec::new();
+        let listing = retry_eintr(self.eintr_retries, || {
+            call(self.panic_mode, || self.target.readdir_with_attrs(req.info(), path, fh))
+        })?;
+        self.attr_cache.sweep();
+        for (entry, attr) in listing {
+            if let Some((ttl, attr)) = attr {
+                if entry.name != Path::new(".") && entry.name != Path::new("..") {
+                    if entry.kind != attr.kind {
+                        // The kernel passes the entry's kind on to readdir callers as d_type, so
+                        // tools trusting it would disagree with stat.
+                        warn!("readdir_with_attrs: {:?} in {:?} is listed as {:?} but its attributes say {:?}",
+                            entry.name, path, entry.kind, attr.kind);
+                    }
+                    self.attr_cache.insert(path.join(&entry.name), ttl, attr);
+                }
+            }
+            entries.push(entry);
+        }
+        check_duplicate_entries(&path, &mut entries, self.dedup_readdir);
+        check_entry_names(&path, &mut entries, self.max_name_len);
+        Ok(entries)
+    }
+
+    /// Make sure the entries of an open directory have been fetched into the directory cache,
+    /// fetching them again when reading starts over from offset 0. If the filesystem doesn't
+    /// implement opendir, the kernel stops calling it, and there isn't a directory cache entry to
+    /// use, so the entries are fetched anew and returned instead.
+    fn load_dir_entries(&mut self, req: &fuser::Request<'_>, path: &Path, fh: u64, offset: i64)
+        -> Result<Option<Vec<DirectoryEntry>>, libc::c_int>
+    {
+        if fh == 0 {
+            return self.fetch_dir_entries(req, path, 0).map(Some);
+        }
+        // The cache is set aside while the entries are fetched, since that needs `self`.
+        let mut cache = std::mem::replace(&mut self.directory_cache, DirectoryCache::new());
+        let result = cache.load(fh, offset as u64, |real_fh| self.fetch_dir_entries(req, path, real_fh));
+        self.directory_cache = cache;
+        result.map(|()| None)
+    }
+
+    /// The inode of the parent of a directory, for its `..` entry.
+    fn parent_inode(&mut self, ino: u64, path: &Path) -> Option<u64> {
+        if ino == 1 {
+            Some(ino)
+        } else {
+            self.inodes.get_inode(path.parent().unwrap())
+        }
+    }
+
+    /// Add an entry, with its attributes, to a `readdirplus` reply. Entries whose attributes can't
+    /// be had are left out. Returns whether the reply is full, in which case the entry wasn't
+    /// added.
+    #[cfg(feature = "readdirplus")]
+    #[allow(clippy::too_many_arguments)]
+    fn add_readdirplus_entry(&mut self, req: &fuser::Request<'_>, reply: &mut fuser::ReplyDirectoryPlus, ino: u64,
+        parent_inode: u64, path: &Path, entry: &DirectoryEntry, next_offset: i64) -> bool
+    {
+        // The kernel ignores the attributes of `.` and `..`, and doesn't count them as lookups.
+        if entry.name == Path::new(".") || entry.name == Path::new("..") {
+            let entry_inode = if entry.name == Path::new(".") { ino } else { parent_inode };
+            return reply.add(entry_inode, next_offset, &entry.name, &Duration::ZERO, &negative_entry_attr(), 0);
+        }
+        let entry_path = Arc::new(path.join(&entry.name));
+        let (ttl, attr) = match self.getattr_cached(req, &entry_path) {
+            Ok(entry) => entry,
+            Err(e) => {
+                // Most likely it's been removed since the listing was made.
+                debug!("readdirplus: skipping {:?}: getattr failed: {}", entry_path, e);
+                return false;
+            }
+        };
+        let (entry_inode, generation) = self.inodes.add_or_get(entry_path);
+        self.inodes.lookup(entry_inode);
+        let full = reply.add(entry_inode, next_offset, &entry.name, &Ttl::clamp(ttl),
+            &self.fuse_fileattr(attr, entry_inode), generation);
+        if full {
+            // The entry didn't make it in, so the kernel won't count the lookup.
+            self.forget_inode(entry_inode, 1);
+        } else {
+            debug!("readdirplus: added entry {:?}", entry.name);
         }
+        full
     }
 
     fn threadpool_run<F: FnOnce() + Send + 'static>(&mut self, f: F) {
+        self.threadpool_run_buffered(0, f)
+    }
+
+    /// Like `threadpool_run`, but counting the given number of bytes as buffered for the request
+    /// until it's done.
+    fn threadpool_run_buffered<F: FnOnce() + Send + 'static>(&mut self, bytes: u64, f: F) {
+        let guard = self.usage.track(bytes);
+        let panic_mode = self.panic_mode;
+        let pause = self.pause.clone();
+        let f = move || {
+            // Waiting here rather than on the session thread leaves it free to take forgets,
+            // releases and the like from the kernel while paused.
+            pause.wait();
+            // If the filesystem panics, the reply is dropped without being sent, and `fuser`
+            // answers with `EIO`.
+            catch_panic(panic_mode, f);
+            drop(guard);
+        };
         if self.num_threads == 0 {
             f()
         } else {
             if self.threads.is_none() {
                 debug!("initializing threadpool with {} threads", self.num_threads);
-                self.threads = Some(ThreadPool::new(self.num_threads));
+                let mut builder = threadpool::Builder::new()
+                    .num_threads(self.num_threads)
+                    .thread_name(self.thread_name.clone());
+                if let Some(size) = self.thread_stack_size {
+                    builder = builder.thread_stack_size(size);
+                }
+                self.threads = Some(builder.build());
             }
             self.threads.as_ref().unwrap().execute(f);
         }
     }
 }
 
+macro_rules! check_writable {
+    ($s:expr, $reply:expr) => {
+        if $s.read_only.is_read_only() {
+            debug!("filesystem is read-only");
+            $reply.error(libc::EROFS);
+            return;
+        }
+    }
+}
+
+macro_rules! check_supported {
+    ($s:expr, $op:expr, $reply:expr) => {
+        if !$s.capabilities.contains($op) {
+            debug!("{:?} is not supported by the filesystem", $op);
+            $reply.error(libc::ENOSYS);
+            return;
+        }
+    }
+}
+
+macro_rules! check_name_len {
+    ($s:expr, $name:expr, $reply:expr) => {
+        if name_too_long($name, $s.max_name_len) {
+            debug!("{:?} is longer than the maximum name length", $name);
+            $reply.error(libc::ENAMETOOLONG);
+            return;
+        }
+    }
+}
+
 macro_rules! get_path {
     ($s:expr, $ino:expr, $reply:expr) => {
         if let Some(path) = $s.inodes.get_path($ino) {
@@ -141,15 +1248,29 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
     fn init(
         &mut self,
         req: &fuser::Request<'_>,
-        _config: &mut fuser::KernelConfig, // TODO
+        config: &mut fuser::KernelConfig,
     ) -> Result<(), libc::c_int> {
-        debug!("init");
-        self.target.init(req.info())
+        trace!("init: {:?}", req.info());
+        #[cfg(feature = "umask")]
+        if self.dont_mask {
+            if let Err(unsupported) = config.add_capabilities(fuser::consts::FUSE_DONT_MASK) {
+                warn!("kernel doesn't support dont_mask (missing capabilities {:#x}); it will apply the umask itself", unsupported);
+            }
+        }
+        let result = call(self.panic_mode, || self.target.init_with_config(req.info(), &mut KernelConfig::new(config)));
+        if result.is_ok() {
+            check_required_ops(self.capabilities);
+        }
+        result
     }
 
     fn destroy(&mut self) {
         debug!("destroy");
-        self.target.destroy();
+        let result = call(self.panic_mode, || self.target.try_destroy());
+        if let Err(e) = result {
+            error!("destroy failed: {}", std::io::Error::from_raw_os_error(e));
+        }
+        self.destroyed.set(result);
     }
 
     fn lookup(
@@ -159,25 +1280,29 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         name: &OsStr,
         reply: fuser::ReplyEntry,
     ) {
+        self.pause.wait();
+        trace!("lookup: parent={}, name={:?}, {:?}", parent, name, req.info());
+        check_name_len!(self, name, reply);
 
-        debug!("lookup: {:?}, {:?}", parent, name);
-
-        match self.target.lookup(req.info(), parent, name) {
+        let result = retry_eintr(self.eintr_retries, || {
+            call(self.panic_mode, || self.target.lookup(req.info(), self.fs_ino(parent), name))
+        });
+        match result {
             Ok((ttl, attr)) => {
-                reply.entry(&ttl, &fuse_fileattr(attr.into(), attr.inode), attr.generation);
+                reply.entry(&Ttl::clamp(ttl), &self.fuse_fileattr(attr.into(), self.kernel_ino(attr.inode)), attr.generation);
             },
-            Err(e) => reply.error(e),
+            Err(e) => self.lookup_error(e, reply),
         }
     }
 
     fn forget(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         ino: u64,
         nlookup: u64,
     ) {
-        debug!("forget: {:?}, {:?}", ino, nlookup);
-        self.target.forget(_req.info(), ino, nlookup);
+        trace!("forget: ino={}, nlookup={}, {:?}", ino, nlookup, req.info());
+        catch_panic(self.panic_mode, || self.target.forget(req.info(), self.fs_ino(ino), nlookup));
     }
 
     fn getattr(
@@ -186,11 +1311,11 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         ino: u64,
         reply: fuser::ReplyAttr,
     ) {
-        debug!("getattr: {:?}", ino);
-        match self.target.getattr(req.info(), ino, None) {
-            Ok((ttl, attr)) => {
-                reply.attr(&ttl, &fuse_fileattr(attr.into(), ino))
-            },
+        self.pause.wait();
+        trace!("getattr: ino={}, {:?}", ino, req.info());
+        check_supported!(self, OpSet::GETATTR, reply);
+        match self.getattr_reply(req.info(), ino) {
+            Ok((ttl, attr)) => reply.attr(&ttl, &attr),
             Err(e) => reply.error(e),
         }
     }
@@ -204,7 +1329,7 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         size: Option<u64>,              // truncate
         atime: Option<TimeOrNow>,       // utimens
         mtime: Option<TimeOrNow>,       // utimens
-        _ctime: Option<SystemTime>,     // ? TODO
+        ctime: Option<SystemTime>,      // setattr only
         fh: Option<u64>,                // passed to all
         crtime: Option<SystemTime>,     // utimens_osx  (OS X only)
         chgtime: Option<SystemTime>,    // utimens_osx  (OS X only)
@@ -212,60 +1337,32 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         flags: Option<u32>,             // utimens_osx  (OS X only)
         reply: fuser::ReplyAttr,
     ) {
-
-        debug!("setattr: {:?}", ino);
-
-        debug!("\tino:\t{:?}", ino);
-        debug!("\tmode:\t{:?}", mode);
-        debug!("\tuid:\t{:?}", uid);
-        debug!("\tgid:\t{:?}", gid);
-        debug!("\tsize:\t{:?}", size);
-        debug!("\tatime:\t{:?}", atime);
-        debug!("\tmtime:\t{:?}", mtime);
-        debug!("\tfh:\t{:?}", fh);
-
-        // TODO: figure out what C FUSE does when only some of these are implemented.
-
-        if let Some(mode) = mode {
-            if let Err(e) = self.target.chmod(req.info(), ino, fh, mode) {
-                reply.error(e);
-                return;
-            }
-        }
-
-        if uid.is_some() || gid.is_some() {
-            if let Err(e) = self.target.chown(req.info(), ino, fh, uid, gid) {
-                reply.error(e);
-                return;
-            }
-        }
-
-        if let Some(size) = size {
-            if let Err(e) = self.target.truncate(req.info(), ino, fh, size) {
-                reply.error(e);
-                return;
-            }
-        }
-
-        if atime.is_some() || mtime.is_some() {
-            let atime = atime.map(TimeOrNowExt::time);
-            let mtime = mtime.map(TimeOrNowExt::time);
-            if let Err(e) = self.target.utimens(req.info(), ino, fh, atime, mtime) {
-                reply.error(e);
-                return;
-            }
-        }
-
-        if crtime.is_some() || chgtime.is_some() || bkuptime.is_some() || flags.is_some() {
-            if let Err(e) = self.target.utimens_macos(req.info(), ino, fh, crtime, chgtime, bkuptime, flags) {
-                reply.error(e);
-                return
-            }
-        }
-
-        match self.target.getattr(req.info(), ino, fh) {
-            Ok((ttl, attr)) => reply.attr(&ttl, &fuse_fileattr(attr.into(), ino)),
-            Err(e) => reply.error(e),
+        self.pause.wait();
+        trace!("setattr: ino={}, mode={:?}, uid={:?}, gid={:?}, size={:?}, atime={:?}, mtime={:?}, ctime={:?}, fh={:?}, crtime={:?}, chgtime={:?}, bkuptime={:?}, flags={:?}, {:?}", ino, mode.map(Mode), uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime, flags, req.info());
+        check_writable!(self, reply);
+
+        let attrs = SetAttrRequest { fh, mode, uid, gid, size, atime, mtime, ctime, crtime, chgtime, bkuptime, flags };
+        let fs_ino = self.fs_ino(ino);
+        match self.write_locks {
+            // Truncating waits for writes to the file to finish, so it's done on the thread pool,
+            // to not hold up everything else meanwhile.
+            Some(ref locks) if size.is_some() => {
+                let (target, capabilities, req_info) = (self.target.clone(), self.capabilities, req.info());
+                let locks = locks.clone();
+                let (strict_attrs, time_granularity) = (self.strict_attrs, self.time_granularity);
+                self.threadpool_run(move || {
+                    let _guard = locks.lock(fs_ino);
+                    match setattr_calls(&*target, capabilities, req_info, fs_ino, attrs) {
+                        Ok((ttl, attr)) => reply.attr(&Ttl::clamp(ttl),
+                            &fuse_fileattr(attr.into(), fs_ino, strict_attrs, time_granularity)),
+                        Err(e) => reply.error(e),
+                    }
+                });
+            },
+            _ => match call(self.panic_mode, || setattr_calls(&*self.target, self.capabilities, req.info(), fs_ino, attrs)) {
+                Ok((ttl, attr)) => reply.attr(&Ttl::clamp(ttl), &self.fuse_fileattr(attr.into(), fs_ino)),
+                Err(e) => reply.error(e),
+            },
         }
     }
 
@@ -276,8 +1373,10 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         ino: u64,
         reply: fuser::ReplyData,
     ) {
-        debug!("readlink: {:?}", ino);
-        match self.target.readlink(req.info(), ino) {
+        self.pause.wait();
+        trace!("readlink: ino={}, {:?}", ino, req.info());
+        check_supported!(self, OpSet::READLINK, reply);
+        match call(self.panic_mode, || self.target.readlink(req.info(), self.fs_ino(ino))) {
             Ok(data) => reply.data(&data),
             Err(e) => reply.error(e),
         }
@@ -289,14 +1388,18 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32, // TODO
+        umask: u32,
         rdev: u32,
         reply: fuser::ReplyEntry,
     ) {
-        debug!("mknod: {:?}/{:?}", parent, name);
-        match self.target.mknod(req.info(), parent, name, mode, rdev) {
+        self.pause.wait();
+        trace!("mknod: parent={}, name={:?}, mode={:?}, umask={:#o}, rdev={}, {:?}", parent, name, Mode(mode), umask, rdev, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::MKNOD, reply);
+        check_name_len!(self, name, reply);
+        match call(self.panic_mode, || self.target.mknod(req.info(), self.fs_ino(parent), name, mode, umask, rdev)) {
             Ok((ttl, attr)) => {
-                reply.entry(&ttl, &fuse_fileattr(attr.into(), attr.inode), attr.generation)
+                reply.entry(&Ttl::clamp(ttl), &self.fuse_fileattr(attr.into(), self.kernel_ino(attr.inode)), attr.generation)
             },
             Err(e) => reply.error(e),
         }
@@ -308,13 +1411,17 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32, // TODO
+        umask: u32,
         reply: fuser::ReplyEntry,
     ) {
-        debug!("mkdir: {:?}/{:?}", parent, name);
-        match self.target.mkdir(req.info(), parent, name, mode) {
+        self.pause.wait();
+        trace!("mkdir: parent={}, name={:?}, mode={:?}, umask={:#o}, {:?}", parent, name, Mode(mode), umask, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::MKDIR, reply);
+        check_name_len!(self, name, reply);
+        match call(self.panic_mode, || self.target.mkdir(req.info(), self.fs_ino(parent), name, mode, umask)) {
             Ok((ttl, attr)) => {
-                reply.entry(&ttl, &fuse_fileattr(attr.into(), attr.inode), attr.generation)
+                reply.entry(&Ttl::clamp(ttl), &self.fuse_fileattr(attr.into(), self.kernel_ino(attr.inode)), attr.generation)
             },
             Err(e) => reply.error(e),
         }
@@ -327,8 +1434,11 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         name: &OsStr,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!("unlink: {:?}/{:?}", parent, name);
-        match self.target.unlink(req.info(), parent, name) {
+        self.pause.wait();
+        trace!("unlink: parent={}, name={:?}, {:?}", parent, name, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::UNLINK, reply);
+        match call(self.panic_mode, || self.target.unlink(req.info(), self.fs_ino(parent), name)) {
             Ok(()) => {
                 reply.ok()
             },
@@ -343,8 +1453,11 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         name: &OsStr,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!("rmdir: {:?}/{:?}", parent, name);
-        match self.target.rmdir(req.info(), parent, name) {
+        self.pause.wait();
+        trace!("rmdir: parent={}, name={:?}, {:?}", parent, name, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::RMDIR, reply);
+        match call(self.panic_mode, || self.target.rmdir(req.info(), self.fs_ino(parent), name)) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
@@ -358,10 +1471,14 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         link: &Path,
         reply: fuser::ReplyEntry,
     ) {
-        debug!("symlink: {:?}/{:?} -> {:?}", parent, name, link);
-        match self.target.symlink(req.info(), parent, name, link) {
+        self.pause.wait();
+        trace!("symlink: parent={}, name={:?}, link={:?}, {:?}", parent, name, link, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::SYMLINK, reply);
+        check_name_len!(self, name, reply);
+        match call(self.panic_mode, || self.target.symlink(req.info(), self.fs_ino(parent), name, link)) {
             Ok((ttl, attr)) => {
-                reply.entry(&ttl, &fuse_fileattr(attr.into(), attr.inode), attr.generation)
+                reply.entry(&Ttl::clamp(ttl), &self.fuse_fileattr(attr.into(), self.kernel_ino(attr.inode)), attr.generation)
             },
             Err(e) => reply.error(e),
         }
@@ -375,12 +1492,18 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
-        _flags: u32, // TODO
+        flags: u32,
         reply: fuser::ReplyEmpty,
     ) {
-
-        debug!("rename: {:?}/{:?} -> {:?}/{:?}", parent, name, newparent, newname);
-        match self.target.rename(req.info(), parent, name, newparent, newname) {
+        self.pause.wait();
+        trace!("rename: parent={}, name={:?}, newparent={}, newname={:?}, flags={:?}, {:?}", parent, name, newparent, newname, RenameFlags(flags), req.info());
+        check_writable!(self, reply);
+        check_name_len!(self, newname, reply);
+
+        let (parent, newparent) = (self.fs_ino(parent), self.fs_ino(newparent));
+        let result = call(self.panic_mode, || rename_with_flags(&*self.target, self.capabilities, req.info(),
+            parent, name, newparent, newname, flags));
+        match result {
             Ok(()) => {
                 reply.ok()
             },
@@ -396,10 +1519,14 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         newname: &OsStr,
         reply: fuser::ReplyEntry,
     ) {
-        debug!("link: {:?} -> {:?}/{:?}", ino, newparent, newname);
-        match self.target.link(req.info(), ino, newparent, newname) {
+        self.pause.wait();
+        trace!("link: ino={}, newparent={}, newname={:?}, {:?}", ino, newparent, newname, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::LINK, reply);
+        check_name_len!(self, newname, reply);
+        match call(self.panic_mode, || self.target.link(req.info(), self.fs_ino(ino), self.fs_ino(newparent), newname)) {
             Ok((ttl, attr)) => {
-                reply.entry(&ttl, &fuse_fileattr(attr.into(), attr.inode), attr.generation);
+                reply.entry(&Ttl::clamp(ttl), &self.fuse_fileattr(attr.into(), self.kernel_ino(attr.inode)), attr.generation);
             },
             Err(e) => reply.error(e),
         }
@@ -412,10 +1539,46 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         flags: i32,
         reply: fuser::ReplyOpen,
     ) {
-        debug!("open: {:?}", ino);
-        match self.target.open(req.info(), ino, flags as u32) { // TODO: change flags to i32
-            Ok((fh, flags)) => reply.opened(fh, flags),
-            Err(e) => reply.error(e),
+        self.pause.wait();
+        trace!("open: ino={}, flags={:?}, {:?}", ino, OpenFlags(flags), req.info());
+        if opens_for_writing(flags) {
+            check_writable!(self, reply);
+        }
+        check_supported!(self, OpSet::OPEN, reply);
+        if let Err(e) = self.open_counts.acquire(ino) {
+            reply.error(e);
+            return;
+        }
+        if let (Some(locks), true) = (&self.write_locks, flags & libc::O_TRUNC != 0) {
+            // Truncating waits for writes to the file to finish, so it's done on the thread pool,
+            // to not hold up everything else meanwhile. The file is changed by it, so its cache
+            // isn't kept.
+            self.auto_cache.forget(ino);
+            let (target, req_info, locks, fs_ino) = (self.target.clone(), req.info(), locks.clone(), self.fs_ino(ino));
+            let (open_counts, panic_mode) = (self.open_counts.clone(), self.panic_mode);
+            self.threadpool_run(move || {
+                let _guard = locks.lock(fs_ino);
+                // A panic has to come back as an error here, or the open wouldn't be uncounted.
+                match call(panic_mode, || open_truncating(&*target, req_info, fs_ino, flags as u32)) {
+                    Ok((fh, flags)) => reply.opened(fh, flags & !FOPEN_AUTO_CACHE),
+                    Err(e) => {
+                        open_counts.release(ino);
+                        reply.error(e)
+                    },
+                }
+            });
+            return;
+        }
+        let result = call(self.panic_mode, || open_truncating(&*self.target, req.info(), self.fs_ino(ino), flags as u32)); // TODO: change flags to i32
+        match result {
+            Ok((fh, flags)) => {
+                let flags = self.auto_cache_flags(req, ino, fh, flags);
+                reply.opened(fh, flags)
+            },
+            Err(e) => {
+                self.open_counts.release(ino);
+                reply.error(e)
+            },
         }
     }
 
@@ -428,28 +1591,28 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         fh: u64,
         offset: i64,
         size: u32,
-        _flags: i32,                // TODO
-        _lock_owner: Option<u64>,   // TODO
+        flags: i32,                // logged only
+        lock_owner: Option<u64>,   // logged only
         reply: fuser::ReplyData,
     ) {
+        trace!("read: ino={}, fh={}, offset={:#x}, size={:#x}, flags={:?}, lock_owner={:?}, {:?}", ino, fh, offset, size, OpenFlags(flags), lock_owner, req.info());
+        check_supported!(self, OpSet::READ, reply);
 
-        debug!("read: {:?} {:#x} @ {:#x}", ino, size, offset);
         if offset < 0 {
             error!("read called with a negative offset");
             reply.error(libc::EINVAL);
             return;
         }
+        let ino = self.fs_ino(ino);
         let target = self.target.clone();
         let req_info = req.info();
-        self.threadpool_run(move || {
-            target.read(req_info, ino, fh, offset as u64, size, |result| {
+        let retries = self.eintr_retries;
+        self.threadpool_run_buffered(size.into(), move || {
+            read_retrying(&*target, req_info, ino, fh, offset as u64, size, retries, |result| {
                 match result {
                     Ok(data) => reply.data(data),
                     Err(e) => reply.error(e),
                 }
-                CallbackResult {
-                    _private: std::marker::PhantomData {},
-                }
             });
         });
     }
@@ -461,27 +1624,44 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         fh: u64,
         offset: i64,
         data: &[u8],
-        _write_flags: u32,          // TODO
-        flags: i32,
-        _lock_owner: Option<u64>,   // TODO
+        write_flags: u32,          // logged only
+        flags: i32,                // write
+        lock_owner: Option<u64>,   // logged only
         reply: fuser::ReplyWrite,
     ) {
+        trace!("write: ino={}, fh={}, offset={:#x}, len={:#x}, write_flags={:?}, flags={:?}, lock_owner={:?}, {:?}", ino, fh, offset, data.len(), WriteFlags(write_flags), OpenFlags(flags), lock_owner, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::WRITE, reply);
 
-        debug!("write: {:?} {:#x} @ {:#x}", ino, data.len(), offset);
         if offset < 0 {
             error!("write called with a negative offset");
             reply.error(libc::EINVAL);
             return;
         }
+        let ino = self.fs_ino(ino);
         let target = self.target.clone();
         let req_info = req.info();
 
+        let write_locks = self.write_locks.clone();
+        if self.num_threads == 0 {
+            // Without a threadpool, the data can be passed on straight from `fuser`'s buffer.
+            self.pause.wait();
+            let _usage = self.usage.track(data.len() as u64);
+            let _guard = write_locks.as_ref().map(|locks| locks.lock(ino));
+            match call(self.panic_mode, || write_data(&*target, req_info, ino, fh, offset as u64, Cow::Borrowed(data), flags as u32)) {
+                Ok(written) => reply.written(written),
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+
         // The data needs to be copied here before dispatching to the threadpool because it's a
         // slice of a single buffer that `fuser` re-uses for the entire session.
         let data_buf = Vec::from(data);
 
-        self.threadpool_run(move|| {
-            match target.write(req_info, ino, fh, offset as u64, data_buf, flags as u32) {
+        self.threadpool_run_buffered(data_buf.len() as u64, move|| {
+            let _guard = write_locks.as_ref().map(|locks| locks.lock(ino));
+            match write_data(&*target, req_info, ino, fh, offset as u64, Cow::Owned(data_buf), flags as u32) {
                 Ok(written) => reply.written(written),
                 Err(e) => reply.error(e),
             }
@@ -498,7 +1678,9 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         lock_owner: u64,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!("flush: {:?}", ino);
+        trace!("flush: ino={}, fh={}, lock_owner={:#x}, {:?}", ino, fh, lock_owner, req.info());
+        check_supported!(self, OpSet::FLUSH, reply);
+        let ino = self.fs_ino(ino);
         let target = self.target.clone();
         let req_info = req.info();
         self.threadpool_run(move|| {
@@ -519,9 +1701,10 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         flush: bool,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!("release: {:?}", ino);
-        match self.target.release(
-            req.info(), ino, fh, flags as u32, lock_owner.unwrap_or(0) /* TODO */, flush)
+        trace!("release: ino={}, fh={}, flags={:?}, lock_owner={:?}, flush={}, {:?}", ino, fh, OpenFlags(flags), lock_owner, flush, req.info());
+        self.open_counts.release(ino);
+        match call(self.panic_mode, || self.target.release(
+            req.info(), self.fs_ino(ino), fh, flags as u32, lock_owner.unwrap_or(0) /* TODO */, flush))
         {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
@@ -536,7 +1719,9 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         datasync: bool,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!("fsync: {:?}", ino);
+        trace!("fsync: ino={}, fh={}, datasync={}, {:?}", ino, fh, datasync, req.info());
+        check_supported!(self, OpSet::FSYNC, reply);
+        let ino = self.fs_ino(ino);
         let target = self.target.clone();
         let req_info = req.info();
         self.threadpool_run(move|| {
@@ -554,9 +1739,15 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         flags: i32,
         reply: fuser::ReplyOpen,
     ) {
-        debug!("opendir: {:?}", ino);
-        match self.target.opendir(req.info(), ino, flags as u32) {
+        self.pause.wait();
+        trace!("opendir: ino={}, flags={:?}, {:?}", ino, OpenFlags(flags), req.info());
+        check_supported!(self, OpSet::OPENDIR, reply);
+        match call(self.panic_mode, || self.target.opendir(req.info(), self.fs_ino(ino), flags as u32)) {
             Ok((fh, flags)) => {
+                let fh = match self.directory_cache {
+                    Some(ref mut cache) => cache.new_entry(fh),
+                    None => fh,
+                };
                 reply.opened(fh, flags);
             },
             Err(e) => reply.error(e),
@@ -571,8 +1762,9 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         offset: i64,
         mut reply: fuser::ReplyDirectory,
     ) {
-
-        debug!("readdir: {:?} @ {}", ino, offset);
+        self.pause.wait();
+        trace!("readdir: ino={}, fh={}, offset={:#x}, {:?}", ino, fh, offset, req.info());
+        check_supported!(self, OpSet::READDIR, reply);
 
         if offset < 0 {
             error!("readdir called with a negative offset");
@@ -580,19 +1772,8 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
             return;
         }
 
-        // TODO: We're relying on the implementation caching here
-        let entries = match self.target.readdir(req.info(), ino, fh) {
-            Ok(entries) => {
-                entries
-            },
-            Err(e) => {
-                reply.error(e);
-                return;
-            }
-        };
-
-        let parent_inode = match self.target.parent(req.info(), ino) {
-            Ok(inode) => inode,
+        let parent_inode = match call(self.panic_mode, || self.target.parent(req.info(), self.fs_ino(ino))) {
+            Ok(inode) => self.kernel_ino(inode),
             Err(errno) => {
                 error!("readdir: unable to get inode for parent of {:?}", ino);
                 reply.error(errno);
@@ -600,15 +1781,73 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
             }
         };
 
-        debug!("directory has {} entries", entries.len());
-
-        for (index, entry) in entries.iter().skip(offset as usize).enumerate() {
-            let entry_inode = if entry.name == Path::new(".") {
-                ino
-            } else if entry.name == Path::new("..") {
-                parent_inode
-            } else {
-                // Don't bother looking in the inode table for the entry; FUSE doesn't pre-
+        let real_fh = match self.directory_cache {
+            Some(ref cache) if fh != 0 => cache.real_fh(fh),
+            _ => fh,
+        };
+        let result = {
+            let kernel_ino = |fs_ino| self.kernel_ino(fs_ino);
+            let max_name_len = self.max_name_len;
+            let mut add = |entry_ino: u64, entry_offset: i64, kind: FileType, name: &OsStr| {
+                if name_too_long(name, max_name_len) {
+                    warn!("readdir: {:?} has entry {:?}, which is longer than the maximum name length", ino, name);
+                    return true;
+                }
+                let entry_inode = if name == "." {
+                    ino
+                } else if name == ".." {
+                    parent_inode
+                } else {
+                    kernel_ino(entry_ino)
+                };
+                !reply.add(entry_inode, entry_offset, kind, name)
+            };
+            call(self.panic_mode, || self.target.readdir_offset(req.info(), self.fs_ino(ino), real_fh, offset as u64,
+                &mut DirFiller::new(&mut add)))
+        };
+        match result {
+            Ok(()) => {
+                reply.ok();
+                return;
+            },
+            Err(libc::ENOSYS) => debug!("readdir_offset not implemented; using readdir"),
+            Err(e) => {
+                reply.error(e);
+                return;
+            },
+        }
+
+        // Without a snapshot, this relies on the filesystem returning the same entries each time.
+        // The cache is set aside while the entries are fetched, since that needs `self`.
+        let mut cache = self.directory_cache.take();
+        let result = match cache {
+            Some(ref mut cache) if fh != 0 => {
+                cache.load(fh, offset as u64, |real_fh| self.fetch_dir_entries(req, ino, real_fh)).map(|()| None)
+            },
+            _ => self.fetch_dir_entries(req, ino, fh).map(Some),
+        };
+        self.directory_cache = cache;
+        let uncached_entries = match result {
+            Ok(entries) => entries,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+        let entries: &[DirectoryEntry] = match uncached_entries {
+            Some(ref entries) => entries,
+            None => self.directory_cache.as_ref().unwrap().entries(fh).unwrap(),
+        };
+
+        debug!("directory has {} entries", entries.len());
+
+        for (index, entry) in entries.iter().skip(offset as usize).enumerate() {
+            let entry_inode = if entry.name == Path::new(".") {
+                ino
+            } else if entry.name == Path::new("..") {
+                parent_inode
+            } else {
+                // Don't bother looking in the inode table for the entry; FUSE doesn't pre-
                 // populate its inode cache with this value, so subsequent access to these
                 // files is going to involve it issuing a LOOKUP operation anyway.
                 !1
@@ -639,13 +1878,19 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         flags: i32,
         reply: fuser::ReplyEmpty,
     ) {
+        trace!("releasedir: ino={}, fh={}, flags={:?}, {:?}", ino, fh, OpenFlags(flags), req.info());
 
-        debug!("releasedir: {:?}", ino);
-
-        match self.target.releasedir(req.info(), ino, fh, flags as u32) {
+        let real_fh = match self.directory_cache {
+            Some(ref cache) if fh != 0 => cache.real_fh(fh),
+            _ => fh,
+        };
+        match call(self.panic_mode, || self.target.releasedir(req.info(), self.fs_ino(ino), real_fh, flags as u32)) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
+        if let Some(ref mut cache) = self.directory_cache {
+            cache.delete(fh);
+        }
     }
 
     fn fsyncdir(
@@ -656,9 +1901,15 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         datasync: bool,
         reply: fuser::ReplyEmpty,
     ) {
+        self.pause.wait();
+        trace!("fsyncdir: ino={}, fh={}, datasync={}, {:?}", ino, fh, datasync, req.info());
+        check_supported!(self, OpSet::FSYNCDIR, reply);
 
-        debug!("fsyncdir: {:?} (datasync: {:?})", ino, datasync);
-        match self.target.fsyncdir(req.info(), ino, fh, datasync) {
+        let real_fh = match self.directory_cache {
+            Some(ref cache) if fh != 0 => cache.real_fh(fh),
+            _ => fh,
+        };
+        match call(self.panic_mode, || self.target.fsyncdir(req.info(), self.fs_ino(ino), real_fh, datasync)) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
@@ -670,17 +1921,26 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         ino: u64,
         reply: fuser::ReplyStatfs,
     ) {
-        debug!("statfs: {:?}", ino);
-        match self.target.statfs(req.info(), ino) {
-            Ok(statfs) => reply.statfs(
-                statfs.blocks,
-                statfs.bfree,
-                statfs.bavail,
-                statfs.files,
-                statfs.ffree,
-                statfs.bsize,
-                statfs.namelen,
-                statfs.frsize),
+        self.pause.wait();
+        trace!("statfs: ino={}, {:?}", ino, req.info());
+        check_supported!(self, OpSet::STATFS, reply);
+        match call(self.panic_mode, || self.target.statfs(req.info(), self.fs_ino(ino))) {
+            Ok(statfs) => {
+                if self.strict_attrs {
+                    for problem in statfs_problems(&statfs) {
+                        error!("statfs has bad values: {}: {:?}", problem, statfs);
+                    }
+                }
+                reply.statfs(
+                    statfs.blocks,
+                    statfs.bfree,
+                    statfs.bavail,
+                    statfs.files,
+                    statfs.ffree,
+                    statfs.bsize,
+                    self.max_name_len.map_or(statfs.namelen, |max| statfs.namelen.min(max)),
+                    statfs.frsize)
+            },
             Err(e) => reply.error(e),
         }
     }
@@ -695,9 +1955,11 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         position: u32,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!("setxattr: {:?} {:?} ({} bytes, flags={:#x}, pos={:#x}",
-            ino, name, value.len(), flags, position);
-        match self.target.setxattr(req.info(), ino, name, value, flags as u32, position) {
+        self.pause.wait();
+        trace!("setxattr: ino={}, name={:?}, value={:?}, flags={:?}, position={}, {:?}", ino, name, String::from_utf8_lossy(value), XattrFlags(flags), position, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::SETXATTR, reply);
+        match call(self.panic_mode, || self.target.setxattr(req.info(), self.fs_ino(ino), name, value, flags as u32, position)) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
@@ -711,8 +1973,18 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         size: u32,
         reply: fuser::ReplyXattr,
     ) {
-        debug!("getxattr: {:?} {:?}", ino, name);
-        match self.target.getxattr(req.info(), ino, name, size) {
+        self.pause.wait();
+        trace!("getxattr: ino={}, name={:?}, size={:#x}, {:?}", ino, name, size, req.info());
+        if self.capabilities_xattr && name == CAPABILITIES_XATTR {
+            match get_capabilities_xattr(self.capabilities, size) {
+                Ok(Xattr::Size(size)) => reply.size(size),
+                Ok(Xattr::Data(vec)) => reply.data(&vec),
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+        check_supported!(self, OpSet::GETXATTR, reply);
+        match call(self.panic_mode, || self.target.getxattr(req.info(), self.fs_ino(ino), name, size)) {
             Ok(Xattr::Size(size)) => {
                 debug!("getxattr: sending size {}", size);
                 reply.size(size)
@@ -735,8 +2007,20 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         size: u32,
         reply: fuser::ReplyXattr,
     ) {
-        debug!("listxattr: {:?}", ino);
-        match self.target.listxattr(req.info(), ino, size) {
+        self.pause.wait();
+        trace!("listxattr: ino={}, size={:#x}, {:?}", ino, size, req.info());
+        if !self.capabilities_xattr {
+            check_supported!(self, OpSet::LISTXATTR, reply);
+        }
+        let mut result = if self.capabilities.contains(OpSet::LISTXATTR) {
+            call(self.panic_mode, || self.target.listxattr(req.info(), self.fs_ino(ino), size))
+        } else {
+            Err(libc::ENOSYS)
+        };
+        if self.capabilities_xattr {
+            result = list_capabilities_xattr(result, size);
+        }
+        match result {
             Ok(Xattr::Size(size)) => {
                 debug!("listxattr: sending size {}", size);
                 reply.size(size)
@@ -756,8 +2040,11 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         name: &OsStr,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!("removexattr: {:?}, {:?}", ino, name);
-        match self.target.removexattr(req.info(), ino, name) {
+        self.pause.wait();
+        trace!("removexattr: ino={}, name={:?}, {:?}", ino, name, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::REMOVEXATTR, reply);
+        match call(self.panic_mode, || self.target.removexattr(req.info(), self.fs_ino(ino), name)) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
@@ -770,8 +2057,13 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         mask: i32,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!("access: {:?}, mask={:#o}", ino, mask);
-        match self.target.access(req.info(), ino, mask as u32) {
+        self.pause.wait();
+        trace!("access: ino={}, mask={:?}, {:?}", ino, AccessMask(mask), req.info());
+        if mask & libc::W_OK != 0 {
+            check_writable!(self, reply);
+        }
+        check_supported!(self, OpSet::ACCESS, reply);
+        match call(self.panic_mode, || self.target.access(req.info(), self.fs_ino(ino), mask as u32)) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
@@ -783,20 +2075,175 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32, // TODO
+        umask: u32,
         flags: i32,
         reply: fuser::ReplyCreate,
     ) {
-        debug!("create: {:?}/{:?} (mode={:#o}, flags={:#x})", parent, name, mode, flags);
-        match self.target.create(req.info(), parent, name, mode, flags as u32) {
+        self.pause.wait();
+        trace!("create: parent={}, name={:?}, mode={:?}, umask={:#o}, flags={:?}, {:?}", parent, name, Mode(mode), umask, OpenFlags(flags), req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::CREATE, reply);
+        check_name_len!(self, name, reply);
+        match call(self.panic_mode, || self.target.create(req.info(), self.fs_ino(parent), name, mode, umask, flags as u32)) {
             Ok(create) => {
-                let attr = fuse_fileattr(create.attr.into(), create.attr.inode);
-                reply.created(&create.ttl, &attr, create.attr.generation, create.fh, create.flags);
+                let attr = self.fuse_fileattr(create.attr.into(), self.kernel_ino(create.attr.inode));
+                self.open_counts.add(attr.ino);
+                let mut flags = create.flags;
+                if flags & FOPEN_AUTO_CACHE != 0 {
+                    // There's nothing cached for a new file; just remember it for the next open.
+                    flags &= !FOPEN_AUTO_CACHE;
+                    self.auto_cache.check(attr.ino, attr.mtime, attr.size);
+                }
+                reply.created(&Ttl::clamp(create.ttl), &attr, create.attr.generation, create.fh, flags);
             },
             Err(e) => reply.error(e),
         }
     }
 
+    #[cfg(feature = "poll")]
+    fn poll(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        kh: u64,
+        events: u32,
+        flags: u32,
+        reply: fuser::ReplyPoll,
+    ) {
+        trace!("poll: ino={}, fh={}, kh={:#x}, events={:#x}, flags={:#x}, {:?}", ino, fh, kh, events, flags, req.info());
+        check_supported!(self, OpSet::POLL, reply);
+        let ino = self.fs_ino(ino);
+        let target = self.target.clone();
+        let req_info = req.info();
+        self.threadpool_run(move|| {
+            match target.poll(req_info, ino, fh, PollHandle(kh), events, flags) {
+                Ok(revents) => reply.poll(revents),
+                Err(e) => reply.error(e),
+            }
+        });
+    }
+
+    #[cfg(feature = "ioctl")]
+    fn ioctl(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: fuser::ReplyIoctl,
+    ) {
+        trace!("ioctl: ino={}, fh={}, flags={:#x}, cmd={:#x}, in_data.len={:#x}, out_size={:#x}, {:?}", ino, fh, flags, cmd, in_data.len(), out_size, req.info());
+        check_supported!(self, OpSet::IOCTL, reply);
+        let ino = self.fs_ino(ino);
+        let fh = match self.directory_cache {
+            Some(ref cache) if flags & FUSE_IOCTL_DIR != 0 && fh != 0 => cache.real_fh(fh),
+            _ => fh,
+        };
+        let target = self.target.clone();
+        let req_info = req.info();
+        // Like write data, this is part of a buffer `fuser` re-uses, so it's copied for the thread.
+        let in_data = Vec::from(in_data);
+        self.threadpool_run(move|| {
+            match target.ioctl(req_info, ino, fh, flags, cmd, &in_data, out_size) {
+                Ok((result, out_data)) => reply.ioctl(result, &out_data),
+                Err(e) => reply.error(e),
+            }
+        });
+    }
+
+    #[cfg(feature = "fallocate")]
+    fn fallocate(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        trace!("fallocate: ino={}, fh={}, offset={:#x}, length={:#x}, mode={:#x}, {:?}", ino, fh, offset, length, mode, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::FALLOCATE, reply);
+        if offset < 0 || length <= 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let ino = self.fs_ino(ino);
+        let target = self.target.clone();
+        let req_info = req.info();
+        let write_locks = self.write_locks.clone();
+        self.threadpool_run(move|| {
+            let _guard = write_locks.as_ref().map(|locks| locks.lock(ino));
+            match target.fallocate(req_info, ino, fh, offset as u64, length as u64, mode) {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e),
+            }
+        });
+    }
+
+    #[cfg(feature = "lseek")]
+    fn lseek(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: fuser::ReplyLseek,
+    ) {
+        self.pause.wait();
+        trace!("lseek: ino={}, fh={}, offset={}, whence={}, {:?}", ino, fh, offset, whence, req.info());
+        check_supported!(self, OpSet::LSEEK, reply);
+        if offset < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        match call(self.panic_mode, || self.target.lseek(req.info(), self.fs_ino(ino), fh, offset as u64, whence)) {
+            Ok(offset) => reply.offset(offset as i64),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    #[cfg(feature = "copy_file_range")]
+    fn copy_file_range(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: fuser::ReplyWrite,
+    ) {
+        trace!("copy_file_range: ino_in={}, fh_in={}, offset_in={:#x}, ino_out={}, fh_out={}, offset_out={:#x}, len={:#x}, flags={:#x}, {:?}", ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::COPY_FILE_RANGE, reply);
+        if offset_in < 0 || offset_out < 0 {
+            error!("copy_file_range called with a negative offset");
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let ino_in = self.fs_ino(ino_in);
+        let ino_out = self.fs_ino(ino_out);
+        let target = self.target.clone();
+        let req_info = req.info();
+        let write_locks = self.write_locks.clone();
+        self.threadpool_run(move|| {
+            let _guard = write_locks.as_ref().map(|locks| locks.lock(ino_out));
+            match target.copy_file_range(req_info, ino_in, fh_in, offset_in as u64, ino_out, fh_out, offset_out as u64, len, flags) {
+                Ok(written) => reply.written(written),
+                Err(e) => reply.error(e),
+            }
+        });
+    }
 
     // getlk
 
@@ -811,8 +2258,11 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         name: &OsStr,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!("setvolname: {:?}", name);
-        match self.target.setvolname(req.info(), name) {
+        self.pause.wait();
+        trace!("setvolname: name={:?}, {:?}", name, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::SETVOLNAME, reply);
+        match call(self.panic_mode, || self.target.setvolname(req.info(), name)) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
@@ -827,8 +2277,10 @@ impl<T: RawFilesystemMT + Sync + Send + 'static> fuser::Filesystem for RawFuseMT
         ino: u64,
         reply: fuser::ReplyXTimes,
     ) {
-        debug!("getxtimes: {:?}", ino);
-        match self.target.getxtimes(req.info(), ino) {
+        self.pause.wait();
+        trace!("getxtimes: ino={}, {:?}", ino, req.info());
+        check_supported!(self, OpSet::GETXTIMES, reply);
+        match call(self.panic_mode, || self.target.getxtimes(req.info(), self.fs_ino(ino))) {
             Ok(xtimes) => {
                 reply.xtimes(xtimes.bkuptime, xtimes.crtime);
             }
@@ -842,15 +2294,38 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
     fn init(
         &mut self,
         req: &fuser::Request<'_>,
-        _config: &mut fuser::KernelConfig, // TODO
+        config: &mut fuser::KernelConfig,
     ) -> Result<(), libc::c_int> {
-        debug!("init");
-        self.target.init(req.info())
+        trace!("init: {:?}", req.info());
+        #[cfg(feature = "umask")]
+        if self.dont_mask {
+            if let Err(unsupported) = config.add_capabilities(fuser::consts::FUSE_DONT_MASK) {
+                warn!("kernel doesn't support dont_mask (missing capabilities {:#x}); it will apply the umask itself", unsupported);
+            }
+        }
+        #[cfg(feature = "readdirplus")]
+        {
+            // Let the kernel decide when readdirplus is worth it, as libfuse does: it uses it for
+            // listings which are followed by lookups of their entries, like `ls -l`.
+            use fuser::consts::{FUSE_DO_READDIRPLUS, FUSE_READDIRPLUS_AUTO};
+            if let Err(unsupported) = config.add_capabilities(FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO) {
+                debug!("kernel doesn't support readdirplus (missing capabilities {:#x})", unsupported);
+            }
+        }
+        let result = call(self.panic_mode, || self.target.init_with_config(req.info(), &mut KernelConfig::new(config)));
+        if result.is_ok() {
+            check_required_ops(self.capabilities);
+        }
+        result
     }
 
     fn destroy(&mut self) {
         debug!("destroy");
-        self.target.destroy();
+        let result = call(self.panic_mode, || self.target.try_destroy());
+        if let Err(e) = result {
+            error!("destroy failed: {}", std::io::Error::from_raw_os_error(e));
+        }
+        self.destroyed.set(result);
     }
 
     fn lookup(
@@ -860,30 +2335,30 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         name: &OsStr,
         reply: fuser::ReplyEntry,
     ) {
+        self.pause.wait();
+        trace!("lookup: parent={}, name={:?}, {:?}", parent, name, req.info());
+        check_name_len!(self, name, reply);
         let parent_path = get_path!(self, parent, reply);
         debug!("lookup: {:?}, {:?}", parent_path, name);
         let path = Arc::new((*parent_path).clone().join(name));
-        match self.target.getattr(req.info(), &path, None) {
+        match self.getattr_cached(req, &path) {
             Ok((ttl, attr)) => {
                 let (ino, generation) = self.inodes.add_or_get(path.clone());
                 self.inodes.lookup(ino);
-                reply.entry(&ttl, &fuse_fileattr(attr, ino), generation);
+                reply.entry(&Ttl::clamp(ttl), &self.fuse_fileattr(attr, ino), generation);
             },
-            Err(e) => reply.error(e),
+            Err(e) => self.lookup_error(e, reply),
         }
     }
 
     fn forget(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         ino: u64,
         nlookup: u64,
     ) {
-        let path = self.inodes.get_path(ino).unwrap_or_else(|| {
-            Arc::new(PathBuf::from("[unknown]"))
-        });
-        let lookups = self.inodes.forget(ino, nlookup);
-        debug!("forget: inode {} ({:?}) now at {} lookups", ino, path, lookups);
+        trace!("forget: ino={}, nlookup={}, {:?}", ino, nlookup, req.info());
+        self.forget_inode(ino, nlookup);
     }
 
     fn getattr(
@@ -892,11 +2367,16 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         ino: u64,
         reply: fuser::ReplyAttr,
     ) {
+        self.pause.wait();
+        trace!("getattr: ino={}, {:?}", ino, req.info());
+        check_supported!(self, OpSet::GETATTR, reply);
         let path = get_path!(self, ino, reply);
         debug!("getattr: {:?}", path);
-        match self.target.getattr(req.info(), &path, None) {
+        let result = self.getattr_cached(req, &path);
+        check_root_getattr(ino, &result, &mut self.warned_getattr);
+        match result {
             Ok((ttl, attr)) => {
-                reply.attr(&ttl, &fuse_fileattr(attr, ino))
+                reply.attr(&Ttl::clamp(ttl), &self.fuse_fileattr(attr, ino))
             },
             Err(e) => reply.error(e),
         }
@@ -912,7 +2392,7 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         size: Option<u64>,              // truncate
         atime: Option<TimeOrNow>,       // utimens
         mtime: Option<TimeOrNow>,       // utimens
-        _ctime: Option<SystemTime>,     // ? TODO
+        ctime: Option<SystemTime>,      // setattr only
         fh: Option<u64>,                // passed to all
         crtime: Option<SystemTime>,     // utimens_osx  (OS X only)
         chgtime: Option<SystemTime>,    // utimens_osx  (OS X only)
@@ -920,62 +2400,38 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         flags: Option<u32>,             // utimens_osx  (OS X only)
         reply: fuser::ReplyAttr,
     ) {
+        self.pause.wait();
+        trace!("setattr: ino={}, mode={:?}, uid={:?}, gid={:?}, size={:?}, atime={:?}, mtime={:?}, ctime={:?}, fh={:?}, crtime={:?}, chgtime={:?}, bkuptime={:?}, flags={:?}, {:?}", ino, mode.map(Mode), uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime, flags, req.info());
+        check_writable!(self, reply);
         let path = get_path!(self, ino, reply);
         debug!("setattr: {:?}", path);
-
-        debug!("\tino:\t{:?}", ino);
-        debug!("\tmode:\t{:?}", mode);
-        debug!("\tuid:\t{:?}", uid);
-        debug!("\tgid:\t{:?}", gid);
-        debug!("\tsize:\t{:?}", size);
-        debug!("\tatime:\t{:?}", atime);
-        debug!("\tmtime:\t{:?}", mtime);
-        debug!("\tfh:\t{:?}", fh);
-
-        // TODO: figure out what C FUSE does when only some of these are implemented.
-
-        if let Some(mode) = mode {
-            if let Err(e) = self.target.chmod(req.info(), &path, fh, mode) {
-                reply.error(e);
-                return;
-            }
-        }
-
-        if uid.is_some() || gid.is_some() {
-            if let Err(e) = self.target.chown(req.info(), &path, fh, uid, gid) {
-                reply.error(e);
-                return;
-            }
-        }
-
-        if let Some(size) = size {
-            if let Err(e) = self.target.truncate(req.info(), &path, fh, size) {
-                reply.error(e);
-                return;
-            }
-        }
-
-        if atime.is_some() || mtime.is_some() {
-            let atime = atime.map(TimeOrNowExt::time);
-            let mtime = mtime.map(TimeOrNowExt::time);
-            if let Err(e) = self.target.utimens(req.info(), &path, fh, atime, mtime) {
-                reply.error(e);
-                return;
-            }
-        }
-
-        if crtime.is_some() || chgtime.is_some() || bkuptime.is_some() || flags.is_some() {
-            if let Err(e) = self.target.utimens_macos(req.info(), &path, fh, crtime, chgtime, bkuptime, flags) {
-                reply.error(e);
-                return
-            }
-        }
-
-        match self.target.getattr(req.info(), &path, fh) {
-            Ok((ttl, attr)) => reply.attr(&ttl, &fuse_fileattr(attr, ino)),
-            Err(e) => reply.error(e),
+        self.attr_cache.remove(&path);
+
+        let attrs = SetAttrRequest { fh, mode, uid, gid, size, atime, mtime, ctime, crtime, chgtime, bkuptime, flags };
+        match self.write_locks {
+            // Truncating waits for writes to the file to finish, so it's done on the thread pool,
+            // to not hold up everything else meanwhile.
+            Some(ref locks) if size.is_some() => {
+                let (target, capabilities, req_info) = (self.target.clone(), self.capabilities, req.info());
+                let (locks, attr_cache) = (locks.clone(), self.attr_cache.clone());
+                let (strict_attrs, time_granularity) = (self.strict_attrs, self.time_granularity);
+                self.threadpool_run(move || {
+                    let _guard = locks.lock(ino);
+                    let result = setattr_calls(&*target, capabilities, req_info, &path, attrs);
+                    attr_cache.remove(&path);
+                    match result {
+                        Ok((ttl, attr)) => reply.attr(&Ttl::clamp(ttl),
+                            &fuse_fileattr(attr, ino, strict_attrs, time_granularity)),
+                        Err(e) => reply.error(e),
+                    }
+                });
+            },
+            _ => match call(self.panic_mode, || setattr_calls(&*self.target, self.capabilities, req.info(), &path, attrs)) {
+                Ok((ttl, attr)) => reply.attr(&Ttl::clamp(ttl), &self.fuse_fileattr(attr, ino)),
+                Err(e) => reply.error(e),
+            },
         }
-   }
+    }
 
     fn readlink(
         &mut self,
@@ -983,9 +2439,12 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         ino: u64,
         reply: fuser::ReplyData,
     ) {
+        self.pause.wait();
+        trace!("readlink: ino={}, {:?}", ino, req.info());
+        check_supported!(self, OpSet::READLINK, reply);
         let path = get_path!(self, ino, reply);
         debug!("readlink: {:?}", path);
-        match self.target.readlink(req.info(), &path) {
+        match call(self.panic_mode, || self.target.readlink(req.info(), &path)) {
             Ok(data) => reply.data(&data),
             Err(e) => reply.error(e),
         }
@@ -997,16 +2456,23 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32, // TODO
+        umask: u32,
         rdev: u32,
         reply: fuser::ReplyEntry,
     ) {
+        self.pause.wait();
+        trace!("mknod: parent={}, name={:?}, mode={:?}, umask={:#o}, rdev={}, {:?}", parent, name, Mode(mode), umask, rdev, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::MKNOD, reply);
+        check_name_len!(self, name, reply);
         let parent_path = get_path!(self, parent, reply);
         debug!("mknod: {:?}/{:?}", parent_path, name);
-        match self.target.mknod(req.info(), &parent_path, name, mode, rdev) {
+        self.attr_cache.remove(&parent_path);
+        self.attr_cache.remove(&parent_path.join(name));
+        match call(self.panic_mode, || self.target.mknod(req.info(), &parent_path, name, mode, umask, rdev)) {
             Ok((ttl, attr)) => {
                 let (ino, generation) = self.inodes.add(Arc::new(parent_path.join(name)));
-                reply.entry(&ttl, &fuse_fileattr(attr, ino), generation)
+                reply.entry(&Ttl::clamp(ttl), &self.fuse_fileattr(attr, ino), generation)
             },
             Err(e) => reply.error(e),
         }
@@ -1018,15 +2484,22 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32, // TODO
+        umask: u32,
         reply: fuser::ReplyEntry,
     ) {
+        self.pause.wait();
+        trace!("mkdir: parent={}, name={:?}, mode={:?}, umask={:#o}, {:?}", parent, name, Mode(mode), umask, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::MKDIR, reply);
+        check_name_len!(self, name, reply);
         let parent_path = get_path!(self, parent, reply);
         debug!("mkdir: {:?}/{:?}", parent_path, name);
-        match self.target.mkdir(req.info(), &parent_path, name, mode) {
+        self.attr_cache.remove(&parent_path);
+        self.attr_cache.remove(&parent_path.join(name));
+        match call(self.panic_mode, || self.target.mkdir(req.info(), &parent_path, name, mode, umask)) {
             Ok((ttl, attr)) => {
                 let (ino, generation) = self.inodes.add(Arc::new(parent_path.join(name)));
-                reply.entry(&ttl, &fuse_fileattr(attr, ino), generation)
+                reply.entry(&Ttl::clamp(ttl), &self.fuse_fileattr(attr, ino), generation)
             },
             Err(e) => reply.error(e),
         }
@@ -1039,9 +2512,15 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         name: &OsStr,
         reply: fuser::ReplyEmpty,
     ) {
+        self.pause.wait();
+        trace!("unlink: parent={}, name={:?}, {:?}", parent, name, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::UNLINK, reply);
         let parent_path = get_path!(self, parent, reply);
         debug!("unlink: {:?}/{:?}", parent_path, name);
-        match self.target.unlink(req.info(), &parent_path, name) {
+        // The file's other names, if it has any, have a new link count now.
+        self.attr_cache.clear();
+        match call(self.panic_mode, || self.target.unlink(req.info(), &parent_path, name)) {
             Ok(()) => {
                 self.inodes.unlink(&parent_path.join(name));
                 reply.ok()
@@ -1057,9 +2536,15 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         name: &OsStr,
         reply: fuser::ReplyEmpty,
     ) {
+        self.pause.wait();
+        trace!("rmdir: parent={}, name={:?}, {:?}", parent, name, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::RMDIR, reply);
         let parent_path = get_path!(self, parent, reply);
         debug!("rmdir: {:?}/{:?}", parent_path, name);
-        match self.target.rmdir(req.info(), &parent_path, name) {
+        self.attr_cache.remove(&parent_path);
+        self.attr_cache.invalidate(&parent_path.join(name));
+        match call(self.panic_mode, || self.target.rmdir(req.info(), &parent_path, name)) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
@@ -1073,12 +2558,19 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         link: &Path,
         reply: fuser::ReplyEntry,
     ) {
+        self.pause.wait();
+        trace!("symlink: parent={}, name={:?}, link={:?}, {:?}", parent, name, link, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::SYMLINK, reply);
+        check_name_len!(self, name, reply);
         let parent_path = get_path!(self, parent, reply);
         debug!("symlink: {:?}/{:?} -> {:?}", parent_path, name, link);
-        match self.target.symlink(req.info(), &parent_path, name, link) {
+        self.attr_cache.remove(&parent_path);
+        self.attr_cache.remove(&parent_path.join(name));
+        match call(self.panic_mode, || self.target.symlink(req.info(), &parent_path, name, link)) {
             Ok((ttl, attr)) => {
                 let (ino, generation) = self.inodes.add(Arc::new(parent_path.join(name)));
-                reply.entry(&ttl, &fuse_fileattr(attr, ino), generation)
+                reply.entry(&Ttl::clamp(ttl), &self.fuse_fileattr(attr, ino), generation)
             },
             Err(e) => reply.error(e),
         }
@@ -1091,15 +2583,30 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
-        _flags: u32, // TODO
+        flags: u32,
         reply: fuser::ReplyEmpty,
     ) {
+        self.pause.wait();
+        trace!("rename: parent={}, name={:?}, newparent={}, newname={:?}, flags={:?}, {:?}", parent, name, newparent, newname, RenameFlags(flags), req.info());
+        check_writable!(self, reply);
+        check_name_len!(self, newname, reply);
         let parent_path = get_path!(self, parent, reply);
         let newparent_path = get_path!(self, newparent, reply);
-        debug!("rename: {:?}/{:?} -> {:?}/{:?}", parent_path, name, newparent_path, newname);
-        match self.target.rename(req.info(), &parent_path, name, &newparent_path, newname) {
+        debug!("rename: {:?}/{:?} -> {:?}/{:?}, flags {:#x}", parent_path, name, newparent_path, newname, flags);
+        self.attr_cache.remove(&parent_path);
+        self.attr_cache.remove(&newparent_path);
+        self.attr_cache.invalidate(&parent_path.join(name));
+        self.attr_cache.invalidate(&newparent_path.join(newname));
+        let result = call(self.panic_mode, || rename_with_flags(&*self.target, self.capabilities, req.info(),
+            &parent_path, name, &newparent_path, newname, flags));
+        match result {
             Ok(()) => {
-                self.inodes.rename(&parent_path.join(name), Arc::new(newparent_path.join(newname)));
+                let (path, newpath) = (parent_path.join(name), newparent_path.join(newname));
+                if flags & RENAME_EXCHANGE != 0 {
+                    self.inodes.exchange(&path, &newpath);
+                } else {
+                    self.inodes.rename(&path, Arc::new(newpath));
+                }
                 reply.ok()
             },
             Err(e) => reply.error(e),
@@ -1114,15 +2621,21 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         newname: &OsStr,
         reply: fuser::ReplyEntry,
     ) {
+        self.pause.wait();
+        trace!("link: ino={}, newparent={}, newname={:?}, {:?}", ino, newparent, newname, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::LINK, reply);
+        check_name_len!(self, newname, reply);
         let path = get_path!(self, ino, reply);
         let newparent_path = get_path!(self, newparent, reply);
         debug!("link: {:?} -> {:?}/{:?}", path, newparent_path, newname);
-        match self.target.link(req.info(), &path, &newparent_path, newname) {
+        self.attr_cache.clear();
+        match call(self.panic_mode, || self.target.link(req.info(), &path, &newparent_path, newname)) {
             Ok((ttl, attr)) => {
                 // NOTE: this results in the new link having a different inode from the original.
                 // This is needed because our inode table is a 1:1 map between paths and inodes.
                 let (new_ino, generation) = self.inodes.add(Arc::new(newparent_path.join(newname)));
-                reply.entry(&ttl, &fuse_fileattr(attr, new_ino), generation);
+                reply.entry(&Ttl::clamp(ttl), &self.fuse_fileattr(attr, new_ino), generation);
             },
             Err(e) => reply.error(e),
         }
@@ -1135,11 +2648,54 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         flags: i32,
         reply: fuser::ReplyOpen,
     ) {
+        self.pause.wait();
+        trace!("open: ino={}, flags={:?}, {:?}", ino, OpenFlags(flags), req.info());
+        if opens_for_writing(flags) {
+            check_writable!(self, reply);
+        }
+        check_supported!(self, OpSet::OPEN, reply);
         let path = get_path!(self, ino, reply);
         debug!("open: {:?}", path);
-        match self.target.open(req.info(), &path, flags as u32) { // TODO: change flags to i32
-            Ok((fh, flags)) => reply.opened(fh, flags),
-            Err(e) => reply.error(e),
+        if let Err(e) = self.open_counts.acquire(ino) {
+            reply.error(e);
+            return;
+        }
+        if flags & libc::O_TRUNC != 0 {
+            self.attr_cache.remove(&path);
+        }
+        if let (Some(locks), true) = (&self.write_locks, flags & libc::O_TRUNC != 0) {
+            // Truncating waits for writes to the file to finish, so it's done on the thread pool,
+            // to not hold up everything else meanwhile. The file is changed by it, so its cache
+            // isn't kept.
+            self.auto_cache.forget(ino);
+            let (target, req_info, locks) = (self.target.clone(), req.info(), locks.clone());
+            let (attr_cache, open_counts) = (self.attr_cache.clone(), self.open_counts.clone());
+            let panic_mode = self.panic_mode;
+            self.threadpool_run(move || {
+                let _guard = locks.lock(ino);
+                // A panic has to come back as an error here, or the open wouldn't be uncounted.
+                let result = call(panic_mode, || open_truncating(&*target, req_info, &path, flags as u32));
+                attr_cache.remove(&path);
+                match result {
+                    Ok((fh, flags)) => reply.opened(fh, flags & !FOPEN_AUTO_CACHE),
+                    Err(e) => {
+                        open_counts.release(ino);
+                        reply.error(e)
+                    },
+                }
+            });
+            return;
+        }
+        let result = call(self.panic_mode, || open_truncating(&*self.target, req.info(), &path, flags as u32)); // TODO: change flags to i32
+        match result {
+            Ok((fh, flags)) => {
+                let flags = self.auto_cache_flags(req, ino, &path, fh, flags);
+                reply.opened(fh, flags)
+            },
+            Err(e) => {
+                self.open_counts.release(ino);
+                reply.error(e)
+            },
         }
     }
 
@@ -1150,10 +2706,12 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         fh: u64,
         offset: i64,
         size: u32,
-        _flags: i32,                // TODO
-        _lock_owner: Option<u64>,   // TODO
+        flags: i32,                // logged only
+        lock_owner: Option<u64>,   // logged only
         reply: fuser::ReplyData,
     ) {
+        trace!("read: ino={}, fh={}, offset={:#x}, size={:#x}, flags={:?}, lock_owner={:?}, {:?}", ino, fh, offset, size, OpenFlags(flags), lock_owner, req.info());
+        check_supported!(self, OpSet::READ, reply);
         let path = get_path!(self, ino, reply);
         debug!("read: {:?} {:#x} @ {:#x}", path, size, offset);
         if offset < 0 {
@@ -1163,15 +2721,13 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         }
         let target = self.target.clone();
         let req_info = req.info();
-        self.threadpool_run(move || {
-            target.read(req_info, &path, fh, offset as u64, size, |result| {
+        let retries = self.eintr_retries;
+        self.threadpool_run_buffered(size.into(), move || {
+            read_retrying(&*target, req_info, &path, fh, offset as u64, size, retries, |result| {
                 match result {
                     Ok(data) => reply.data(data),
                     Err(e) => reply.error(e),
                 }
-                CallbackResult {
-                    _private: std::marker::PhantomData {},
-                }
             });
         });
     }
@@ -1183,13 +2739,17 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         fh: u64,
         offset: i64,
         data: &[u8],
-        _write_flags: u32,          // TODO
-        flags: i32,
-        _lock_owner: Option<u64>,   // TODO
+        write_flags: u32,          // logged only
+        flags: i32,                // write
+        lock_owner: Option<u64>,   // logged only
         reply: fuser::ReplyWrite,
     ) {
+        trace!("write: ino={}, fh={}, offset={:#x}, len={:#x}, write_flags={:?}, flags={:?}, lock_owner={:?}, {:?}", ino, fh, offset, data.len(), WriteFlags(write_flags), OpenFlags(flags), lock_owner, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::WRITE, reply);
         let path = get_path!(self, ino, reply);
         debug!("write: {:?} {:#x} @ {:#x}", path, data.len(), offset);
+        self.attr_cache.remove(&path);
         if offset < 0 {
             error!("write called with a negative offset");
             reply.error(libc::EINVAL);
@@ -1198,12 +2758,30 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         let target = self.target.clone();
         let req_info = req.info();
 
+        let write_locks = self.write_locks.clone();
+        if self.num_threads == 0 {
+            // Without a threadpool, the data can be passed on straight from `fuser`'s buffer.
+            self.pause.wait();
+            let _usage = self.usage.track(data.len() as u64);
+            let _guard = write_locks.as_ref().map(|locks| locks.lock(ino));
+            match call(self.panic_mode, || write_data(&*target, req_info, &path, fh, offset as u64, Cow::Borrowed(data), flags as u32)) {
+                Ok(written) => reply.written(written),
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+
         // The data needs to be copied here before dispatching to the threadpool because it's a
         // slice of a single buffer that `fuser` re-uses for the entire session.
         let data_buf = Vec::from(data);
 
-        self.threadpool_run(move|| {
-            match target.write(req_info, &path, fh, offset as u64, data_buf, flags as u32) {
+        let attr_cache = self.attr_cache.clone();
+        self.threadpool_run_buffered(data_buf.len() as u64, move|| {
+            let _guard = write_locks.as_ref().map(|locks| locks.lock(ino));
+            let result = write_data(&*target, req_info, &path, fh, offset as u64, Cow::Owned(data_buf), flags as u32);
+            // Again, in case attributes were cached while this was waiting to run.
+            attr_cache.remove(&path);
+            match result {
                 Ok(written) => reply.written(written),
                 Err(e) => reply.error(e),
             }
@@ -1218,6 +2796,8 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         lock_owner: u64,
         reply: fuser::ReplyEmpty,
     ) {
+        trace!("flush: ino={}, fh={}, lock_owner={:#x}, {:?}", ino, fh, lock_owner, req.info());
+        check_supported!(self, OpSet::FLUSH, reply);
         let path = get_path!(self, ino, reply);
         debug!("flush: {:?}", path);
         let target = self.target.clone();
@@ -1240,10 +2820,12 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         flush: bool,
         reply: fuser::ReplyEmpty,
     ) {
+        trace!("release: ino={}, fh={}, flags={:?}, lock_owner={:?}, flush={}, {:?}", ino, fh, OpenFlags(flags), lock_owner, flush, req.info());
+        self.open_counts.release(ino);
         let path = get_path!(self, ino, reply);
         debug!("release: {:?}", path);
-        match self.target.release(
-            req.info(), &path, fh, flags as u32, lock_owner.unwrap_or(0) /* TODO */, flush)
+        match call(self.panic_mode, || self.target.release(
+            req.info(), &path, fh, flags as u32, lock_owner.unwrap_or(0) /* TODO */, flush))
         {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
@@ -1258,6 +2840,8 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         datasync: bool,
         reply: fuser::ReplyEmpty,
     ) {
+        trace!("fsync: ino={}, fh={}, datasync={}, {:?}", ino, fh, datasync, req.info());
+        check_supported!(self, OpSet::FSYNC, reply);
         let path = get_path!(self, ino, reply);
         debug!("fsync: {:?}", path);
         let target = self.target.clone();
@@ -1277,9 +2861,12 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         flags: i32,
         reply: fuser::ReplyOpen,
     ) {
+        self.pause.wait();
+        trace!("opendir: ino={}, flags={:?}, {:?}", ino, OpenFlags(flags), req.info());
+        check_supported!(self, OpSet::OPENDIR, reply);
         let path = get_path!(self, ino, reply);
         debug!("opendir: {:?}", path);
-        match self.target.opendir(req.info(), &path, flags as u32) {
+        match call(self.panic_mode, || self.target.opendir(req.info(), &path, flags as u32)) {
             Ok((fh, flags)) => {
                 let dcache_key = self.directory_cache.new_entry(fh);
                 reply.opened(dcache_key, flags);
@@ -1296,6 +2883,9 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         offset: i64,
         mut reply: fuser::ReplyDirectory,
     ) {
+        self.pause.wait();
+        trace!("readdir: ino={}, fh={}, offset={:#x}, {:?}", ino, fh, offset, req.info());
+        check_supported!(self, OpSet::READDIR, reply);
         let path = get_path!(self, ino, reply);
         debug!("readdir: {:?} @ {}", path, offset);
 
@@ -1305,39 +2895,60 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
             return;
         }
 
-        let entries: &[DirectoryEntry] = {
-            let dcache_entry = self.directory_cache.get_mut(fh);
-            if let Some(ref entries) = dcache_entry.entries {
-                entries
-            } else {
-                debug!("entries not yet fetched; requesting with fh {}", dcache_entry.fh);
-                match self.target.readdir(req.info(), &path, dcache_entry.fh) {
-                    Ok(entries) => {
-                        dcache_entry.entries = Some(entries);
-                        dcache_entry.entries.as_ref().unwrap()
-                    },
-                    Err(e) => {
-                        reply.error(e);
-                        return;
-                    }
-                }
+        let parent_inode = match self.parent_inode(ino, &path) {
+            Some(inode) => inode,
+            None => {
+                error!("readdir: unable to get inode for parent of {:?}", path);
+                reply.error(libc::EIO);
+                return;
             }
         };
 
-        let parent_inode = if ino == 1 {
-            ino
-        } else {
-            let parent_path: &Path = path.parent().unwrap();
-            match self.inodes.get_inode(parent_path) {
-                Some(inode) => inode,
-                None => {
-                    error!("readdir: unable to get inode for parent of {:?}", path);
-                    reply.error(libc::EIO);
-                    return;
+        let real_fh = if fh == 0 { 0 } else { self.directory_cache.real_fh(fh) };
+        let result = {
+            let max_name_len = self.max_name_len;
+            let mut add = |_ino: u64, entry_offset: i64, kind: FileType, name: &OsStr| {
+                if name_too_long(name, max_name_len) {
+                    warn!("readdir: {:?} has entry {:?}, which is longer than the maximum name length", path, name);
+                    return true;
                 }
+                // As below, the kernel looks the entries up anyway.
+                let entry_inode = if name == "." {
+                    ino
+                } else if name == ".." {
+                    parent_inode
+                } else {
+                    !1
+                };
+                !reply.add(entry_inode, entry_offset, kind, name)
+            };
+            call(self.panic_mode, || self.target.readdir_offset(req.info(), &path, real_fh, offset as u64, &mut DirFiller::new(&mut add)))
+        };
+        match result {
+            Ok(()) => {
+                reply.ok();
+                return;
+            },
+            Err(libc::ENOSYS) => debug!("readdir_offset not implemented; using readdir"),
+            Err(e) => {
+                reply.error(e);
+                return;
+            },
+        }
+
+        let uncached_entries = match self.load_dir_entries(req, &path, fh, offset) {
+            Ok(entries) => entries,
+            Err(e) => {
+                reply.error(e);
+                return;
             }
         };
 
+        let entries: &[DirectoryEntry] = match uncached_entries {
+            Some(ref entries) => entries,
+            None => self.directory_cache.entries(fh).unwrap(),
+        };
+
         debug!("directory has {} entries", entries.len());
 
         for (index, entry) in entries.iter().skip(offset as usize).enumerate() {
@@ -1369,6 +2980,84 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         reply.ok();
     }
 
+    #[cfg(feature = "readdirplus")]
+    fn readdirplus(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectoryPlus,
+    ) {
+        self.pause.wait();
+        trace!("readdirplus: ino={}, fh={}, offset={:#x}, {:?}", ino, fh, offset, req.info());
+        check_supported!(self, OpSet::READDIR, reply);
+        let path = get_path!(self, ino, reply);
+        debug!("readdirplus: {:?} @ {}", path, offset);
+
+        if offset < 0 {
+            error!("readdirplus called with a negative offset");
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        let parent_inode = match self.parent_inode(ino, &path) {
+            Some(inode) => inode,
+            None => {
+                error!("readdirplus: unable to get inode for parent of {:?}", path);
+                reply.error(libc::EIO);
+                return;
+            }
+        };
+
+        let real_fh = if fh == 0 { 0 } else { self.directory_cache.real_fh(fh) };
+        let result = call(self.panic_mode, || readdir_offset_entries(&*self.target, req.info(), &path, real_fh,
+            offset as u64, self.max_name_len));
+        match result {
+            Ok(entries) => {
+                for (next_offset, entry) in entries {
+                    if self.add_readdirplus_entry(req, &mut reply, ino, parent_inode, &path, &entry, next_offset) {
+                        debug!("readdirplus: reply buffer is full");
+                        break;
+                    }
+                }
+                reply.ok();
+                return;
+            },
+            Err(libc::ENOSYS) => debug!("readdir_offset not implemented; using readdir"),
+            Err(e) => {
+                reply.error(e);
+                return;
+            },
+        }
+
+        let uncached_entries = match self.load_dir_entries(req, &path, fh, offset) {
+            Ok(entries) => entries,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        for index in offset as usize .. {
+            // Entries are taken one at a time, since getting their attributes needs `self`.
+            let entry = match uncached_entries {
+                Some(ref entries) => entries.get(index),
+                None => self.directory_cache.entries(fh).unwrap().get(index),
+            };
+            let entry = match entry {
+                Some(entry) => entry.clone(),
+                None => break,
+            };
+            if self.add_readdirplus_entry(req, &mut reply, ino, parent_inode, &path, &entry, index as i64 + 1) {
+                debug!("readdirplus: reply buffer is full");
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+
     fn releasedir(
         &mut self,
         req: &fuser::Request<'_>,
@@ -1377,10 +3066,11 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         flags: i32,
         reply: fuser::ReplyEmpty,
     ) {
+        trace!("releasedir: ino={}, fh={}, flags={:?}, {:?}", ino, fh, OpenFlags(flags), req.info());
         let path = get_path!(self, ino, reply);
         debug!("releasedir: {:?}", path);
         let real_fh = self.directory_cache.real_fh(fh);
-        match self.target.releasedir(req.info(), &path, real_fh, flags as u32) {
+        match call(self.panic_mode, || self.target.releasedir(req.info(), &path, real_fh, flags as u32)) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
@@ -1395,10 +3085,13 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         datasync: bool,
         reply: fuser::ReplyEmpty,
     ) {
+        self.pause.wait();
+        trace!("fsyncdir: ino={}, fh={}, datasync={}, {:?}", ino, fh, datasync, req.info());
+        check_supported!(self, OpSet::FSYNCDIR, reply);
         let path = get_path!(self, ino, reply);
         debug!("fsyncdir: {:?} (datasync: {:?})", path, datasync);
-        let real_fh = self.directory_cache.real_fh(fh);
-        match self.target.fsyncdir(req.info(), &path, real_fh, datasync) {
+        let real_fh = if fh == 0 { 0 } else { self.directory_cache.real_fh(fh) };
+        match call(self.panic_mode, || self.target.fsyncdir(req.info(), &path, real_fh, datasync)) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
@@ -1410,6 +3103,9 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         ino: u64,
         reply: fuser::ReplyStatfs,
     ) {
+        self.pause.wait();
+        trace!("statfs: ino={}, {:?}", ino, req.info());
+        check_supported!(self, OpSet::STATFS, reply);
         let path = if ino == 1 {
             Arc::new(PathBuf::from("/"))
         } else {
@@ -1417,16 +3113,23 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         };
 
         debug!("statfs: {:?}", path);
-        match self.target.statfs(req.info(), &path) {
-            Ok(statfs) => reply.statfs(
-                statfs.blocks,
-                statfs.bfree,
-                statfs.bavail,
-                statfs.files,
-                statfs.ffree,
-                statfs.bsize,
-                statfs.namelen,
-                statfs.frsize),
+        match call(self.panic_mode, || self.target.statfs(req.info(), &path)) {
+            Ok(statfs) => {
+                if self.strict_attrs {
+                    for problem in statfs_problems(&statfs) {
+                        error!("statfs has bad values: {}: {:?}", problem, statfs);
+                    }
+                }
+                reply.statfs(
+                    statfs.blocks,
+                    statfs.bfree,
+                    statfs.bavail,
+                    statfs.files,
+                    statfs.ffree,
+                    statfs.bsize,
+                    self.max_name_len.map_or(statfs.namelen, |max| statfs.namelen.min(max)),
+                    statfs.frsize)
+            },
             Err(e) => reply.error(e),
         }
     }
@@ -1441,10 +3144,15 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         position: u32,
         reply: fuser::ReplyEmpty,
     ) {
+        self.pause.wait();
+        trace!("setxattr: ino={}, name={:?}, value={:?}, flags={:?}, position={}, {:?}", ino, name, String::from_utf8_lossy(value), XattrFlags(flags), position, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::SETXATTR, reply);
         let path = get_path!(self, ino, reply);
         debug!("setxattr: {:?} {:?} ({} bytes, flags={:#x}, pos={:#x}",
             path, name, value.len(), flags, position);
-        match self.target.setxattr(req.info(), &path, name, value, flags as u32, position) {
+        self.attr_cache.remove(&path);
+        match call(self.panic_mode, || self.target.setxattr(req.info(), &path, name, value, flags as u32, position)) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
@@ -1458,9 +3166,20 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         size: u32,
         reply: fuser::ReplyXattr,
     ) {
+        self.pause.wait();
+        trace!("getxattr: ino={}, name={:?}, size={:#x}, {:?}", ino, name, size, req.info());
+        if self.capabilities_xattr && name == CAPABILITIES_XATTR {
+            match get_capabilities_xattr(self.capabilities, size) {
+                Ok(Xattr::Size(size)) => reply.size(size),
+                Ok(Xattr::Data(vec)) => reply.data(&vec),
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+        check_supported!(self, OpSet::GETXATTR, reply);
         let path = get_path!(self, ino, reply);
         debug!("getxattr: {:?} {:?}", path, name);
-        match self.target.getxattr(req.info(), &path, name, size) {
+        match call(self.panic_mode, || self.target.getxattr(req.info(), &path, name, size)) {
             Ok(Xattr::Size(size)) => {
                 debug!("getxattr: sending size {}", size);
                 reply.size(size)
@@ -1483,9 +3202,22 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         size: u32,
         reply: fuser::ReplyXattr,
     ) {
+        self.pause.wait();
+        trace!("listxattr: ino={}, size={:#x}, {:?}", ino, size, req.info());
+        if !self.capabilities_xattr {
+            check_supported!(self, OpSet::LISTXATTR, reply);
+        }
         let path = get_path!(self, ino, reply);
         debug!("listxattr: {:?}", path);
-        match self.target.listxattr(req.info(), &path, size) {
+        let mut result = if self.capabilities.contains(OpSet::LISTXATTR) {
+            call(self.panic_mode, || self.target.listxattr(req.info(), &path, size))
+        } else {
+            Err(libc::ENOSYS)
+        };
+        if self.capabilities_xattr {
+            result = list_capabilities_xattr(result, size);
+        }
+        match result {
             Ok(Xattr::Size(size)) => {
                 debug!("listxattr: sending size {}", size);
                 reply.size(size)
@@ -1505,9 +3237,14 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         name: &OsStr,
         reply: fuser::ReplyEmpty,
     ) {
+        self.pause.wait();
+        trace!("removexattr: ino={}, name={:?}, {:?}", ino, name, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::REMOVEXATTR, reply);
         let path = get_path!(self, ino, reply);
         debug!("removexattr: {:?}, {:?}", path, name);
-        match self.target.removexattr(req.info(), &path, name) {
+        self.attr_cache.remove(&path);
+        match call(self.panic_mode, || self.target.removexattr(req.info(), &path, name)) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
@@ -1520,9 +3257,15 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         mask: i32,
         reply: fuser::ReplyEmpty,
     ) {
+        self.pause.wait();
+        trace!("access: ino={}, mask={:?}, {:?}", ino, AccessMask(mask), req.info());
+        if mask & libc::W_OK != 0 {
+            check_writable!(self, reply);
+        }
+        check_supported!(self, OpSet::ACCESS, reply);
         let path = get_path!(self, ino, reply);
         debug!("access: {:?}, mask={:#o}", path, mask);
-        match self.target.access(req.info(), &path, mask as u32) {
+        match call(self.panic_mode, || self.target.access(req.info(), &path, mask as u32)) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
@@ -1534,22 +3277,192 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32, // TODO
+        umask: u32,
         flags: i32,
         reply: fuser::ReplyCreate,
     ) {
+        self.pause.wait();
+        trace!("create: parent={}, name={:?}, mode={:?}, umask={:#o}, flags={:?}, {:?}", parent, name, Mode(mode), umask, OpenFlags(flags), req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::CREATE, reply);
+        check_name_len!(self, name, reply);
         let parent_path = get_path!(self, parent, reply);
         debug!("create: {:?}/{:?} (mode={:#o}, flags={:#x})", parent_path, name, mode, flags);
-        match self.target.create(req.info(), &parent_path, name, mode, flags as u32) {
+        self.attr_cache.remove(&parent_path);
+        self.attr_cache.remove(&parent_path.join(name));
+        match call(self.panic_mode, || self.target.create(req.info(), &parent_path, name, mode, umask, flags as u32)) {
             Ok(create) => {
                 let (ino, generation) = self.inodes.add(Arc::new(parent_path.join(name)));
-                let attr = fuse_fileattr(create.attr, ino);
-                reply.created(&create.ttl, &attr, generation, create.fh, create.flags);
+                self.open_counts.add(ino);
+                let attr = self.fuse_fileattr(create.attr, ino);
+                let mut flags = create.flags;
+                if flags & FOPEN_AUTO_CACHE != 0 {
+                    // There's nothing cached for a new file; just remember it for the next open.
+                    flags &= !FOPEN_AUTO_CACHE;
+                    self.auto_cache.check(ino, attr.mtime, attr.size);
+                }
+                reply.created(&Ttl::clamp(create.ttl), &attr, generation, create.fh, flags);
             },
             Err(e) => reply.error(e),
         }
     }
 
+    #[cfg(feature = "poll")]
+    fn poll(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        kh: u64,
+        events: u32,
+        flags: u32,
+        reply: fuser::ReplyPoll,
+    ) {
+        trace!("poll: ino={}, fh={}, kh={:#x}, events={:#x}, flags={:#x}, {:?}", ino, fh, kh, events, flags, req.info());
+        check_supported!(self, OpSet::POLL, reply);
+        let path = get_path!(self, ino, reply);
+        debug!("poll: {:?}", path);
+        let target = self.target.clone();
+        let req_info = req.info();
+        self.threadpool_run(move|| {
+            match target.poll(req_info, &path, fh, PollHandle(kh), events, flags) {
+                Ok(revents) => reply.poll(revents),
+                Err(e) => reply.error(e),
+            }
+        });
+    }
+
+    #[cfg(feature = "ioctl")]
+    fn ioctl(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: fuser::ReplyIoctl,
+    ) {
+        trace!("ioctl: ino={}, fh={}, flags={:#x}, cmd={:#x}, in_data.len={:#x}, out_size={:#x}, {:?}", ino, fh, flags, cmd, in_data.len(), out_size, req.info());
+        check_supported!(self, OpSet::IOCTL, reply);
+        let path = get_path!(self, ino, reply);
+        debug!("ioctl: {:?} {:#x}", path, cmd);
+        let fh = if flags & FUSE_IOCTL_DIR != 0 && fh != 0 { self.directory_cache.real_fh(fh) } else { fh };
+        let target = self.target.clone();
+        let req_info = req.info();
+        // Like write data, this is part of a buffer `fuser` re-uses, so it's copied for the thread.
+        let in_data = Vec::from(in_data);
+        self.threadpool_run(move|| {
+            match target.ioctl(req_info, &path, fh, flags, cmd, &in_data, out_size) {
+                Ok((result, out_data)) => reply.ioctl(result, &out_data),
+                Err(e) => reply.error(e),
+            }
+        });
+    }
+
+    #[cfg(feature = "fallocate")]
+    fn fallocate(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        trace!("fallocate: ino={}, fh={}, offset={:#x}, length={:#x}, mode={:#x}, {:?}", ino, fh, offset, length, mode, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::FALLOCATE, reply);
+        let path = get_path!(self, ino, reply);
+        debug!("fallocate: {:?} {:#x} @ {:#x}", path, length, offset);
+        self.attr_cache.remove(&path);
+        if offset < 0 || length <= 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let target = self.target.clone();
+        let req_info = req.info();
+        let write_locks = self.write_locks.clone();
+        let attr_cache = self.attr_cache.clone();
+        self.threadpool_run(move|| {
+            let _guard = write_locks.as_ref().map(|locks| locks.lock(ino));
+            let result = target.fallocate(req_info, &path, fh, offset as u64, length as u64, mode);
+            attr_cache.remove(&path);
+            match result {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e),
+            }
+        });
+    }
+
+    #[cfg(feature = "lseek")]
+    fn lseek(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: fuser::ReplyLseek,
+    ) {
+        self.pause.wait();
+        trace!("lseek: ino={}, fh={}, offset={}, whence={}, {:?}", ino, fh, offset, whence, req.info());
+        check_supported!(self, OpSet::LSEEK, reply);
+        let path = get_path!(self, ino, reply);
+        debug!("lseek: {:?} @ {}", path, offset);
+        if offset < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        match call(self.panic_mode, || self.target.lseek(req.info(), &path, fh, offset as u64, whence)) {
+            Ok(offset) => reply.offset(offset as i64),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    #[cfg(feature = "copy_file_range")]
+    fn copy_file_range(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: fuser::ReplyWrite,
+    ) {
+        trace!("copy_file_range: ino_in={}, fh_in={}, offset_in={:#x}, ino_out={}, fh_out={}, offset_out={:#x}, len={:#x}, flags={:#x}, {:?}", ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::COPY_FILE_RANGE, reply);
+        let path_in = get_path!(self, ino_in, reply);
+        let path_out = get_path!(self, ino_out, reply);
+        debug!("copy_file_range: {:?} @ {:#x} -> {:?} @ {:#x}, {:#x} bytes", path_in, offset_in, path_out, offset_out, len);
+        self.attr_cache.remove(&path_out);
+        if offset_in < 0 || offset_out < 0 {
+            error!("copy_file_range called with a negative offset");
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let target = self.target.clone();
+        let req_info = req.info();
+        let write_locks = self.write_locks.clone();
+        let attr_cache = self.attr_cache.clone();
+        self.threadpool_run(move|| {
+            let _guard = write_locks.as_ref().map(|locks| locks.lock(ino_out));
+            let result = target.copy_file_range(req_info, &path_in, fh_in, offset_in as u64, &path_out, fh_out, offset_out as u64, len, flags);
+            attr_cache.remove(&path_out);
+            match result {
+                Ok(written) => reply.written(written),
+                Err(e) => reply.error(e),
+            }
+        });
+    }
+
     // getlk
 
     // setlk
@@ -1563,8 +3476,11 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         name: &OsStr,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!("setvolname: {:?}", name);
-        match self.target.setvolname(req.info(), name) {
+        self.pause.wait();
+        trace!("setvolname: name={:?}, {:?}", name, req.info());
+        check_writable!(self, reply);
+        check_supported!(self, OpSet::SETVOLNAME, reply);
+        match call(self.panic_mode, || self.target.setvolname(req.info(), name)) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
@@ -1579,9 +3495,12 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         ino: u64,
         reply: fuser::ReplyXTimes,
     ) {
+        self.pause.wait();
+        trace!("getxtimes: ino={}, {:?}", ino, req.info());
+        check_supported!(self, OpSet::GETXTIMES, reply);
         let path = get_path!(self, ino, reply);
         debug!("getxtimes: {:?}", path);
-        match self.target.getxtimes(req.info(), &path) {
+        match call(self.panic_mode, || self.target.getxtimes(req.info(), &path)) {
             Ok(xtimes) => {
                 reply.xtimes(xtimes.bkuptime, xtimes.crtime);
             }
@@ -1589,3 +3508,494 @@ impl<T: for<'a> FilesystemMT<'a, &'a Path> + Sync + Send + 'static> fuser::Files
         }
     }
 }
+
+#[test]
+fn test_swap_root_inode() {
+    // By default, nothing is translated.
+    assert_eq!(1, swap_root_inode(1, 1));
+    assert_eq!(5, swap_root_inode(1, 5));
+
+    // With a different root, node ID 1 and the root inode trade places.
+    assert_eq!(2, swap_root_inode(2, 1));
+    assert_eq!(1, swap_root_inode(2, 2));
+    assert_eq!(5, swap_root_inode(2, 5));
+}
+
+#[test]
+fn test_root_st_ino() {
+    // A filesystem with its root at inode 2, which leaves the inode in its attributes unset.
+    struct RootFS;
+    impl FilesystemMT<'_, Inode, RawFileAttr> for RootFS {
+        fn getattr(&self, _req: RequestInfo, _ino: Inode, _fh: Option<u64>) -> ResultEntry<RawFileAttr> {
+            let attr = FileAttr { kind: fuser::FileType::Directory, perm: 0o755, nlink: 2, ..Default::default() };
+            Ok((Duration::from_secs(1), attr.as_raw(0, 0)))
+        }
+    }
+    impl RawFilesystemMT for RootFS {
+        fn lookup(&self, _req: RequestInfo, _parent: Inode, _name: &OsStr) -> ResultEntry<RawFileAttr> {
+            Err(libc::ENOENT)
+        }
+        fn forget(&self, _req: RequestInfo, _ino: Inode, _nlookup: u64) {}
+        fn parent(&self, _req: RequestInfo, _ino: Inode) -> ResultInode {
+            Err(libc::ENOENT)
+        }
+        fn root(&self) -> Inode {
+            2
+        }
+    }
+
+    // getattr on node ID 1 reports the filesystem's own root inode as st_ino, and other nodes
+    // keep their node ID, whatever the filesystem put in its attributes.
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    let mut fs = RawFuseMT::new(RootFS, 0);
+    assert_eq!(2, fs.getattr_reply(req, fuser::FUSE_ROOT_ID).unwrap().1.ino);
+    assert_eq!(1, fs.getattr_reply(req, 2).unwrap().1.ino);
+    assert_eq!(5, fs.getattr_reply(req, 5).unwrap().1.ino);
+}
+
+#[test]
+fn test_duplicate_entries() {
+    let entry = |name: &str| DirectoryEntry { name: name.into(), kind: fuser::FileType::RegularFile };
+    let names = |entries: &[DirectoryEntry]| -> Vec<String> {
+        entries.iter().map(|e| e.name.to_string_lossy().into_owned()).collect()
+    };
+
+    let mut entries = vec![entry("a"), entry("b"), entry("a"), entry("c"), entry("b")];
+
+    // Without dedup, the listing is left alone.
+    check_duplicate_entries(&"/", &mut entries, false);
+    assert_eq!(vec!["a", "b", "a", "c", "b"], names(&entries));
+
+    // With it, only the first entry with each name is kept.
+    check_duplicate_entries(&"/", &mut entries, true);
+    assert_eq!(vec!["a", "b", "c"], names(&entries));
+}
+
+#[test]
+fn test_attr_problems() {
+    let now = SystemTime::now();
+    let good = FileAttr {
+        size: 4096,
+        blocks: 8,
+        atime: now,
+        mtime: now,
+        ctime: now,
+        crtime: now,
+        kind: fuser::FileType::Directory,
+        perm: 0o755,
+        nlink: 2,
+        ..Default::default()
+    };
+    assert!(attr_problems(&good, now).is_empty());
+
+    let bad = FileAttr {
+        blocks: 1 << 20,
+        mtime: now + Duration::from_secs(10 * 365 * 24 * 60 * 60),
+        perm: FileType::Directory.to_mode_bits() as u16 | 0o755,
+        nlink: 1,
+        .. good
+    };
+    assert_eq!(4, attr_problems(&bad, now).len());
+}
+
+#[test]
+fn test_statfs_problems() {
+    let statfs = |blocks, bfree, bavail| Statfs {
+        blocks, bfree, bavail, files: 100, ffree: 50, bsize: 4096, namelen: 255, frsize: 4096,
+    };
+
+    // Free space which is all reserved for root is fine: df shows it as neither used nor
+    // available.
+    assert!(statfs_problems(&statfs(10_000, 1000, 0)).is_empty());
+    assert_eq!(vec!["more blocks available than free"], statfs_problems(&statfs(10_000, 1000, 2000)));
+    assert_eq!(vec!["more free blocks than blocks"], statfs_problems(&statfs(10, 1000, 0)));
+}
+
+#[test]
+fn test_negative_entry_ttl() {
+    // By default, a failed lookup isn't remembered, so a file created afterwards is found.
+    assert_eq!(None, negative_entry_ttl(libc::ENOENT, Duration::ZERO));
+
+    let ttl = Duration::from_secs(5);
+    assert_eq!(Some(ttl), negative_entry_ttl(libc::ENOENT, ttl));
+    assert_eq!(None, negative_entry_ttl(libc::EACCES, ttl));
+    assert_eq!(Some(Ttl::MAX), negative_entry_ttl(libc::ENOENT, Duration::MAX));
+}
+
+#[test]
+fn test_retry_eintr() {
+    let mut calls = 0;
+    assert_eq!(Ok(3), retry_eintr(5, || {
+        calls += 1;
+        if calls < 3 { Err(libc::EINTR) } else { Ok(calls) }
+    }));
+
+    let mut calls = 0;
+    assert_eq!(Err(libc::EINTR), retry_eintr(2, || {
+        calls += 1;
+        Err::<(), _>(libc::EINTR)
+    }));
+    assert_eq!(3, calls);
+
+    // Other errors aren't retried.
+    let mut calls = 0;
+    assert_eq!(Err(libc::EIO), retry_eintr(2, || {
+        calls += 1;
+        Err::<(), _>(libc::EIO)
+    }));
+    assert_eq!(1, calls);
+}
+
+#[test]
+fn test_on_forget() {
+    use std::sync::Mutex;
+
+    struct ForgetFS {
+        forgotten: Mutex<Vec<PathBuf>>,
+    }
+
+    impl FilesystemMT<'_> for ForgetFS {
+        fn on_forget(&self, path: &Path) {
+            self.forgotten.lock().unwrap().push(path.to_owned());
+        }
+    }
+
+    let mut fs = FuseMT::new(ForgetFS { forgotten: Mutex::new(vec![]) }, 0);
+
+    // Looked up twice, as by two lookups from the kernel.
+    let (ino, _) = fs.inodes.add_or_get(Arc::new(PathBuf::from("/a")));
+    fs.inodes.lookup(ino);
+    fs.inodes.lookup(ino);
+
+    fs.forget_inode(ino, 1);
+    assert!(fs.target.forgotten.lock().unwrap().is_empty());
+    fs.forget_inode(ino, 1);
+    assert_eq!(vec![PathBuf::from("/a")], *fs.target.forgotten.lock().unwrap());
+}
+
+#[test]
+fn test_open_truncating() {
+    use std::sync::Mutex;
+
+    struct FileFS {
+        data: Mutex<Vec<u8>>,
+        open_flags: Mutex<Vec<u32>>,
+    }
+
+    impl FilesystemMT<'_> for FileFS {
+        fn open(&self, _req: RequestInfo, _path: &Path, flags: u32) -> ResultOpen {
+            self.open_flags.lock().unwrap().push(flags);
+            Ok((1, 0))
+        }
+
+        fn truncate(&self, _req: RequestInfo, _path: &Path, fh: Option<u64>, size: u64) -> ResultEmpty {
+            assert_eq!(Some(1), fh);
+            self.data.lock().unwrap().truncate(size as usize);
+            Ok(())
+        }
+    }
+
+    let fs = FileFS { data: Mutex::new(b"contents".to_vec()), open_flags: Mutex::new(vec![]) };
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    let path = Path::new("/file");
+
+    open_truncating(&fs, req, path, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(b"contents", &fs.data.lock().unwrap()[..]);
+
+    // The open itself never sees O_TRUNC; the file is truncated separately.
+    let flags = (libc::O_WRONLY | libc::O_TRUNC) as u32;
+    assert_eq!((1, 0), open_truncating(&fs, req, path, flags).unwrap());
+    assert!(fs.data.lock().unwrap().is_empty());
+    assert_eq!(vec![libc::O_RDONLY as u32, libc::O_WRONLY as u32], *fs.open_flags.lock().unwrap());
+}
+
+#[test]
+fn test_read_errors() {
+    struct FailingFS;
+
+    impl FilesystemMT<'_> for FailingFS {
+        fn read(&self, _req: RequestInfo, _path: &Path, fh: u64, _offset: u64, _size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
+            // The file handle is the error to fail with.
+            callback(Err(fh as libc::c_int))
+        }
+    }
+
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    for errno in [libc::EIO, libc::ENOENT, libc::ESTALE, libc::EAGAIN, libc::ENODATA, libc::EINTR] {
+        let mut replied = None;
+        read_retrying(&FailingFS, req, Path::new("/file"), errno as u64, 0, 4096, 1, |result| {
+            replied = Some(result.map(<[u8]>::to_vec));
+        });
+        assert_eq!(Some(Err(errno)), replied);
+    }
+}
+
+#[test]
+fn test_read_eof() {
+    struct ShortFS;
+
+    impl FilesystemMT<'_> for ShortFS {
+        fn read(&self, _req: RequestInfo, _path: &Path, _fh: u64, offset: u64, size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
+            // An 8-byte file, with a buffer which is always full.
+            let buffer = [b'x'; 16];
+            let len = std::cmp::min(8u64.saturating_sub(offset), u64::from(size)) as usize;
+            callback(Ok(&buffer[..len]))
+        }
+    }
+
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    let read_at = |offset, size| {
+        let mut replied = None;
+        read_retrying(&ShortFS, req, Path::new("/file"), 0, offset, size, 0, |result| {
+            replied = Some(result.map(<[u8]>::to_vec));
+        });
+        replied.unwrap()
+    };
+    assert_eq!(Ok(vec![b'x'; 8]), read_at(0, 4096));
+    assert_eq!(Ok(vec![b'x'; 3]), read_at(5, 4096));
+    // At and past the end of the file, the reader gets zero bytes, not an error.
+    assert_eq!(Ok(vec![]), read_at(8, 4096));
+    assert_eq!(Ok(vec![]), read_at(100, 4096));
+
+    struct OverlongFS;
+
+    impl FilesystemMT<'_> for OverlongFS {
+        fn read(&self, _req: RequestInfo, _path: &Path, _fh: u64, _offset: u64, _size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
+            callback(Ok(&[b'x'; 16]))
+        }
+    }
+
+    let mut replied = None;
+    read_retrying(&OverlongFS, req, Path::new("/file"), 0, 0, 4, 0, |result| {
+        replied = Some(result.map(<[u8]>::to_vec));
+    });
+    assert_eq!(Some(Ok(vec![b'x'; 4])), replied);
+}
+
+#[test]
+fn test_name_too_long() {
+    let at_limit = "a".repeat(200);
+    let over_limit = "a".repeat(201);
+    assert!(!name_too_long(OsStr::new(&at_limit), Some(200)));
+    assert!(name_too_long(OsStr::new(&over_limit), Some(200)));
+    assert!(!name_too_long(OsStr::new(&over_limit), None));
+}
+
+#[test]
+fn test_capabilities_xattr() {
+    let caps = OpSet::GETATTR | OpSet::READ | OpSet::READDIR;
+    let value = b"getattr,read,readdir".to_vec();
+    assert!(matches!(get_capabilities_xattr(caps, 0), Ok(Xattr::Size(20))));
+    assert!(matches!(get_capabilities_xattr(caps, 100), Ok(Xattr::Data(ref data)) if *data == value));
+    assert!(matches!(get_capabilities_xattr(caps, 10), Err(libc::ERANGE)));
+
+    // The name is added to the filesystem's own list, or makes up the whole list if it has none.
+    let name = b"user.fuse_mt.capabilities\0";
+    let mut list = b"user.a\0".to_vec();
+    list.extend_from_slice(name);
+    assert!(matches!(list_capabilities_xattr(Ok(Xattr::Size(7)), 0), Ok(Xattr::Size(33))));
+    assert!(matches!(list_capabilities_xattr(Ok(Xattr::Data(b"user.a\0".to_vec())), 100),
+        Ok(Xattr::Data(ref data)) if *data == list));
+    assert!(matches!(list_capabilities_xattr(Err(libc::ENOSYS), 100),
+        Ok(Xattr::Data(ref data)) if data == name));
+    assert!(matches!(list_capabilities_xattr(Err(libc::EIO), 100), Err(libc::EIO)));
+}
+
+#[test]
+fn test_check_entry_names() {
+    use std::ffi::OsString;
+
+    let entry = |name: &str| DirectoryEntry { name: OsString::from(name), kind: FileType::RegularFile };
+    let mut entries = vec![entry("."), entry("short"), entry("much_too_long"), entry("eight_ch")];
+    check_entry_names(&"/", &mut entries, None);
+    assert_eq!(4, entries.len());
+    check_entry_names(&"/", &mut entries, Some(8));
+    let names: Vec<_> = entries.iter().map(|entry| entry.name.to_str().unwrap()).collect();
+    assert_eq!(vec![".", "short", "eight_ch"], names);
+}
+
+#[test]
+fn test_check_root_getattr() {
+    let mut warned = false;
+    // Other errors, like a root which isn't there yet, are left for the filesystem to deal with.
+    check_root_getattr(fuser::FUSE_ROOT_ID, &Err::<(), _>(libc::EIO), &mut warned);
+    assert!(!warned);
+    // Only the root counts.
+    check_root_getattr(2, &Err::<(), _>(libc::ENOSYS), &mut warned);
+    assert!(!warned);
+    check_root_getattr(fuser::FUSE_ROOT_ID, &Err::<(), _>(libc::ENOSYS), &mut warned);
+    assert!(warned);
+}
+
+#[test]
+fn test_write_data() {
+    use std::sync::Mutex;
+
+    // Records which method the data arrived through.
+    struct RecordingFS {
+        by_ref: bool,
+        calls: Mutex<Vec<(&'static str, Vec<u8>)>>,
+    }
+
+    impl FilesystemMT<'_> for RecordingFS {
+        fn write(&self, _req: RequestInfo, _path: &Path, _fh: u64, _offset: u64, data: Vec<u8>, _flags: u32) -> ResultWrite {
+            self.calls.lock().unwrap().push(("write", data.clone()));
+            Ok(data.len() as u32)
+        }
+
+        fn write_buf(&self, _req: RequestInfo, _path: &Path, _fh: u64, _offset: u64, data: &[u8], _flags: u32) -> ResultWrite {
+            if !self.by_ref {
+                return Err(libc::ENOSYS);
+            }
+            self.calls.lock().unwrap().push(("write_buf", data.to_vec()));
+            Ok(data.len() as u32)
+        }
+    }
+
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    for (by_ref, method) in [(true, "write_buf"), (false, "write")] {
+        let fs = RecordingFS { by_ref, calls: Mutex::new(vec![]) };
+        let data = b"hello".to_vec();
+        assert_eq!(Ok(5), write_data(&fs, req, Path::new("/file"), 0, 0, Cow::Borrowed(&data), 0));
+        assert_eq!(Ok(5), write_data(&fs, req, Path::new("/file"), 0, 0, Cow::Owned(data.clone()), 0));
+        assert_eq!(vec![(method, data.clone()), (method, data)], *fs.calls.lock().unwrap());
+    }
+}
+
+#[test]
+fn test_catch_panic() {
+    assert_eq!(Ok(1), call(PanicMode::ReturnEio, || Ok(1)));
+    assert_eq!(Err(libc::ENOENT), call(PanicMode::ReturnEio, || Err::<(), _>(libc::ENOENT)));
+    assert_eq!(Err(libc::EIO), call(PanicMode::ReturnEio, || -> ResultEmpty { panic!("oops") }));
+    let message = String::from("formatted");
+    assert_eq!(None, catch_panic(PanicMode::ReturnEio, || panic!("{}", message)));
+}
+
+#[test]
+fn test_destroy_error() {
+    struct FlushingFS;
+
+    impl FilesystemMT<'_> for FlushingFS {
+        fn try_destroy(&self) -> ResultEmpty {
+            // Writing out the buffered data failed.
+            Err(libc::EIO)
+        }
+    }
+
+    let mut fs = FuseMT::new(FlushingFS, 0);
+    let handle = fs.destroy_handle();
+    assert_eq!(None, handle.result());
+    fuser::Filesystem::destroy(&mut fs);
+    assert_eq!(Some(Err(libc::EIO)), handle.result());
+}
+
+#[cfg(feature = "readdirplus")]
+#[test]
+fn test_readdir_offset_entries() {
+    // A directory of ten files, listed only through readdir_offset.
+    struct OffsetFS;
+
+    impl FilesystemMT<'_> for OffsetFS {
+        fn readdir_offset(&self, _req: RequestInfo, _path: &Path, _fh: u64, offset: u64, filler: &mut DirFiller<'_>)
+            -> ResultEmpty
+        {
+            for index in offset..10 {
+                if !filler.add(0, index + 1, FileType::RegularFile, format!("file{}", index)) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    let entries = readdir_offset_entries(&OffsetFS, req, Path::new("/"), 0, 8, None).unwrap();
+    let listed: Vec<(i64, String)> = entries.into_iter()
+        .map(|(offset, entry)| (offset, entry.name.into_string().unwrap()))
+        .collect();
+    assert_eq!(vec![(9, "file8".to_owned()), (10, "file9".to_owned())], listed);
+
+    // Without readdir_offset, readdirplus falls back to readdir.
+    struct PlainFS;
+    impl FilesystemMT<'_> for PlainFS {}
+    assert_eq!(Err(libc::ENOSYS), readdir_offset_entries(&PlainFS, req, Path::new("/"), 0, 0, None).map(|_| ()));
+}
+
+#[test]
+fn test_rename_with_flags() {
+    use std::sync::Mutex;
+
+    // Records which method each rename ends up in.
+    struct RenameFS {
+        calls: Mutex<Vec<&'static str>>,
+    }
+
+    impl FilesystemMT<'_> for RenameFS {
+        fn rename(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _newparent: &Path, _newname: &OsStr) -> ResultEmpty {
+            self.calls.lock().unwrap().push("rename");
+            Ok(())
+        }
+
+        fn exchange(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _newparent: &Path, _newname: &OsStr) -> ResultEmpty {
+            self.calls.lock().unwrap().push("exchange");
+            Ok(())
+        }
+    }
+
+    let fs = RenameFS { calls: Mutex::new(vec![]) };
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    let rename = |capabilities, flags| {
+        rename_with_flags(&fs, capabilities, req, Path::new("/"), OsStr::new("a"), Path::new("/"), OsStr::new("b"), flags)
+    };
+
+    assert_eq!(Ok(()), rename(OpSet::ALL, 0));
+    assert_eq!(Ok(()), rename(OpSet::ALL, RENAME_EXCHANGE));
+    // Flags the filesystem doesn't handle in rename_flags are refused by the default.
+    assert_eq!(Err(libc::EINVAL), rename(OpSet::ALL, RENAME_NOREPLACE));
+    assert_eq!(Err(libc::EINVAL), rename(OpSet::ALL, RENAME_EXCHANGE | RENAME_NOREPLACE));
+    assert_eq!(vec!["rename", "exchange"], *fs.calls.lock().unwrap());
+
+    // Without the capabilities, the filesystem isn't called at all.
+    assert_eq!(Err(libc::ENOSYS), rename(OpSet::ALL.without(OpSet::RENAME), 0));
+    assert_eq!(Err(libc::EINVAL), rename(OpSet::ALL.without(OpSet::EXCHANGE), RENAME_EXCHANGE));
+    assert_eq!(2, fs.calls.lock().unwrap().len());
+}
+
+#[test]
+fn test_setattr_calls() {
+    use std::sync::Mutex;
+
+    // Implements the separate calls only, recording them.
+    struct SeparateFS {
+        calls: Mutex<Vec<&'static str>>,
+    }
+
+    impl FilesystemMT<'_> for SeparateFS {
+        fn getattr(&self, _req: RequestInfo, _path: &Path, _fh: Option<u64>) -> ResultEntry {
+            self.calls.lock().unwrap().push("getattr");
+            Ok((Duration::ZERO, FileAttr::default()))
+        }
+
+        fn chmod(&self, _req: RequestInfo, _path: &Path, _fh: Option<u64>, _mode: u32) -> ResultEmpty {
+            self.calls.lock().unwrap().push("chmod");
+            Ok(())
+        }
+
+        fn truncate(&self, _req: RequestInfo, _path: &Path, _fh: Option<u64>, size: u64) -> ResultEmpty {
+            self.calls.lock().unwrap().push("truncate");
+            if size > 100 { Err(libc::EFBIG) } else { Ok(()) }
+        }
+    }
+
+    let fs = SeparateFS { calls: Mutex::new(vec![]) };
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    let attrs = SetAttrRequest { mode: Some(0o600), size: Some(0), ..SetAttrRequest::default() };
+    setattr_calls(&fs, OpSet::ALL, req, Path::new("/file"), attrs).unwrap();
+    assert_eq!(vec!["chmod", "truncate", "getattr"], *fs.calls.lock().unwrap());
+
+    // The first call to fail is the result, and nothing after it is called.
+    fs.calls.lock().unwrap().clear();
+    let attrs = SetAttrRequest { size: Some(1000), mtime: Some(TimeOrNow::Now), ..SetAttrRequest::default() };
+    assert_eq!(Err(libc::EFBIG), setattr_calls(&fs, OpSet::ALL, req, Path::new("/file"), attrs).map(|_| ()));
+    assert_eq!(vec!["truncate"], *fs.calls.lock().unwrap());
+}
@@ -0,0 +1,504 @@
+// Helpers :: utilities built on top of a filesystem's own operations.
+//
+
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use crate::types::*;
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Atomically replace the entire contents of the file at `path` with `data`, using the
+/// filesystem's own operations.
+///
+/// The data is written to a new temporary file in the same directory, which is then renamed over
+/// `path`. As long as the filesystem's `rename` is atomic, anyone opening `path` sees either the
+/// old contents or the new contents in full, never a mix or a partially-written file. Anyone who
+/// already has the old file open keeps reading the old contents, to the extent the filesystem
+/// keeps unlinked files readable while they are open.
+///
+/// The new file gets the permissions of the file it replaces, or `0o644` if there wasn't one.
+/// The data is `fsync`ed before the rename if the filesystem implements it.
+///
+/// If anything fails, the temporary file is removed and the error is returned; `path` is left
+/// unchanged.
+pub fn write_atomic<FS>(fs: &FS, req: RequestInfo, path: &Path, data: &[u8]) -> ResultEmpty
+    where FS: for<'a> FilesystemMT<'a>
+{
+    let parent = path.parent().ok_or(libc::EINVAL)?;
+    let name = path.file_name().ok_or(libc::EINVAL)?;
+
+    let mode = match fs.getattr(req, path, None) {
+        Ok((_, attr)) => u32::from(attr.perm),
+        Err(libc::ENOENT) => 0o644,
+        Err(e) => return Err(e),
+    };
+
+    let mut temp_name = OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(".{}.tmp", TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let temp_path = parent.join(&temp_name);
+
+    let mode = crate::FileType::RegularFile.to_mode_bits() | mode;
+    let flags = (libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL) as u32;
+    let created = fs.create(req, parent, &temp_name, mode, 0, flags)?;
+
+    let result = write_all(fs, req, &temp_path, created.fh, data);
+    let release_result = match fs.release(req, &temp_path, created.fh, flags, 0, true) {
+        Err(libc::ENOSYS) => Ok(()),
+        other => other,
+    };
+    let result = result
+        .and(release_result)
+        .and_then(|()| fs.rename(req, parent, &temp_name, parent, name));
+
+    if result.is_err() {
+        if let Err(e) = fs.unlink(req, parent, &temp_name) {
+            warn!("write_atomic: failed to remove temporary file {:?}: {}", temp_path, e);
+        }
+    }
+    result
+}
+
+fn write_all<FS>(fs: &FS, req: RequestInfo, path: &Path, fh: u64, data: &[u8]) -> ResultEmpty
+    where FS: for<'a> FilesystemMT<'a>
+{
+    let mut offset = 0;
+    while offset < data.len() {
+        let written = fs.write(req, path, fh, offset as u64, data[offset..].to_vec(), 0)?;
+        if written == 0 {
+            return Err(libc::EIO);
+        }
+        offset += written as usize;
+    }
+
+    match fs.fsync(req, path, fh, false) {
+        Ok(()) | Err(libc::ENOSYS) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Update the attributes of a file which has gained a hard link, for filesystems which keep
+/// attributes themselves (such as in-memory ones): the link count goes up by one, and the change
+/// time is set to now.
+///
+/// Call this from `link` on the attributes shared by all the file's names, and return the result.
+pub fn add_link(attr: &mut FileAttr) {
+    attr.nlink = attr.nlink.saturating_add(1);
+    attr.ctime = SystemTime::now();
+}
+
+/// Update the attributes of a file which has lost a hard link: the link count goes down by one,
+/// and the change time is set to now.
+///
+/// Call this from `unlink` on the attributes shared by all the file's names. Returns whether the
+/// file still has any links; if not, it can be deleted once it is no longer open.
+pub fn remove_link(attr: &mut FileAttr) -> bool {
+    attr.nlink = attr.nlink.saturating_sub(1);
+    attr.ctime = SystemTime::now();
+    attr.nlink > 0
+}
+
+/// Get the part of `data` which a `read` at `offset` of up to `size` bytes should return, for
+/// files whose contents are in memory.
+///
+/// Reads past the end of a file are normal (the kernel reads until it gets a short or empty
+/// result), so this returns only what there is, and an empty slice for an offset at or past the
+/// end, rather than panicking like slicing `data[offset..offset + size]` would.
+///
+/// ```
+/// # use fuse_mt::helpers::read_from_slice;
+/// let data = b"hello";
+/// assert_eq!(b"ell", read_from_slice(data, 1, 3));
+/// assert_eq!(b"lo", read_from_slice(data, 3, 4096));
+/// assert!(read_from_slice(data, 10, 4096).is_empty());
+/// ```
+pub fn read_from_slice(data: &[u8], offset: u64, size: u32) -> &[u8] {
+    let start = usize::try_from(offset).unwrap_or(usize::MAX).min(data.len());
+    let end = start.saturating_add(size as usize).min(data.len());
+    &data[start .. end]
+}
+
+/// Get the errno to return for an I/O error: its raw OS error if it has one, and otherwise one
+/// picked from its kind with `errno_from_error_kind`.
+///
+/// This is for filesystems built on APIs which don't necessarily come from a system call, such as
+/// a network client which returns `ErrorKind::NotFound` for a missing object.
+pub fn errno_from_io_error(e: &io::Error) -> libc::c_int {
+    e.raw_os_error().unwrap_or_else(|| errno_from_error_kind(e.kind()))
+}
+
+/// Get the errno corresponding to an I/O error kind. Kinds with no particular counterpart,
+/// including `ErrorKind::Other`, give `EIO`.
+pub fn errno_from_error_kind(kind: io::ErrorKind) -> libc::c_int {
+    use io::ErrorKind::*;
+    match kind {
+        NotFound => libc::ENOENT,
+        PermissionDenied => libc::EACCES,
+        ConnectionRefused => libc::ECONNREFUSED,
+        ConnectionReset => libc::ECONNRESET,
+        HostUnreachable => libc::EHOSTUNREACH,
+        NetworkUnreachable => libc::ENETUNREACH,
+        ConnectionAborted => libc::ECONNABORTED,
+        NotConnected => libc::ENOTCONN,
+        AddrInUse => libc::EADDRINUSE,
+        AddrNotAvailable => libc::EADDRNOTAVAIL,
+        NetworkDown => libc::ENETDOWN,
+        BrokenPipe => libc::EPIPE,
+        AlreadyExists => libc::EEXIST,
+        WouldBlock => libc::EAGAIN,
+        NotADirectory => libc::ENOTDIR,
+        IsADirectory => libc::EISDIR,
+        DirectoryNotEmpty => libc::ENOTEMPTY,
+        ReadOnlyFilesystem => libc::EROFS,
+        StaleNetworkFileHandle => libc::ESTALE,
+        InvalidInput => libc::EINVAL,
+        TimedOut => libc::ETIMEDOUT,
+        StorageFull => libc::ENOSPC,
+        NotSeekable => libc::ESPIPE,
+        QuotaExceeded => libc::EDQUOT,
+        FileTooLarge => libc::EFBIG,
+        ResourceBusy => libc::EBUSY,
+        ExecutableFileBusy => libc::ETXTBSY,
+        Deadlock => libc::EDEADLK,
+        CrossesDevices => libc::EXDEV,
+        TooManyLinks => libc::EMLINK,
+        InvalidFilename => libc::ENAMETOOLONG,
+        ArgumentListTooLong => libc::E2BIG,
+        Interrupted => libc::EINTR,
+        Unsupported => libc::ENOTSUP,
+        OutOfMemory => libc::ENOMEM,
+        _ => libc::EIO,
+    }
+}
+
+/// The largest amount of data one `CasWrite` can carry.
+pub const CAS_WRITE_MAX: usize = 4096;
+
+/// The ioctl command for a compare-and-swap write, as encoded by `_IOWR('M', 1, CasWrite)`: a
+/// write which only happens if the file is still at the version the caller expects. This isn't a
+/// FUSE operation, so the filesystem answers it from `FilesystemMT::ioctl` with `cas_write`; see
+/// there.
+pub const CAS_WRITE_IOCTL: u32 = IOC_READ_WRITE
+    | ((std::mem::size_of::<CasWrite>() as u32) << 16)
+    | ((b'M' as u32) << 8)
+    | 1;
+
+/// The direction bits of an `_IOWR` ioctl number. Most platforms, macOS included, use a two-bit
+/// field at bit 30, but Linux on these architectures uses a three-bit one at bit 29.
+#[cfg(all(target_os = "linux", any(target_arch = "powerpc", target_arch = "powerpc64",
+    target_arch = "mips", target_arch = "mips64", target_arch = "sparc", target_arch = "sparc64")))]
+const IOC_READ_WRITE: u32 = 6 << 29;
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "powerpc", target_arch = "powerpc64",
+    target_arch = "mips", target_arch = "mips64", target_arch = "sparc", target_arch = "sparc64"))))]
+const IOC_READ_WRITE: u32 = 3 << 30;
+
+/// The argument of the `CAS_WRITE_IOCTL` ioctl, which a process fills in and passes by pointer
+/// to `ioctl(fd, CAS_WRITE_IOCTL, &mut cas)`. If the file's version is `version`, `len` bytes of
+/// `data` are written at `offset`, and `version` is set to the file's new version. Otherwise
+/// nothing is written, and the ioctl fails with `EAGAIN`.
+///
+/// What a version is, is up to the filesystem: a counter bumped on every change is typical.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CasWrite {
+    /// In: the version the file must be at. Out: its version after the write.
+    pub version: u64,
+    /// Where in the file to write.
+    pub offset: u64,
+    /// How much of `data` to write.
+    pub len: u32,
+    /// Unused; must be zero, or the ioctl fails with `EINVAL`.
+    pub reserved: u32,
+    /// The data to write, in the first `len` bytes.
+    pub data: [u8; CAS_WRITE_MAX],
+}
+
+impl CasWrite {
+    /// Make the argument for a write of `data` at `offset`, if the file is at `version`. Returns
+    /// `None` if there's more than `CAS_WRITE_MAX` bytes of data.
+    pub fn new(version: u64, offset: u64, data: &[u8]) -> Option<CasWrite> {
+        if data.len() > CAS_WRITE_MAX {
+            return None;
+        }
+        let mut cas = CasWrite { version, offset, len: data.len() as u32, reserved: 0, data: [0; CAS_WRITE_MAX] };
+        cas.data[.. data.len()].copy_from_slice(data);
+        Some(cas)
+    }
+}
+
+impl std::fmt::Debug for CasWrite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CasWrite")
+            .field("version", &self.version)
+            .field("offset", &self.offset)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+/// Answer a `CAS_WRITE_IOCTL` ioctl, given the `in_data` passed to `FilesystemMT::ioctl`.
+///
+/// `write` is called with the version the caller expects, the offset, and the data. It must
+/// compare the file's version and write the data as one atomic step (say, with a lock held), and
+/// return the new version, or `EAGAIN` if the version didn't match. Malformed arguments fail with
+/// `EINVAL` without calling it.
+///
+/// ```
+/// # use fuse_mt::helpers::*;
+/// # use fuse_mt::*;
+/// # use std::sync::Mutex;
+/// struct Counter { version: Mutex<u64> }
+///
+/// impl Counter {
+///     fn ioctl(&self, cmd: u32, in_data: &[u8]) -> ResultIoctl {
+///         match cmd {
+///             CAS_WRITE_IOCTL => cas_write(in_data, |expected, _offset, _data| {
+///                 let mut version = self.version.lock().unwrap();
+///                 if *version != expected {
+///                     return Err(libc::EAGAIN);
+///                 }
+///                 // ... write the data ...
+///                 *version += 1;
+///                 Ok(*version)
+///             }),
+///             _ => Err(libc::ENOTTY),
+///         }
+///     }
+/// }
+/// ```
+pub fn cas_write(in_data: &[u8], write: impl FnOnce(u64, u64, &[u8]) -> Result<u64, libc::c_int>) -> ResultIoctl {
+    if in_data.len() != std::mem::size_of::<CasWrite>() {
+        return Err(libc::EINVAL);
+    }
+    let u64_at = |at: usize| u64::from_ne_bytes(in_data[at .. at + 8].try_into().unwrap());
+    let version = u64_at(0);
+    let offset = u64_at(8);
+    let u32_at = |at: usize| u32::from_ne_bytes(in_data[at .. at + 4].try_into().unwrap());
+    let len = u32_at(16) as usize;
+    // Rejecting anything in `reserved` leaves it free for later use.
+    if len > CAS_WRITE_MAX || u32_at(20) != 0 {
+        return Err(libc::EINVAL);
+    }
+    let data = &in_data[24 .. 24 + len];
+
+    let new_version = write(version, offset, data)?;
+    // The argument goes back out as it came in, but with the new version.
+    let mut out = in_data.to_vec();
+    out[.. 8].copy_from_slice(&new_version.to_ne_bytes());
+    Ok((0, out))
+}
+
+#[test]
+fn test_write_atomic() {
+    use std::collections::HashMap;
+    use std::ffi::OsStr;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    // Just enough of a filesystem to create, write and rename files.
+    struct MemFS {
+        files: Mutex<HashMap<PathBuf, Vec<u8>>>,
+    }
+
+    impl FilesystemMT<'_> for MemFS {
+        fn getattr(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
+            let files = self.files.lock().unwrap();
+            let data = files.get(path).ok_or(libc::ENOENT)?;
+            Ok((Duration::from_secs(1), FileAttr { size: data.len() as u64, perm: 0o600, ..Default::default() }))
+        }
+
+        fn create(&self, req: RequestInfo, parent: &Path, name: &OsStr, _mode: u32, _umask: u32, _flags: u32) -> ResultCreate {
+            let path = parent.join(name);
+            self.files.lock().unwrap().insert(path.clone(), vec![]);
+            let (ttl, attr) = self.getattr(req, &path, None)?;
+            Ok(CreatedEntry::new(attr, 1).ttl(ttl))
+        }
+
+        fn write(&self, _req: RequestInfo, path: &Path, _fh: u64, offset: u64, data: Vec<u8>, _flags: u32) -> ResultWrite {
+            let mut files = self.files.lock().unwrap();
+            let file = files.get_mut(path).ok_or(libc::ENOENT)?;
+            // Write at most 4 bytes at a time, to exercise short writes.
+            let len = std::cmp::min(data.len(), 4);
+            file.truncate(offset as usize);
+            file.extend_from_slice(&data[..len]);
+            Ok(len as u32)
+        }
+
+        fn release(&self, _req: RequestInfo, _path: &Path, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
+            Ok(())
+        }
+
+        fn rename(&self, _req: RequestInfo, parent: &Path, name: &OsStr, newparent: &Path, newname: &OsStr) -> ResultEmpty {
+            let mut files = self.files.lock().unwrap();
+            let data = files.remove(&parent.join(name)).ok_or(libc::ENOENT)?;
+            files.insert(newparent.join(newname), data);
+            Ok(())
+        }
+    }
+
+    let fs = MemFS { files: Mutex::new(HashMap::new()) };
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    let path = Path::new("/config");
+
+    write_atomic(&fs, req, path, b"first version").unwrap();
+    write_atomic(&fs, req, path, b"second version").unwrap();
+
+    // Only the target file remains, with the complete new contents.
+    let files = fs.files.lock().unwrap();
+    assert_eq!(1, files.len());
+    assert_eq!(b"second version", &files[path][..]);
+}
+
+#[test]
+fn test_hard_links() {
+    use std::collections::HashMap;
+    use std::ffi::OsStr;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    // Names map to file IDs, and the attributes are per file, shared by all its names.
+    struct LinkFS {
+        names: Mutex<HashMap<PathBuf, u64>>,
+        files: Mutex<HashMap<u64, FileAttr>>,
+    }
+
+    impl FilesystemMT<'_> for LinkFS {
+        fn getattr(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
+            let id = *self.names.lock().unwrap().get(path).ok_or(libc::ENOENT)?;
+            Ok((Duration::from_secs(1), self.files.lock().unwrap()[&id]))
+        }
+
+        fn link(&self, _req: RequestInfo, path: &Path, newparent: &Path, newname: &OsStr) -> ResultEntry {
+            let mut names = self.names.lock().unwrap();
+            let id = *names.get(path).ok_or(libc::ENOENT)?;
+            names.insert(newparent.join(newname), id);
+            let mut files = self.files.lock().unwrap();
+            let attr = files.get_mut(&id).unwrap();
+            add_link(attr);
+            Ok((Duration::from_secs(1), *attr))
+        }
+
+        fn unlink(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+            let id = self.names.lock().unwrap().remove(&parent.join(name)).ok_or(libc::ENOENT)?;
+            let mut files = self.files.lock().unwrap();
+            if !remove_link(files.get_mut(&id).unwrap()) {
+                files.remove(&id);
+            }
+            Ok(())
+        }
+    }
+
+    let attr = FileAttr { perm: 0o644, ..Default::default() };
+    let fs = LinkFS {
+        names: Mutex::new([(PathBuf::from("/a"), 1)].into_iter().collect()),
+        files: Mutex::new([(1, attr)].into_iter().collect()),
+    };
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    let root = Path::new("/");
+
+    assert_eq!(2, fs.link(req, Path::new("/a"), root, OsStr::new("b")).unwrap().1.nlink);
+    assert_eq!(3, fs.link(req, Path::new("/a"), root, OsStr::new("c")).unwrap().1.nlink);
+
+    fs.unlink(req, root, OsStr::new("a")).unwrap();
+    assert_eq!(Err(libc::ENOENT), fs.getattr(req, Path::new("/a"), None).map(|_| ()));
+    assert_eq!(2, fs.getattr(req, Path::new("/b"), None).unwrap().1.nlink);
+    assert_eq!(2, fs.getattr(req, Path::new("/c"), None).unwrap().1.nlink);
+
+    fs.unlink(req, root, OsStr::new("b")).unwrap();
+    fs.unlink(req, root, OsStr::new("c")).unwrap();
+    assert!(fs.files.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_errno_from_error_kind() {
+    use io::ErrorKind::*;
+    let expected = [
+        (NotFound, libc::ENOENT),
+        (PermissionDenied, libc::EACCES),
+        (ConnectionRefused, libc::ECONNREFUSED),
+        (ConnectionReset, libc::ECONNRESET),
+        (HostUnreachable, libc::EHOSTUNREACH),
+        (NetworkUnreachable, libc::ENETUNREACH),
+        (ConnectionAborted, libc::ECONNABORTED),
+        (NotConnected, libc::ENOTCONN),
+        (AddrInUse, libc::EADDRINUSE),
+        (AddrNotAvailable, libc::EADDRNOTAVAIL),
+        (NetworkDown, libc::ENETDOWN),
+        (BrokenPipe, libc::EPIPE),
+        (AlreadyExists, libc::EEXIST),
+        (WouldBlock, libc::EAGAIN),
+        (NotADirectory, libc::ENOTDIR),
+        (IsADirectory, libc::EISDIR),
+        (DirectoryNotEmpty, libc::ENOTEMPTY),
+        (ReadOnlyFilesystem, libc::EROFS),
+        (StaleNetworkFileHandle, libc::ESTALE),
+        (InvalidInput, libc::EINVAL),
+        (TimedOut, libc::ETIMEDOUT),
+        (StorageFull, libc::ENOSPC),
+        (NotSeekable, libc::ESPIPE),
+        (QuotaExceeded, libc::EDQUOT),
+        (FileTooLarge, libc::EFBIG),
+        (ResourceBusy, libc::EBUSY),
+        (ExecutableFileBusy, libc::ETXTBSY),
+        (Deadlock, libc::EDEADLK),
+        (CrossesDevices, libc::EXDEV),
+        (TooManyLinks, libc::EMLINK),
+        (InvalidFilename, libc::ENAMETOOLONG),
+        (ArgumentListTooLong, libc::E2BIG),
+        (Interrupted, libc::EINTR),
+        (Unsupported, libc::ENOTSUP),
+        (OutOfMemory, libc::ENOMEM),
+        (InvalidData, libc::EIO),
+        (UnexpectedEof, libc::EIO),
+        (Other, libc::EIO),
+    ];
+    for (kind, errno) in expected {
+        assert_eq!(errno, errno_from_error_kind(kind), "{:?}", kind);
+    }
+
+    // A raw OS error is used as is, even if the kind would map to something else.
+    let e = io::Error::from_raw_os_error(libc::ENAMETOOLONG);
+    assert_eq!(libc::ENAMETOOLONG, errno_from_io_error(&e));
+    let e = io::Error::new(NotFound, "no such object");
+    assert_eq!(libc::ENOENT, errno_from_io_error(&e));
+}
+
+#[test]
+fn test_cas_write() {
+    let mut version = 1;
+    let mut contents = b"leader: a".to_vec();
+    let mut call = |cas: &CasWrite| {
+        // Pass the argument as the kernel would: as bytes, sized from the command number.
+        assert_eq!(std::mem::size_of::<CasWrite>() as u32, (CAS_WRITE_IOCTL >> 16) & 0x1fff);
+        let bytes = unsafe {
+            std::slice::from_raw_parts(cas as *const CasWrite as *const u8, std::mem::size_of::<CasWrite>())
+        };
+        cas_write(bytes, |expected, offset, data| {
+            if expected != version {
+                return Err(libc::EAGAIN);
+            }
+            contents[offset as usize ..][.. data.len()].copy_from_slice(data);
+            version += 1;
+            Ok(version)
+        }).map(|(_, out)| u64::from_ne_bytes(out[.. 8].try_into().unwrap()))
+    };
+
+    assert_eq!(Ok(2), call(&CasWrite::new(1, 8, b"b").unwrap()));
+    // The version has moved on, so a second writer expecting the old one loses.
+    assert_eq!(Err(libc::EAGAIN), call(&CasWrite::new(1, 8, b"c").unwrap()));
+    // Anything in `reserved` is rejected.
+    let mut reserved = CasWrite::new(2, 8, b"c").unwrap();
+    reserved.reserved = 1;
+    assert_eq!(Err(libc::EINVAL), call(&reserved));
+    assert_eq!(b"leader: b", &contents[..]);
+
+    assert!(CasWrite::new(1, 0, &[0; CAS_WRITE_MAX + 1]).is_none());
+    assert_eq!(Err(libc::EINVAL), cas_write(&[0; 8], |_, _, _| unreachable!()));
+}
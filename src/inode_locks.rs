@@ -0,0 +1,73 @@
+// InodeLocks :: exclusive locks on individual inodes, for serializing writes.
+//
+
+use std::collections::HashSet;
+use std::sync::{Condvar, Mutex};
+
+/// A set of locks, one per inode, created on demand.
+///
+/// Only inodes which are currently locked take up any space.
+#[derive(Debug, Default)]
+pub struct InodeLocks {
+    locked: Mutex<HashSet<u64>>,
+    condvar: Condvar,
+}
+
+impl InodeLocks {
+    pub fn new() -> InodeLocks {
+        InodeLocks::default()
+    }
+
+    /// Lock the given inode, waiting until no one else has it locked. The lock is held until the
+    /// returned guard is dropped.
+    pub fn lock(&self, ino: u64) -> InodeLockGuard<'_> {
+        let mut locked = self.locked.lock().unwrap();
+        while locked.contains(&ino) {
+            locked = self.condvar.wait(locked).unwrap();
+        }
+        locked.insert(ino);
+        InodeLockGuard { locks: self, ino }
+    }
+}
+
+#[derive(Debug)]
+pub struct InodeLockGuard<'a> {
+    locks: &'a InodeLocks,
+    ino: u64,
+}
+
+impl Drop for InodeLockGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.locked.lock().unwrap().remove(&self.ino);
+        self.locks.condvar.notify_all();
+    }
+}
+
+#[test]
+fn test_inode_locks() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let locks = Arc::new(InodeLocks::new());
+    let busy = Arc::new(AtomicBool::new(false));
+
+    // Different inodes don't block each other.
+    let _other = locks.lock(2);
+
+    let threads: Vec<_> = (0..4).map(|_| {
+        let locks = locks.clone();
+        let busy = busy.clone();
+        std::thread::spawn(move || {
+            for _ in 0..100 {
+                let _guard = locks.lock(1);
+                assert!(!busy.swap(true, Ordering::SeqCst));
+                std::thread::yield_now();
+                busy.store(false, Ordering::SeqCst);
+            }
+        })
+    }).collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
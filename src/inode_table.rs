@@ -6,8 +6,8 @@
 use std::borrow::Borrow;
 use std::cmp::{Eq, PartialEq};
 use std::collections::{HashMap, VecDeque};
-use std::collections::hash_map::Entry::*;
-use std::hash::{Hash, Hasher};
+use std::collections::hash_map::{Entry::*, RandomState};
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -15,6 +15,39 @@ pub type Inode = u64;
 pub type Generation = u64;
 pub type LookupCount = u64;
 
+/// How generation numbers are assigned to inodes in the path-based API (`FuseMT`).
+///
+/// Inode numbers are re-used once the kernel forgets about them, and the generation number is what
+/// tells the different files that have had the same inode number apart. This matters mostly when
+/// the filesystem is exported over NFS: NFS file handles contain the inode and generation numbers,
+/// and a handle whose generation doesn't match any more is reported to the client as stale
+/// (`ESTALE`), rather than silently referring to whatever file has the inode number now.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GenerationStrategy {
+    /// Start at 0, and increase by one each time an inode number is re-used. This guarantees
+    /// that generations are unique within a mount, but a new mount starts over from 0, so file
+    /// handles from before a remount can match the wrong files.
+    #[default]
+    Incrementing,
+    /// Pick a random generation each time an inode number is assigned. Collisions are possible,
+    /// but very unlikely, even across remounts.
+    Random,
+    /// Always use the given generation. For filesystems which don't care about generations, or
+    /// which are never exported.
+    Fixed(Generation),
+}
+
+impl GenerationStrategy {
+    /// Get the generation for an inode number, given its previous generation if it had one.
+    fn next(self, previous: Option<Generation>) -> Generation {
+        match self {
+            GenerationStrategy::Incrementing => previous.map_or(0, |g| g.wrapping_add(1)),
+            GenerationStrategy::Random => RandomState::new().hash_one(previous),
+            GenerationStrategy::Fixed(generation) => generation,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct InodeTableEntry {
     path: Option<Arc<PathBuf>>,
@@ -28,6 +61,10 @@ pub struct InodeTable {
     table: Vec<InodeTableEntry>,
     free_list: VecDeque<usize>,
     by_path: HashMap<Arc<PathBuf>, usize>,
+    generation_strategy: GenerationStrategy,
+    /// With stable inode numbers, the entries of forgotten paths, which are kept for them instead
+    /// of going on the free list.
+    retired: Option<HashMap<Arc<PathBuf>, usize>>,
 }
 
 impl InodeTable {
@@ -42,7 +79,9 @@ impl InodeTable {
         let mut inode_table = InodeTable {
             table: Vec::new(),
             free_list: VecDeque::new(),
-            by_path: HashMap::new()
+            by_path: HashMap::new(),
+            generation_strategy: GenerationStrategy::default(),
+            retired: None,
         };
         let root = Arc::new(PathBuf::from("/"));
         inode_table.table.push(InodeTableEntry {
@@ -54,6 +93,24 @@ impl InodeTable {
         inode_table
     }
 
+    /// Set how generation numbers are assigned to inodes from now on. The root's generation is
+    /// reset according to the new strategy.
+    pub fn set_generation_strategy(&mut self, strategy: GenerationStrategy) {
+        self.generation_strategy = strategy;
+        self.table[0].generation = strategy.next(None);
+    }
+
+    /// Give each path the same inode number for as long as the table exists, and never re-use it
+    /// for a different path, even after the kernel forgets it. Inode numbers then depend only on
+    /// the order in which paths are first added, not on when the kernel happens to forget them.
+    /// Every path ever added is remembered, so this is meant for tests. The inodes of paths which
+    /// were unlinked or renamed over can't be given back to any path, so once they're forgotten,
+    /// their numbers are retired for good rather than re-used, which would make them depend on
+    /// when the forget arrives again.
+    pub fn set_stable_inodes(&mut self, stable: bool) {
+        self.retired = if stable { Some(HashMap::new()) } else { None };
+    }
+
     /// Add a path to the inode table.
     ///
     /// Returns the inode number the path is now mapped to.
@@ -65,7 +122,8 @@ impl InodeTable {
     /// This operation runs in O(log n) time.
     pub fn add(&mut self, path: Arc<PathBuf>) -> (Inode, Generation) {
         let (inode, generation) = {
-            let (inode, entry) = Self::get_inode_entry(&mut self.free_list, &mut self.table);
+            let retired = self.retired.as_mut().and_then(|retired| retired.remove(&path));
+            let (inode, entry) = Self::get_inode_entry(&mut self.free_list, &mut self.table, self.generation_strategy, retired);
             entry.path = Some(path.clone());
             entry.lookups = 1;
             (inode, entry.generation)
@@ -89,7 +147,8 @@ impl InodeTable {
     pub fn add_or_get(&mut self, path: Arc<PathBuf>) -> (Inode, Generation) {
         match self.by_path.entry(path.clone()) {
             Vacant(path_entry) => {
-                let (inode, entry) = Self::get_inode_entry(&mut self.free_list, &mut self.table);
+                let retired = self.retired.as_mut().and_then(|retired| retired.remove(&path));
+                let (inode, entry) = Self::get_inode_entry(&mut self.free_list, &mut self.table, self.generation_strategy, retired);
                 debug!("adding {} -> {:?} with 0 lookups", inode, path);
                 entry.path = Some(path);
                 path_entry.insert(inode as usize - 1);
@@ -154,6 +213,7 @@ impl InodeTable {
         }
 
         let mut delete = false;
+        let mut mapped = false;
         let lookups: LookupCount;
         let idx = inode as usize - 1;
 
@@ -165,13 +225,28 @@ impl InodeTable {
             lookups = entry.lookups;
             if lookups == 0 {
                 delete = true;
-                self.by_path.remove(entry.path.as_ref().unwrap());
+                // If the path was unlinked or renamed over, it may have been given to a different
+                // inode since; only remove the mapping if it's still ours.
+                let path = entry.path.as_ref().unwrap();
+                if self.by_path.get(path) == Some(&idx) {
+                    self.by_path.remove(path);
+                    mapped = true;
+                }
             }
         }
 
         if delete {
-            self.table[idx].path = None;
-            self.free_list.push_back(idx);
+            let path = self.table[idx].path.take().unwrap();
+            match self.retired {
+                // Keep the inode number for the path to get back if it's added again.
+                Some(ref mut retired) if mapped => {
+                    retired.insert(path, idx);
+                },
+                // If the path has a different inode now, nothing can get this one back, but
+                // re-using it would depend on when it was forgotten, so it's never used again.
+                Some(_) => (),
+                None => self.free_list.push_back(idx),
+            }
         }
 
         lookups
@@ -179,10 +254,58 @@ impl InodeTable {
 
     /// Change an inode's path to a different one, without changing the inode number.
     /// Lookup counts remain unchanged, even if this is replacing another file.
+    ///
+    /// If the inode is a directory, all the paths under it are changed to be under the new path
+    /// as well, so that inodes for files inside it still resolve to the right place.
+    ///
+    /// This operation runs in O(n) time.
     pub fn rename(&mut self, oldpath: &Path, newpath: Arc<PathBuf>) {
         let idx = self.by_path.remove(Pathish::new(oldpath)).unwrap();
         self.table[idx].path = Some(newpath.clone());
-        self.by_path.insert(newpath, idx); // this can replace a path with a new inode
+        self.by_path.insert(newpath.clone(), idx); // this can replace a path with a new inode
+
+        let descendants: Vec<(Arc<PathBuf>, usize)> = self.by_path.iter()
+            .filter(|&(path, &i)| i != idx && path.starts_with(oldpath))
+            .map(|(path, &i)| (path.clone(), i))
+            .collect();
+        for (path, i) in descendants {
+            let moved = Arc::new(newpath.join(path.strip_prefix(oldpath).unwrap()));
+            debug!("rename: moving {:?} -> {:?}", path, moved);
+            self.by_path.remove(&path);
+            self.table[i].path = Some(moved.clone());
+            self.by_path.insert(moved, i);
+        }
+    }
+
+    /// Swap the paths of two entries, and of everything under them, as when they're exchanged by
+    /// a rename with `RENAME_EXCHANGE`. Either path may be missing from the table.
+    pub fn exchange(&mut self, path1: &Path, path2: &Path) {
+        let swap = |path: &Path| -> Option<PathBuf> {
+            let (from, to) = if path.starts_with(path1) {
+                (path1, path2)
+            } else if path.starts_with(path2) {
+                (path2, path1)
+            } else {
+                return None;
+            };
+            match path.strip_prefix(from).unwrap() {
+                rest if rest.as_os_str().is_empty() => Some(to.to_owned()),
+                rest => Some(to.join(rest)),
+            }
+        };
+
+        let moved: Vec<(Arc<PathBuf>, usize, PathBuf)> = self.by_path.iter()
+            .filter_map(|(path, &i)| swap(path).map(|new| (path.clone(), i, new)))
+            .collect();
+        for (path, _, _) in &moved {
+            self.by_path.remove(path);
+        }
+        for (path, i, new) in moved {
+            debug!("exchange: moving {:?} -> {:?}", path, new);
+            let new = Arc::new(new);
+            self.table[i].path = Some(new.clone());
+            self.by_path.insert(new, i);
+        }
     }
 
     /// Remove the path->inode mapping for a given path, but keep the inode around.
@@ -194,22 +317,28 @@ impl InodeTable {
     /// Get a free indode table entry and its number, either by allocating a new one, or re-using
     /// one that had its lookup count previously go to zero.
     ///
-    /// 1st arg should be `&mut self.free_list`; 2nd arg should be `&mut self.table`.
+    /// 1st arg should be `&mut self.free_list`; 2nd arg should be `&mut self.table`; 3rd arg
+    /// should be `self.generation_strategy`; 4th arg is the path's retired entry, if it has one,
+    /// which is used as it is.
     /// This function's signature is like this instead of taking &mut self so that it can avoid
     /// mutably borrowing *all* fields of self when we only need those two.
-    fn get_inode_entry<'a>(free_list: &mut VecDeque<usize>, table: &'a mut Vec<InodeTableEntry>)
-            -> (Inode, &'a mut InodeTableEntry) {
+    fn get_inode_entry<'a>(free_list: &mut VecDeque<usize>, table: &'a mut Vec<InodeTableEntry>,
+            strategy: GenerationStrategy, retired: Option<usize>) -> (Inode, &'a mut InodeTableEntry) {
+        if let Some(idx) = retired {
+            debug!("giving inode {} back to its path", idx + 1);
+            return ((idx + 1) as Inode, &mut table[idx]);
+        }
         let idx = match free_list.pop_front() {
             Some(idx) => {
                 debug!("re-using inode {}", idx + 1);
-                table[idx].generation += 1;
+                table[idx].generation = strategy.next(Some(table[idx].generation));
                 idx
             },
             None => {
                 table.push(InodeTableEntry {
                     path: None,
                     lookups: 0,
-                    generation: 0,
+                    generation: strategy.next(None),
                 });
                 table.len() - 1
             }
@@ -322,6 +451,62 @@ fn test_inode_rename() {
     assert_eq!(*path2, *table.get_path(inode).unwrap());
 }
 
+#[test]
+fn test_rename_subtree() {
+    let mut table = InodeTable::new();
+    let dir = Arc::new(PathBuf::from("/foo"));
+    let file = Arc::new(PathBuf::from("/foo/bar/baz"));
+
+    // Add a directory and a file nested inside it.
+    let dir_inode = table.add(dir.clone()).0;
+    let subdir_inode = table.add(Arc::new(PathBuf::from("/foo/bar"))).0;
+    let file_inode = table.add(file.clone()).0;
+
+    // Rename the directory; verify that the file moved along with it, keeping its inode.
+    table.rename(&dir, Arc::new(PathBuf::from("/qux")));
+    assert_eq!(Path::new("/qux"), *table.get_path(dir_inode).unwrap());
+    assert_eq!(Path::new("/qux/bar"), *table.get_path(subdir_inode).unwrap());
+    assert_eq!(Path::new("/qux/bar/baz"), *table.get_path(file_inode).unwrap());
+    assert_eq!(file_inode, table.get_inode(Path::new("/qux/bar/baz")).unwrap());
+    assert!(table.get_inode(&file).is_none());
+
+    // A sibling which merely shares a name prefix is not affected.
+    let sibling_inode = table.add(Arc::new(PathBuf::from("/quxx"))).0;
+    table.rename(Path::new("/qux"), Arc::new(PathBuf::from("/a")));
+    assert_eq!(Path::new("/quxx"), *table.get_path(sibling_inode).unwrap());
+    assert_eq!(Path::new("/a/bar/baz"), *table.get_path(file_inode).unwrap());
+}
+
+#[test]
+fn test_exchange() {
+    let mut table = InodeTable::new();
+
+    // Two files which are open (so looked up), and a directory with a file in it.
+    let a = table.add(Arc::new(PathBuf::from("/a"))).0;
+    let b = table.add(Arc::new(PathBuf::from("/b"))).0;
+    let dir = table.add(Arc::new(PathBuf::from("/dir"))).0;
+    let dir_file = table.add(Arc::new(PathBuf::from("/dir/file"))).0;
+
+    // Exchange the files; each inode now has the other's path.
+    table.exchange(Path::new("/a"), Path::new("/b"));
+    assert_eq!(Path::new("/b"), *table.get_path(a).unwrap());
+    assert_eq!(Path::new("/a"), *table.get_path(b).unwrap());
+    assert_eq!(b, table.get_inode(Path::new("/a")).unwrap());
+    assert_eq!(a, table.get_inode(Path::new("/b")).unwrap());
+
+    // Exchange a file with a directory; the directory's contents move along with it.
+    table.exchange(Path::new("/dir"), Path::new("/a"));
+    assert_eq!(Path::new("/a"), *table.get_path(dir).unwrap());
+    assert_eq!(Path::new("/a/file"), *table.get_path(dir_file).unwrap());
+    assert_eq!(Path::new("/dir"), *table.get_path(b).unwrap());
+    assert!(table.get_inode(Path::new("/dir/file")).is_none());
+
+    // Exchanging with a path that isn't in the table just moves the one that is.
+    table.exchange(Path::new("/a"), Path::new("/c"));
+    assert_eq!(Path::new("/c/file"), *table.get_path(dir_file).unwrap());
+    assert!(table.get_inode(Path::new("/a")).is_none());
+}
+
 #[test]
 fn test_unlink() {
     let mut table = InodeTable::new();
@@ -341,3 +526,99 @@ fn test_unlink() {
     assert_eq!(0, table.forget(inode, 1));
     assert!(table.get_path(inode).is_none());
 }
+
+#[test]
+fn test_unlink_open() {
+    let mut table = InodeTable::new();
+    let path = Arc::new(PathBuf::from("/foo"));
+
+    // A file is opened, then unlinked while still open, and a new file is created in its place.
+    let old_inode = table.add(path.clone()).0;
+    table.unlink(&path);
+    let new_inode = table.add(path.clone()).0;
+    assert_ne!(old_inode, new_inode);
+
+    // The old file keeps its inode until the kernel forgets it, after it is closed, and then the
+    // path stays with the new file.
+    assert_eq!(*path, *table.get_path(old_inode).unwrap());
+    assert_eq!(0, table.forget(old_inode, 1));
+    assert!(table.get_path(old_inode).is_none());
+    assert_eq!(Some(new_inode), table.get_inode(&path));
+    assert_eq!(*path, *table.get_path(new_inode).unwrap());
+}
+
+#[test]
+fn test_generation_strategy() {
+    // Add a path, forget it, and add another, re-using the inode number.
+    fn reuse(table: &mut InodeTable) -> (Generation, Generation) {
+        let (inode1, generation1) = table.add(Arc::new(PathBuf::from("/a")));
+        table.forget(inode1, 1);
+        let (inode2, generation2) = table.add(Arc::new(PathBuf::from("/b")));
+        assert_eq!(inode1, inode2);
+        table.forget(inode2, 1);
+        (generation1, generation2)
+    }
+
+    let mut table = InodeTable::new();
+    assert_eq!((0, 1), reuse(&mut table));
+    assert_eq!((2, 3), reuse(&mut table));
+
+    table.set_generation_strategy(GenerationStrategy::Fixed(7));
+    assert_eq!((7, 7), reuse(&mut table));
+
+    table.set_generation_strategy(GenerationStrategy::Random);
+    let (generation1, generation2) = reuse(&mut table);
+    assert_ne!(generation1, generation2);
+}
+
+#[test]
+fn test_stable_inodes() {
+    // Add some paths, forgetting them after the given number of steps.
+    fn run(forget_after: usize) -> Vec<Inode> {
+        let mut table = InodeTable::new();
+        table.set_stable_inodes(true);
+        let mut inodes = vec![];
+        let mut live = VecDeque::new();
+        for name in ["a", "b", "c", "a", "d", "b", "e"] {
+            let (inode, _) = table.add_or_get(Arc::new(Path::new("/").join(name)));
+            table.lookup(inode);
+            inodes.push(inode);
+            live.push_back(inode);
+            if live.len() > forget_after {
+                table.forget(live.pop_front().unwrap(), 1);
+            }
+        }
+        inodes
+    }
+
+    // The same paths get the same numbers however soon they're forgotten.
+    let expected = vec![2, 3, 4, 2, 5, 3, 6];
+    assert_eq!(expected, run(0));
+    assert_eq!(expected, run(1));
+    assert_eq!(expected, run(100));
+}
+
+#[test]
+fn test_stable_inodes_unlinked() {
+    // /b is renamed over /a, the kernel may or may not have forgotten the old /a yet, and then /c
+    // is added.
+    let run = |forget_first: bool| {
+        let mut table = InodeTable::new();
+        table.set_stable_inodes(true);
+        let (a, _) = table.add_or_get(Arc::new(PathBuf::from("/a")));
+        let (b, _) = table.add_or_get(Arc::new(PathBuf::from("/b")));
+        table.lookup(a);
+        table.lookup(b);
+        table.rename(Path::new("/b"), Arc::new(PathBuf::from("/a")));
+        if forget_first {
+            assert_eq!(0, table.forget(a, 1));
+        }
+        assert_eq!(Some(b), table.get_inode(Path::new("/a")));
+        let (c, _) = table.add_or_get(Arc::new(PathBuf::from("/c")));
+        assert_ne!(a, c);
+        c
+    };
+
+    // The old /a's number is never re-used, so /c gets the same number either way.
+    assert_eq!(run(false), run(true));
+}
@@ -0,0 +1,59 @@
+// KernelConfig :: the settings for the connection with the kernel, negotiated at mount time.
+//
+
+/// The settings for the connection with the kernel, which can be changed in
+/// `FilesystemMT::init_with_config`, before any requests are handled.
+///
+/// Capabilities are bits from `fuse_mt::consts` (such as `FUSE_EXPORT_SUPPORT` or
+/// `FUSE_WRITEBACK_CACHE`), some of which only exist at the FUSE protocol versions enabled by this
+/// crate's features. `fuser` doesn't let the capabilities the kernel offered be read directly;
+/// `add_capabilities` fails for the ones it didn't offer, and leaves the rest added.
+///
+/// Capabilities change what the kernel sends, and it's up to the filesystem to be ready for that.
+/// For example, with `FUSE_WRITEBACK_CACHE`, the kernel reads from files which were opened
+/// write-only, to fill in partly written pages, and keeps its own idea of files' sizes and
+/// modification times while it has writes buffered.
+#[derive(Debug)]
+pub struct KernelConfig<'a> {
+    inner: &'a mut fuser::KernelConfig,
+}
+
+impl<'a> KernelConfig<'a> {
+    pub(crate) fn new(inner: &'a mut fuser::KernelConfig) -> KernelConfig<'a> {
+        KernelConfig { inner }
+    }
+
+    /// Ask for the given capabilities. If the kernel doesn't support all of them, the bits it
+    /// doesn't support are returned, and the others are still added.
+    pub fn add_capabilities(&mut self, capabilities: u32) -> Result<(), u32> {
+        self.inner.add_capabilities(capabilities)
+    }
+
+    /// Set the largest write the kernel sends in one request. Returns the previous value, or the
+    /// nearest value which is allowed if this one isn't.
+    pub fn set_max_write(&mut self, bytes: u32) -> Result<u32, u32> {
+        self.inner.set_max_write(bytes)
+    }
+
+    /// Set how far the kernel reads ahead of what's been asked for. Returns the previous value, or
+    /// the nearest value which is allowed if this one isn't.
+    pub fn set_max_readahead(&mut self, bytes: u32) -> Result<u32, u32> {
+        self.inner.set_max_readahead(bytes)
+    }
+
+    /// Set how many background requests (readahead, writeback, and the like) the kernel keeps
+    /// outstanding at once. Returns the previous value, or the nearest value which is allowed if
+    /// this one isn't. This needs the `congestion` feature.
+    #[cfg(feature = "congestion")]
+    pub fn set_max_background(&mut self, requests: u16) -> Result<u16, u16> {
+        self.inner.set_max_background(requests)
+    }
+
+    /// Set how many outstanding background requests the kernel considers the filesystem to be
+    /// congested at, after which it holds back on sending more. Returns the previous value, or the
+    /// nearest value which is allowed if this one isn't. This needs the `congestion` feature.
+    #[cfg(feature = "congestion")]
+    pub fn set_congestion_threshold(&mut self, requests: u16) -> Result<u16, u16> {
+        self.inner.set_congestion_threshold(requests)
+    }
+}
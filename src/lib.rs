@@ -9,6 +9,16 @@
 //! To implement a filesystem, implement the `FilesystemMT` trait. Not all functions in it need to
 //! be implemented -- the default behavior is to return `ENOSYS` ("Function not implemented"). For
 //! example, a read-only filesystem can skip implementing the `write` call and many others.
+//!
+//! A filesystem already written against `fuser::Filesystem` (or the older `fuse` crate, which has
+//! the same inode-based interface) can be moved over piece by piece by implementing
+//! `RawFilesystemMT`, which keeps inodes but has the simpler calling conventions. Attributes can be
+//! converted with `FileAttr::from` and `FileAttr::to_fuser`.
+//!
+//! Requests are logged through the `log` crate. At the `trace` level, every request is logged as
+//! it arrives from the kernel, with all of its arguments, and flags decoded into their names
+//! (`O_WRONLY|O_APPEND`, `R_OK|W_OK`, and so on). This formatting is only done when `trace`
+//! messages are enabled.
 
 //
 // Copyright (c) 2016-2022 by William R. Fraser
@@ -19,16 +29,50 @@
 #[macro_use]
 extern crate log;
 
+mod attr_cache;
+mod audit;
+mod auto_cache;
+mod control_files;
+mod destroy;
 mod directory_cache;
+mod flags;
+mod flusher;
 mod fusemt;
+pub mod helpers;
+mod inode_locks;
 mod inode_table;
+mod kernel_config;
+mod mount_options;
+#[cfg(feature = "notify")]
+mod notify;
+mod open_counts;
+mod path_rewrite;
+mod pause;
+mod read_cache;
+mod read_only;
+mod session;
+pub mod tree;
 mod types;
+mod usage;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-pub use fuser::FileType;
+pub use fuser::{consts, FileType, TimeOrNow};
+pub use crate::audit::{AuditLog, AuditRecord};
+pub use crate::control_files::{ControlFiles, CONTROL_OFFSET};
+pub use crate::destroy::DestroyHandle;
+pub use crate::flusher::BackgroundFlusher;
 pub use crate::fusemt::*;
+pub use crate::mount_options::*;
+#[cfg(feature = "notify")]
+pub use crate::notify::Notifier;
+pub use crate::path_rewrite::{PathMapping, PathRewrite};
+pub use crate::pause::PauseHandle;
+pub use crate::read_cache::ReadCache;
+pub use crate::read_only::{ReadOnlyHandle, ReadOnlyPaths};
+pub use crate::session::BackgroundSession;
 pub use crate::types::*;
+pub use crate::usage::UsageHandle;
 
 // Forward to similarly-named fuser functions to work around deprecation for now.
 // When these are removed, we'll have to either reimplement or break reverse compat.
@@ -52,14 +96,54 @@ pub fn mount<FS: fuser::Filesystem, P: AsRef<Path>>(
 
 /// Mount the given filesystem to the given mountpoint. This function spawns a background thread to
 /// handle filesystem operations while being mounted and therefore returns immediately. The
-/// returned handle should be stored to reference the mounted filesystem. If it's dropped, the
-/// filesystem will be unmounted.
-#[inline(always)]
+/// returned handle should be stored to reference the mounted filesystem. If it's dropped, or its
+/// `unmount` method is called, the filesystem will be unmounted.
 pub fn spawn_mount<FS: fuser::Filesystem + Send + 'static, P: AsRef<Path>>(
     fs: FS,
     mountpoint: P,
     options: &[&OsStr],
-) -> io::Result<fuser::BackgroundSession> {
+) -> io::Result<BackgroundSession> {
     #[allow(deprecated)]
-    fuser::spawn_mount(fs, mountpoint, options)
+    fuser::spawn_mount(fs, mountpoint, options).map(BackgroundSession::new)
+}
+
+/// Mount the given filesystem to the given mountpoint, after preparing and checking the mountpoint
+/// (and the environment) according to the given options. This function will not return until the
+/// filesystem is unmounted.
+pub fn mount_with_options<FS: fuser::Filesystem, P: AsRef<Path>>(
+    fs: FS,
+    mountpoint: P,
+    options: &MountOptions,
+) -> io::Result<()> {
+    let mountpoint = mountpoint.as_ref();
+    options.check_environment()?;
+    let created = options.prepare_mountpoint(mountpoint)?;
+    let result = options.check_mountpoint(mountpoint)
+        .and_then(|()| fuser::mount2(fs, mountpoint, &options.fuse_options()));
+    options.finish_mountpoint(mountpoint, created, result.is_err());
+    result
+}
+
+/// Mount the given filesystem to the given mountpoint, after preparing and checking the mountpoint
+/// (and the environment) according to the given options. This function spawns a background thread
+/// to handle filesystem operations while being mounted and therefore returns immediately. The
+/// returned handle should be stored to reference the mounted filesystem. If it's dropped, or its
+/// `unmount` method is called, the filesystem will be unmounted.
+pub fn spawn_mount_with_options<FS: fuser::Filesystem + Send + 'static, P: AsRef<Path>>(
+    fs: FS,
+    mountpoint: P,
+    options: &MountOptions,
+) -> io::Result<BackgroundSession> {
+    let mountpoint = mountpoint.as_ref();
+    options.check_environment()?;
+    let created = options.prepare_mountpoint(mountpoint)?;
+    let result = options.check_mountpoint(mountpoint)
+        .and_then(|()| fuser::spawn_mount2(fs, mountpoint, &options.fuse_options()));
+    match result {
+        Ok(session) => Ok(BackgroundSession::new(session).with_cleanup(options, created)),
+        Err(e) => {
+            options.finish_mountpoint(mountpoint, created, true);
+            Err(e)
+        }
+    }
 }
@@ -0,0 +1,342 @@
+// MountOptions :: options controlling how a FuseMT filesystem gets mounted.
+//
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub use fuser::MountOption;
+
+/// Options for mounting a filesystem with `mount_with_options` or `spawn_mount_with_options`.
+///
+/// In addition to the FUSE mount options passed through to `fuser`, this carries checks which
+/// FuseMT performs on the mountpoint before mounting.
+///
+/// The mounting itself is done by `libfuse`, which uses the `mount(2)` system call directly when
+/// the process is privileged enough to (as root, or with `CAP_SYS_ADMIN`), and only falls back to
+/// the setuid `fusermount` helper when that fails with a permission error. So a privileged process
+/// doesn't need `fusermount` installed, e.g. in a minimal container image, with one exception:
+/// `MountOption::AutoUnmount` is implemented by `fusermount`, and always uses it. Unmounting
+/// works the same way, using `umount2(2)` directly when running as root. For mounting inside a
+/// user namespace, as in a rootless container, see `userns_compatible`.
+#[derive(Clone, Debug, Default)]
+pub struct MountOptions {
+    options: Vec<MountOption>,
+    require_empty_mountpoint: bool,
+    nonempty: bool,
+    create_mountpoint: bool,
+    remove_created_mountpoint: bool,
+    userns: bool,
+}
+
+impl MountOptions {
+    /// Create a new set of mount options, with no FUSE options and no mountpoint checks.
+    pub fn new() -> MountOptions {
+        MountOptions::default()
+    }
+
+    /// Add a FUSE mount option.
+    pub fn option(mut self, option: MountOption) -> MountOptions {
+        self.options.push(option);
+        self
+    }
+
+    /// Add several FUSE mount options.
+    pub fn options(mut self, options: &[MountOption]) -> MountOptions {
+        self.options.extend_from_slice(options);
+        self
+    }
+
+    /// Refuse to mount unless the mountpoint is an empty directory.
+    ///
+    /// Mounting over a directory hides its contents until the filesystem is unmounted, which is
+    /// easy to do by mistake. With this set, mounting fails with `ENOTDIR` if the mountpoint is
+    /// not a directory, or `ENOTEMPTY` if it has any entries.
+    ///
+    /// This is mutually exclusive with `nonempty`; setting one clears the other.
+    pub fn require_empty_mountpoint(mut self, require: bool) -> MountOptions {
+        self.require_empty_mountpoint = require;
+        if require {
+            self.nonempty = false;
+        }
+        self
+    }
+
+    /// Explicitly allow mounting over a non-empty directory.
+    ///
+    /// This passes the `nonempty` option to FUSE, which older versions of `fusermount` require in
+    /// order to mount over a directory with entries in it.
+    ///
+    /// This is mutually exclusive with `require_empty_mountpoint`; setting one clears the other.
+    pub fn nonempty(mut self, nonempty: bool) -> MountOptions {
+        self.nonempty = nonempty;
+        if nonempty {
+            self.require_empty_mountpoint = false;
+        }
+        self
+    }
+
+    /// Create the mountpoint directory if it doesn't exist, along with any missing parent
+    /// directories.
+    ///
+    /// If the directory appears in the meantime (say, another process creating it at the same
+    /// time), that one is used. If it can't be created, mounting fails with an error saying so,
+    /// rather than the less helpful one from the mount itself.
+    pub fn create_mountpoint(mut self, create: bool) -> MountOptions {
+        self.create_mountpoint = create;
+        self
+    }
+
+    /// Remove the mountpoint directory again after unmounting, if it was created because of
+    /// `create_mountpoint`. A mountpoint which already existed is always left alone.
+    ///
+    /// With `spawn_mount_with_options`, this is done when the returned session is dropped or
+    /// unmounted. Either way, a directory which was created for a mount that then failed is
+    /// removed straight away.
+    pub fn remove_created_mountpoint(mut self, remove: bool) -> MountOptions {
+        self.remove_created_mountpoint = remove;
+        self
+    }
+
+    /// Adjust these options for mounting inside a user namespace, as in a rootless container.
+    ///
+    /// There, the mount is done directly with `mount(2)`, using the namespace's `CAP_SYS_ADMIN`,
+    /// since the setuid `fusermount` helper has no privileges inside it. This requires Linux 4.18
+    /// or later, and the container to be given `/dev/fuse` (e.g. `--device /dev/fuse`). The
+    /// kernel then limits the mount in a few ways, so this removes the options which can't work:
+    ///
+    /// * `AllowOther` and `AllowRoot`: the kernel only lets processes in the same namespace (or
+    ///   ones nested in it) into the filesystem anyway, so with these removed, only the user who
+    ///   mounted it has access. Add `AllowOther` back after this to give the rest of the
+    ///   namespace access.
+    /// * `AutoUnmount`, which is implemented by `fusermount`.
+    /// * `Dev` and `Suid`, which are replaced with `NoDev` and `NoSuid`, as the kernel never
+    ///   honors device files or set-user-ID bits on such a mount.
+    ///
+    /// File ownership is reported as is; user and group IDs with no mapping in the namespace show
+    /// up as the overflow ID (usually 65534, `nobody`), so a filesystem should report IDs from
+    /// inside the namespace, such as those in the `RequestInfo` of requests.
+    ///
+    /// Before mounting, `/dev/fuse` is checked, so that a container which wasn't given it fails
+    /// with an error saying so rather than a less helpful one from the mount.
+    pub fn userns_compatible(mut self) -> MountOptions {
+        self.options.retain(|option| !matches!(option,
+            MountOption::AllowOther | MountOption::AllowRoot | MountOption::AutoUnmount
+            | MountOption::Dev | MountOption::Suid | MountOption::NoDev | MountOption::NoSuid));
+        self.options.push(MountOption::NoDev);
+        self.options.push(MountOption::NoSuid);
+        self.userns = true;
+        self
+    }
+
+    /// Get the FUSE mount options to pass to `fuser`.
+    pub(crate) fn fuse_options(&self) -> Vec<MountOption> {
+        let mut options = self.options.clone();
+        if self.nonempty {
+            options.push(MountOption::CUSTOM("nonempty".to_owned()));
+        }
+        options
+    }
+
+    /// Create the mountpoint if it's missing and these options say to. Returns whether it was
+    /// created.
+    pub(crate) fn prepare_mountpoint(&self, mountpoint: &Path) -> io::Result<bool> {
+        if !self.create_mountpoint {
+            return Ok(false);
+        }
+
+        // Just try to create it, rather than checking first, so there's no window for it to
+        // appear in between.
+        let mut result = fs::create_dir(mountpoint);
+        if matches!(result, Err(ref e) if e.kind() == io::ErrorKind::NotFound) {
+            if let Some(parent) = mountpoint.parent() {
+                fs::create_dir_all(parent).map_err(|e| create_error(mountpoint, e))?;
+                result = fs::create_dir(mountpoint);
+            }
+        }
+        match result {
+            Ok(()) => {
+                debug!("created mountpoint {:?}", mountpoint);
+                Ok(true)
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(create_error(mountpoint, e)),
+        }
+    }
+
+    /// Clean up after a mount which has ended (or failed), given whether `prepare_mountpoint`
+    /// created the mountpoint.
+    pub(crate) fn finish_mountpoint(&self, mountpoint: &Path, created: bool, failed: bool) {
+        if created && (failed || self.remove_created_mountpoint) {
+            if let Err(e) = fs::remove_dir(mountpoint) {
+                warn!("failed to remove mountpoint {:?}: {}", mountpoint, e);
+            }
+        }
+    }
+
+    /// Check that the options can work in the environment the process is running in, and warn
+    /// about any which won't.
+    pub(crate) fn check_environment(&self) -> io::Result<()> {
+        if !in_user_namespace() {
+            return Ok(());
+        }
+        debug!("running in a user namespace");
+
+        for option in &self.options {
+            match option {
+                // Mounted directly, the option works (for the namespace); through `fusermount`, it
+                // only does for root or with `user_allow_other` set.
+                MountOption::AllowOther | MountOption::AllowRoot
+                    if self.userns || allow_other_permitted() => debug!(
+                    "in a user namespace, {:?} only gives access to processes in the namespace", option),
+                MountOption::AllowOther | MountOption::AllowRoot => warn!(
+                    "{:?} needs user_allow_other in /etc/fuse.conf, which isn't set; mounting will likely fail", option),
+                MountOption::AutoUnmount => warn!(
+                    "in a user namespace, {:?} needs fusermount, which won't work; mounting will likely fail", option),
+                MountOption::Dev | MountOption::Suid => warn!(
+                    "in a user namespace, {:?} has no effect", option),
+                _ => (),
+            }
+        }
+
+        if self.userns {
+            fs::OpenOptions::new().read(true).write(true).open("/dev/fuse").map_err(|e| {
+                io::Error::new(e.kind(), format!(
+                    "can't open /dev/fuse: {}; containers need to be given it to mount FUSE filesystems", e))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Check the mountpoint against the requirements set in these options.
+    pub(crate) fn check_mountpoint(&self, mountpoint: &Path) -> io::Result<()> {
+        if !self.require_empty_mountpoint {
+            return Ok(());
+        }
+
+        let metadata = fs::metadata(mountpoint).map_err(|e| {
+            io::Error::new(e.kind(), format!("mountpoint {:?}: {}", mountpoint, e))
+        })?;
+        if !metadata.is_dir() {
+            return Err(mountpoint_error(mountpoint, libc::ENOTDIR));
+        }
+
+        let mut entries = fs::read_dir(mountpoint).map_err(|e| {
+            io::Error::new(e.kind(), format!("mountpoint {:?}: {}", mountpoint, e))
+        })?;
+        if entries.next().is_some() {
+            return Err(mountpoint_error(mountpoint, libc::ENOTEMPTY));
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether this process is running in a user namespace, other than the initial one.
+fn in_user_namespace() -> bool {
+    match fs::read_to_string("/proc/self/uid_map") {
+        Ok(uid_map) => !is_identity_uid_map(&uid_map),
+        Err(_) => false,
+    }
+}
+
+/// Whether `fusermount` lets this process mount with `AllowOther` or `AllowRoot`: as root, or with
+/// `user_allow_other` set in `/etc/fuse.conf`.
+fn allow_other_permitted() -> bool {
+    let root = unsafe { libc::getuid() } == 0;
+    root || fs::read_to_string("/etc/fuse.conf").is_ok_and(|conf| fuse_conf_allows_other(&conf))
+}
+
+/// Whether a `fuse.conf` sets `user_allow_other`.
+fn fuse_conf_allows_other(conf: &str) -> bool {
+    conf.lines().any(|line| line.split('#').next().unwrap().trim() == "user_allow_other")
+}
+
+/// Whether a `uid_map` maps all user IDs to themselves, as it does in the initial namespace.
+fn is_identity_uid_map(uid_map: &str) -> bool {
+    let fields: Vec<&str> = uid_map.split_whitespace().collect();
+    fields == ["0", "0", "4294967295"]
+}
+
+fn create_error(mountpoint: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("can't create mountpoint {:?}: {}", mountpoint, e))
+}
+
+fn mountpoint_error(mountpoint: &Path, errno: libc::c_int) -> io::Error {
+    let err = io::Error::from_raw_os_error(errno);
+    io::Error::new(err.kind(), format!("mountpoint {:?}: {}", mountpoint, err))
+}
+
+#[test]
+fn test_require_empty_mountpoint() {
+    let dir = std::env::temp_dir().join(format!("fuse_mt_test_mountpoint_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let options = MountOptions::new().require_empty_mountpoint(true);
+    options.check_mountpoint(&dir).unwrap();
+
+    // A non-empty directory is rejected.
+    let file = dir.join("file");
+    fs::write(&file, b"contents").unwrap();
+    let err = options.check_mountpoint(&dir).unwrap_err();
+    assert_eq!(io::Error::from_raw_os_error(libc::ENOTEMPTY).kind(), err.kind());
+
+    // So is something that isn't a directory at all.
+    let err = options.check_mountpoint(&file).unwrap_err();
+    assert_eq!(io::Error::from_raw_os_error(libc::ENOTDIR).kind(), err.kind());
+
+    // Unless mounting over content is explicitly requested.
+    let options = options.nonempty(true);
+    options.check_mountpoint(&dir).unwrap();
+    assert_eq!(vec![MountOption::CUSTOM("nonempty".to_owned())], options.fuse_options());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_create_mountpoint() {
+    let dir = std::env::temp_dir().join(format!("fuse_mt_test_create_{}", std::process::id()));
+    let mountpoint = dir.join("a").join("mnt");
+
+    let options = MountOptions::new();
+    assert!(!options.prepare_mountpoint(&mountpoint).unwrap());
+    assert!(!mountpoint.exists());
+
+    // Missing parents are created too, and only the mountpoint itself is removed afterwards.
+    let options = options.create_mountpoint(true).remove_created_mountpoint(true);
+    assert!(options.prepare_mountpoint(&mountpoint).unwrap());
+    assert!(mountpoint.is_dir());
+    options.check_mountpoint(&mountpoint).unwrap();
+    options.finish_mountpoint(&mountpoint, true, false);
+    assert!(!mountpoint.exists());
+    assert!(dir.join("a").is_dir());
+
+    // One that already exists is used, and left in place.
+    fs::create_dir(&mountpoint).unwrap();
+    assert!(!options.prepare_mountpoint(&mountpoint).unwrap());
+    options.finish_mountpoint(&mountpoint, false, false);
+    assert!(mountpoint.is_dir());
+
+    // Something in the way of the path is reported as such.
+    let file = dir.join("file");
+    fs::write(&file, b"contents").unwrap();
+    let err = options.prepare_mountpoint(&file.join("mnt")).unwrap_err();
+    assert!(err.to_string().starts_with("can't create mountpoint"), "{}", err);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_userns_compatible() {
+    let options = MountOptions::new()
+        .options(&[MountOption::AllowOther, MountOption::FSName("test".to_owned()), MountOption::Suid])
+        .userns_compatible();
+    assert_eq!(vec![MountOption::FSName("test".to_owned()), MountOption::NoDev, MountOption::NoSuid],
+        options.fuse_options());
+
+    assert!(fuse_conf_allows_other("# mount_max = 1000\nuser_allow_other\n"));
+    assert!(!fuse_conf_allows_other("#user_allow_other\n"));
+
+    assert!(is_identity_uid_map("         0          0 4294967295\n"));
+    assert!(!is_identity_uid_map("         0       1000          1\n         1     100000      65536\n"));
+}
@@ -0,0 +1,66 @@
+// Notifier :: telling the kernel to drop what it has cached about a filesystem.
+//
+
+use std::ffi::OsStr;
+use std::io;
+
+/// A handle for telling the kernel that files have changed without it knowing, so that it drops
+/// what it has cached about them, obtained with `BackgroundSession::notifier`. This needs the
+/// `notify` feature, which also raises the FUSE protocol version used to 7.18.
+///
+/// Files are named by the kernel's inode numbers for them. With `RawFuseMT`, those are the
+/// filesystem's own inode numbers, except that its root is always `fuser::FUSE_ROOT_ID` (1).
+/// With `FuseMT`, the numbers aren't visible to the filesystem, so only the root can be named.
+///
+/// Invalidating something the kernel doesn't have cached is not an error. The methods can be
+/// called from any thread, but not from inside an operation on the same files, since the kernel
+/// may be holding locks for it which the invalidation needs too; that deadlocks.
+#[derive(Debug)]
+pub struct Notifier {
+    inner: fuser::Notifier,
+}
+
+impl Notifier {
+    pub(crate) fn new(inner: fuser::Notifier) -> Notifier {
+        Notifier { inner }
+    }
+
+    /// Wake the processes waiting in `poll` on a file, for which `FilesystemMT::poll` was given
+    /// this handle, so that the kernel polls the file again.
+    pub fn poll(&self, ph: crate::PollHandle) -> io::Result<()> {
+        debug!("notify: poll {:#x}", ph.0);
+        self.inner.poll(ph.0)
+    }
+
+    /// Drop the cached attributes of a file, and some or all of its cached data.
+    ///
+    /// The attributes are always dropped, and fetched with `getattr` the next time they're
+    /// needed. If `offset` is negative, that's all; otherwise, cached data is dropped from `offset`
+    /// for `len` bytes, or to the end of the file if `len` is zero or negative.
+    pub fn inval_inode(&self, ino: u64, offset: i64, len: i64) -> io::Result<()> {
+        debug!("notify: inval_inode {} @ {} len {}", ino, offset, len);
+        self.inner.inval_inode(ino, offset, len)
+    }
+
+    /// Drop all the kernel has cached about a file: its attributes and all of its data. This is
+    /// `inval_inode(ino, 0, 0)`, and like it, does nothing if the file isn't cached.
+    pub fn flush_inode_cache(&self, ino: u64) -> io::Result<()> {
+        self.inval_inode(ino, 0, 0)
+    }
+
+    /// Drop the cached entry for a name in a directory, so that it's looked up again the next time
+    /// it's used. Use this when a name appears, disappears, or refers to a different file.
+    pub fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        debug!("notify: inval_entry {}/{:?}", parent, name);
+        self.inner.inval_entry(parent, name)
+    }
+
+    /// Tell the kernel that a file has been deleted: this drops its entry like `inval_entry`, and
+    /// also lets `inotify` watchers know. `child` is the inode the name referred to; if the kernel
+    /// has the name cached as referring to something else, nothing is done. Deleting a directory
+    /// which the kernel knows to have entries in it fails with `ENOTEMPTY`.
+    pub fn delete(&self, parent: u64, child: u64, name: &OsStr) -> io::Result<()> {
+        debug!("notify: delete {}/{:?} (inode {})", parent, name, child);
+        self.inner.delete(parent, child, name)
+    }
+}
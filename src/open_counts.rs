@@ -0,0 +1,84 @@
+// OpenCounts :: counts of open file handles per inode, for limiting them.
+//
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Tracks how many times each inode is open, and enforces an optional limit on it. It can be
+/// shared with the thread pool, for opens which finish there.
+#[derive(Debug, Default)]
+pub struct OpenCounts {
+    limit: Option<usize>,
+    counts: Mutex<HashMap<u64, usize>>,
+}
+
+impl OpenCounts {
+    pub fn new(limit: Option<usize>) -> OpenCounts {
+        OpenCounts {
+            limit,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a new open of the given inode, unless it is already open as many times as the limit
+    /// allows, in which case fail with `ENFILE`.
+    pub fn acquire(&self, ino: u64) -> Result<(), libc::c_int> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ino).or_insert(0);
+        if *count >= limit {
+            debug!("inode {} is already open {} times", ino, count);
+            if *count == 0 {
+                counts.remove(&ino);
+            }
+            return Err(libc::ENFILE);
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    /// Count a new open of the given inode regardless of the limit. This is for opens which have
+    /// already happened by the time they can be counted, such as `create`.
+    pub fn add(&self, ino: u64) {
+        if self.limit.is_some() {
+            *self.counts.lock().unwrap().entry(ino).or_insert(0) += 1;
+        }
+    }
+
+    /// Count a handle to the given inode being closed.
+    pub fn release(&self, ino: u64) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&ino) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&ino);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_open_limit() {
+    let counts = OpenCounts::new(Some(3));
+    for _ in 0..3 {
+        counts.acquire(1).unwrap();
+    }
+    assert_eq!(Err(libc::ENFILE), counts.acquire(1));
+
+    // Other inodes have their own count.
+    counts.acquire(2).unwrap();
+
+    counts.release(1);
+    counts.acquire(1).unwrap();
+    assert_eq!(Err(libc::ENFILE), counts.acquire(1));
+
+    // Without a limit, there's nothing to track.
+    let counts = OpenCounts::new(None);
+    for _ in 0..100 {
+        counts.acquire(1).unwrap();
+    }
+    assert!(counts.counts.lock().unwrap().is_empty());
+}
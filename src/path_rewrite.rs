@@ -0,0 +1,417 @@
+// PathRewrite :: a wrapper translating paths before passing requests on.
+//
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::types::*;
+
+/// A translation between the paths requests arrive with and the paths the wrapped filesystem
+/// uses, for `PathRewrite`.
+///
+/// Any `Fn(&Path) -> PathBuf` is a mapping which only translates incoming paths, leaving the
+/// names in directory listings alone.
+pub trait PathMapping {
+    /// Translate a path from a request into the path to pass to the wrapped filesystem.
+    fn to_inner(&self, path: &Path) -> PathBuf;
+
+    /// Translate a path of the wrapped filesystem back, for the names returned by `readdir`.
+    /// Returning `None` hides the entry. The default returns the path unchanged.
+    ///
+    /// An entry is also hidden if it translates to a path outside the directory being listed.
+    fn to_outer(&self, path: &Path) -> Option<PathBuf> {
+        Some(path.to_owned())
+    }
+}
+
+impl<F: Fn(&Path) -> PathBuf> PathMapping for F {
+    fn to_inner(&self, path: &Path) -> PathBuf {
+        self(path)
+    }
+}
+
+/// Wraps a filesystem, translating the path in every request with a `PathMapping` before passing
+/// it on, and translating the names in directory listings back.
+///
+/// This can be used to hide a prefix (so a subdirectory of the wrapped filesystem appears as the
+/// root), to redirect some paths elsewhere, or to normalize names, for example.
+///
+/// For operations which take a directory and a name, the whole path is translated and then split
+/// up again, so the mapping can change names as well as directories; if it maps such a path to the
+/// root, the operation fails with `EINVAL`. Symbolic link targets, both in `symlink` and from
+/// `readlink`, are passed through unchanged, since they are data rather than paths in this
+/// filesystem.
+pub struct PathRewrite<FS, M> {
+    inner: FS,
+    mapping: M,
+}
+
+impl<FS, M: PathMapping> PathRewrite<FS, M> {
+    /// Wrap a filesystem, translating paths with the given mapping.
+    pub fn new(inner: FS, mapping: M) -> PathRewrite<FS, M> {
+        PathRewrite { inner, mapping }
+    }
+
+    /// Get the wrapped filesystem.
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    fn path(&self, path: &Path) -> PathBuf {
+        let inner = self.mapping.to_inner(path);
+        trace!("rewrite: {:?} -> {:?}", path, inner);
+        inner
+    }
+
+    /// Translate a directory and a name in it, giving the translated directory and name.
+    fn entry(&self, parent: &Path, name: &OsStr) -> Result<(PathBuf, OsString), libc::c_int> {
+        let inner = self.path(&parent.join(name));
+        match (inner.parent(), inner.file_name()) {
+            (Some(parent), Some(name)) => Ok((parent.to_owned(), name.to_owned())),
+            _ => {
+                error!("rewrite: {:?}/{:?} has no parent or name after rewriting: {:?}", parent, name, inner);
+                Err(libc::EINVAL)
+            }
+        }
+    }
+
+    /// Translate the names in a directory listing back, dropping any which don't belong in it.
+    fn names<E>(&self, dir: &Path, inner_dir: &Path, entries: Vec<E>, name: impl Fn(&mut E) -> &mut OsString)
+        -> Vec<E>
+    {
+        entries.into_iter()
+            .filter_map(|mut entry| {
+                let entry_name = name(&mut entry);
+                *entry_name = self.outer_name(dir, inner_dir, entry_name)?;
+                Some(entry)
+            })
+            .collect()
+    }
+
+    /// Translate a name in a directory listing back, or return `None` if it doesn't belong in it.
+    fn outer_name(&self, dir: &Path, inner_dir: &Path, name: &OsStr) -> Option<OsString> {
+        if name == "." || name == ".." {
+            return Some(name.to_owned());
+        }
+        let outer = self.mapping.to_outer(&inner_dir.join(name))?;
+        if outer.parent() != Some(dir) {
+            return None;
+        }
+        Some(outer.file_name()?.to_owned())
+    }
+}
+
+impl<'a, FS, M> FilesystemMT<'a> for PathRewrite<FS, M>
+    where FS: for<'b> FilesystemMT<'b>,
+          M: PathMapping,
+{
+    fn init(&self, req: RequestInfo) -> ResultEmpty {
+        self.inner.init(req)
+    }
+
+    fn init_with_config(&self, req: RequestInfo, config: &mut KernelConfig<'_>) -> ResultEmpty {
+        self.inner.init_with_config(req, config)
+    }
+
+    fn destroy(&self) {
+        self.inner.destroy()
+    }
+
+    fn try_destroy(&self) -> ResultEmpty {
+        self.inner.try_destroy()
+    }
+
+    fn capabilities(&self) -> OpSet {
+        self.inner.capabilities()
+    }
+
+    fn on_forget(&self, path: &'a Path) {
+        self.inner.on_forget(&self.path(path))
+    }
+
+    fn getattr(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>) -> ResultEntry {
+        self.inner.getattr(req, &self.path(path), fh)
+    }
+
+    fn setattr(&self, req: RequestInfo, path: &'a Path, attrs: SetAttrRequest) -> ResultEntry {
+        self.inner.setattr(req, &self.path(path), attrs)
+    }
+
+    fn chmod(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, mode: u32) -> ResultEmpty {
+        self.inner.chmod(req, &self.path(path), fh, mode)
+    }
+
+    fn chown(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, uid: Option<u32>, gid: Option<u32>) -> ResultEmpty {
+        self.inner.chown(req, &self.path(path), fh, uid, gid)
+    }
+
+    fn truncate(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, size: u64) -> ResultEmpty {
+        self.inner.truncate(req, &self.path(path), fh, size)
+    }
+
+    fn utimens(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, atime: Option<crate::TimeOrNow>, mtime: Option<crate::TimeOrNow>) -> ResultEmpty {
+        self.inner.utimens(req, &self.path(path), fh, atime, mtime)
+    }
+
+    fn utimens_macos(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> ResultEmpty {
+        self.inner.utimens_macos(req, &self.path(path), fh, crtime, chgtime, bkuptime, flags)
+    }
+
+    fn readlink(&self, req: RequestInfo, path: &'a Path) -> ResultData {
+        self.inner.readlink(req, &self.path(path))
+    }
+
+    fn mknod(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32, rdev: u32) -> ResultEntry {
+        let (parent, name) = self.entry(parent, name)?;
+        self.inner.mknod(req, &parent, &name, mode, umask, rdev)
+    }
+
+    fn mkdir(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32) -> ResultEntry {
+        let (parent, name) = self.entry(parent, name)?;
+        self.inner.mkdir(req, &parent, &name, mode, umask)
+    }
+
+    fn unlink(&self, req: RequestInfo, parent: &'a Path, name: &OsStr) -> ResultEmpty {
+        let (parent, name) = self.entry(parent, name)?;
+        self.inner.unlink(req, &parent, &name)
+    }
+
+    fn rmdir(&self, req: RequestInfo, parent: &'a Path, name: &OsStr) -> ResultEmpty {
+        let (parent, name) = self.entry(parent, name)?;
+        self.inner.rmdir(req, &parent, &name)
+    }
+
+    fn symlink(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, target: &Path) -> ResultEntry {
+        let (parent, name) = self.entry(parent, name)?;
+        self.inner.symlink(req, &parent, &name, target)
+    }
+
+    fn rename(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr) -> ResultEmpty {
+        let (parent, name) = self.entry(parent, name)?;
+        let (newparent, newname) = self.entry(newparent, newname)?;
+        self.inner.rename(req, &parent, &name, &newparent, &newname)
+    }
+
+    fn exchange(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr) -> ResultEmpty {
+        let (parent, name) = self.entry(parent, name)?;
+        let (newparent, newname) = self.entry(newparent, newname)?;
+        self.inner.exchange(req, &parent, &name, &newparent, &newname)
+    }
+
+    fn rename_flags(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr, flags: u32) -> ResultEmpty {
+        let (parent, name) = self.entry(parent, name)?;
+        let (newparent, newname) = self.entry(newparent, newname)?;
+        self.inner.rename_flags(req, &parent, &name, &newparent, &newname, flags)
+    }
+
+    fn link(&self, req: RequestInfo, path: &'a Path, newparent: &'a Path, newname: &OsStr) -> ResultEntry {
+        let (newparent, newname) = self.entry(newparent, newname)?;
+        self.inner.link(req, &self.path(path), &newparent, &newname)
+    }
+
+    fn open(&self, req: RequestInfo, path: &'a Path, flags: u32) -> ResultOpen {
+        self.inner.open(req, &self.path(path), flags)
+    }
+
+    fn read(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
+        self.inner.read(req, &self.path(path), fh, offset, size, callback)
+    }
+
+    fn write(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, data: Vec<u8>, flags: u32) -> ResultWrite {
+        self.inner.write(req, &self.path(path), fh, offset, data, flags)
+    }
+
+    fn write_buf(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, data: &[u8], flags: u32) -> ResultWrite {
+        self.inner.write_buf(req, &self.path(path), fh, offset, data, flags)
+    }
+
+    fn flush(&self, req: RequestInfo, path: &'a Path, fh: u64, lock_owner: u64) -> ResultEmpty {
+        self.inner.flush(req, &self.path(path), fh, lock_owner)
+    }
+
+    fn release(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> ResultEmpty {
+        self.inner.release(req, &self.path(path), fh, flags, lock_owner, flush)
+    }
+
+    fn fsync(&self, req: RequestInfo, path: &'a Path, fh: u64, datasync: bool) -> ResultEmpty {
+        self.inner.fsync(req, &self.path(path), fh, datasync)
+    }
+
+    fn opendir(&self, req: RequestInfo, path: &'a Path, flags: u32) -> ResultOpen {
+        self.inner.opendir(req, &self.path(path), flags)
+    }
+
+    fn readdir(&self, req: RequestInfo, path: &'a Path, fh: u64) -> ResultReaddir {
+        let inner_path = self.path(path);
+        let entries = self.inner.readdir(req, &inner_path, fh)?;
+        Ok(self.names(path, &inner_path, entries, |entry| &mut entry.name))
+    }
+
+    fn readdir_with_attrs(&self, req: RequestInfo, path: &'a Path, fh: u64) -> ResultReaddirAttrs {
+        let inner_path = self.path(path);
+        let entries = self.inner.readdir_with_attrs(req, &inner_path, fh)?;
+        Ok(self.names(path, &inner_path, entries, |(entry, _)| &mut entry.name))
+    }
+
+    fn readdir_offset(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, filler: &mut DirFiller<'_>)
+        -> ResultEmpty
+    {
+        let inner_path = self.path(path);
+        // Entries which are left out count as added, so the listing carries on past them.
+        let mut add = |ino, offset, kind, name: &OsStr| {
+            match self.outer_name(path, &inner_path, name) {
+                Some(name) => filler.add(ino, offset as u64, kind, name),
+                None => true,
+            }
+        };
+        self.inner.readdir_offset(req, &inner_path, fh, offset, &mut DirFiller::new(&mut add))
+    }
+
+    fn releasedir(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32) -> ResultEmpty {
+        self.inner.releasedir(req, &self.path(path), fh, flags)
+    }
+
+    fn fsyncdir(&self, req: RequestInfo, path: &'a Path, fh: u64, datasync: bool) -> ResultEmpty {
+        self.inner.fsyncdir(req, &self.path(path), fh, datasync)
+    }
+
+    fn statfs(&self, req: RequestInfo, path: &'a Path) -> ResultStatfs {
+        self.inner.statfs(req, &self.path(path))
+    }
+
+    fn setxattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr, value: &[u8], flags: u32, position: u32) -> ResultEmpty {
+        self.inner.setxattr(req, &self.path(path), name, value, flags, position)
+    }
+
+    fn getxattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr, size: u32) -> ResultXattr {
+        self.inner.getxattr(req, &self.path(path), name, size)
+    }
+
+    fn listxattr(&self, req: RequestInfo, path: &'a Path, size: u32) -> ResultXattr {
+        self.inner.listxattr(req, &self.path(path), size)
+    }
+
+    fn removexattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr) -> ResultEmpty {
+        self.inner.removexattr(req, &self.path(path), name)
+    }
+
+    fn access(&self, req: RequestInfo, path: &'a Path, mask: u32) -> ResultEmpty {
+        self.inner.access(req, &self.path(path), mask)
+    }
+
+    fn create(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32, flags: u32) -> ResultCreate {
+        let (parent, name) = self.entry(parent, name)?;
+        self.inner.create(req, &parent, &name, mode, umask, flags)
+    }
+
+    fn lseek(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, whence: i32) -> ResultLseek {
+        self.inner.lseek(req, &self.path(path), fh, offset, whence)
+    }
+
+    fn copy_file_range(&self, req: RequestInfo, path_in: &'a Path, fh_in: u64, offset_in: u64, path_out: &'a Path, fh_out: u64, offset_out: u64, len: u64, flags: u32) -> ResultWrite {
+        self.inner.copy_file_range(req, &self.path(path_in), fh_in, offset_in, &self.path(path_out), fh_out, offset_out, len, flags)
+    }
+
+    fn fallocate(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, length: u64, mode: i32) -> ResultEmpty {
+        self.inner.fallocate(req, &self.path(path), fh, offset, length, mode)
+    }
+
+    fn ioctl(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32, cmd: u32, in_data: &[u8], out_size: u32) -> ResultIoctl {
+        self.inner.ioctl(req, &self.path(path), fh, flags, cmd, in_data, out_size)
+    }
+
+    fn poll(&self, req: RequestInfo, path: &'a Path, fh: u64, ph: PollHandle, events: u32, flags: u32) -> ResultPoll {
+        self.inner.poll(req, &self.path(path), fh, ph, events, flags)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&self, req: RequestInfo, name: &OsStr) -> ResultEmpty {
+        self.inner.setvolname(req, name)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&self, req: RequestInfo, path: &'a Path) -> ResultXTimes {
+        self.inner.getxtimes(req, &self.path(path))
+    }
+}
+
+#[test]
+fn test_path_rewrite() {
+    use std::sync::Mutex;
+
+    // A filesystem which records the paths it's asked about, and has a fixed directory listing.
+    struct RecordingFS {
+        paths: Mutex<Vec<PathBuf>>,
+    }
+
+    impl FilesystemMT<'_> for RecordingFS {
+        fn unlink(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+            self.paths.lock().unwrap().push(parent.join(name));
+            Ok(())
+        }
+
+        fn readdir(&self, _req: RequestInfo, path: &Path, _fh: u64) -> ResultReaddir {
+            self.paths.lock().unwrap().push(path.to_owned());
+            Ok([".", "a", ".hidden"].iter().map(|name| DirectoryEntry {
+                name: name.into(),
+                kind: crate::FileType::RegularFile,
+            }).collect())
+        }
+
+        fn readdir_offset(&self, _req: RequestInfo, _path: &Path, _fh: u64, offset: u64, filler: &mut DirFiller<'_>)
+            -> ResultEmpty
+        {
+            for (index, name) in [".", "a", ".hidden", "b"].iter().enumerate().skip(offset as usize) {
+                if !filler.add(0, index as u64 + 1, crate::FileType::RegularFile, name) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    // Present /export as the root, and hide dotfiles.
+    struct Export;
+
+    impl PathMapping for Export {
+        fn to_inner(&self, path: &Path) -> PathBuf {
+            Path::new("/export").join(path.strip_prefix("/").unwrap())
+        }
+
+        fn to_outer(&self, path: &Path) -> Option<PathBuf> {
+            if path.file_name()?.to_str()?.starts_with('.') {
+                return None;
+            }
+            Some(Path::new("/").join(path.strip_prefix("/export").ok()?))
+        }
+    }
+
+    let fs = PathRewrite::new(RecordingFS { paths: Mutex::new(vec![]) }, Export);
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+
+    fs.unlink(req, Path::new("/dir"), OsStr::new("file")).unwrap();
+    let names: Vec<OsString> = fs.readdir(req, Path::new("/dir"), 0).unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(vec![OsString::from("."), OsString::from("a")], names);
+    assert_eq!(vec![PathBuf::from("/export/dir/file"), PathBuf::from("/export/dir")],
+        *fs.inner().paths.lock().unwrap());
+
+    // Streamed listings are filtered the same way, and the offsets are passed through.
+    let mut streamed = vec![];
+    let mut add = |_ino, offset, _kind, name: &OsStr| {
+        streamed.push((offset, name.to_owned()));
+        true
+    };
+    fs.readdir_offset(req, Path::new("/dir"), 0, 1, &mut DirFiller::new(&mut add)).unwrap();
+    assert_eq!(vec![(2, OsString::from("a")), (4, OsString::from("b"))], streamed);
+
+    // A plain function works as a mapping too.
+    let fs = PathRewrite::new(RecordingFS { paths: Mutex::new(vec![]) }, |path: &Path| {
+        path.with_file_name(path.file_name().unwrap().to_ascii_lowercase())
+    });
+    fs.unlink(req, Path::new("/dir"), OsStr::new("FILE")).unwrap();
+    assert_eq!(vec![PathBuf::from("/dir/file")], *fs.inner().paths.lock().unwrap());
+}
@@ -0,0 +1,93 @@
+// Pause :: a switch for holding off request processing while the filesystem's backend is away.
+//
+
+use std::sync::{Arc, Condvar, Mutex};
+
+/// A handle for pausing and resuming the processing of requests, obtained with
+/// `FuseMT::pause_handle` or `RawFuseMT::pause_handle` before mounting.
+///
+/// While paused, new requests are held back instead of reaching the filesystem; requests already
+/// being handled (including I/O running on the thread pool) are not affected. This is meant for
+/// riding out a short outage of whatever the filesystem is backed by, such as a network
+/// connection being re-established, without returning errors.
+///
+/// I/O requests (`read`, `write`, `flush`, `fsync` and the others which run on the thread pool)
+/// wait on the pool, leaving the thread which takes requests from the kernel free to carry on.
+/// Forgetting inodes and releasing open files and directories are never held back, since they
+/// only finish with something already in use. Any other request waits on the thread taking
+/// requests, so once one arrives, nothing more is taken from the kernel until processing is
+/// resumed, and the rest queue up there.
+///
+/// Processes making requests in the meantime simply wait. The kernel limits how many background
+/// requests (readahead, writeback, and the like) it keeps outstanding, by default 12, after which
+/// processes start blocking before their request is even queued; other requests are not limited.
+/// The kernel doesn't time out FUSE requests by default, but some kernels (Linux 6.14 and later)
+/// can be configured to, in which case a pause longer than the timeout aborts the whole
+/// connection, as does writing to the connection's `abort` file under `/sys/fs/fuse/connections`.
+/// Unmounting also has to wait until the filesystem is resumed.
+///
+/// The handle can be cloned and used from any thread.
+#[derive(Clone, Debug, Default)]
+pub struct PauseHandle {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl PauseHandle {
+    pub(crate) fn new() -> PauseHandle {
+        PauseHandle::default()
+    }
+
+    /// Stop taking new requests until `resume` is called.
+    pub fn pause(&self) {
+        let (paused, _) = &*self.state;
+        *paused.lock().unwrap() = true;
+    }
+
+    /// Start taking requests again.
+    pub fn resume(&self) {
+        let (paused, condvar) = &*self.state;
+        *paused.lock().unwrap() = false;
+        condvar.notify_all();
+    }
+
+    /// Whether request processing is currently paused.
+    pub fn is_paused(&self) -> bool {
+        *self.state.0.lock().unwrap()
+    }
+
+    /// Block while request processing is paused.
+    pub(crate) fn wait(&self) {
+        let (paused, condvar) = &*self.state;
+        let mut paused = paused.lock().unwrap();
+        if *paused {
+            debug!("request processing is paused");
+            while *paused {
+                paused = condvar.wait(paused).unwrap();
+            }
+            debug!("request processing resumed");
+        }
+    }
+}
+
+#[test]
+fn test_pause() {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let handle = PauseHandle::new();
+    handle.wait();
+
+    handle.pause();
+    assert!(handle.is_paused());
+    let (tx, rx) = mpsc::channel();
+    let thread_handle = handle.clone();
+    let thread = std::thread::spawn(move || {
+        thread_handle.wait();
+        tx.send(()).unwrap();
+    });
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+    handle.resume();
+    rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    thread.join().unwrap();
+}
@@ -0,0 +1,525 @@
+// ReadCache :: a wrapper keeping recently read file data in memory.
+//
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::types::*;
+
+/// Wraps a filesystem, keeping the data read from files in memory so that reading it again
+/// doesn't go back to the wrapped filesystem. This is for filesystems where reads are expensive,
+/// such as ones backed by a remote store.
+///
+/// Data is read from the wrapped filesystem, and cached, in fixed-size blocks at multiples of the
+/// block size, whatever the size of the reads the kernel makes, so this also lets a filesystem
+/// whose backend works in large chunks serve reads in that chunk size. Blocks are kept per path,
+/// up to a total number of bytes, after which the least recently used ones are dropped. A file's
+/// blocks are dropped when it is written to, truncated, allocated, created, removed or renamed
+/// through this filesystem; changes made to the files any other way aren't noticed, so this is
+/// only suitable where there are none, or where reading stale data is acceptable.
+///
+/// The wrapped filesystem should return all the data asked for in `read` except at the end of a
+/// file, since a short block is taken to be the end of the file. Anything it returns past the end
+/// of the block asked for is ignored. Hard links are cached separately under each of their names,
+/// so writing through one name doesn't drop what was read through another.
+pub struct ReadCache<FS> {
+    inner: FS,
+    block_size: u32,
+    blocks: Mutex<Blocks>,
+}
+
+impl<FS> ReadCache<FS> {
+    /// Wrap a filesystem, caching up to `capacity` bytes of data read from it, in blocks of
+    /// `block_size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// If `block_size` is zero.
+    pub fn new(inner: FS, capacity: u64, block_size: u32) -> ReadCache<FS> {
+        assert!(block_size > 0, "block size must not be zero");
+        ReadCache {
+            inner,
+            block_size,
+            blocks: Mutex::new(Blocks::new(capacity)),
+        }
+    }
+
+    /// Get the wrapped filesystem.
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    /// The number of bytes of data currently cached.
+    pub fn cached_bytes(&self) -> u64 {
+        self.blocks.lock().unwrap().bytes
+    }
+
+    /// Drop the cached data of a path, and of everything under it.
+    pub fn invalidate(&self, path: &Path) {
+        self.blocks.lock().unwrap().invalidate(path);
+    }
+}
+
+impl<'a, FS: FilesystemMT<'a>> ReadCache<FS> {
+    /// Get a block of a file, from the cache or else from the wrapped filesystem.
+    fn block(&self, req: RequestInfo, path: &'a Path, fh: u64, index: u64) -> Result<Arc<[u8]>, libc::c_int> {
+        let generation = {
+            let mut blocks = self.blocks.lock().unwrap();
+            if let Some(block) = blocks.get(path, index) {
+                return Ok(block);
+            }
+            blocks.generation
+        };
+
+        let mut result: Result<Arc<[u8]>, libc::c_int> = Err(libc::EIO);
+        let offset = index * u64::from(self.block_size);
+        self.inner.read(req, path, fh, offset, self.block_size, |data| {
+            // Don't trust the filesystem to stop at the end of the block, or later blocks would be
+            // out of place.
+            result = data.map(|data| Arc::from(&data[.. data.len().min(self.block_size as usize)]));
+            CallbackResult {
+                _private: std::marker::PhantomData {},
+            }
+        });
+        let block = result?;
+        self.blocks.lock().unwrap().insert(path, index, block.clone(), generation);
+        Ok(block)
+    }
+}
+
+/// A path, and the index of a block in it.
+type BlockKey = (PathBuf, u64);
+
+/// The cached blocks, and the order they were last used in.
+struct Blocks {
+    capacity: u64,
+    bytes: u64,
+    /// Ordered by path, so the blocks of a file, and of everything under a directory, are together.
+    blocks: BTreeMap<BlockKey, (Arc<[u8]>, u64)>,
+    by_use: BTreeMap<u64, BlockKey>,
+    clock: u64,
+    /// Bumped on each invalidation, so that blocks read from before it aren't cached after it.
+    generation: u64,
+}
+
+impl Blocks {
+    fn new(capacity: u64) -> Blocks {
+        Blocks {
+            capacity,
+            bytes: 0,
+            blocks: BTreeMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+            generation: 0,
+        }
+    }
+
+    fn get(&mut self, path: &Path, index: u64) -> Option<Arc<[u8]>> {
+        let key = (path.to_owned(), index);
+        let (block, used) = self.blocks.get_mut(&key)?;
+        self.clock += 1;
+        let entry = self.by_use.remove(used).unwrap();
+        *used = self.clock;
+        self.by_use.insert(self.clock, entry);
+        Some(block.clone())
+    }
+
+    fn insert(&mut self, path: &Path, index: u64, block: Arc<[u8]>, generation: u64) {
+        if generation != self.generation || block.len() as u64 > self.capacity {
+            return;
+        }
+        self.remove(&(path.to_owned(), index));
+        while self.bytes + block.len() as u64 > self.capacity {
+            let (_, key) = self.by_use.pop_first().unwrap();
+            let (evicted, _) = self.blocks.remove(&key).unwrap();
+            self.bytes -= evicted.len() as u64;
+        }
+        self.clock += 1;
+        self.bytes += block.len() as u64;
+        self.by_use.insert(self.clock, (path.to_owned(), index));
+        self.blocks.insert((path.to_owned(), index), (block, self.clock));
+    }
+
+    fn remove(&mut self, key: &BlockKey) {
+        if let Some((block, used)) = self.blocks.remove(key) {
+            self.by_use.remove(&used);
+            self.bytes -= block.len() as u64;
+        }
+    }
+
+    fn invalidate(&mut self, path: &Path) {
+        self.generation += 1;
+        let keys: Vec<BlockKey> = self.blocks.range((path.to_owned(), 0) ..)
+            .map(|(key, _)| key)
+            .take_while(|(block_path, _)| block_path.starts_with(path))
+            .cloned()
+            .collect();
+        if !keys.is_empty() {
+            debug!("read cache: dropping {} blocks of {:?}", keys.len(), path);
+        }
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+}
+
+impl<'a, FS: FilesystemMT<'a>> FilesystemMT<'a> for ReadCache<FS>
+{
+    fn init(&self, req: RequestInfo) -> ResultEmpty {
+        self.inner.init(req)
+    }
+
+    fn init_with_config(&self, req: RequestInfo, config: &mut KernelConfig<'_>) -> ResultEmpty {
+        self.inner.init_with_config(req, config)
+    }
+
+    fn destroy(&self) {
+        self.inner.destroy()
+    }
+
+    fn try_destroy(&self) -> ResultEmpty {
+        self.inner.try_destroy()
+    }
+
+    fn capabilities(&self) -> OpSet {
+        self.inner.capabilities()
+    }
+
+    fn on_forget(&self, path: &'a Path) {
+        self.inner.on_forget(path)
+    }
+
+    fn getattr(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>) -> ResultEntry {
+        self.inner.getattr(req, path, fh)
+    }
+
+    fn setattr(&self, req: RequestInfo, path: &'a Path, attrs: SetAttrRequest) -> ResultEntry {
+        let result = self.inner.setattr(req, path, attrs);
+        if attrs.size.is_some() {
+            self.invalidate(path);
+        }
+        result
+    }
+
+    fn chmod(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, mode: u32) -> ResultEmpty {
+        self.inner.chmod(req, path, fh, mode)
+    }
+
+    fn chown(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, uid: Option<u32>, gid: Option<u32>) -> ResultEmpty {
+        self.inner.chown(req, path, fh, uid, gid)
+    }
+
+    fn truncate(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, size: u64) -> ResultEmpty {
+        let result = self.inner.truncate(req, path, fh, size);
+        self.invalidate(path);
+        result
+    }
+
+    fn utimens(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, atime: Option<crate::TimeOrNow>, mtime: Option<crate::TimeOrNow>) -> ResultEmpty {
+        self.inner.utimens(req, path, fh, atime, mtime)
+    }
+
+    fn utimens_macos(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> ResultEmpty {
+        self.inner.utimens_macos(req, path, fh, crtime, chgtime, bkuptime, flags)
+    }
+
+    fn readlink(&self, req: RequestInfo, path: &'a Path) -> ResultData {
+        self.inner.readlink(req, path)
+    }
+
+    fn mknod(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32, rdev: u32) -> ResultEntry {
+        self.inner.mknod(req, parent, name, mode, umask, rdev)
+    }
+
+    fn mkdir(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32) -> ResultEntry {
+        self.inner.mkdir(req, parent, name, mode, umask)
+    }
+
+    fn unlink(&self, req: RequestInfo, parent: &'a Path, name: &OsStr) -> ResultEmpty {
+        let result = self.inner.unlink(req, parent, name);
+        self.invalidate(&parent.join(name));
+        result
+    }
+
+    fn rmdir(&self, req: RequestInfo, parent: &'a Path, name: &OsStr) -> ResultEmpty {
+        let result = self.inner.rmdir(req, parent, name);
+        self.invalidate(&parent.join(name));
+        result
+    }
+
+    fn symlink(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, target: &Path) -> ResultEntry {
+        self.inner.symlink(req, parent, name, target)
+    }
+
+    fn rename(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr) -> ResultEmpty {
+        let result = self.inner.rename(req, parent, name, newparent, newname);
+        self.invalidate(&parent.join(name));
+        self.invalidate(&newparent.join(newname));
+        result
+    }
+
+    fn exchange(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr) -> ResultEmpty {
+        let result = self.inner.exchange(req, parent, name, newparent, newname);
+        self.invalidate(&parent.join(name));
+        self.invalidate(&newparent.join(newname));
+        result
+    }
+
+    fn rename_flags(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr, flags: u32) -> ResultEmpty {
+        let result = self.inner.rename_flags(req, parent, name, newparent, newname, flags);
+        self.invalidate(&parent.join(name));
+        self.invalidate(&newparent.join(newname));
+        result
+    }
+
+    fn link(&self, req: RequestInfo, path: &'a Path, newparent: &'a Path, newname: &OsStr) -> ResultEntry {
+        self.inner.link(req, path, newparent, newname)
+    }
+
+    fn open(&self, req: RequestInfo, path: &'a Path, flags: u32) -> ResultOpen {
+        self.inner.open(req, path, flags)
+    }
+
+    fn read(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
+        let block_size = u64::from(self.block_size);
+        let first = offset / block_size;
+        let end = offset + u64::from(size);
+        let mut data = vec![];
+        let mut index = first;
+        while index * block_size < end {
+            let block = match self.block(req, path, fh, index) {
+                Ok(block) => block,
+                Err(e) => return callback(Err(e)),
+            };
+            data.extend_from_slice(&block);
+            if block.len() < self.block_size as usize {
+                // The end of the file.
+                break;
+            }
+            index += 1;
+        }
+        let start = ((offset - first * block_size) as usize).min(data.len());
+        let len = (size as usize).min(data.len() - start);
+        callback(Ok(&data[start .. start + len]))
+    }
+
+    fn write(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, data: Vec<u8>, flags: u32) -> ResultWrite {
+        let result = self.inner.write(req, path, fh, offset, data, flags);
+        self.invalidate(path);
+        result
+    }
+
+    fn write_buf(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, data: &[u8], flags: u32) -> ResultWrite {
+        let result = self.inner.write_buf(req, path, fh, offset, data, flags);
+        self.invalidate(path);
+        result
+    }
+
+    fn flush(&self, req: RequestInfo, path: &'a Path, fh: u64, lock_owner: u64) -> ResultEmpty {
+        self.inner.flush(req, path, fh, lock_owner)
+    }
+
+    fn release(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> ResultEmpty {
+        self.inner.release(req, path, fh, flags, lock_owner, flush)
+    }
+
+    fn fsync(&self, req: RequestInfo, path: &'a Path, fh: u64, datasync: bool) -> ResultEmpty {
+        self.inner.fsync(req, path, fh, datasync)
+    }
+
+    fn opendir(&self, req: RequestInfo, path: &'a Path, flags: u32) -> ResultOpen {
+        self.inner.opendir(req, path, flags)
+    }
+
+    fn readdir(&self, req: RequestInfo, path: &'a Path, fh: u64) -> ResultReaddir {
+        self.inner.readdir(req, path, fh)
+    }
+
+    fn readdir_with_attrs(&self, req: RequestInfo, path: &'a Path, fh: u64) -> ResultReaddirAttrs {
+        self.inner.readdir_with_attrs(req, path, fh)
+    }
+
+    fn readdir_offset(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, filler: &mut DirFiller<'_>)
+        -> ResultEmpty
+    {
+        self.inner.readdir_offset(req, path, fh, offset, filler)
+    }
+
+    fn releasedir(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32) -> ResultEmpty {
+        self.inner.releasedir(req, path, fh, flags)
+    }
+
+    fn fsyncdir(&self, req: RequestInfo, path: &'a Path, fh: u64, datasync: bool) -> ResultEmpty {
+        self.inner.fsyncdir(req, path, fh, datasync)
+    }
+
+    fn statfs(&self, req: RequestInfo, path: &'a Path) -> ResultStatfs {
+        self.inner.statfs(req, path)
+    }
+
+    fn setxattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr, value: &[u8], flags: u32, position: u32) -> ResultEmpty {
+        self.inner.setxattr(req, path, name, value, flags, position)
+    }
+
+    fn getxattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr, size: u32) -> ResultXattr {
+        self.inner.getxattr(req, path, name, size)
+    }
+
+    fn listxattr(&self, req: RequestInfo, path: &'a Path, size: u32) -> ResultXattr {
+        self.inner.listxattr(req, path, size)
+    }
+
+    fn removexattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr) -> ResultEmpty {
+        self.inner.removexattr(req, path, name)
+    }
+
+    fn access(&self, req: RequestInfo, path: &'a Path, mask: u32) -> ResultEmpty {
+        self.inner.access(req, path, mask)
+    }
+
+    fn create(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32, flags: u32) -> ResultCreate {
+        let result = self.inner.create(req, parent, name, mode, umask, flags);
+        self.invalidate(&parent.join(name));
+        result
+    }
+
+    fn lseek(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, whence: i32) -> ResultLseek {
+        self.inner.lseek(req, path, fh, offset, whence)
+    }
+
+    fn copy_file_range(&self, req: RequestInfo, path_in: &'a Path, fh_in: u64, offset_in: u64, path_out: &'a Path, fh_out: u64, offset_out: u64, len: u64, flags: u32) -> ResultWrite {
+        let result = self.inner.copy_file_range(req, path_in, fh_in, offset_in, path_out, fh_out, offset_out, len, flags);
+        self.invalidate(path_out);
+        result
+    }
+
+    fn fallocate(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, length: u64, mode: i32) -> ResultEmpty {
+        let result = self.inner.fallocate(req, path, fh, offset, length, mode);
+        self.invalidate(path);
+        result
+    }
+
+    fn ioctl(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32, cmd: u32, in_data: &[u8], out_size: u32) -> ResultIoctl {
+        self.inner.ioctl(req, path, fh, flags, cmd, in_data, out_size)
+    }
+
+    fn poll(&self, req: RequestInfo, path: &'a Path, fh: u64, ph: PollHandle, events: u32, flags: u32) -> ResultPoll {
+        self.inner.poll(req, path, fh, ph, events, flags)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&self, req: RequestInfo, name: &OsStr) -> ResultEmpty {
+        self.inner.setvolname(req, name)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&self, req: RequestInfo, path: &'a Path) -> ResultXTimes {
+        self.inner.getxtimes(req, path)
+    }
+}
+
+#[test]
+fn test_read_cache() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A file of 10 bytes, counting the reads made of it.
+    struct CountingFS {
+        data: Mutex<Vec<u8>>,
+        reads: AtomicUsize,
+    }
+
+    impl FilesystemMT<'_> for CountingFS {
+        fn read(&self, _req: RequestInfo, _path: &Path, _fh: u64, offset: u64, size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            let data = self.data.lock().unwrap();
+            let start = (offset as usize).min(data.len());
+            let end = (start + size as usize).min(data.len());
+            callback(Ok(&data[start .. end]))
+        }
+
+        fn write(&self, _req: RequestInfo, _path: &Path, _fh: u64, offset: u64, data: Vec<u8>, _flags: u32) -> ResultWrite {
+            self.data.lock().unwrap()[offset as usize ..][.. data.len()].copy_from_slice(&data);
+            Ok(data.len() as u32)
+        }
+    }
+
+    let inner = CountingFS { data: Mutex::new(b"0123456789".to_vec()), reads: AtomicUsize::new(0) };
+    let fs = ReadCache::new(inner, 8, 4);
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    let path = Path::new("/file");
+    let read = |offset, size| {
+        let mut data = vec![];
+        fs.read(req, path, 0, offset, size, |result| {
+            data = result.unwrap().to_vec();
+            CallbackResult { _private: std::marker::PhantomData {} }
+        });
+        data
+    };
+    let reads = || fs.inner().reads.load(Ordering::SeqCst);
+
+    // Spanning two blocks reads both; reading within them again doesn't read anything.
+    assert_eq!(b"2345", &read(2, 4)[..]);
+    assert_eq!(2, reads());
+    assert_eq!(b"4567", &read(4, 4)[..]);
+    assert_eq!(2, reads());
+    assert_eq!(8, fs.cached_bytes());
+
+    // The short last block ends the read, and pushes out the least recently used block.
+    assert_eq!(b"6789", &read(6, 100)[..]);
+    assert_eq!(3, reads());
+    assert_eq!(6, fs.cached_bytes());
+    assert_eq!(b"0123", &read(0, 4)[..]);
+    assert_eq!(4, reads());
+
+    // Writing drops the file's blocks.
+    fs.write(req, path, 0, 0, b"ab".to_vec(), 0).unwrap();
+    assert_eq!(0, fs.cached_bytes());
+    assert_eq!(b"ab23", &read(0, 4)[..]);
+    assert_eq!(5, reads());
+}
+
+#[test]
+fn test_read_cache_oversized_reads() {
+    // A filesystem which returns the whole rest of the file, however much is asked for.
+    struct GreedyFS;
+
+    impl FilesystemMT<'_> for GreedyFS {
+        fn read(&self, _req: RequestInfo, _path: &Path, _fh: u64, offset: u64, _size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
+            let data = b"0123456789";
+            callback(Ok(&data[(offset as usize).min(data.len()) ..]))
+        }
+    }
+
+    let fs = ReadCache::new(GreedyFS, 100, 4);
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    let mut data = vec![];
+    fs.read(req, Path::new("/file"), 0, 3, 6, |result| {
+        data = result.unwrap().to_vec();
+        CallbackResult { _private: std::marker::PhantomData {} }
+    });
+    assert_eq!(b"345678", &data[..]);
+    // The three blocks read are cached without the extra data.
+    assert_eq!(4 + 4 + 2, fs.cached_bytes());
+}
+
+#[test]
+fn test_read_cache_invalidate() {
+    let mut blocks = Blocks::new(100);
+    for path in ["/a", "/a/x", "/a/x/y", "/a-b", "/b"] {
+        for index in 0 .. 2 {
+            blocks.insert(Path::new(path), index, Arc::from(&b"x"[..]), 0);
+        }
+    }
+    assert_eq!(10, blocks.bytes);
+
+    // A path's blocks go with those of everything under it, but not those of its neighbours.
+    blocks.invalidate(Path::new("/a"));
+    assert_eq!(4, blocks.bytes);
+    assert!(blocks.get(Path::new("/a/x/y"), 1).is_none());
+    assert!(blocks.get(Path::new("/a-b"), 0).is_some());
+    assert!(blocks.get(Path::new("/b"), 1).is_some());
+    assert_eq!(blocks.blocks.len(), blocks.by_use.len());
+}
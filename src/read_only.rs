@@ -0,0 +1,411 @@
+// ReadOnlyPaths :: a wrapper making parts of a filesystem read-only.
+// ReadOnlyHandle :: a switch for making a whole filesystem read-only while it's mounted.
+//
+
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use crate::types::*;
+
+/// Wraps a filesystem, refusing all changes to paths matching a predicate with `EROFS`, and
+/// passing everything else through.
+///
+/// For example, `ReadOnlyPaths::new(fs, |path| path.starts_with("/system"))` keeps everything
+/// under `/system` as it is, while the rest of the filesystem stays writable. Files under
+/// read-only paths can only be opened for reading, and can't be created, removed, renamed or
+/// linked, or have their attributes or extended attributes changed.
+///
+/// A rename, exchange or link is refused if either its source or its destination is read-only:
+/// moving a file out of a read-only path would remove it from there, and moving one in would add
+/// it. (They fail with `EROFS` rather than `EXDEV`, since `EXDEV` would only make `mv` copy the
+/// file and then fail to remove the original.)
+///
+/// The predicate is only checked against the paths the operations are given, so it should match
+/// whole subtrees: if it matches a directory, it should match everything under it. Renaming a
+/// writable directory which has read-only paths somewhere beneath it isn't detected.
+///
+/// `ioctl` requests are passed through, since only the filesystem knows which of its commands
+/// change anything.
+///
+/// Since this is itself a filesystem, wrappers can be stacked to combine several predicates. A
+/// predicate which is always true makes the whole filesystem read-only.
+pub struct ReadOnlyPaths<FS, F> {
+    inner: FS,
+    read_only: F,
+}
+
+impl<FS, F: Fn(&Path) -> bool> ReadOnlyPaths<FS, F> {
+    /// Wrap a filesystem, making paths for which `read_only` returns true read-only.
+    pub fn new(inner: FS, read_only: F) -> ReadOnlyPaths<FS, F> {
+        ReadOnlyPaths { inner, read_only }
+    }
+
+    /// Get the wrapped filesystem.
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    fn check(&self, path: &Path) -> ResultEmpty {
+        if (self.read_only)(path) {
+            debug!("{:?} is read-only", path);
+            Err(libc::EROFS)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_name(&self, parent: &Path, name: &OsStr) -> ResultEmpty {
+        self.check(&parent.join(name))
+    }
+}
+
+/// Whether `open` flags ask for the file to be changed: opened for writing, or truncated.
+pub(crate) fn opens_for_writing(flags: libc::c_int) -> bool {
+    flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0
+}
+
+impl<'a, FS, F> FilesystemMT<'a> for ReadOnlyPaths<FS, F>
+    where FS: FilesystemMT<'a>,
+          F: Fn(&Path) -> bool,
+{
+    fn init(&self, req: RequestInfo) -> ResultEmpty {
+        self.inner.init(req)
+    }
+
+    fn init_with_config(&self, req: RequestInfo, config: &mut KernelConfig<'_>) -> ResultEmpty {
+        self.inner.init_with_config(req, config)
+    }
+
+    fn destroy(&self) {
+        self.inner.destroy()
+    }
+
+    fn try_destroy(&self) -> ResultEmpty {
+        self.inner.try_destroy()
+    }
+
+    fn capabilities(&self) -> OpSet {
+        self.inner.capabilities()
+    }
+
+    fn on_forget(&self, path: &'a Path) {
+        self.inner.on_forget(path)
+    }
+
+    fn getattr(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>) -> ResultEntry {
+        self.inner.getattr(req, path, fh)
+    }
+
+    fn setattr(&self, req: RequestInfo, path: &'a Path, attrs: SetAttrRequest) -> ResultEntry {
+        self.check(path)?;
+        self.inner.setattr(req, path, attrs)
+    }
+
+    fn chmod(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, mode: u32) -> ResultEmpty {
+        self.check(path)?;
+        self.inner.chmod(req, path, fh, mode)
+    }
+
+    fn chown(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, uid: Option<u32>, gid: Option<u32>) -> ResultEmpty {
+        self.check(path)?;
+        self.inner.chown(req, path, fh, uid, gid)
+    }
+
+    fn truncate(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, size: u64) -> ResultEmpty {
+        self.check(path)?;
+        self.inner.truncate(req, path, fh, size)
+    }
+
+    fn utimens(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, atime: Option<crate::TimeOrNow>, mtime: Option<crate::TimeOrNow>) -> ResultEmpty {
+        self.check(path)?;
+        self.inner.utimens(req, path, fh, atime, mtime)
+    }
+
+    fn utimens_macos(&self, req: RequestInfo, path: &'a Path, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> ResultEmpty {
+        self.check(path)?;
+        self.inner.utimens_macos(req, path, fh, crtime, chgtime, bkuptime, flags)
+    }
+
+    fn readlink(&self, req: RequestInfo, path: &'a Path) -> ResultData {
+        self.inner.readlink(req, path)
+    }
+
+    fn mknod(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32, rdev: u32) -> ResultEntry {
+        self.check_name(parent, name)?;
+        self.inner.mknod(req, parent, name, mode, umask, rdev)
+    }
+
+    fn mkdir(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32) -> ResultEntry {
+        self.check_name(parent, name)?;
+        self.inner.mkdir(req, parent, name, mode, umask)
+    }
+
+    fn unlink(&self, req: RequestInfo, parent: &'a Path, name: &OsStr) -> ResultEmpty {
+        self.check_name(parent, name)?;
+        self.inner.unlink(req, parent, name)
+    }
+
+    fn rmdir(&self, req: RequestInfo, parent: &'a Path, name: &OsStr) -> ResultEmpty {
+        self.check_name(parent, name)?;
+        self.inner.rmdir(req, parent, name)
+    }
+
+    fn symlink(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, target: &Path) -> ResultEntry {
+        self.check_name(parent, name)?;
+        self.inner.symlink(req, parent, name, target)
+    }
+
+    fn rename(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr) -> ResultEmpty {
+        self.check_name(parent, name)?;
+        self.check_name(newparent, newname)?;
+        self.inner.rename(req, parent, name, newparent, newname)
+    }
+
+    fn exchange(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr) -> ResultEmpty {
+        self.check_name(parent, name)?;
+        self.check_name(newparent, newname)?;
+        self.inner.exchange(req, parent, name, newparent, newname)
+    }
+
+    fn rename_flags(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, newparent: &'a Path, newname: &OsStr, flags: u32) -> ResultEmpty {
+        self.check_name(parent, name)?;
+        self.check_name(newparent, newname)?;
+        self.inner.rename_flags(req, parent, name, newparent, newname, flags)
+    }
+
+    fn link(&self, req: RequestInfo, path: &'a Path, newparent: &'a Path, newname: &OsStr) -> ResultEntry {
+        // A new name for a read-only file would be a way to change it.
+        self.check(path)?;
+        self.check_name(newparent, newname)?;
+        self.inner.link(req, path, newparent, newname)
+    }
+
+    fn open(&self, req: RequestInfo, path: &'a Path, flags: u32) -> ResultOpen {
+        if opens_for_writing(flags as libc::c_int) {
+            self.check(path)?;
+        }
+        self.inner.open(req, path, flags)
+    }
+
+    fn read(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
+        self.inner.read(req, path, fh, offset, size, callback)
+    }
+
+    fn write(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, data: Vec<u8>, flags: u32) -> ResultWrite {
+        // Normally the file couldn't have been opened for writing, but the filesystem might not
+        // implement open.
+        self.check(path)?;
+        self.inner.write(req, path, fh, offset, data, flags)
+    }
+
+    fn write_buf(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, data: &[u8], flags: u32) -> ResultWrite {
+        self.check(path)?;
+        self.inner.write_buf(req, path, fh, offset, data, flags)
+    }
+
+    fn flush(&self, req: RequestInfo, path: &'a Path, fh: u64, lock_owner: u64) -> ResultEmpty {
+        self.inner.flush(req, path, fh, lock_owner)
+    }
+
+    fn release(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> ResultEmpty {
+        self.inner.release(req, path, fh, flags, lock_owner, flush)
+    }
+
+    fn fsync(&self, req: RequestInfo, path: &'a Path, fh: u64, datasync: bool) -> ResultEmpty {
+        self.inner.fsync(req, path, fh, datasync)
+    }
+
+    fn opendir(&self, req: RequestInfo, path: &'a Path, flags: u32) -> ResultOpen {
+        self.inner.opendir(req, path, flags)
+    }
+
+    fn readdir(&self, req: RequestInfo, path: &'a Path, fh: u64) -> ResultReaddir {
+        self.inner.readdir(req, path, fh)
+    }
+
+    fn readdir_with_attrs(&self, req: RequestInfo, path: &'a Path, fh: u64) -> ResultReaddirAttrs {
+        self.inner.readdir_with_attrs(req, path, fh)
+    }
+
+    fn readdir_offset(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, filler: &mut DirFiller<'_>)
+        -> ResultEmpty
+    {
+        self.inner.readdir_offset(req, path, fh, offset, filler)
+    }
+
+    fn releasedir(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32) -> ResultEmpty {
+        self.inner.releasedir(req, path, fh, flags)
+    }
+
+    fn fsyncdir(&self, req: RequestInfo, path: &'a Path, fh: u64, datasync: bool) -> ResultEmpty {
+        self.inner.fsyncdir(req, path, fh, datasync)
+    }
+
+    fn statfs(&self, req: RequestInfo, path: &'a Path) -> ResultStatfs {
+        self.inner.statfs(req, path)
+    }
+
+    fn setxattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr, value: &[u8], flags: u32, position: u32) -> ResultEmpty {
+        self.check(path)?;
+        self.inner.setxattr(req, path, name, value, flags, position)
+    }
+
+    fn getxattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr, size: u32) -> ResultXattr {
+        self.inner.getxattr(req, path, name, size)
+    }
+
+    fn listxattr(&self, req: RequestInfo, path: &'a Path, size: u32) -> ResultXattr {
+        self.inner.listxattr(req, path, size)
+    }
+
+    fn removexattr(&self, req: RequestInfo, path: &'a Path, name: &OsStr) -> ResultEmpty {
+        self.check(path)?;
+        self.inner.removexattr(req, path, name)
+    }
+
+    fn access(&self, req: RequestInfo, path: &'a Path, mask: u32) -> ResultEmpty {
+        if mask & libc::W_OK as u32 != 0 {
+            self.check(path)?;
+        }
+        self.inner.access(req, path, mask)
+    }
+
+    fn create(&self, req: RequestInfo, parent: &'a Path, name: &OsStr, mode: u32, umask: u32, flags: u32) -> ResultCreate {
+        self.check_name(parent, name)?;
+        self.inner.create(req, parent, name, mode, umask, flags)
+    }
+
+    fn lseek(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, whence: i32) -> ResultLseek {
+        self.inner.lseek(req, path, fh, offset, whence)
+    }
+
+    fn copy_file_range(&self, req: RequestInfo, path_in: &'a Path, fh_in: u64, offset_in: u64, path_out: &'a Path, fh_out: u64, offset_out: u64, len: u64, flags: u32) -> ResultWrite {
+        self.check(path_out)?;
+        self.inner.copy_file_range(req, path_in, fh_in, offset_in, path_out, fh_out, offset_out, len, flags)
+    }
+
+    fn fallocate(&self, req: RequestInfo, path: &'a Path, fh: u64, offset: u64, length: u64, mode: i32) -> ResultEmpty {
+        self.check(path)?;
+        self.inner.fallocate(req, path, fh, offset, length, mode)
+    }
+
+    fn ioctl(&self, req: RequestInfo, path: &'a Path, fh: u64, flags: u32, cmd: u32, in_data: &[u8], out_size: u32) -> ResultIoctl {
+        self.inner.ioctl(req, path, fh, flags, cmd, in_data, out_size)
+    }
+
+    fn poll(&self, req: RequestInfo, path: &'a Path, fh: u64, ph: PollHandle, events: u32, flags: u32) -> ResultPoll {
+        self.inner.poll(req, path, fh, ph, events, flags)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&self, req: RequestInfo, name: &OsStr) -> ResultEmpty {
+        self.inner.setvolname(req, name)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&self, req: RequestInfo, path: &'a Path) -> ResultXTimes {
+        self.inner.getxtimes(req, path)
+    }
+}
+
+/// A handle for making the whole filesystem read-only and writable again while it's mounted,
+/// obtained with `FuseMT::read_only_handle` or `RawFuseMT::read_only_handle` before mounting.
+///
+/// While it's set, every request which would change anything fails with `EROFS` without reaching
+/// the filesystem, just as if it had been mounted read-only: creating, removing, renaming or
+/// linking entries, writing, changing attributes or extended attributes, opening files for
+/// writing, and `access` checks for write permission. Everything else carries on as normal.
+/// This is meant for when whatever the filesystem is backed by stops accepting changes at
+/// runtime, such as a remote server switching to a degraded mode.
+///
+/// Files which were already opened for writing stay open, but writes to them fail. The kernel
+/// may still be holding writes to them in its page cache, which fail when they're written back.
+///
+/// The handle can be cloned and used from any thread.
+#[derive(Clone, Debug, Default)]
+pub struct ReadOnlyHandle {
+    read_only: Arc<AtomicBool>,
+}
+
+impl ReadOnlyHandle {
+    pub(crate) fn new() -> ReadOnlyHandle {
+        ReadOnlyHandle::default()
+    }
+
+    /// Make the filesystem read-only, or writable again.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
+    }
+
+    /// Whether the filesystem is currently read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+}
+
+#[test]
+fn test_read_only_paths() {
+    // A filesystem which allows everything.
+    struct AnythingFS;
+
+    impl FilesystemMT<'_> for AnythingFS {
+        fn open(&self, _req: RequestInfo, _path: &Path, _flags: u32) -> ResultOpen {
+            Ok((0, 0))
+        }
+
+        fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+            Ok(())
+        }
+
+        fn rename(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _newparent: &Path, _newname: &OsStr) -> ResultEmpty {
+            Ok(())
+        }
+    }
+
+    let fs = ReadOnlyPaths::new(AnythingFS, |path: &Path| path.starts_with("/system"));
+    let fs = ReadOnlyPaths::new(fs, |path: &Path| path == Path::new("/config/locked"));
+    let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    let name = OsStr::new("file");
+    let (root, config, system) = (Path::new("/"), Path::new("/config"), Path::new("/system"));
+
+    assert_eq!(Ok(()), fs.unlink(req, config, name));
+    assert_eq!(Err(libc::EROFS), fs.unlink(req, system, name));
+    assert_eq!(Err(libc::EROFS), fs.unlink(req, config, OsStr::new("locked")));
+    assert_eq!(Err(libc::EROFS), fs.unlink(req, root, OsStr::new("system")));
+
+    assert!(fs.open(req, Path::new("/system/file"), libc::O_RDONLY as u32).is_ok());
+    assert_eq!(Err(libc::EROFS), fs.open(req, Path::new("/system/file"), libc::O_RDWR as u32));
+    assert_eq!(Err(libc::EROFS), fs.open(req, Path::new("/system/file"), (libc::O_RDONLY | libc::O_TRUNC) as u32));
+
+    // Renames can't move things into or out of a read-only area.
+    assert_eq!(Ok(()), fs.rename(req, config, name, root, name));
+    assert_eq!(Err(libc::EROFS), fs.rename(req, config, name, system, name));
+    assert_eq!(Err(libc::EROFS), fs.rename(req, system, name, config, name));
+
+    // Attribute changes made all at once are checked too, before the filesystem says it doesn't
+    // implement them.
+    let truncate = SetAttrRequest { size: Some(0), ..SetAttrRequest::default() };
+    assert_eq!(Some(libc::ENOSYS), fs.setattr(req, Path::new("/config/file"), truncate).err());
+    assert_eq!(Some(libc::EROFS), fs.setattr(req, Path::new("/system/file"), truncate).err());
+}
+
+#[test]
+fn test_read_only_handle() {
+    let handle = ReadOnlyHandle::new();
+    assert!(!handle.is_read_only());
+
+    // Clones are how the switch reaches the session after mounting.
+    let session_side = handle.clone();
+    handle.set_read_only(true);
+    assert!(session_side.is_read_only());
+    handle.set_read_only(false);
+    assert!(!session_side.is_read_only());
+
+    assert!(!opens_for_writing(libc::O_RDONLY | libc::O_NONBLOCK));
+    assert!(opens_for_writing(libc::O_WRONLY));
+    assert!(opens_for_writing(libc::O_RDWR | libc::O_APPEND));
+    assert!(opens_for_writing(libc::O_RDONLY | libc::O_TRUNC));
+}
@@ -0,0 +1,96 @@
+// BackgroundSession :: a handle for a filesystem mounted in the background.
+//
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::mount_options::MountOptions;
+
+/// A filesystem mounted by `spawn_mount` or `spawn_mount_with_options`, which is served by a
+/// background thread until this is dropped or `unmount` is called.
+///
+/// Either way, the filesystem is unmounted and the background thread is waited for, so once this
+/// is gone, none of the filesystem's code is running any more. If the filesystem is still in use
+/// (say, a process has a file open in it), the unmount is done lazily: the mountpoint goes away
+/// straight away, but the filesystem keeps serving requests for the files which are still open,
+/// and this waits until they are closed.
+#[derive(Debug)]
+pub struct BackgroundSession {
+    session: Option<fuser::BackgroundSession>,
+    mountpoint: PathBuf,
+    cleanup: Option<(MountOptions, bool)>,
+}
+
+impl BackgroundSession {
+    pub(crate) fn new(session: fuser::BackgroundSession) -> BackgroundSession {
+        BackgroundSession {
+            mountpoint: session.mountpoint.clone(),
+            session: Some(session),
+            cleanup: None,
+        }
+    }
+
+    /// Clean up the mountpoint according to the given options once the session ends, given
+    /// whether it was created for this mount.
+    pub(crate) fn with_cleanup(mut self, options: &MountOptions, created: bool) -> BackgroundSession {
+        self.cleanup = Some((options.clone(), created));
+        self
+    }
+
+    /// The path the filesystem is mounted at.
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Get a handle for telling the kernel to drop what it has cached about files which have
+    /// changed. See `Notifier` for details.
+    #[cfg(feature = "notify")]
+    pub fn notifier(&self) -> crate::Notifier {
+        let session = self.session.as_ref().expect("session has ended");
+        crate::Notifier::new(session.notifier())
+    }
+
+    /// Drop all the kernel has cached about a file, for when it has changed without the kernel
+    /// knowing. See `Notifier::flush_inode_cache` for details.
+    #[cfg(feature = "notify")]
+    pub fn flush_inode_cache(&self, ino: u64) -> io::Result<()> {
+        self.notifier().flush_inode_cache(ino)
+    }
+
+    /// Unmount the filesystem and wait for the background thread to finish, returning the error
+    /// that ended the session, if any.
+    pub fn unmount(mut self) -> io::Result<()> {
+        self.end()
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        let session = match self.session.take() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        debug!("unmounting {:?}", self.mountpoint);
+
+        // Taking the thread's handle out drops the rest of the session at the end of the block,
+        // which unmounts it, so that the thread then finishes.
+        let thread = {
+            let session = session;
+            session.guard
+        };
+        let result = thread.join().unwrap_or_else(|_| {
+            Err(io::Error::other("filesystem session thread panicked"))
+        });
+
+        if let Some((options, created)) = self.cleanup.take() {
+            options.finish_mountpoint(&self.mountpoint, created, false);
+        }
+        result
+    }
+}
+
+impl Drop for BackgroundSession {
+    fn drop(&mut self) {
+        if let Err(e) = self.end() {
+            error!("filesystem session at {:?} ended with an error: {}", self.mountpoint, e);
+        }
+    }
+}
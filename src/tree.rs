@@ -0,0 +1,276 @@
+// Tree :: an in-memory tree of directories and files, for building RAM-backed filesystems on.
+//
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::SystemTime;
+
+use crate::types::*;
+
+/// The contents of an entry in a `Tree`.
+#[derive(Clone, Debug)]
+pub enum Node {
+    /// A regular file, with its data.
+    File(Vec<u8>),
+    /// A directory, with its entries (not including `.` and `..`).
+    Dir(BTreeMap<OsString, Entry>),
+    /// A symbolic link, with its target.
+    Symlink(PathBuf),
+}
+
+/// An entry in a `Tree`: a node, along with its attributes and extended attributes.
+///
+/// The tree keeps the link counts and change times of directories up to date as entries are
+/// added and removed; everything else in `attr` (including the size of files) is left to the
+/// filesystem.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub node: Node,
+    pub attr: FileAttr,
+    pub xattrs: BTreeMap<OsString, Vec<u8>>,
+}
+
+impl Entry {
+    /// Make an entry with the given contents and attributes, and no extended attributes.
+    pub fn new(node: Node, attr: FileAttr) -> Entry {
+        Entry {
+            node,
+            attr,
+            xattrs: BTreeMap::new(),
+        }
+    }
+
+    /// Whether this entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        matches!(self.node, Node::Dir(_))
+    }
+
+    /// The entries of this directory, or `ENOTDIR` if it isn't one.
+    pub fn entries(&self) -> Result<&BTreeMap<OsString, Entry>, libc::c_int> {
+        match self.node {
+            Node::Dir(ref entries) => Ok(entries),
+            _ => Err(libc::ENOTDIR),
+        }
+    }
+
+    fn entries_mut(&mut self) -> Result<&mut BTreeMap<OsString, Entry>, libc::c_int> {
+        match self.node {
+            Node::Dir(ref mut entries) => Ok(entries),
+            _ => Err(libc::ENOTDIR),
+        }
+    }
+
+    /// Update the link count and times of this directory for an entry being added (`+1`) or
+    /// removed (`-1`).
+    fn entry_changed(&mut self, is_dir: bool, delta: i32) {
+        if is_dir {
+            // Subdirectories link back to this one with their `..` entries.
+            self.attr.nlink = self.attr.nlink.saturating_add_signed(delta);
+        }
+        let now = SystemTime::now();
+        self.attr.mtime = now;
+        self.attr.ctime = now;
+    }
+}
+
+/// Split a path into the names of its components, ignoring the root and any `.` components.
+fn names(path: &Path) -> Result<Vec<&OsStr>, libc::c_int> {
+    let mut names = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => return Err(libc::EINVAL),
+        }
+    }
+    Ok(names)
+}
+
+fn resolve<'a>(root: &'a Entry, path: &Path) -> Result<&'a Entry, libc::c_int> {
+    let mut entry = root;
+    for name in names(path)? {
+        entry = entry.entries()?.get(name).ok_or(libc::ENOENT)?;
+    }
+    Ok(entry)
+}
+
+fn resolve_mut<'a>(root: &'a mut Entry, path: &Path) -> Result<&'a mut Entry, libc::c_int> {
+    let mut entry = root;
+    for name in names(path)? {
+        entry = entry.entries_mut()?.get_mut(name).ok_or(libc::ENOENT)?;
+    }
+    Ok(entry)
+}
+
+/// A thread-safe tree of directories, files and symbolic links, addressed by path, meant to be
+/// wrapped by a `FilesystemMT` implementation which keeps its data in memory.
+///
+/// The whole tree is protected by a single lock, so each operation is atomic with respect to all
+/// the others, and there are no lock ordering problems to worry about (notably, in `rename`).
+/// Reads can go on in parallel, but any change to the tree holds up everything else until it's
+/// done. Hard links aren't supported: each entry has exactly one path.
+///
+/// Errors are the same as the corresponding system calls would return, so they can be passed
+/// straight back to the kernel.
+#[derive(Debug)]
+pub struct Tree {
+    root: RwLock<Entry>,
+}
+
+impl Tree {
+    /// Make a tree with just an empty root directory with the given attributes.
+    pub fn new(root_attr: FileAttr) -> Tree {
+        Tree {
+            root: RwLock::new(Entry::new(Node::Dir(BTreeMap::new()), root_attr)),
+        }
+    }
+
+    fn read_lock(&self) -> RwLockReadGuard<'_, Entry> {
+        self.root.read().unwrap()
+    }
+
+    fn write_lock(&self) -> RwLockWriteGuard<'_, Entry> {
+        self.root.write().unwrap()
+    }
+
+    /// Call a function with the entry at the given path, and return what it returns.
+    pub fn read<R>(&self, path: &Path, f: impl FnOnce(&Entry) -> R) -> Result<R, libc::c_int> {
+        let root = self.read_lock();
+        resolve(&root, path).map(f)
+    }
+
+    /// Call a function with the entry at the given path, allowing it to make changes, and return
+    /// what it returns. This is how file data, attributes and extended attributes are changed.
+    pub fn write<R>(&self, path: &Path, f: impl FnOnce(&mut Entry) -> R) -> Result<R, libc::c_int> {
+        let mut root = self.write_lock();
+        resolve_mut(&mut root, path).map(f)
+    }
+
+    /// Add a new entry to a directory. Fails with `EEXIST` if the name is already taken.
+    pub fn insert(&self, parent: &Path, name: &OsStr, entry: Entry) -> ResultEmpty {
+        let mut root = self.write_lock();
+        let dir = resolve_mut(&mut root, parent)?;
+        let entries = dir.entries_mut()?;
+        if entries.contains_key(name) {
+            return Err(libc::EEXIST);
+        }
+        let is_dir = entry.is_dir();
+        entries.insert(name.to_owned(), entry);
+        dir.entry_changed(is_dir, 1);
+        Ok(())
+    }
+
+    /// Remove a file or symbolic link from a directory, as with `unlink`, and return it.
+    pub fn unlink(&self, parent: &Path, name: &OsStr) -> Result<Entry, libc::c_int> {
+        self.remove(parent, name, |entry| {
+            if entry.is_dir() { Err(libc::EISDIR) } else { Ok(()) }
+        })
+    }
+
+    /// Remove an empty directory, as with `rmdir`, and return it.
+    pub fn rmdir(&self, parent: &Path, name: &OsStr) -> Result<Entry, libc::c_int> {
+        self.remove(parent, name, |entry| {
+            if entry.entries()?.is_empty() { Ok(()) } else { Err(libc::ENOTEMPTY) }
+        })
+    }
+
+    fn remove(&self, parent: &Path, name: &OsStr, check: impl FnOnce(&Entry) -> ResultEmpty)
+        -> Result<Entry, libc::c_int>
+    {
+        let mut root = self.write_lock();
+        let dir = resolve_mut(&mut root, parent)?;
+        let entries = dir.entries_mut()?;
+        check(entries.get(name).ok_or(libc::ENOENT)?)?;
+        let entry = entries.remove(name).unwrap();
+        dir.entry_changed(entry.is_dir(), -1);
+        Ok(entry)
+    }
+
+    /// Move an entry to a new name, possibly in a different directory, as with `rename`.
+    ///
+    /// Anything already at the new name is replaced, with the same restrictions as `rename`: a
+    /// directory can only replace an empty directory, and anything else can't replace a
+    /// directory. A directory can't be moved inside itself.
+    pub fn rename(&self, parent: &Path, name: &OsStr, newparent: &Path, newname: &OsStr) -> ResultEmpty {
+        let oldpath = parent.join(name);
+        let newpath = newparent.join(newname);
+        let mut root = self.write_lock();
+
+        // Check everything before changing anything.
+        let moving_dir = resolve(&root, &oldpath)?.is_dir();
+        if oldpath == newpath {
+            return Ok(());
+        }
+        if newpath.starts_with(&oldpath) {
+            return Err(libc::EINVAL);
+        }
+        match resolve(&root, newparent)?.entries()?.get(newname) {
+            Some(existing) if existing.is_dir() => {
+                if !moving_dir {
+                    return Err(libc::EISDIR);
+                }
+                if !existing.entries()?.is_empty() {
+                    return Err(libc::ENOTEMPTY);
+                }
+            }
+            Some(_) if moving_dir => return Err(libc::ENOTDIR),
+            _ => (),
+        }
+
+        let dir = resolve_mut(&mut root, parent)?;
+        let mut entry = dir.entries_mut()?.remove(name).unwrap();
+        dir.entry_changed(moving_dir, -1);
+        entry.attr.ctime = SystemTime::now();
+
+        let newdir = resolve_mut(&mut root, newparent)?;
+        if let Some(replaced) = newdir.entries_mut()?.insert(newname.to_owned(), entry) {
+            newdir.entry_changed(replaced.is_dir(), -1);
+        }
+        newdir.entry_changed(moving_dir, 1);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_tree() {
+    let attr = |kind, nlink| FileAttr { kind, perm: 0o755, nlink, ..Default::default() };
+    let dir = || Entry::new(Node::Dir(BTreeMap::new()), attr(crate::FileType::Directory, 2));
+    let file = |data: &[u8]| Entry::new(Node::File(data.to_vec()), attr(crate::FileType::RegularFile, 1));
+    let nlink = |tree: &Tree, path: &str| tree.read(Path::new(path), |e| e.attr.nlink).unwrap();
+    let root = Path::new("/");
+
+    let tree = Tree::new(attr(crate::FileType::Directory, 2));
+    tree.insert(root, OsStr::new("a"), dir()).unwrap();
+    tree.insert(Path::new("/a"), OsStr::new("f"), file(b"hello")).unwrap();
+    tree.insert(root, OsStr::new("b"), dir()).unwrap();
+    assert_eq!(Err(libc::EEXIST), tree.insert(root, OsStr::new("a"), dir()));
+    assert_eq!(Err(libc::ENOTDIR), tree.insert(Path::new("/a/f"), OsStr::new("x"), dir()));
+    assert_eq!(4, nlink(&tree, "/"));
+
+    // Directories can't be moved into themselves, or over non-empty directories.
+    assert_eq!(Err(libc::EINVAL), tree.rename(root, OsStr::new("a"), Path::new("/a"), OsStr::new("x")));
+    assert_eq!(Err(libc::ENOTEMPTY), tree.rename(root, OsStr::new("b"), root, OsStr::new("a")));
+    assert_eq!(Err(libc::EISDIR), tree.rename(Path::new("/a"), OsStr::new("f"), root, OsStr::new("b")));
+
+    // Move a directory with its contents into another one.
+    tree.rename(root, OsStr::new("a"), Path::new("/b"), OsStr::new("c")).unwrap();
+    assert_eq!(Err(libc::ENOENT), tree.read(Path::new("/a/f"), |_| ()));
+    let data = tree.read(Path::new("/b/c/f"), |e| match e.node {
+        Node::File(ref data) => data.clone(),
+        _ => panic!("not a file"),
+    });
+    assert_eq!(Ok(b"hello".to_vec()), data);
+    assert_eq!(3, nlink(&tree, "/"));
+    assert_eq!(3, nlink(&tree, "/b"));
+
+    tree.write(Path::new("/b/c/f"), |e| e.xattrs.insert("user.x".into(), b"1".to_vec())).unwrap();
+    assert_eq!(1, tree.read(Path::new("/b/c/f"), |e| e.xattrs.len()).unwrap());
+
+    assert_eq!(Some(libc::ENOTEMPTY), tree.rmdir(Path::new("/b"), OsStr::new("c")).err());
+    assert_eq!(Some(libc::EISDIR), tree.unlink(root, OsStr::new("b")).err());
+    tree.unlink(Path::new("/b/c"), OsStr::new("f")).unwrap();
+    tree.rmdir(Path::new("/b"), OsStr::new("c")).unwrap();
+    assert_eq!(2, nlink(&tree, "/b"));
+}
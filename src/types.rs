@@ -4,10 +4,11 @@
 //
 
 use std::ffi::{OsStr, OsString};
-use std::ops::{Deref, DerefMut};
+use std::ops::{BitAnd, BitOr, Deref, DerefMut};
 use std::path::Path;
 use std::time::{Duration, SystemTime};
-pub use crate::inode_table::Inode;
+pub use crate::inode_table::{GenerationStrategy, Inode};
+pub use crate::kernel_config::KernelConfig;
 
 /// Info about a request.
 #[derive(Clone, Copy, Debug)]
@@ -22,6 +23,118 @@ pub struct RequestInfo {
     pub pid: u32,
 }
 
+impl RequestInfo {
+    /// Linux only: the cgroup of the process making the request, as its path in the cgroup v2
+    /// hierarchy (like `/system.slice/foo.service`), read from `/proc/<pid>/cgroup`.
+    ///
+    /// This reads and parses a file on each call, so it's best only called for the requests that
+    /// need it, such as `open` and `create`, rather than every `read` and `write`. It's also racy:
+    /// by the time the file is read, the process may have moved to another cgroup, or exited and
+    /// had its process ID reused, so it's only as good as checks based on `pid` itself. Some
+    /// requests aren't made on behalf of any process, and have a `pid` of 0; for those, and for
+    /// processes in a PID namespace the filesystem can't see into, this fails with `ENOENT`, as it
+    /// does on systems with no cgroup v2 hierarchy mounted.
+    ///
+    /// ```
+    /// # use fuse_mt::RequestInfo;
+    /// let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: std::process::id() };
+    /// if let Ok(cgroup) = req.cgroup() {
+    ///     assert!(cgroup.is_absolute());
+    /// }
+    /// ```
+    #[cfg(target_os = "linux")]
+    pub fn cgroup(&self) -> std::io::Result<std::path::PathBuf> {
+        if self.pid == 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        let contents = std::fs::read_to_string(format!("/proc/{}/cgroup", self.pid))?;
+        // Each line is "hierarchy-ID:controllers:path"; the v2 hierarchy is the one with ID 0 and
+        // no controllers.
+        contents.lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .map(std::path::PathBuf::from)
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    /// Linux only: the supplementary group IDs of the process making the request, read from
+    /// `/proc/<pid>/status`, for permission checks which honor membership of groups other than
+    /// `gid`. The kernel doesn't pass these along with requests, so this has the same cost and
+    /// races as `cgroup`, and fails with `ENOENT` for the same requests. The list may or may not
+    /// include `gid` itself.
+    ///
+    /// ```
+    /// # use fuse_mt::RequestInfo;
+    /// let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: std::process::id() };
+    /// let groups = req.groups().unwrap();
+    /// let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    /// assert_eq!(count as usize, groups.len());
+    /// ```
+    #[cfg(target_os = "linux")]
+    pub fn groups(&self) -> std::io::Result<Vec<u32>> {
+        if self.pid == 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        let contents = std::fs::read_to_string(format!("/proc/{}/status", self.pid))?;
+        // The line is "Groups:" followed by the IDs, separated by spaces.
+        let groups = contents.lines()
+            .find_map(|line| line.strip_prefix("Groups:"))
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::ENOENT))?;
+        groups.split_whitespace()
+            .map(|gid| gid.parse().map_err(|_| std::io::Error::from_raw_os_error(libc::EIO)))
+            .collect()
+    }
+}
+
+/// Conversions between `FileType` and the file type bits of a mode (`S_IFREG`, `S_IFDIR` and so
+/// on), such as the `mode` given to `mknod` and `create`. `FileType` comes from `fuser`, so these
+/// are in a trait, which `use fuse_mt::*` brings in.
+///
+/// ```
+/// # use fuse_mt::*;
+/// assert_eq!(Some(FileType::Directory), FileType::from_mode(0o40755));
+/// assert_eq!(None, FileType::from_mode(0o644));
+/// assert_eq!(0o100644, FileType::RegularFile.to_mode_bits() | 0o644);
+/// ```
+pub trait FileTypeExt: Sized {
+    /// The file type given by the `S_IFMT` bits of a mode, or `None` if they're zero or not a
+    /// known type.
+    fn from_mode(mode: u32) -> Option<Self>;
+
+    /// The `S_IFMT` bits for this file type, to be combined with permission bits.
+    fn to_mode_bits(self) -> u32;
+}
+
+impl FileTypeExt for crate::FileType {
+    #[allow(clippy::unnecessary_cast)] // mode_t is u16 on macOS
+    fn from_mode(mode: u32) -> Option<crate::FileType> {
+        use crate::FileType::*;
+        Some(match mode & libc::S_IFMT as u32 {
+            kind if kind == libc::S_IFREG as u32 => RegularFile,
+            kind if kind == libc::S_IFDIR as u32 => Directory,
+            kind if kind == libc::S_IFLNK as u32 => Symlink,
+            kind if kind == libc::S_IFIFO as u32 => NamedPipe,
+            kind if kind == libc::S_IFSOCK as u32 => Socket,
+            kind if kind == libc::S_IFCHR as u32 => CharDevice,
+            kind if kind == libc::S_IFBLK as u32 => BlockDevice,
+            _ => return None,
+        })
+    }
+
+    #[allow(clippy::unnecessary_cast)] // mode_t is u16 on macOS
+    fn to_mode_bits(self) -> u32 {
+        use crate::FileType::*;
+        (match self {
+            RegularFile => libc::S_IFREG,
+            Directory => libc::S_IFDIR,
+            Symlink => libc::S_IFLNK,
+            NamedPipe => libc::S_IFIFO,
+            Socket => libc::S_IFSOCK,
+            CharDevice => libc::S_IFCHR,
+            BlockDevice => libc::S_IFBLK,
+        }) as u32
+    }
+}
+
 /// A directory entry.
 #[derive(Clone, Debug)]
 pub struct DirectoryEntry {
@@ -31,24 +144,79 @@ pub struct DirectoryEntry {
     pub kind: crate::FileType,
 }
 
+/// The reply buffer that `FilesystemMT::readdir_offset` adds directory entries to.
+pub struct DirFiller<'a> {
+    add: &'a mut dyn FnMut(u64, i64, crate::FileType, &OsStr) -> bool,
+}
+
+impl<'a> DirFiller<'a> {
+    /// Make a filler which passes entries on to the given function, which returns whether there
+    /// was room for the entry.
+    pub(crate) fn new(add: &'a mut dyn FnMut(u64, i64, crate::FileType, &OsStr) -> bool) -> Self {
+        DirFiller { add }
+    }
+
+    /// Add an entry to the listing.
+    ///
+    /// * `ino`: the entry's inode number. `FuseMT` ignores this, as it has its own.
+    /// * `offset`: the offset to continue the listing from after this entry; it is passed back to
+    ///   `readdir_offset` if the listing is continued from here. It must not be zero, which means
+    ///   the start of the directory.
+    /// * `kind`: the kind of file the entry is.
+    /// * `name`: the entry's name.
+    ///
+    /// Returns whether the entry was added. Once it returns false, the reply is full and the
+    /// entry wasn't added, so `readdir_offset` should return, and the kernel will ask for more
+    /// starting from the offset of the last entry which was added.
+    pub fn add(&mut self, ino: u64, offset: u64, kind: crate::FileType, name: impl AsRef<OsStr>) -> bool {
+        (self.add)(ino, offset as i64, kind, name.as_ref())
+    }
+}
+
+impl std::fmt::Debug for DirFiller<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DirFiller").finish_non_exhaustive()
+    }
+}
+
 /// Filesystem statistics.
-#[derive(Clone, Copy, Debug)]
+///
+/// There is no filesystem ID (`f_fsid`) here because the FUSE protocol has no way to report one:
+/// the kernel fills it in by itself, and for FUSE filesystems leaves it zero. Tools which need
+/// to tell filesystems apart should use the device ID (`st_dev`) from `stat` instead, which the
+/// kernel assigns uniquely to each mount. To export a FUSE filesystem over NFS, give it a fixed
+/// ID with the `fsid=` export option.
+///
+/// These values are passed to the kernel exactly as they are, without any rounding or clamping.
+/// `bfree` counts all free blocks, and `bavail` only those which unprivileged users may use, so
+/// the difference is space reserved for root (or, for a network filesystem, beyond the user's
+/// quota). `df` shows `blocks - bfree` as used and `bavail` as available, so reserved space is
+/// counted as neither, and `bavail` should never be more than `bfree`.
+///
+/// The block counts are in units of `frsize`, which is what `df` and `statvfs` assume. `bsize` is
+/// only reported by `statfs` as the preferred I/O size; it has no effect on the `st_blksize` of
+/// files, which tools like `cp` use to size their buffers. See `FileAttr::to_fuser` for that.
+///
+/// The default has every field zero, for filling in only some of them with
+/// `Statfs { blocks, bfree, bavail, ..Default::default() }`; a real filesystem should at least
+/// set the sizes, since `df` can't make sense of zero ones.
+#[derive(Clone, Copy, Debug, Default)]
 pub struct Statfs {
-    /// Total data blocks in the filesystem
+    /// Total data blocks in the filesystem, in units of `frsize`
     pub blocks: u64,
-    /// Free blocks in filesystem
+    /// Free blocks in filesystem, in units of `frsize`
     pub bfree: u64,
-    /// Free blocks available to unprivileged user
+    /// Free blocks available to unprivileged user, in units of `frsize`
     pub bavail: u64,
     /// Total file nodes in filesystem
     pub files: u64,
     /// Free file nodes in filesystem
     pub ffree: u64,
-    /// Optimal transfer block size
+    /// Optimal transfer block size, as reported by `statfs` (`f_bsize`)
     pub bsize: u32,
     /// Maximum length of filenames
     pub namelen: u32,
-    /// Fragment size
+    /// Fragment size: the unit of the block counts (`f_frsize`)
     pub frsize: u32,
 }
 
@@ -57,7 +225,7 @@ pub struct Statfs {
 pub struct FileAttr {
     /// Size in bytes
     pub size: u64,
-    /// Size in blocks
+    /// Size in 512-byte blocks, regardless of any block size the filesystem reports elsewhere
     pub blocks: u64,
     /// Time of last access
     pub atime: SystemTime,
@@ -65,7 +233,8 @@ pub struct FileAttr {
     pub mtime: SystemTime,
     /// Time of last metadata change
     pub ctime: SystemTime,
-    /// Time of creation (macOS only)
+    /// Time of creation (macOS only). Linux can only be told it with the `FUSE_STATX` request of
+    /// protocol 7.39, which `fuser` doesn't support, so `statx` there never reports a birth time.
     pub crtime: SystemTime,
     /// Kind of file (directory, file, pipe, etc.)
     pub kind: crate::FileType,
@@ -114,6 +283,40 @@ impl From<RawFileAttr> for FileAttr {
     }
 }
 
+/// The attributes of an empty regular file with no permissions and one link, owned by root, with
+/// all its times at the Unix epoch, for filling in only some of the fields with
+/// `FileAttr { size, kind, perm, ..Default::default() }`.
+///
+/// The kind and times should still be set to something meaningful: the kernel and tools trust
+/// the kind over what the file actually is, and times at the epoch make files look decades out
+/// of date to tools like `make` and `rsync`.
+///
+/// ```
+/// # use fuse_mt::*;
+/// let attr = FileAttr { size: 11, kind: FileType::Directory, perm: 0o755, nlink: 2, ..Default::default() };
+/// assert_eq!(0, attr.uid);
+/// assert_eq!(std::time::SystemTime::UNIX_EPOCH, attr.mtime);
+/// ```
+impl Default for FileAttr {
+    fn default() -> FileAttr {
+        FileAttr {
+            size: 0,
+            blocks: 0,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind: crate::FileType::RegularFile,
+            perm: 0,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+}
+
 impl FileAttr {
     /// Convert this `FileAttr` instance to an instance of `RawFileAttr`
     /// by adding an inode and its generation
@@ -124,6 +327,304 @@ impl FileAttr {
             attr: self
         }
     }
+
+    /// Round all the timestamps down to a multiple of `granularity`, which is how precisely the
+    /// filesystem actually stores them.
+    ///
+    /// This matters to tools which compare timestamps, if a filesystem reports more precision
+    /// than it keeps. For example, a file written at 12:00:00.7 might first be reported with that
+    /// time from what the filesystem has in memory, and later as 12:00:00 once it has been stored
+    /// and read back: the file appears to have become older, which can make `make` rebuild
+    /// targets over and over or miss changes, and `rsync` copy files again which haven't changed.
+    /// Reporting the stored precision from the start keeps the times consistent. (Tools still
+    /// have to allow for the coarser times: `make` treats equal times as up to date, and `rsync`
+    /// has its `--modify-window` option for this.)
+    ///
+    /// `FuseMT::time_granularity` and `RawFuseMT::time_granularity` apply this to all attributes
+    /// returned by a filesystem.
+    ///
+    /// ```
+    /// # use std::time::{Duration, SystemTime};
+    /// # use fuse_mt::*;
+    /// let time = SystemTime::UNIX_EPOCH + Duration::new(1_000_000_000, 700_000_000);
+    /// let attr = FileAttr { atime: time, mtime: time, ctime: time, crtime: time, perm: 0o644,
+    ///     ..Default::default() };
+    ///
+    /// let attr = attr.round_times(Duration::from_secs(1));
+    /// assert_eq!(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000), attr.mtime);
+    /// ```
+    pub fn round_times(self, granularity: Duration) -> FileAttr {
+        FileAttr {
+            atime: round_time(self.atime, granularity),
+            mtime: round_time(self.mtime, granularity),
+            ctime: round_time(self.ctime, granularity),
+            crtime: round_time(self.crtime, granularity),
+            ..self
+        }
+    }
+
+    /// Convert this `FileAttr` to the form `fuser` uses, for the given inode number.
+    ///
+    /// `fuser::FileAttr` has a preferred I/O block size (`blksize`), which `FileAttr` lacks; it is
+    /// set to 4096. The kernel doesn't use it at the FUSE protocol version `fuser` currently
+    /// negotiates, and always reports the page size as files' `st_blksize` instead.
+    ///
+    /// This is the whole of FuseMT's part in translating attributes for the kernel, so it can be
+    /// used to check what a filesystem's attributes turn into. The rest of the encoding (file type
+    /// bits, device numbers, timestamps) is done by `fuser` when the reply is sent.
+    ///
+    /// ```
+    /// # use std::time::{Duration, SystemTime};
+    /// # use fuse_mt::*;
+    /// let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    /// let attr = FileAttr { size: 11, blocks: 1, atime: mtime, mtime, ctime: mtime, crtime: mtime,
+    ///     kind: FileType::CharDevice, perm: 0o640, nlink: 1, uid: 1000, gid: 1000, rdev: 0x0501,
+    ///     flags: 0 };
+    ///
+    /// let fuser_attr = attr.to_fuser(42);
+    /// assert_eq!(42, fuser_attr.ino);
+    /// assert_eq!(FileType::CharDevice, fuser_attr.kind);
+    /// assert_eq!(0o640, fuser_attr.perm);
+    /// assert_eq!(0x0501, fuser_attr.rdev);
+    /// assert_eq!(mtime, fuser_attr.mtime);
+    /// ```
+    pub fn to_fuser(self, ino: u64) -> fuser::FileAttr {
+        fuser::FileAttr {
+            ino,
+            size: self.size,
+            blocks: self.blocks,
+            atime: self.atime,
+            mtime: self.mtime,
+            ctime: self.ctime,
+            crtime: self.crtime,
+            kind: self.kind,
+            perm: self.perm,
+            nlink: self.nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: self.rdev,
+            blksize: 4096, // TODO
+            flags: self.flags,
+        }
+    }
+}
+
+/// Round a time down to a multiple of `granularity` since the Unix epoch (so times before the
+/// epoch round away from it).
+fn round_time(time: SystemTime, granularity: Duration) -> SystemTime {
+    let gran = granularity.as_nanos();
+    if gran <= 1 {
+        return time;
+    }
+    let nanos = |d: u128| Duration::new((d / 1_000_000_000) as u64, (d % 1_000_000_000) as u32);
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => {
+            let since = since.as_nanos();
+            SystemTime::UNIX_EPOCH + nanos(since - since % gran)
+        },
+        Err(e) => {
+            let before = e.duration().as_nanos();
+            SystemTime::UNIX_EPOCH - nanos(before.div_ceil(gran) * gran)
+        },
+    }
+}
+
+/// Convert from the attributes `fuser` uses, dropping the inode number and block size.
+impl From<fuser::FileAttr> for FileAttr {
+    fn from(attr: fuser::FileAttr) -> FileAttr {
+        FileAttr {
+            size: attr.size,
+            blocks: attr.blocks,
+            atime: attr.atime,
+            mtime: attr.mtime,
+            ctime: attr.ctime,
+            crtime: attr.crtime,
+            kind: attr.kind,
+            perm: attr.perm,
+            nlink: attr.nlink,
+            uid: attr.uid,
+            gid: attr.gid,
+            rdev: attr.rdev,
+            flags: attr.flags,
+        }
+    }
+}
+
+/// Convert from the metadata of a file in another filesystem, as returned by `std::fs::metadata`,
+/// `std::fs::symlink_metadata` or `File::metadata`, for filesystems which pass files through from
+/// a real directory. Every field is taken from the underlying `stat` values. `crtime` is the
+/// file's creation time where the platform reports one, and the Unix epoch otherwise; `flags` is
+/// only filled in on macOS.
+///
+/// ```
+/// # use fuse_mt::*;
+/// let metadata = std::fs::symlink_metadata(".").unwrap();
+/// let attr = FileAttr::from(&metadata);
+/// assert_eq!(FileType::Directory, attr.kind);
+/// assert_eq!(metadata.modified().unwrap(), attr.mtime);
+/// ```
+#[cfg(unix)]
+impl From<&std::fs::Metadata> for FileAttr {
+    fn from(metadata: &std::fs::Metadata) -> FileAttr {
+        use std::os::unix::fs::MetadataExt;
+
+        let time = |secs: i64, nsecs: i64| {
+            let nsecs = Duration::from_nanos(nsecs as u64);
+            if secs >= 0 {
+                SystemTime::UNIX_EPOCH + Duration::from_secs(secs as u64) + nsecs
+            } else {
+                SystemTime::UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nsecs
+            }
+        };
+
+        // Every type a Unix file can have is covered, so the fallback is never needed.
+        let kind = crate::FileType::from_mode(metadata.mode()).unwrap_or(crate::FileType::RegularFile);
+
+        #[cfg(target_os = "macos")]
+        let flags = std::os::macos::fs::MetadataExt::st_flags(metadata);
+        #[cfg(not(target_os = "macos"))]
+        let flags = 0;
+
+        FileAttr {
+            size: metadata.size(),
+            blocks: metadata.blocks(),
+            atime: time(metadata.atime(), metadata.atime_nsec()),
+            mtime: time(metadata.mtime(), metadata.mtime_nsec()),
+            ctime: time(metadata.ctime(), metadata.ctime_nsec()),
+            crtime: metadata.created().unwrap_or(SystemTime::UNIX_EPOCH),
+            kind,
+            perm: (metadata.mode() & 0o7777) as u16,
+            nlink: metadata.nlink() as u32,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            rdev: metadata.rdev() as u32,
+            flags,
+        }
+    }
+}
+
+/// Helpers for the durations returned along with attributes and entries, which say how long the
+/// kernel may cache them.
+///
+/// The kernel takes these as whole seconds plus nanoseconds, with the seconds as a signed 64-bit
+/// number, so the longest a duration can usefully be is `Ttl::MAX` (about 292 billion years).
+/// Longer durations, up to `Duration::MAX`, are clamped to that before they are passed on, so
+/// they don't overflow into negative (already expired) times. In practice, the kernel counts in
+/// much coarser units and caps the time further itself.
+#[derive(Clone, Copy, Debug)]
+pub struct Ttl;
+
+impl Ttl {
+    /// The longest duration the kernel can be given.
+    pub const MAX: Duration = Duration::from_secs(i64::MAX as u64);
+
+    /// Cache for as long as possible.
+    pub const fn forever() -> Duration {
+        Ttl::MAX
+    }
+
+    /// Don't cache at all: the kernel asks again every time.
+    pub const fn none() -> Duration {
+        Duration::ZERO
+    }
+
+    /// The duration `CreatedEntry::new` uses: one second, the same as libfuse's default.
+    pub const DEFAULT: Duration = Duration::from_secs(1);
+
+    /// Limit a duration to `Ttl::MAX`.
+    pub fn clamp(ttl: Duration) -> Duration {
+        std::cmp::min(ttl, Ttl::MAX)
+    }
+}
+
+/// A flag for `open` and `create` to return, to have the kernel bypass its page cache for the
+/// file: every read and write goes to the filesystem, and reads may return less data than asked
+/// for without it meaning the end of the file. This is for files whose contents can change
+/// without the kernel knowing, or whose size isn't known, such as pipes or generated files.
+pub const FOPEN_DIRECT_IO: u32 = 1 << 0;
+
+/// A flag for `open` and `create` to return, to have the kernel keep the data it has cached for
+/// the file, rather than dropping it as it otherwise does whenever the file is opened. See also
+/// `FOPEN_AUTO_CACHE`.
+pub const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+/// A flag for `open` and `create` to return, to say that offsets are meaningless for the file:
+/// the kernel refuses `lseek` on it, and reads and writes at an offset (`pread`, `pwrite`) fail
+/// with `ESPIPE`. The filesystem still gets an offset, which it should ignore.
+pub const FOPEN_NONSEEKABLE: u32 = 1 << 2;
+
+/// A flag which `open` and `create` can return along with the other `FOPEN_*` flags, to
+/// have FuseMT decide whether the kernel can keep the data it has cached for the file, like the
+/// `auto_cache` option of libfuse.
+///
+/// By default, the kernel drops its cached data for a file whenever the file is opened, unless
+/// `FOPEN_KEEP_CACHE` is returned. With this flag, FuseMT calls `getattr` (with the new file
+/// handle) and compares the file's modification time and size with what they were when it was
+/// last opened; if neither has changed, it returns `FOPEN_KEEP_CACHE` on the filesystem's behalf.
+/// This suits a filesystem whose files can change behind the kernel's back (such as on a server)
+/// but which can't tell by itself whether they have since the last open. The first open of a
+/// file, and any open where `getattr` fails, doesn't keep the cache. This flag itself is never
+/// passed on to the kernel.
+///
+/// The check is only made when a file is opened. Changes while it stays open aren't noticed, so
+/// a file which changes underneath open handles still needs `FOPEN_DIRECT_IO`, or its cached data
+/// dropped some other way, for readers to see them.
+pub const FOPEN_AUTO_CACHE: u32 = 1 << 31;
+
+/// The kernel's handle for a `poll` call waiting on a file, as passed to `FilesystemMT::poll`.
+///
+/// Pass it to `Notifier::poll` to wake the waiters once the file becomes ready. A handle stays
+/// the same for as long as the file is being polled, and may be used more than once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PollHandle(pub(crate) u64);
+
+/// The `flags` bit in `FilesystemMT::poll` saying the kernel wants to be notified, with
+/// `Notifier::poll`, when the file becomes ready.
+pub const FUSE_POLL_SCHEDULE_NOTIFY: u32 = 1 << 0;
+
+/// A flag for `FilesystemMT::rename_flags`, as with `renameat2`: fail with `EEXIST` rather than
+/// replace the entry at the new name, if there is one. (`libc` only defines the `RENAME_*` flags
+/// for Linux, but they're the same in the FUSE protocol everywhere.)
+pub const RENAME_NOREPLACE: u32 = 1 << 0;
+
+/// A flag for `FilesystemMT::rename_flags`, as with `renameat2`: atomically exchange the two
+/// entries, which must both exist. See `FilesystemMT::exchange`.
+pub const RENAME_EXCHANGE: u32 = 1 << 1;
+
+/// A flag for `FilesystemMT::rename_flags`, as with `renameat2`: leave a whiteout (a character
+/// device with device number 0/0) in place of the entry being renamed. This is for overlay and
+/// union filesystems using this one as their upper layer.
+pub const RENAME_WHITEOUT: u32 = 1 << 2;
+
+/// All the attribute changes requested by a single `setattr` call from the kernel, as passed to
+/// `FilesystemMT::setattr`. Each field is `None` if that attribute is to be left alone.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SetAttrRequest {
+    /// A file handle, if the change is made through an open file.
+    pub fh: Option<u64>,
+    /// The new permissions, as for `chmod`.
+    pub mode: Option<u32>,
+    /// The new owner, as for `chown`.
+    pub uid: Option<u32>,
+    /// The new group, as for `chown`.
+    pub gid: Option<u32>,
+    /// The new size, as for `truncate`.
+    pub size: Option<u64>,
+    /// The new access time, as for `utimens`.
+    pub atime: Option<crate::TimeOrNow>,
+    /// The new modification time, as for `utimens`.
+    pub mtime: Option<crate::TimeOrNow>,
+    /// The new change time. This is only sent by macOS, and not passed to any of the separate
+    /// methods.
+    pub ctime: Option<SystemTime>,
+    /// The new creation time, as for `utimens_macos`.
+    pub crtime: Option<SystemTime>,
+    /// The new change time, as for `utimens_macos`.
+    pub chgtime: Option<SystemTime>,
+    /// The new backup time, as for `utimens_macos`.
+    pub bkuptime: Option<SystemTime>,
+    /// The new BSD flags, as for `utimens_macos`.
+    pub flags: Option<u32>,
 }
 
 /// The return value for `create`: contains info on the newly-created file, as well as a handle to
@@ -136,6 +637,41 @@ pub struct CreatedEntry<Attr = FileAttr> where Attr: Copy + Clone {
     pub flags: u32,
 }
 
+impl<Attr: Copy + Clone> CreatedEntry<Attr> {
+    /// Return a new file's attributes and handle, to be cached for `Ttl::DEFAULT` and with no
+    /// flags; change those with `ttl` and `flags`.
+    ///
+    /// ```
+    /// # use std::time::{Duration, SystemTime};
+    /// # use fuse_mt::*;
+    /// # let attr = FileAttr { perm: 0o644, ..Default::default() };
+    /// let entry = CreatedEntry::new(attr, 42)
+    ///     .ttl(Duration::from_secs(10))
+    ///     .flags(FOPEN_KEEP_CACHE);
+    /// assert_eq!((42, FOPEN_KEEP_CACHE), (entry.fh, entry.flags));
+    /// ```
+    pub fn new(attr: Attr, fh: u64) -> CreatedEntry<Attr> {
+        CreatedEntry {
+            ttl: Ttl::DEFAULT,
+            attr,
+            fh,
+            flags: 0,
+        }
+    }
+
+    /// How long the kernel may cache the entry and its attributes.
+    pub fn ttl(mut self, ttl: Duration) -> CreatedEntry<Attr> {
+        self.ttl = ttl;
+        self
+    }
+
+    /// The flags to return, as for `open`.
+    pub fn flags(mut self, flags: u32) -> CreatedEntry<Attr> {
+        self.flags = flags;
+        self
+    }
+}
+
 /// Represents the return value from the `listxattr` and `getxattr` calls, which can be either a
 /// size or contain data, depending on how they are called.
 #[derive(Clone, Debug)]
@@ -154,15 +690,29 @@ pub struct XTimes {
 
 
 pub type ResultEmpty = Result<(), libc::c_int>;
+/// The result of an operation which returns the attributes of an entry, along with how long the
+/// kernel may cache them.
+///
+/// For operations which also create a directory entry in the kernel (`lookup`, `mknod`, `mkdir`,
+/// `symlink`, `link`), the same duration is used both for how long the name stays mapped to the
+/// entry and for how long its attributes stay cached: `fuser` does not support giving them
+/// separate timeouts.
 pub type ResultEntry<Attr = FileAttr> = Result<(Duration, Attr), libc::c_int>;
 pub type ResultOpen = Result<(u64, u32), libc::c_int>;
 pub type ResultReaddir = Result<Vec<DirectoryEntry>, libc::c_int>;
+/// Directory entries, each with the attributes of the file it refers to (and how long they may be
+/// cached) if they are available.
+pub type ResultReaddirAttrs<Attr = FileAttr> =
+    Result<Vec<(DirectoryEntry, Option<(Duration, Attr)>)>, libc::c_int>;
 pub type ResultData = Result<Vec<u8>, libc::c_int>;
 pub type ResultSlice<'a> = Result<&'a [u8], libc::c_int>;
 pub type ResultWrite = Result<u32, libc::c_int>;
 pub type ResultStatfs = Result<Statfs, libc::c_int>;
 pub type ResultCreate<Attr = FileAttr> = Result<CreatedEntry<Attr>, libc::c_int>;
 pub type ResultXattr = Result<Xattr, libc::c_int>;
+pub type ResultLseek = Result<u64, libc::c_int>;
+pub type ResultIoctl = Result<(i32, Vec<u8>), libc::c_int>;
+pub type ResultPoll = Result<u32, libc::c_int>;
 pub type ResultInode = Result<Inode, libc::c_int>;
 
 #[cfg(target_os = "macos")]
@@ -178,27 +728,320 @@ pub struct CallbackResult {
     pub(crate) _private: std::marker::PhantomData<()>,
 }
 
+/// What `FuseMT` and `RawFuseMT` do when the filesystem panics, set with their `panic_mode`
+/// option.
+///
+/// Either way, the panic is logged first, through the `log` crate, after the usual panic message
+/// on stderr. Without this, a panic in an operation which runs on the thread receiving requests
+/// from the kernel (anything but I/O) would end the session, leaving the mount point unusable
+/// until it is unmounted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PanicMode {
+    /// Answer the request with `EIO`, and carry on. Note that any locks the filesystem held
+    /// when it panicked are poisoned, which may make later requests fail too. This is the default.
+    #[default]
+    ReturnEio,
+    /// Abort the process straight away, for filesystems which would rather not keep running in
+    /// an unknown state. The kernel then fails all requests with `ENOTCONN`.
+    Abort,
+}
+
+/// A set of `FilesystemMT` operations, as returned by `FilesystemMT::capabilities`.
+///
+/// There is a constant for each operation, named after its method, and sets can be combined with
+/// `|` and `&`. `READDIR` stands for `readdir`, `readdir_with_attrs` and `readdir_offset`,
+/// and `WRITE` for both `write` and `write_buf`.
+///
+/// ```
+/// # use fuse_mt::*;
+/// let read_only = OpSet::GETATTR | OpSet::OPEN | OpSet::READ | OpSet::OPENDIR | OpSet::READDIR;
+/// assert!(read_only.contains(OpSet::OPEN | OpSet::READ));
+/// assert!(!read_only.contains(OpSet::WRITE));
+/// assert_eq!(read_only, OpSet::ALL.without(OpSet::WRITE) & read_only);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OpSet(u64);
+
+impl OpSet {
+    pub const GETATTR: OpSet = OpSet(1 << 0);
+    pub const CHMOD: OpSet = OpSet(1 << 1);
+    pub const CHOWN: OpSet = OpSet(1 << 2);
+    pub const TRUNCATE: OpSet = OpSet(1 << 3);
+    pub const UTIMENS: OpSet = OpSet(1 << 4);
+    pub const UTIMENS_MACOS: OpSet = OpSet(1 << 5);
+    pub const READLINK: OpSet = OpSet(1 << 6);
+    pub const MKNOD: OpSet = OpSet(1 << 7);
+    pub const MKDIR: OpSet = OpSet(1 << 8);
+    pub const UNLINK: OpSet = OpSet(1 << 9);
+    pub const RMDIR: OpSet = OpSet(1 << 10);
+    pub const SYMLINK: OpSet = OpSet(1 << 11);
+    pub const RENAME: OpSet = OpSet(1 << 12);
+    pub const EXCHANGE: OpSet = OpSet(1 << 13);
+    pub const LINK: OpSet = OpSet(1 << 14);
+    pub const OPEN: OpSet = OpSet(1 << 15);
+    pub const READ: OpSet = OpSet(1 << 16);
+    pub const WRITE: OpSet = OpSet(1 << 17);
+    pub const FLUSH: OpSet = OpSet(1 << 18);
+    pub const RELEASE: OpSet = OpSet(1 << 19);
+    pub const FSYNC: OpSet = OpSet(1 << 20);
+    pub const OPENDIR: OpSet = OpSet(1 << 21);
+    pub const READDIR: OpSet = OpSet(1 << 22);
+    pub const RELEASEDIR: OpSet = OpSet(1 << 23);
+    pub const FSYNCDIR: OpSet = OpSet(1 << 24);
+    pub const STATFS: OpSet = OpSet(1 << 25);
+    pub const SETXATTR: OpSet = OpSet(1 << 26);
+    pub const GETXATTR: OpSet = OpSet(1 << 27);
+    pub const LISTXATTR: OpSet = OpSet(1 << 28);
+    pub const REMOVEXATTR: OpSet = OpSet(1 << 29);
+    pub const ACCESS: OpSet = OpSet(1 << 30);
+    pub const CREATE: OpSet = OpSet(1 << 31);
+    pub const SETVOLNAME: OpSet = OpSet(1 << 32);
+    pub const GETXTIMES: OpSet = OpSet(1 << 33);
+    pub const SETATTR: OpSet = OpSet(1 << 34);
+    pub const LSEEK: OpSet = OpSet(1 << 35);
+    pub const COPY_FILE_RANGE: OpSet = OpSet(1 << 36);
+    pub const FALLOCATE: OpSet = OpSet(1 << 37);
+    pub const IOCTL: OpSet = OpSet(1 << 38);
+    pub const POLL: OpSet = OpSet(1 << 39);
+
+    /// No operations at all.
+    pub const NONE: OpSet = OpSet(0);
+    /// Every operation.
+    pub const ALL: OpSet = OpSet((1 << 40) - 1);
+
+    /// Whether all the operations in `ops` are in this set.
+    pub fn contains(self, ops: OpSet) -> bool {
+        self.0 & ops.0 == ops.0
+    }
+
+    /// This set, minus the operations in `ops`.
+    pub fn without(self, ops: OpSet) -> OpSet {
+        OpSet(self.0 & !ops.0)
+    }
+
+    /// The names of the operations in this set, which are the names of their methods (and of
+    /// their constants, in lower case), in the order of the constants.
+    ///
+    /// ```
+    /// # use fuse_mt::OpSet;
+    /// let names: Vec<&str> = (OpSet::READ | OpSet::GETATTR).names().collect();
+    /// assert_eq!(vec!["getattr", "read"], names);
+    /// ```
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        OP_NAMES.iter()
+            .filter(move |(op, _)| self.contains(*op))
+            .map(|(_, name)| *name)
+    }
+}
+
+const OP_NAMES: [(OpSet, &str); 40] = [
+    (OpSet::GETATTR, "getattr"),
+    (OpSet::CHMOD, "chmod"),
+    (OpSet::CHOWN, "chown"),
+    (OpSet::TRUNCATE, "truncate"),
+    (OpSet::UTIMENS, "utimens"),
+    (OpSet::UTIMENS_MACOS, "utimens_macos"),
+    (OpSet::READLINK, "readlink"),
+    (OpSet::MKNOD, "mknod"),
+    (OpSet::MKDIR, "mkdir"),
+    (OpSet::UNLINK, "unlink"),
+    (OpSet::RMDIR, "rmdir"),
+    (OpSet::SYMLINK, "symlink"),
+    (OpSet::RENAME, "rename"),
+    (OpSet::EXCHANGE, "exchange"),
+    (OpSet::LINK, "link"),
+    (OpSet::OPEN, "open"),
+    (OpSet::READ, "read"),
+    (OpSet::WRITE, "write"),
+    (OpSet::FLUSH, "flush"),
+    (OpSet::RELEASE, "release"),
+    (OpSet::FSYNC, "fsync"),
+    (OpSet::OPENDIR, "opendir"),
+    (OpSet::READDIR, "readdir"),
+    (OpSet::RELEASEDIR, "releasedir"),
+    (OpSet::FSYNCDIR, "fsyncdir"),
+    (OpSet::STATFS, "statfs"),
+    (OpSet::SETXATTR, "setxattr"),
+    (OpSet::GETXATTR, "getxattr"),
+    (OpSet::LISTXATTR, "listxattr"),
+    (OpSet::REMOVEXATTR, "removexattr"),
+    (OpSet::ACCESS, "access"),
+    (OpSet::CREATE, "create"),
+    (OpSet::SETVOLNAME, "setvolname"),
+    (OpSet::GETXTIMES, "getxtimes"),
+    (OpSet::SETATTR, "setattr"),
+    (OpSet::LSEEK, "lseek"),
+    (OpSet::COPY_FILE_RANGE, "copy_file_range"),
+    (OpSet::FALLOCATE, "fallocate"),
+    (OpSet::IOCTL, "ioctl"),
+    (OpSet::POLL, "poll"),
+];
+
+impl BitOr for OpSet {
+    type Output = OpSet;
+    fn bitor(self, other: OpSet) -> OpSet {
+        OpSet(self.0 | other.0)
+    }
+}
+
+impl BitAnd for OpSet {
+    type Output = OpSet;
+    fn bitand(self, other: OpSet) -> OpSet {
+        OpSet(self.0 & other.0)
+    }
+}
+
 /// This trait must be implemented to implement a filesystem with FuseMT.
 pub trait FilesystemMT<'a, T = &'a Path, Attr = FileAttr> where Attr: Copy + Clone  {
     /// Called on mount, before any other function.
+    ///
+    /// Note that the FUSE protocol version and capabilities negotiated with the kernel are not
+    /// available here: `fuser` keeps them private, and only lets capabilities be asked for, with
+    /// `init_with_config`. A filesystem which needs to know whether the
+    /// kernel supports some operation should be prepared for that operation's call to never
+    /// arrive, and treat `ENOSYS` from the kernel as meaning it is unsupported.
     fn init(&self, _req: RequestInfo) -> ResultEmpty {
         Ok(())
     }
 
+    /// Called on mount, before any other function, with the settings for the connection with the
+    /// kernel, which can be changed here: capabilities such as writeback caching, and limits such
+    /// as the largest write. See `KernelConfig`.
+    ///
+    /// This is what `FuseMT` and `RawFuseMT` actually call; the default implementation calls
+    /// `init`, so a filesystem only needs to implement one of the two.
+    fn init_with_config(&self, req: RequestInfo, _config: &mut KernelConfig<'_>) -> ResultEmpty {
+        self.init(req)
+    }
+
+    /// Declare which operations this filesystem actually implements. This is called once, when
+    /// the `FuseMT` or `RawFuseMT` wrapping it is created.
+    ///
+    /// Requests for operations which aren't in the set are answered straight away with the error
+    /// their default implementation returns (`ENOSYS`, or `EINVAL` for `exchange`), without
+    /// calling the filesystem or waiting for a thread from the pool. The exceptions are `setattr`
+    /// requests, which are split into calls to `chmod`, `chown`, `truncate` and so on, and
+    /// `release` and `releasedir`, which always reach the filesystem.
+    ///
+    /// This also lets wrappers around a filesystem find out what it supports without calling
+    /// each operation to see whether it fails with `ENOSYS`.
+    ///
+    /// The default is `OpSet::ALL`, meaning every request is passed on.
+    fn capabilities(&self) -> OpSet {
+        OpSet::ALL
+    }
+
     /// Called on filesystem unmount.
     fn destroy(&self) {
         // Nothing.
     }
 
+    /// Called on filesystem unmount, like `destroy`, but able to fail: for example, if writing out
+    /// buffered data fails. The error can't be passed on to the kernel, which has already let go
+    /// of the mount, but it is logged and kept for `DestroyHandle::result`.
+    ///
+    /// This is what `FuseMT` and `RawFuseMT` actually call; the default implementation calls
+    /// `destroy` and returns `Ok(())`.
+    fn try_destroy(&self) -> ResultEmpty {
+        self.destroy();
+        Ok(())
+    }
+
+    /// Called by `FuseMT` when the kernel has forgotten about a path, so FuseMT no longer has an
+    /// inode for it.
+    ///
+    /// This is a chance to free anything kept for the path, such as cached data or a handle to a
+    /// backend object, which would otherwise be kept until the filesystem is unmounted. The kernel
+    /// forgets paths when it drops them from its caches, and after they are deleted and no longer
+    /// open; `path` is the path the inode last had, which may since have been reused for a new
+    /// file. It is not called for paths still known at unmount.
+    ///
+    /// `RawFuseMT` doesn't call this; it calls `RawFilesystemMT::forget` instead.
+    fn on_forget(&self, _path: T) {
+        // Nothing.
+    }
+
     /// Get the attributes of a filesystem entry.
     ///
     /// * `fh`: a file handle if this is called on an open file.
+    ///
+    /// When `fh` is given, return the attributes of the open file it refers to, which need not
+    /// match whatever is currently at `path`. For example, a filesystem that gives each handle a
+    /// snapshot of the file as of `open` can report the snapshot's attributes here, while calls
+    /// with no handle report the current state of the path. FuseMT passes the handle through
+    /// unchanged, and only uses attributes from `readdir_with_attrs` in place of calls with no
+    /// handle, so the two views are never mixed up.
+    ///
+    /// `FuseMT` also calls this to look up names. If it returns `ENOENT`, the kernel doesn't
+    /// remember that the name doesn't exist, unless `FuseMT::negative_lookup_ttl` is set, so a
+    /// file which appears later is visible right away.
+    ///
+    /// Note that `fuser` does not pass along the handle for `getattr` requests coming from the
+    /// kernel, so currently `fh` is only set when fetching the new attributes after a `setattr`
+    /// on an open file (e.g. `ftruncate` or `fchmod`).
+    ///
+    /// Both `FuseMT` and `RawFuseMT` call this on the thread which receives requests from the
+    /// kernel, not the thread pool, so calls don't overlap and those for the same file never need
+    /// merging. The exception is the call after a truncating `setattr` with `serialize_writes`
+    /// set: that runs on the thread pool, with the file's write lock held, and can overlap with
+    /// calls from the receiving thread. A slow `getattr` holds up every other request, though;
+    /// for listings like `ls -l`, which would otherwise need a call per entry, return attributes
+    /// from `readdir_with_attrs` instead, and give them a long enough TTL that the kernel doesn't
+    /// ask again straight away.
+    ///
+    /// ```
+    /// # use std::collections::HashMap;
+    /// # use std::path::Path;
+    /// # use std::sync::Mutex;
+    /// # use std::time::{Duration, SystemTime};
+    /// # use fuse_mt::*;
+    /// struct SnapshotFS {
+    ///     current: Mutex<FileAttr>,
+    ///     snapshots: Mutex<HashMap<u64, FileAttr>>,
+    /// }
+    ///
+    /// impl FilesystemMT<'_> for SnapshotFS {
+    ///     fn getattr(&self, _req: RequestInfo, _path: &Path, fh: Option<u64>) -> ResultEntry {
+    ///         let attr = match fh {
+    ///             Some(fh) => *self.snapshots.lock().unwrap().get(&fh).ok_or(libc::EBADF)?,
+    ///             None => *self.current.lock().unwrap(),
+    ///         };
+    ///         Ok((Duration::from_secs(1), attr))
+    ///     }
+    /// }
+    ///
+    /// # let attr = FileAttr { perm: 0o644, ..Default::default() };
+    /// # let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    /// let fs = SnapshotFS { current: Mutex::new(attr), snapshots: Mutex::new(HashMap::new()) };
+    ///
+    /// // Open the file, taking a snapshot, and then modify it.
+    /// fs.snapshots.lock().unwrap().insert(1, attr);
+    /// fs.current.lock().unwrap().size = 100;
+    ///
+    /// let path = Path::new("/file");
+    /// assert_eq!(0, fs.getattr(req, path, Some(1)).unwrap().1.size);
+    /// assert_eq!(100, fs.getattr(req, path, None).unwrap().1.size);
+    /// ```
     fn getattr(&self, _req: RequestInfo, _path: T, _fh: Option<u64>) -> ResultEntry<Attr> {
         Err(libc::ENOSYS)
     }
 
+    /// Change any number of attributes of a filesystem entry at once, and return its new
+    /// attributes.
+    ///
+    /// The kernel changes attributes with a single request, which is normally split up into calls
+    /// to `chmod`, `chown`, `truncate`, `utimens` and `utimens_macos` (in that order) for the
+    /// attributes being changed, followed by `getattr`. If one of those fails, the ones before it
+    /// have already taken effect. A filesystem which needs all the changes to be made together,
+    /// such as one backed by a transactional store, can implement this instead, and it is called
+    /// once with all of them. Returning `ENOSYS` (the default) falls back to the separate calls,
+    /// so this can also be implemented for only some combinations of changes.
+    fn setattr(&self, _req: RequestInfo, _path: T, _attrs: SetAttrRequest) -> ResultEntry<Attr> {
+        Err(libc::ENOSYS)
+    }
+
     // The following operations in the FUSE C API are all one kernel call: setattr
-    // We split them out to match the C API's behavior.
+    // We split them out to match the C API's behavior, unless setattr is implemented.
 
     /// Change the mode of a filesystem entry.
     ///
@@ -217,7 +1060,8 @@ pub trait FilesystemMT<'a, T = &'a Path, Attr = FileAttr> where Attr: Copy + Clo
         Err(libc::ENOSYS)
     }
 
-    /// Set the length of a file.
+    /// Set the length of a file. This is also how opening a file with `O_TRUNC` truncates it;
+    /// see `open`.
     ///
     /// * `fh`: a file handle if this is called on an open file.
     /// * `size`: size in bytes to set as the file's length.
@@ -230,7 +1074,12 @@ pub trait FilesystemMT<'a, T = &'a Path, Attr = FileAttr> where Attr: Copy + Clo
     /// * `fh`: a file handle if this is called on an open file.
     /// * `atime`: the time of last access.
     /// * `mtime`: the time of last modification.
-    fn utimens(&self, _req: RequestInfo, _path: T, _fh: Option<u64>, _atime: Option<SystemTime>, _mtime: Option<SystemTime>) -> ResultEmpty {
+    ///
+    /// Each time is `None` if it is to be left unchanged (`UTIME_OMIT` in `utimensat(2)`), and
+    /// `TimeOrNow::Now` if it is to be set to the current time (`UTIME_NOW`, as sent by `touch`
+    /// without a timestamp), so that it can be passed on as such, rather than as a time which
+    /// is already slightly in the past by the time it arrives.
+    fn utimens(&self, _req: RequestInfo, _path: T, _fh: Option<u64>, _atime: Option<crate::TimeOrNow>, _mtime: Option<crate::TimeOrNow>) -> ResultEmpty {
         Err(libc::ENOSYS)
     }
 
@@ -252,8 +1101,9 @@ pub trait FilesystemMT<'a, T = &'a Path, Attr = FileAttr> where Attr: Copy + Clo
     /// * `parent`: path to the directory to make the entry under.
     /// * `name`: name of the entry.
     /// * `mode`: mode for the new entry.
+    /// * `umask`: the umask of the process creating the entry; see `create`.
     /// * `rdev`: if mode has the bits `S_IFCHR` or `S_IFBLK` set, this is the major and minor numbers for the device file. Otherwise it should be ignored.
-    fn mknod(&self, _req: RequestInfo, _parent: T, _name: &OsStr, _mode: u32, _rdev: u32) -> ResultEntry<Attr> {
+    fn mknod(&self, _req: RequestInfo, _parent: T, _name: &OsStr, _mode: u32, _umask: u32, _rdev: u32) -> ResultEntry<Attr> {
         Err(libc::ENOSYS)
     }
 
@@ -262,7 +1112,8 @@ pub trait FilesystemMT<'a, T = &'a Path, Attr = FileAttr> where Attr: Copy + Clo
     /// * `parent`: path to the directory to make the directory under.
     /// * `name`: name of the directory.
     /// * `mode`: permissions for the new directory.
-    fn mkdir(&self, _req: RequestInfo, _parent: T, _name: &OsStr, _mode: u32) -> ResultEntry<Attr> {
+    /// * `umask`: the umask of the process creating the directory; see `create`.
+    fn mkdir(&self, _req: RequestInfo, _parent: T, _name: &OsStr, _mode: u32, _umask: u32) -> ResultEntry<Attr> {
         Err(libc::ENOSYS)
     }
 
@@ -270,6 +1121,15 @@ pub trait FilesystemMT<'a, T = &'a Path, Attr = FileAttr> where Attr: Copy + Clo
     ///
     /// * `parent`: path to the directory containing the file to delete.
     /// * `name`: name of the file to delete.
+    ///
+    /// If the file has other hard links, it stays accessible through them, and its link count
+    /// (`nlink`) should go down by one; see `helpers::remove_link`.
+    ///
+    /// If the file is open, it must stay readable and writable through its open file handles
+    /// until they are all released, as on any Unix filesystem. `FuseMT` keeps the file's inode
+    /// until then, but the calls on those handles still come with the file's old path, which may
+    /// by then name a different file (or none at all); they should use the file handle to find
+    /// the file, not the path.
     fn unlink(&self, _req: RequestInfo, _parent: T, _name: &OsStr) -> ResultEmpty {
         Err(libc::ENOSYS)
     }
@@ -301,11 +1161,68 @@ pub trait FilesystemMT<'a, T = &'a Path, Attr = FileAttr> where Attr: Copy + Clo
         Err(libc::ENOSYS)
     }
 
+    /// Atomically exchange two filesystem entries, as requested by `renameat2` with
+    /// `RENAME_EXCHANGE`. Both entries must exist, and either may be a directory; each takes the
+    /// other's name, along with everything under it.
+    ///
+    /// Files which are open keep referring to the same file, now at its new name; in the
+    /// path-based API, later requests on them are made with their new paths.
+    ///
+    /// The default returns `EINVAL`, which is what the kernel returns for rename flags a
+    /// filesystem doesn't support.
+    ///
+    /// * `parent`: path to the directory containing the first entry.
+    /// * `name`: name of the first entry.
+    /// * `newparent`: path to the directory containing the second entry (may be the same as
+    ///   `parent`).
+    /// * `newname`: name of the second entry.
+    ///
+    /// This is only called if the crate is built with the `rename2` feature, which raises the FUSE
+    /// protocol version used to 7.23; at the default version, the kernel never sends rename flags,
+    /// and fails `renameat2` calls with any flags itself, with `EINVAL`.
+    fn exchange(&self, _req: RequestInfo, _parent: T, _name: &OsStr, _newparent: T, _newname: &OsStr) -> ResultEmpty {
+        Err(libc::EINVAL)
+    }
+
+    /// Rename a filesystem entry, with the flags given to `renameat2`: any of `RENAME_NOREPLACE`,
+    /// `RENAME_EXCHANGE` and `RENAME_WHITEOUT`, or none of them.
+    ///
+    /// This is what `FuseMT` and `RawFuseMT` actually call. The default implementation calls
+    /// `rename` when there are no flags, and `exchange` for `RENAME_EXCHANGE` alone, so a
+    /// filesystem only needs to implement this to support the other flags. It returns `EINVAL`
+    /// for any other flags, which is what a filesystem should return for flags, or combinations
+    /// of them, which it doesn't support; that's what the kernel returns for them too.
+    ///
+    /// * `parent`: path to the directory containing the existing entry.
+    /// * `name`: name of the existing entry.
+    /// * `newparent`: path to the directory it should be renamed into (may be the same as `parent`).
+    /// * `newname`: name of the new entry.
+    /// * `flags`: the `RENAME_*` flags.
+    ///
+    /// The flags are always zero unless the crate is built with the `rename2` feature, which
+    /// raises the FUSE protocol version used to 7.23; at the default version, the kernel never
+    /// sends rename flags, and fails `renameat2` calls with any flags itself, with `EINVAL`.
+    fn rename_flags(&self, req: RequestInfo, parent: T, name: &OsStr, newparent: T, newname: &OsStr, flags: u32) -> ResultEmpty {
+        match flags {
+            0 => self.rename(req, parent, name, newparent, newname),
+            RENAME_EXCHANGE => self.exchange(req, parent, name, newparent, newname),
+            _ => Err(libc::EINVAL),
+        }
+    }
+
     /// Create a hard link.
     ///
     /// * `path`: path to an existing file.
     /// * `newparent`: path to the directory for the new link.
     /// * `newname`: name for the new link.
+    ///
+    /// Return the attributes of the file, with its link count (`nlink`) increased by one; see
+    /// `helpers::add_link`.
+    ///
+    /// `FuseMT` gives each path its own inode, so the kernel sees the new link as a different file
+    /// from the existing one. It keeps the existing file's link count up to date itself when a
+    /// link is added, but when one is removed, the attributes it has cached for the file's other
+    /// names will show the old count until they expire.
     fn link(&self, _req: RequestInfo, _path: T, _newparent: T, _newname: &OsStr) -> ResultEntry<Attr> {
         Err(libc::ENOSYS)
     }
@@ -318,6 +1235,30 @@ pub trait FilesystemMT<'a, T = &'a Path, Attr = FileAttr> where Attr: Copy + Clo
     /// Return a tuple of (file handle, flags). The file handle will be passed to any subsequent
     /// calls that operate on the file, and can be any value you choose, though it should allow
     /// your filesystem to identify the file opened even without any path info.
+    ///
+    /// A filesystem which has no per-open state can leave this unimplemented. The first time it
+    /// returns `ENOSYS`, the kernel (Linux 3.14 and later) treats that as success and stops
+    /// sending `open` and `release` calls altogether: files are then opened without a round-trip
+    /// to the filesystem, and all calls that take a file handle get `0`. Don't rely on this if you
+    /// need to see the open flags or to be told when a file is closed.
+    ///
+    /// `flags` includes `O_NONBLOCK` if the file was opened with it. A file which behaves like a
+    /// pipe or a device should remember this with the file handle, and when it is set, return
+    /// `EAGAIN` from `read` or `write` if no data is available instead of waiting for some; the
+    /// error is passed back to the caller as is. Such a file should also usually return
+    /// `FOPEN_DIRECT_IO` in the returned flags, so that reads bypass the page cache, and
+    /// `FOPEN_NONSEEKABLE` if offsets are meaningless for it.
+    ///
+    /// `flags` never includes `O_TRUNC`. Opening a file with `O_TRUNC` always arrives as an `open`
+    /// followed by a `truncate` to zero with the new file handle, and if that fails, a `release`
+    /// of the handle. This is what the kernel itself does at the FUSE protocol version `fuser`
+    /// uses; kernels which pass `O_TRUNC` along with the open instead (at later versions) get the
+    /// same treatment from FuseMT, so filesystems only ever have to truncate in one place.
+    ///
+    /// The returned flags are a combination of `FOPEN_DIRECT_IO`, `FOPEN_KEEP_CACHE` and
+    /// `FOPEN_NONSEEKABLE`, which are passed on to the kernel, and `FOPEN_AUTO_CACHE`, which lets
+    /// the kernel keep its cached data for the file when it hasn't changed since it was last
+    /// opened.
     fn open(&self, _req: RequestInfo, _path: T, _flags: u32) -> ResultOpen {
         Err(libc::ENOSYS)
     }
@@ -333,9 +1274,75 @@ pub trait FilesystemMT<'a, T = &'a Path, Attr = FileAttr> where Attr: Copy + Clo
     /// * `offset`: offset into the file to start reading.
     /// * `size`: number of bytes to read.
     /// * `callback`: a callback that must be invoked to return the result of the operation: either
-    ///    the result data as a slice, or an error code.
+    ///   the result data as a slice, or an error code.
     ///
     /// Return the return value from the `callback` function.
+    ///
+    /// The length of the slice is the number of bytes read: to return fewer bytes than a buffer
+    /// holds (a short read), pass the callback the part of it that was filled, like
+    /// `&buffer[..n]`. An empty slice means end of file, and the reader sees a read of zero bytes,
+    /// not an error. A slice longer than `size` is cut down to `size`, since the kernel would
+    /// otherwise fail the read with `EIO`. Files opened with `FOPEN_DIRECT_IO` pass short reads
+    /// on to the reader as they are; otherwise the kernel may take a short read to mean the file
+    /// ends there.
+    ///
+    /// A long-running read which gives up partway through (for example, because the process that
+    /// issued it went away) can abort by calling `callback(Err(libc::EINTR))`; this is a valid
+    /// reply and the kernel will pass it on to the reader. Note that `fuser` does not currently
+    /// deliver the kernel's interrupt requests to the filesystem, so there is no way to be told
+    /// that a particular read was interrupted; any such decision has to come from the filesystem
+    /// itself.
+    ///
+    /// A read which would block should return `EAGAIN` if the file was opened with `O_NONBLOCK`
+    /// (see `open`). `read` is not told the file's current flags, so a change made with `fcntl`
+    /// after the file was opened isn't visible here; `write` does get them. A blocking read ties
+    /// up one of FuseMT's I/O threads for as long as it waits, so a filesystem with many such
+    /// files open at once needs enough threads to go around.
+    ///
+    /// ```
+    /// # use std::path::Path;
+    /// # use std::sync::Mutex;
+    /// # use fuse_mt::*;
+    /// // A file that yields whatever has been written to it, like a pipe.
+    /// struct PipeFS {
+    ///     buffer: Mutex<Vec<u8>>,
+    /// }
+    ///
+    /// impl FilesystemMT<'_> for PipeFS {
+    ///     fn open(&self, _req: RequestInfo, _path: &Path, flags: u32) -> ResultOpen {
+    ///         // Use the file handle to remember whether the file is non-blocking.
+    ///         let nonblocking = flags & libc::O_NONBLOCK as u32 != 0;
+    ///         Ok((nonblocking as u64, FOPEN_DIRECT_IO))
+    ///     }
+    ///
+    ///     fn read(&self, _req: RequestInfo, _path: &Path, fh: u64, _offset: u64, size: u32,
+    ///             callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
+    ///         match self.take(fh, size as usize) {
+    ///             Ok(data) => callback(Ok(&data)),
+    ///             Err(e) => callback(Err(e)),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// impl PipeFS {
+    ///     fn take(&self, fh: u64, size: usize) -> Result<Vec<u8>, libc::c_int> {
+    ///         let mut buffer = self.buffer.lock().unwrap();
+    ///         if buffer.is_empty() && fh == 1 {
+    ///             return Err(libc::EAGAIN);
+    ///         }
+    ///         // (A blocking read would wait here for data to arrive.)
+    ///         let len = std::cmp::min(size, buffer.len());
+    ///         Ok(buffer.drain(..len).collect())
+    ///     }
+    /// }
+    ///
+    /// # let req = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    /// let fs = PipeFS { buffer: Mutex::new(vec![]) };
+    /// let (fh, _) = fs.open(req, Path::new("/pipe"), (libc::O_RDONLY | libc::O_NONBLOCK) as u32).unwrap();
+    /// assert_eq!(Err(libc::EAGAIN), fs.take(fh, 4096));
+    /// fs.buffer.lock().unwrap().extend_from_slice(b"hello");
+    /// assert_eq!(Ok(b"hello".to_vec()), fs.take(fh, 4096));
+    /// ```
     fn read(&self, _req: RequestInfo, _path: T, _fh: u64, _offset: u64, _size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
         callback(Err(libc::ENOSYS))
     }
@@ -346,13 +1353,28 @@ pub trait FilesystemMT<'a, T = &'a Path, Attr = FileAttr> where Attr: Copy + Clo
     /// * `fh`: file handle returned from the `open` call.
     /// * `offset`: offset into the file to start writing.
     /// * `data`: the data to write
-    /// * `flags`:
+    /// * `flags`: the file's current flags, as set by `open` or by `fcntl` since.
     ///
-    /// Return the number of bytes written.
+    /// Return the number of bytes written. If the file can't accept any data right now and
+    /// `flags` contains `O_NONBLOCK`, return `EAGAIN` rather than waiting.
     fn write(&self, _req: RequestInfo, _path: T, _fh: u64, _offset: u64, _data: Vec<u8>, _flags: u32) -> ResultWrite {
         Err(libc::ENOSYS)
     }
 
+    /// Write to a file, taking the data by reference.
+    ///
+    /// This is an alternative to `write`, with the same arguments and result, for filesystems
+    /// which pass the data on without keeping it, such as to `pwrite` on a backing file. Both
+    /// `FuseMT` and `RawFuseMT` call this first, and the default implementation returns `ENOSYS`,
+    /// in which case they call `write` instead.
+    ///
+    /// The kernel's buffer is only passed on directly when there is no thread pool (when
+    /// `num_threads` is zero); otherwise the data has to be copied once anyway, to hand it over to
+    /// another thread, and this saves no more than `write` does.
+    fn write_buf(&self, _req: RequestInfo, _path: T, _fh: u64, _offset: u64, _data: &[u8], _flags: u32) -> ResultWrite {
+        Err(libc::ENOSYS)
+    }
+
     /// Called each time a program calls `close` on an open file.
     ///
     /// Note that because file descriptors can be duplicated (by `dup`, `dup2`, `fork`) this may be
@@ -404,6 +1426,17 @@ pub trait FilesystemMT<'a, T = &'a Path, Attr = FileAttr> where Attr: Copy + Clo
     /// Return a tuple of (file handle, flags). The file handle will be passed to any subsequent
     /// calls that operate on the directory, and can be any value you choose, though it should
     /// allow your filesystem to identify the directory opened even without any path info.
+    ///
+    /// As with `open`, returning `ENOSYS` makes the kernel (Linux 5.1 and later) stop sending
+    /// `opendir` and `releasedir` calls. `readdir` is then called with a file handle of `0`, and
+    /// since there is no `releasedir` to say when the listing is finished, FuseMT cannot cache the
+    /// entries between calls: `readdir` is called again for each chunk the kernel requests.
+    ///
+    /// A filesystem which can fetch the attributes of all of a directory's entries at once, such
+    /// as with a single request to a remote store, should do that in `readdir_with_attrs` rather
+    /// than here. `FuseMT` calls it on the first `readdir` after the directory is opened, which is
+    /// before a listing like `ls -l` asks for any attributes, and answers the lookups and
+    /// `getattr` calls which follow from what it returns.
     fn opendir(&self, _req: RequestInfo, _path: T, _flags: u32) -> ResultOpen {
         Err(libc::ENOSYS)
     }
@@ -414,10 +1447,72 @@ pub trait FilesystemMT<'a, T = &'a Path, Attr = FileAttr> where Attr: Copy + Clo
     /// * `fh`: file handle returned from the `opendir` call.
     ///
     /// Return all the entries of the directory.
+    ///
+    /// The listing is all-or-nothing: if an error is returned, the reader gets only the error and
+    /// none of the entries. For a directory which can fail partway through being read (e.g. a
+    /// remote one where the connection drops), the file handle is no help in picking up where the
+    /// last attempt left off, since a retry opens the directory again and gets a new one. To not
+    /// start over each time, keep the entries fetched so far in filesystem-wide state keyed by the
+    /// directory's path, and resume from those on the next `readdir` of the same path.
+    ///
+    /// With `FuseMT`, this is called once per `opendir` (and again after a `rewinddir`) and the
+    /// result is paginated from a cache.
+    /// With `RawFuseMT`, it is called again for each chunk the kernel reads, so a partially-read
+    /// listing can be returned in full up to the point of failure and the error returned from the
+    /// next call, which the reader will see after the entries it already got.
     fn readdir(&self, _req: RequestInfo, _path: T, _fh: u64) -> ResultReaddir {
         Err(libc::ENOSYS)
     }
 
+    /// Get the entries of a directory, along with the attributes of some or all of them.
+    ///
+    /// This is an alternative to `readdir` for filesystems which have the attributes of the
+    /// entries at hand while listing a directory, and it is what `FuseMT` actually calls; the
+    /// default implementation calls `readdir` and returns no attributes.
+    ///
+    /// Attributes returned here are kept by `FuseMT` for the given duration and used to answer
+    /// the next `getattr` (or lookup) of that entry, instead of calling `getattr`. This makes
+    /// listings like `ls -l` cheaper, as they otherwise need a `getattr` call per entry. Each
+    /// returned attribute is used at most once, and is dropped if the file is changed through
+    /// the filesystem in the meantime. Attributes for `.` and `..` are ignored.
+    ///
+    /// If the crate is built with the `readdirplus` feature, `FuseMT` also answers the kernel's
+    /// readdirplus requests from this, which return each entry together with its attributes so
+    /// that the kernel needn't look the entries up one by one afterwards. Entries which have no
+    /// attributes here get them from `getattr`, and are left out if that fails.
+    ///
+    /// `RawFuseMT` doesn't use this, as any caching there is up to the filesystem.
+    fn readdir_with_attrs(&self, req: RequestInfo, path: T, fh: u64) -> ResultReaddirAttrs<Attr> {
+        self.readdir(req, path, fh)
+            .map(|entries| entries.into_iter().map(|entry| (entry, None)).collect())
+    }
+
+    /// Get the entries of a directory a part at a time, for directories too big to return all at
+    /// once from `readdir`.
+    ///
+    /// * `path`: path to the directory.
+    /// * `fh`: file handle returned from the `opendir` call.
+    /// * `offset`: where to continue the listing from: zero for the start of the directory, or
+    ///   otherwise the offset given with an entry previously added to a filler.
+    /// * `filler`: the reply to add entries to, in order, until there are no more or it is full.
+    ///
+    /// The offsets are up to the filesystem, and can be positions in the listing, or cookies from
+    /// the storage backend, so long as each one identifies where the listing continues after that
+    /// entry. The kernel calls this again for each chunk of the listing it reads, and the
+    /// directory is never held in memory as a whole. Returning an error after some entries have
+    /// been added discards them; the reader gets only the error.
+    ///
+    /// The default implementation returns `ENOSYS`, and then both `FuseMT` and `RawFuseMT` call
+    /// `readdir` (or `readdir_with_attrs`) instead, and paginate the entries it returns. Listings
+    /// from this method aren't checked for duplicate names, and don't give `FuseMT` any
+    /// attributes; if the crate is built with the `readdirplus` feature, readdirplus requests are
+    /// answered from this too, getting each entry's attributes from `getattr`.
+    fn readdir_offset(&self, _req: RequestInfo, _path: T, _fh: u64, _offset: u64, _filler: &mut DirFiller<'_>)
+        -> ResultEmpty
+    {
+        Err(libc::ENOSYS)
+    }
+
     /// Close an open directory.
     ///
     /// This will be called exactly once for each `opendir` call.
@@ -495,7 +1590,9 @@ pub trait FilesystemMT<'a, T = &'a Path, Attr = FileAttr> where Attr: Copy + Clo
     /// * `mask`: mode bits to check for access to.
     ///
     /// Return `Ok(())` if all requested permissions are allowed, otherwise return `Err(EACCES)`
-    /// or other error code as appropriate (e.g. `ENOENT` if the file doesn't exist).
+    /// or other error code as appropriate (e.g. `ENOENT` if the file doesn't exist). Checking the
+    /// group permissions properly needs the caller's supplementary groups as well as `req.gid`;
+    /// see `RequestInfo::groups`.
     fn access(&self, _req: RequestInfo, _path: T, _mask: u32) -> ResultEmpty {
         Err(libc::ENOSYS)
     }
@@ -505,11 +1602,140 @@ pub trait FilesystemMT<'a, T = &'a Path, Attr = FileAttr> where Attr: Copy + Clo
     /// * `parent`: path to the directory to create the file in.
     /// * `name`: name of the file to be created.
     /// * `mode`: the mode to set on the new file.
+    /// * `umask`: the umask of the process creating the file.
     /// * `flags`: flags like would be passed to `open`.
     ///
     /// Return a `CreatedEntry` (which contains the new file's attributes as well as a file handle
     /// -- see documentation on `open` for more info on that).
-    fn create(&self, _req: RequestInfo, _parent: T, _name: &OsStr, _mode: u32, _flags: u32) -> ResultCreate<Attr> {
+    ///
+    /// Normally the kernel has already applied the umask to `mode`, and the filesystem should use
+    /// `mode` as it is. With the `dont_mask` option, it doesn't, and it's up to the filesystem to
+    /// clear the umask's bits from `mode`; this is for filesystems which apply default ACLs, since
+    /// a directory's default ACL takes the place of the umask for entries created in it. `umask`
+    /// is only passed with the `umask` feature, and is 0 otherwise, which is also what calls from
+    /// this crate's helpers pass. `mkdir` and `mknod` work the same way.
+    fn create(&self, _req: RequestInfo, _parent: T, _name: &OsStr, _mode: u32, _umask: u32, _flags: u32) -> ResultCreate<Attr> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Check whether an open file is ready for I/O, for `poll(2)`, `select(2)` and `epoll(7)`.
+    ///
+    /// * `path`: path to the file.
+    /// * `fh`: file handle returned from the `open` call.
+    /// * `ph`: the kernel's handle for this poll.
+    /// * `events`: the events being waited for (`POLLIN`, `POLLOUT` and so on, from `libc`).
+    /// * `flags`: if this contains `FUSE_POLL_SCHEDULE_NOTIFY`, the caller is going to wait, and
+    ///   the kernel wants to be told when the file becomes ready.
+    ///
+    /// Return the events which are ready now. If none of those asked for are, and the kernel
+    /// asked to be notified, keep `ph` and call `Notifier::poll` with it once they are (say, from
+    /// the thread which receives the data); the kernel then polls again.
+    ///
+    /// The default returns `events`, so that the file is always ready, as it is for filesystems
+    /// which don't support polling at all.
+    ///
+    /// This is only called if the crate is built with the `poll` feature, which also enables
+    /// `notify`.
+    fn poll(&self, _req: RequestInfo, _path: T, _fh: u64, _ph: PollHandle, events: u32, _flags: u32) -> ResultPoll {
+        Ok(events)
+    }
+
+    /// Handle an `ioctl(2)` on an open file.
+    ///
+    /// * `path`: path to the file.
+    /// * `fh`: file handle returned from the `open` call.
+    /// * `flags`: `FUSE_IOCTL_*` flags; `FUSE_IOCTL_COMPAT` (`1 << 0`) is set for a call made by a
+    ///   32-bit process on a 64-bit system, which may lay its argument out differently.
+    /// * `cmd`: the ioctl command.
+    /// * `in_data`: the data the command passes in.
+    /// * `out_size`: the size of the data the command takes back out.
+    ///
+    /// Return the value for `ioctl` to return (normally 0), and the data to pass out, which must
+    /// be no longer than `out_size`.
+    ///
+    /// The kernel only sends commands which say how much data they pass each way, in the
+    /// direction and size bits of the command number (as encoded by the `_IOR`, `_IOW` and
+    /// `_IOWR` macros), and `in_data` and `out_size` are sized from those. Commands which don't
+    /// (those the kernel calls unrestricted, which it only sends to CUSE devices) are refused
+    /// with `ENOSYS` by `fuser` before getting here. Ioctls on directories are only sent at
+    /// protocol 7.18 or later, which the `notify` feature (and so `poll`) raises it to; they have
+    /// `FUSE_IOCTL_DIR` (`1 << 4`) set in `flags`, and `fh` is the handle returned from `opendir`.
+    ///
+    /// `helpers::cas_write` answers `helpers::CAS_WRITE_IOCTL`, a compare-and-swap write for
+    /// building coordination on top of a filesystem.
+    ///
+    /// This is only called if the crate is built with the `ioctl` feature, which raises the FUSE
+    /// protocol version used to 7.11; at the default version, the kernel never sends it.
+    #[allow(clippy::too_many_arguments)]
+    fn ioctl(&self, _req: RequestInfo, _path: T, _fh: u64, _flags: u32, _cmd: u32, _in_data: &[u8], _out_size: u32) -> ResultIoctl {
+        Err(libc::ENOSYS)
+    }
+
+    /// Allocate or deallocate space for part of a file, as requested by `fallocate(2)` or
+    /// `posix_fallocate(3)`.
+    ///
+    /// * `path`: path to the file.
+    /// * `fh`: file handle returned from the `open` call.
+    /// * `offset`: offset in the file of the range.
+    /// * `length`: length of the range.
+    /// * `mode`: `0` to allocate the range, extending the file if it goes past the end, or a
+    ///   combination of the `FALLOC_FL_*` flags from `libc`: with `FALLOC_FL_KEEP_SIZE`, the size
+    ///   of the file isn't changed, and with `FALLOC_FL_PUNCH_HOLE` (which always comes with
+    ///   `FALLOC_FL_KEEP_SIZE`), the range is deallocated instead, so it reads back as zeroes.
+    ///
+    /// Return `EOPNOTSUPP` for modes which aren't supported. The default returns `ENOSYS`, after
+    /// which the kernel stops asking and fails all `fallocate` calls with `EOPNOTSUPP`;
+    /// `posix_fallocate` then falls back to writing to the range.
+    ///
+    /// This is only called if the crate is built with the `fallocate` feature, which raises the
+    /// FUSE protocol version used to 7.19; at the default version, the kernel never sends it.
+    fn fallocate(&self, _req: RequestInfo, _path: T, _fh: u64, _offset: u64, _length: u64, _mode: i32) -> ResultEmpty {
+        Err(libc::ENOSYS)
+    }
+
+    /// Find where data or a hole starts in a file, for `lseek` with `SEEK_DATA` or `SEEK_HOLE`.
+    /// Other kinds of seek are handled by the kernel and never get here.
+    ///
+    /// * `path`: path to the file.
+    /// * `fh`: file handle returned from the `open` call.
+    /// * `offset`: offset to start looking from.
+    /// * `whence`: `SEEK_DATA` to find the first data at or after `offset`, or `SEEK_HOLE` to find
+    ///   the first hole. The end of the file counts as a hole.
+    ///
+    /// Return the offset found, or `ENXIO` if `offset` is at or past the end of the file (or, for
+    /// `SEEK_DATA`, if there is only a hole after it).
+    ///
+    /// The default returns `ENOSYS`, after which the kernel stops asking and treats the whole file
+    /// as data: `SEEK_DATA` returns `offset` and `SEEK_HOLE` returns the size of the file.
+    ///
+    /// This is only called if the crate is built with the `lseek` feature, which raises the FUSE
+    /// protocol version used to 7.24; at the default version, the kernel never sends it.
+    fn lseek(&self, _req: RequestInfo, _path: T, _fh: u64, _offset: u64, _whence: i32) -> ResultLseek {
+        Err(libc::ENOSYS)
+    }
+
+    /// Copy data from one open file to another, as requested by `copy_file_range(2)`, without it
+    /// passing through the kernel: a filesystem which can copy data itself (by sharing it between
+    /// files, or asking a remote store to do it) can make the copy much cheaper this way.
+    ///
+    /// * `path_in`: path to the file to copy from.
+    /// * `fh_in`: file handle of the file to copy from, returned from the `open` call.
+    /// * `offset_in`: offset in the file to copy from.
+    /// * `path_out`: path to the file to copy to (may be the same file).
+    /// * `fh_out`: file handle of the file to copy to, returned from the `open` call.
+    /// * `offset_out`: offset in the file to copy to.
+    /// * `len`: number of bytes to copy.
+    /// * `flags`: flags passed to `copy_file_range`; currently always zero.
+    ///
+    /// Return the number of bytes copied, which, as with `write`, may be fewer than asked for.
+    ///
+    /// The default returns `ENOSYS`, after which the kernel stops asking and does the copy with
+    /// `read` and `write` instead.
+    ///
+    /// This is only called if the crate is built with the `copy_file_range` feature, which raises
+    /// the FUSE protocol version used to 7.28; at lower versions, the kernel never sends it.
+    #[allow(clippy::too_many_arguments)]
+    fn copy_file_range(&self, _req: RequestInfo, _path_in: T, _fh_in: u64, _offset_in: u64, _path_out: T, _fh_out: u64, _offset_out: u64, _len: u64, _flags: u32) -> ResultWrite {
         Err(libc::ENOSYS)
     }
 
@@ -553,6 +1779,9 @@ pub trait RawFilesystemMT: for <'a> FilesystemMT<'a, Inode, RawFileAttr> {
     /// This method is used to find a file or directory by its name within a parent directory.
     /// It returns the attributes of the found file or directory, or an error if the lookup fails.
     ///
+    /// An `ENOENT` error is not remembered by the kernel, unless `RawFuseMT::negative_lookup_ttl`
+    /// is set.
+    ///
     /// # Arguments
     ///
     /// * `_req` - The request information.
@@ -633,4 +1862,26 @@ pub trait RawFilesystemMT: for <'a> FilesystemMT<'a, Inode, RawFileAttr> {
     /// }
     /// ```
     fn parent(&self, _req: RequestInfo, _path: Inode) -> ResultInode;
+
+    /// Returns the inode number of the root directory of the filesystem.
+    ///
+    /// The kernel always refers to the root directory by node ID 1 (`fuser::FUSE_ROOT_ID`), but a
+    /// filesystem backed by some other store may want to use that store's own inode number for
+    /// the root instead. If so, override this; it is called once, when `RawFuseMT` is created.
+    ///
+    /// `RawFuseMT` then passes this inode to the filesystem wherever the kernel uses node ID 1,
+    /// and translates it back to node ID 1 in replies. If the filesystem also has an inode
+    /// numbered 1, the two are swapped, so that it is still reachable under the root's number.
+    ///
+    /// Note that this means the node ID the kernel uses and the `st_ino` reported by `stat` can
+    /// differ: `getattr` on the root reports this inode number as `st_ino`, so `stat` on the root
+    /// shows it, while other nodes report their node ID. Entries returned by `lookup`, `mkdir`,
+    /// etc. always report their node ID as `st_ino`, because `fuser` uses one field for both there.
+    ///
+    /// # Returns
+    ///
+    /// * `Inode` - The root inode. Defaults to `fuser::FUSE_ROOT_ID`.
+    fn root(&self) -> Inode {
+        fuser::FUSE_ROOT_ID
+    }
 }
\ No newline at end of file
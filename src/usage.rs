@@ -0,0 +1,93 @@
+// Usage :: counters of the resources held by requests which are being handled.
+//
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+#[derive(Debug, Default)]
+struct Counters {
+    buffered_bytes: AtomicU64,
+    peak_buffered_bytes: AtomicU64,
+    in_flight: AtomicUsize,
+}
+
+/// A handle for reading how much the filesystem is holding on to for requests in flight, obtained
+/// with `FuseMT::usage_handle` or `RawFuseMT::usage_handle`.
+///
+/// Only the requests which are dispatched to the thread pool (`read`, `write`, `flush`, `fsync`,
+/// and the like) are counted. These are in flight from when they are queued until their reply is
+/// sent, including any time spent waiting for a free thread. Other requests are handled one at a
+/// time on the thread taking requests from the kernel.
+///
+/// The buffered bytes are the data of `write` requests, which has to be copied for the thread pool,
+/// and the requested size of `read` requests, which bounds the reply being put together. Memory
+/// used by the filesystem itself is not included.
+///
+/// The handle can be cloned and used from any thread.
+#[derive(Clone, Debug, Default)]
+pub struct UsageHandle {
+    counters: Arc<Counters>,
+}
+
+impl UsageHandle {
+    pub(crate) fn new() -> UsageHandle {
+        UsageHandle::default()
+    }
+
+    /// The number of bytes currently buffered for requests in flight.
+    pub fn current_buffered_bytes(&self) -> u64 {
+        self.counters.buffered_bytes.load(Ordering::Relaxed)
+    }
+
+    /// The largest number of bytes that have been buffered at once since mounting.
+    pub fn peak_buffered_bytes(&self) -> u64 {
+        self.counters.peak_buffered_bytes.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests currently in flight.
+    pub fn in_flight_count(&self) -> usize {
+        self.counters.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Count a request as in flight, along with the bytes buffered for it, until the returned
+    /// guard is dropped.
+    pub(crate) fn track(&self, bytes: u64) -> UsageGuard {
+        self.counters.in_flight.fetch_add(1, Ordering::Relaxed);
+        let buffered = self.counters.buffered_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.counters.peak_buffered_bytes.fetch_max(buffered, Ordering::Relaxed);
+        UsageGuard { counters: self.counters.clone(), bytes }
+    }
+}
+
+pub(crate) struct UsageGuard {
+    counters: Arc<Counters>,
+    bytes: u64,
+}
+
+impl Drop for UsageGuard {
+    fn drop(&mut self) {
+        self.counters.buffered_bytes.fetch_sub(self.bytes, Ordering::Relaxed);
+        self.counters.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn test_usage() {
+    let handle = UsageHandle::new();
+    let first = handle.track(100);
+    let second = handle.track(50);
+    assert_eq!(2, handle.in_flight_count());
+    assert_eq!(150, handle.current_buffered_bytes());
+
+    drop(first);
+    let third = handle.track(0);
+    assert_eq!(2, handle.in_flight_count());
+    assert_eq!(50, handle.current_buffered_bytes());
+    assert_eq!(150, handle.peak_buffered_bytes());
+
+    drop(second);
+    drop(third);
+    assert_eq!(0, handle.in_flight_count());
+    assert_eq!(0, handle.current_buffered_bytes());
+    assert_eq!(150, handle.peak_buffered_bytes());
+}